@@ -0,0 +1,189 @@
+//! Headless runs for scripts: run a program to its end and return how it
+//! ended, instead of logging it.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    callstack::CallStack,
+    core::{
+        insts::Xlen,
+        rng::Rng,
+        uninit::{Uninit, UninitFill},
+        vm::VirtualMemory,
+    },
+    elf::{read_elf, LoadElfInfo},
+    error::{Error, Result},
+    multi_stage::cpu::{
+        ControlPolicy, DataHazardPolicy, MultistageCPU, PipelineConfig, PredictPolicy,
+        CPU as PipelineCPU,
+    },
+    single_cycle::cpu::CPU as SingleCycleCPU,
+    syscall::SyscallAbi,
+    CPUMode,
+};
+
+/// CPU and limits of a headless run
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    pub cpu_mode: CPUMode,
+    pub data_hazard_policy: DataHazardPolicy, // pipeline only
+    pub control_policy: ControlPolicy,        // pipeline only
+    pub predict_policy: Option<PredictPolicy>,
+    pub syscall_abi: SyscallAbi,
+    pub load_bias: Option<u64>, // of position-independent executables, by class if none
+    pub uninit: Uninit,
+    pub seed: u64, // of every randomized feature
+    pub max_cycles: Option<u64>,
+    pub args: Vec<String>, // passed to the program after its path
+    pub stdin: Vec<u8>,    // read by the program
+}
+
+/// How a program ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunResult {
+    pub exit_code: i64, // by exit or ebreak, sign-extended from XLEN bits
+    pub clock: u64,
+    pub retired: u64,
+}
+
+impl std::fmt::Display for RunResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "exit_code={} clock={} retired={}",
+            self.exit_code, self.clock, self.retired
+        )
+    }
+}
+
+/// Load the ELF file at `path` and run it to its end.
+pub fn run_elf(path: &Path, config: &RunConfig) -> Result<RunResult> {
+    let info = read_elf(&PathBuf::from(path), config.load_bias)?;
+    let args: Vec<String> = std::iter::once(path.display().to_string())
+        .chain(config.args.iter().cloned())
+        .collect();
+    run(&info, &args, config)
+}
+
+/// Run the program loaded from `info` with argv `args` to its end. The run
+/// fails if it stops without exiting.
+fn run(info: &LoadElfInfo, args: &[String], config: &RunConfig) -> Result<RunResult> {
+    let xlen = if info.is_64_bit() {
+        Xlen::Rv64
+    } else {
+        Xlen::Rv32
+    };
+    let mut vm = VirtualMemory::from_elf_info(info, false);
+    let mut rng = Rng::new(config.seed);
+    let mut uninit_fill = UninitFill::new(config.uninit, rng.fork());
+    vm.fill_uninit(info, &mut uninit_fill);
+    let mut callstack = CallStack::from_elf_info(info, false);
+
+    let (result, exit_code, clock, retired) = match config.cpu_mode {
+        CPUMode::Single => {
+            let mut cpu = SingleCycleCPU::new(
+                &mut vm,
+                &mut callstack,
+                false,
+                false,
+                false,
+                config.syscall_abi,
+                xlen,
+            );
+            cpu.fill_uninit_regs(&mut uninit_fill);
+            cpu.init_elfinfo_64(info, args)?;
+            cpu.set_max_cycles(config.max_cycles);
+            cpu.set_stdin(config.stdin.clone());
+            let result = cpu.cpu_exec(None);
+            (result, cpu.exit_code(), cpu.cycles(), cpu.retired())
+        }
+        CPUMode::Multi => {
+            let mut cpu = MultistageCPU::new(
+                &mut vm,
+                &mut callstack,
+                false,
+                false,
+                config.syscall_abi,
+                xlen,
+            );
+            cpu.fill_uninit_regs(&mut uninit_fill);
+            cpu.init_elfinfo_64(info, args)?;
+            cpu.set_max_cycles(config.max_cycles);
+            cpu.set_stdin(config.stdin.clone());
+            let result = cpu.cpu_exec(None);
+            (result, cpu.exit_code(), cpu.cycles(), cpu.retired())
+        }
+        CPUMode::Pipeline => {
+            let mut cpu = PipelineCPU::new(
+                &mut vm,
+                &mut callstack,
+                PipelineConfig {
+                    data_hazard_policy: config.data_hazard_policy,
+                    control_policy: config.control_policy,
+                    predict_policy: config.predict_policy,
+                    syscall_abi: config.syscall_abi,
+                    xlen,
+                    ..Default::default()
+                },
+            );
+            cpu.fill_uninit_regs(&mut uninit_fill);
+            cpu.init_elfinfo_64(info, args)?;
+            cpu.set_max_cycles(config.max_cycles);
+            cpu.set_stdin(config.stdin.clone());
+            let result = cpu.cpu_exec(None);
+            (result, cpu.exit_code(), cpu.cycles(), cpu.retired())
+        }
+    };
+
+    // the single-cycle CPU stops ebreak with an error after halting
+    let Some(exit_code) = exit_code else {
+        result?;
+        return Err(Error::Execute(
+            "Program stopped without exiting".to_string(),
+        ));
+    };
+    Ok(RunResult {
+        exit_code: xlen.sext(exit_code) as i64,
+        clock,
+        retired,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{core::asm::assemble, elf::read_flat_binary, temp_path};
+
+    #[test]
+    fn run_exit_code() {
+        let program = ["li a0, 42", "li a7, 93", "ecall"];
+        let image: Vec<u8> = program
+            .iter()
+            .flat_map(|line| assemble(line).unwrap().to_le_bytes())
+            .collect();
+        let path = temp_path("run-exit-code.bin");
+        std::fs::write(&path, image).unwrap();
+        let info = read_flat_binary(&path, 0x8000_0000, 0).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        for cpu_mode in [CPUMode::Single, CPUMode::Multi, CPUMode::Pipeline] {
+            let config = RunConfig {
+                cpu_mode: cpu_mode.clone(),
+                data_hazard_policy: DataHazardPolicy::DataForward,
+                control_policy: ControlPolicy::AlwaysNotTaken,
+                predict_policy: None,
+                syscall_abi: SyscallAbi::Linux,
+                load_bias: None,
+                uninit: Uninit::Zero,
+                seed: 0,
+                max_cycles: Some(1000),
+                args: Vec::new(),
+                stdin: Vec::new(),
+            };
+            let result = run(&info, &["prog".to_string()], &config).unwrap();
+            assert_eq!(result.exit_code, 42, "{cpu_mode:?}");
+            assert_eq!(result.retired, 3, "{cpu_mode:?}");
+            assert!(result.clock >= result.retired, "{cpu_mode:?}");
+        }
+    }
+}
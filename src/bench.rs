@@ -0,0 +1,67 @@
+//! Throughput of the emulator itself for `--benchmark`: guest instructions
+//! and cycles run per second of host time.
+
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+
+/// Host time of a run, from just before the CPU starts executing
+#[derive(Debug, Clone, Copy)]
+pub struct Benchmark {
+    start: Instant,
+}
+
+impl Benchmark {
+    pub fn start() -> Benchmark {
+        Benchmark {
+            start: Instant::now(),
+        }
+    }
+}
+
+/// End the run of the `cpu` CPU with `result`, having retired `retired`
+/// instructions in `cycles` cycles. A benchmarked run is reported on stdout,
+/// and may end at `--max-cycles` without an error.
+pub fn finish(
+    benchmark: Option<Benchmark>,
+    cpu: &str,
+    retired: u64,
+    cycles: u64,
+    result: Result<()>,
+) -> Result<()> {
+    let Some(benchmark) = benchmark else {
+        return result;
+    };
+    let elapsed = benchmark.start.elapsed();
+    println!("{}", report(cpu, retired, cycles, elapsed));
+    match result {
+        Err(Error::CycleLimitExceeded(_)) => Ok(()),
+        result => result,
+    }
+}
+
+fn report(cpu: &str, retired: u64, cycles: u64, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    format!(
+        "Retired {retired} insts in {secs:.2}s ({:.1} MIPS), {cycles} cycles ({:.1} M cycles/s) on the {cpu} CPU",
+        retired as f64 / secs / 1e6,
+        cycles as f64 / secs / 1e6,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report_test() {
+        assert_eq!(
+            report("pipeline", 10_000_000, 12_500_000, Duration::from_millis(420)),
+            "Retired 10000000 insts in 0.42s (23.8 MIPS), 12500000 cycles (29.8 M cycles/s) on the pipeline CPU"
+        );
+        let limit = Err(Error::CycleLimitExceeded(0x8000_0000));
+        assert!(finish(Some(Benchmark::start()), "single-cycle", 1, 1, limit).is_ok());
+        let limit = Err(Error::CycleLimitExceeded(0x8000_0000));
+        assert!(finish(None, "single-cycle", 1, 1, limit).is_err());
+    }
+}
@@ -1,60 +1,250 @@
 use std::collections::{HashMap, VecDeque};
 use std::iter;
+use std::path::Path;
 
-use log::trace;
+use log::{info, trace};
 
-use crate::elf::LoadElfInfo;
+use crate::{
+    elf::{FuncSymbol, LoadElfInfo},
+    error::Result,
+    trace::TraceFile,
+};
+
+/// A call which has not returned yet.
+#[derive(Clone)]
+struct Frame {
+    call_site: u64,
+    func_addr: u64,
+    func_name: String,
+    entry_clock: u64,
+}
+
+/// Calls not returned yet and the profile so far, taken by
+/// [`CallStack::snapshot`]. Ftrace records already written stay written.
+#[derive(Clone)]
+pub struct CallStackSnapshot {
+    call_stack: VecDeque<Frame>,
+    profile: Option<HashMap<u64, (u64, u64)>>,
+}
 
 pub struct CallStack<'a> {
     symbol_map: &'a HashMap<u64, String>,
-    call_stack: VecDeque<(u64, String)>,
+    func_symbols: &'a [FuncSymbol], // sorted by address
+    call_stack: VecDeque<Frame>,
     pub ftrace: bool,
+    ftrace_file: Option<TraceFile>,
+    // calls and cycles of each function by its address, if profiling
+    profile: Option<HashMap<u64, (u64, u64)>>,
 }
 
 impl<'a> CallStack<'a> {
     pub fn new(symbol_map: &HashMap<u64, String>, ftrace: bool) -> CallStack {
         CallStack {
             symbol_map,
+            func_symbols: &[],
             call_stack: VecDeque::new(),
             ftrace,
+            ftrace_file: None,
+            profile: None,
         }
     }
 
     pub fn from_elf_info(info: &LoadElfInfo, ftrace: bool) -> CallStack {
-        CallStack::new(info.symbol_map(), ftrace)
+        let mut callstack = CallStack::new(info.symbol_map(), ftrace);
+        callstack.func_symbols = info.func_symbols();
+        callstack
+    }
+
+    /// Write ftrace records of calls and returns into file at `path`.
+    pub fn set_ftrace_file(&mut self, path: &Path) -> Result<()> {
+        self.ftrace_file = Some(TraceFile::create(path)?);
+        Ok(())
+    }
+
+    /// Count calls of each function and cycles spent in it, from the clock at
+    /// the call to the clock at its return. Recursive calls add no cycles.
+    pub fn set_profile(&mut self, profile: bool) {
+        self.profile = profile.then(HashMap::new);
+    }
+
+    /// Name of the function enclosing `pc`.
+    pub fn func_name(&self, pc: u64) -> Option<&str> {
+        let idx = self.func_symbols.partition_point(|func| func.addr <= pc);
+        let func = &self.func_symbols[idx.checked_sub(1)?];
+        // a symbol without size only covers its own address
+        (pc < func.addr + func.size.max(1)).then_some(func.name.as_str())
     }
 
-    pub fn call(&mut self, pc: u64, target_pc: u64) {
+    pub fn call(&mut self, pc: u64, target_pc: u64, clock: u64) {
         if let Some(func_name) = self.symbol_map.get(&target_pc) {
             let len = self.call_stack.len();
             if self.ftrace {
                 trace!(
-                    "{:x}:{} call [{func_name}@{:#x}]",
+                    "{:x}:{} --> {func_name}@{:#x}",
                     pc,
                     iter::repeat(' ').take(len).collect::<String>(),
                     target_pc
                 );
             }
-            self.call_stack.push_back((pc, func_name.clone()));
+            if let Some(ftrace_file) = self.ftrace_file.as_mut() {
+                ftrace_file.call(pc, func_name, target_pc);
+            }
+            self.call_stack.push_back(Frame {
+                call_site: pc,
+                func_addr: target_pc,
+                func_name: func_name.clone(),
+                entry_clock: clock,
+            });
         }
     }
 
-    pub fn ret(&mut self, pc: u64) {
-        if let Some((_, func_name)) = self.call_stack.pop_back() {
+    pub fn ret(&mut self, pc: u64, clock: u64) {
+        if let Some(frame) = self.call_stack.pop_back() {
+            let func_name = frame.func_name;
             let len = self.call_stack.len();
             if self.ftrace {
                 trace!(
-                    "{:x}:{} ret [{func_name}]",
+                    "{:x}:{} <-- {func_name}",
                     pc,
                     iter::repeat(' ').take(len).collect::<String>()
                 );
             }
+            if let Some(ftrace_file) = self.ftrace_file.as_mut() {
+                ftrace_file.ret(pc, &func_name);
+            }
+            // cycles of a recursive call are already in those of the
+            // outermost call of the function, still active
+            let outermost = !self
+                .call_stack
+                .iter()
+                .any(|outer| outer.func_addr == frame.func_addr);
+            if let Some(profile) = self.profile.as_mut() {
+                let (calls, cycles) = profile.entry(frame.func_addr).or_default();
+                *calls += 1;
+                if outermost {
+                    *cycles += clock - frame.entry_clock;
+                }
+            }
         }
     }
 
-    pub fn backtrace(&self) {
-        for (i, (pc, func_name)) in self.call_stack.iter().enumerate() {
-            println!("{} {:#x}: {}", i, pc, func_name);
+    /// Print calls and cycles of each returned function, the most expensive
+    /// first. Cycles of a function include those of its callees.
+    pub fn print_profile(&self) {
+        let Some(profile) = &self.profile else {
+            return;
+        };
+        let mut funcs: Vec<_> = profile.iter().collect();
+        funcs.sort_by(|(addr_a, (_, a)), (addr_b, (_, b))| b.cmp(a).then(addr_a.cmp(addr_b)));
+        info!("Function profile:");
+        info!("{:<24} {:>10} {:>12}", "function", "calls", "cycles");
+        for (addr, &(calls, cycles)) in funcs {
+            let name = match self.symbol_map.get(addr) {
+                Some(name) => name.clone(),
+                None => format!("{:#x}", addr),
+            };
+            info!("{:<24} {:>10} {:>12}", name, calls, cycles);
         }
     }
+
+    /// Take a snapshot of the calls and the profile.
+    pub fn snapshot(&self) -> CallStackSnapshot {
+        CallStackSnapshot {
+            call_stack: self.call_stack.clone(),
+            profile: self.profile.clone(),
+        }
+    }
+
+    /// Put back a snapshot taken by [`CallStack::snapshot`].
+    pub fn restore(&mut self, snapshot: &CallStackSnapshot) {
+        self.call_stack = snapshot.call_stack.clone();
+        self.profile = snapshot.profile.clone();
+    }
+
+    /// Print frames from the innermost one at `pc` out to the call sites.
+    pub fn backtrace(&self, pc: u64) {
+        for (i, (pc, func_name)) in self.frames(pc).into_iter().enumerate() {
+            println!("#{} {:#x} in {}", i, pc, func_name.unwrap_or("??"));
+        }
+    }
+
+    fn frames(&self, pc: u64) -> Vec<(u64, Option<&str>)> {
+        let call_sites = self.call_stack.iter().rev().map(|frame| frame.call_site);
+        iter::once(pc)
+            .chain(call_sites)
+            .map(|pc| (pc, self.func_name(pc)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::elf::read_elf;
+
+    #[test]
+    fn func_name_test() {
+        // main calls add_one at 0x8, see test/data/funcs.s
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test/data/funcs.o");
+        let info = read_elf(&path, None).unwrap();
+        let mut callstack = CallStack::from_elf_info(&info, false);
+        assert_eq!(callstack.func_name(0x4), Some("main"));
+        assert_eq!(callstack.func_name(0x1c), Some("add_one"));
+        assert_eq!(callstack.func_name(0x20), None);
+
+        callstack.call(0x8, 0x18, 0);
+        assert_eq!(
+            callstack.frames(0x1c),
+            [(0x1c, Some("add_one")), (0x8, Some("main"))]
+        );
+        callstack.ret(0x1c, 0);
+        assert_eq!(callstack.frames(0xc), [(0xc, Some("main"))]);
+    }
+
+    #[test]
+    fn profile_test() {
+        let symbol_map = HashMap::from([(0x100, "leaf".to_string()), (0x200, "outer".to_string())]);
+        let mut callstack = CallStack::new(&symbol_map, false);
+        callstack.set_profile(true);
+
+        // leaf called in a loop, 5 cycles each
+        for i in 0..3 {
+            callstack.call(0x10, 0x100, 10 * i);
+            callstack.ret(0x104, 10 * i + 5);
+        }
+        // outer includes the cycles of leaf it calls
+        callstack.call(0x20, 0x200, 100);
+        callstack.call(0x208, 0x100, 102);
+        callstack.ret(0x104, 106);
+        callstack.ret(0x20c, 110);
+        // a jump to an unknown address is not a call
+        callstack.call(0x30, 0x300, 120);
+        // outer calling itself counts its cycles once
+        callstack.call(0x40, 0x200, 200);
+        callstack.call(0x210, 0x200, 202);
+        callstack.ret(0x20c, 208);
+        callstack.ret(0x20c, 210);
+
+        let profile = callstack.profile.as_ref().unwrap();
+        assert_eq!(profile[&0x100], (4, 19));
+        assert_eq!(profile[&0x200], (3, 20));
+        assert_eq!(profile.len(), 2);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let symbol_map = HashMap::from([(0x100, "leaf".to_string())]);
+        let mut callstack = CallStack::new(&symbol_map, false);
+        callstack.set_profile(true);
+        callstack.call(0x10, 0x100, 0);
+        let snapshot = callstack.snapshot();
+
+        callstack.ret(0x104, 5);
+        assert_eq!(callstack.frames(0x14), [(0x14, None)]);
+        callstack.restore(&snapshot);
+        assert_eq!(callstack.frames(0x104), [(0x104, None), (0x10, None)]);
+        assert!(callstack.profile.as_ref().unwrap().is_empty());
+    }
 }
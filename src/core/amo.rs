@@ -0,0 +1,246 @@
+//! Atomic memory operations (A extension).
+//! There is only one hart, so atomics are plain read-modify-writes and only
+//! the hart's own stores could break a reservation.
+
+use crate::{
+    core::{
+        insts::{sext, Inst64, WORD_BITWIDTH},
+        vm::VirtualMemory,
+    },
+    error::{Error, Exception, Result},
+};
+
+/// Size of the reservation set in bytes, naturally aligned
+const GRANULE: u64 = 8;
+
+/// Reservation set of LR/SC.
+#[derive(Debug, Clone, Default)]
+pub struct Reservation(Option<u64>);
+
+impl Reservation {
+    /// lr: reserve the granule containing `vaddr`.
+    pub fn reserve(&mut self, vaddr: u64) {
+        self.0 = Some(vaddr & !(GRANULE - 1));
+    }
+
+    /// sc: whether `vaddr` is still reserved. The reservation is cleared
+    /// whether or not sc succeeds.
+    pub fn check(&mut self, vaddr: u64) -> bool {
+        self.0.take() == Some(vaddr & !(GRANULE - 1))
+    }
+
+    /// A store to `[vaddr, vaddr + len)` breaks the reservation it overlaps.
+    pub fn store(&mut self, vaddr: u64, len: u64) {
+        if let Some(granule) = self.0 {
+            if vaddr < granule + GRANULE && granule < vaddr.wrapping_add(len) {
+                self.0 = None;
+            }
+        }
+    }
+}
+
+/// Whether `op` is an LR, SC or AMO instruction.
+pub fn is_atomic(op: Inst64) -> bool {
+    use Inst64::*;
+    matches!(
+        op,
+        lr_w | lr_d
+            | sc_w
+            | sc_d
+            | amoswap_w
+            | amoswap_d
+            | amoadd_w
+            | amoadd_d
+            | amoxor_w
+            | amoxor_d
+            | amoand_w
+            | amoand_d
+            | amoor_w
+            | amoor_d
+            | amomin_w
+            | amomin_d
+            | amomax_w
+            | amomax_d
+            | amominu_w
+            | amominu_d
+            | amomaxu_w
+            | amomaxu_d
+    )
+}
+
+fn is_word(op: Inst64) -> bool {
+    use Inst64::*;
+    matches!(
+        op,
+        lr_w | sc_w
+            | amoswap_w
+            | amoadd_w
+            | amoxor_w
+            | amoand_w
+            | amoor_w
+            | amomin_w
+            | amomax_w
+            | amominu_w
+            | amomaxu_w
+    )
+}
+
+/// Value written back to memory by AMO `op`, from the `old` memory value and
+/// `src` = x[rs2]. Word variants only look at the low 32 bits.
+fn amo_op(op: Inst64, old: u64, src: u64) -> u64 {
+    use Inst64::*;
+    let (signed_old, signed_src, unsigned_old, unsigned_src) = if is_word(op) {
+        (
+            sext(old, WORD_BITWIDTH),
+            sext(src, WORD_BITWIDTH),
+            old as u32 as u64,
+            src as u32 as u64,
+        )
+    } else {
+        (old as i64, src as i64, old, src)
+    };
+    match op {
+        amoswap_w | amoswap_d => src,
+        amoadd_w | amoadd_d => old.wrapping_add(src),
+        amoxor_w | amoxor_d => old ^ src,
+        amoand_w | amoand_d => old & src,
+        amoor_w | amoor_d => old | src,
+        amomin_w | amomin_d => signed_old.min(signed_src) as u64,
+        amomax_w | amomax_d => signed_old.max(signed_src) as u64,
+        amominu_w | amominu_d => unsigned_old.min(unsigned_src),
+        amomaxu_w | amomaxu_d => unsigned_old.max(unsigned_src),
+        _ => unreachable!("Not an AMO instruction: {:?}", op),
+    }
+}
+
+/// Execute an atomic instruction `op` at `vaddr` with `src` = x[rs2].
+/// Returns the value for x[rd]: the old memory value sign-extended for LR and
+/// AMOs, 0 for a successful SC and 1 for a failed one.
+/// Misaligned or out of memory addresses raise access faults.
+pub fn exec_atomic(
+    op: Inst64,
+    vaddr: u64,
+    src: u64,
+    vm: &mut VirtualMemory,
+    reservation: &mut Reservation,
+) -> Result<u64> {
+    use Inst64::*;
+    let is_load = matches!(op, lr_w | lr_d);
+    let fault = || -> Error {
+        if is_load {
+            Exception::LoadAccessFault(vaddr).into()
+        } else {
+            Exception::StoreAccessFault(vaddr).into()
+        }
+    };
+    let width = if is_word(op) { 4 } else { 8 };
+    if vaddr & (width - 1) != 0 {
+        return Err(fault());
+    }
+
+    let addr = vaddr as usize;
+    let read = |vm: &VirtualMemory| -> Result<u64> {
+        let val = if width == 4 {
+            sext(vm.mread::<u32>(addr)? as u64, WORD_BITWIDTH) as u64
+        } else {
+            vm.mread::<u64>(addr)?
+        };
+        Ok(val)
+    };
+    let write = |vm: &mut VirtualMemory, val: u64| -> Result<()> {
+        if width == 4 {
+            vm.mwrite::<u32>(addr, val as u32)
+        } else {
+            vm.mwrite::<u64>(addr, val)
+        }
+    };
+
+    match op {
+        lr_w | lr_d => {
+            let val = read(vm).map_err(|_| fault())?;
+            reservation.reserve(vaddr);
+            Ok(val)
+        }
+        sc_w | sc_d => {
+            if !reservation.check(vaddr) {
+                return Ok(1);
+            }
+            write(vm, src)?;
+            Ok(0)
+        }
+        _ => {
+            let old = read(vm).map_err(|_| fault())?;
+            write(vm, amo_op(op, old, src))?;
+            reservation.store(vaddr, width);
+            Ok(old)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lr_sc_test() {
+        let mut vm = VirtualMemory::with_base(0x1000, 0x100, false);
+        let mut reservation = Reservation::default();
+        vm.mwrite::<u32>(0x1010, 0xffff_fff0).unwrap();
+
+        // round trip
+        let val = exec_atomic(Inst64::lr_w, 0x1010, 0, &mut vm, &mut reservation).unwrap();
+        assert_eq!(val, 0xffff_ffff_ffff_fff0);
+        let res = exec_atomic(Inst64::sc_w, 0x1010, 7, &mut vm, &mut reservation).unwrap();
+        assert_eq!(res, 0);
+        assert_eq!(vm.mread::<u32>(0x1010).unwrap(), 7);
+
+        // the reservation is gone after sc
+        let res = exec_atomic(Inst64::sc_w, 0x1010, 8, &mut vm, &mut reservation).unwrap();
+        assert_eq!(res, 1);
+        assert_eq!(vm.mread::<u32>(0x1010).unwrap(), 7);
+
+        // an intervening store breaks the reservation
+        exec_atomic(Inst64::lr_d, 0x1010, 0, &mut vm, &mut reservation).unwrap();
+        reservation.store(0x1014, 4);
+        let res = exec_atomic(Inst64::sc_d, 0x1010, 8, &mut vm, &mut reservation).unwrap();
+        assert_eq!(res, 1);
+
+        // misaligned
+        assert!(exec_atomic(Inst64::lr_w, 0x1012, 0, &mut vm, &mut reservation).is_err());
+    }
+
+    #[test]
+    fn amo_test() {
+        use Inst64::*;
+        let mut vm = VirtualMemory::with_base(0x1000, 0x100, false);
+        let mut reservation = Reservation::default();
+        let mut amo = |op, src| {
+            let old = exec_atomic(op, 0x1000, src, &mut vm, &mut reservation).unwrap();
+            (old, vm.mread::<u64>(0x1000).unwrap())
+        };
+
+        assert_eq!(amo(amoswap_d, 5), (0, 5));
+        assert_eq!(amo(amoadd_d, 3), (5, 8));
+        assert_eq!(amo(amoxor_d, 0xf), (8, 7));
+        assert_eq!(amo(amoand_d, 0x6), (7, 6));
+        assert_eq!(amo(amoor_d, 0x9), (6, 0xf));
+        assert_eq!(amo(amomin_d, -1i64 as u64), (0xf, u64::MAX));
+        assert_eq!(amo(amominu_d, 3), (u64::MAX, 3));
+        assert_eq!(amo(amomax_d, -2i64 as u64), (3, 3));
+        assert_eq!(amo(amomaxu_d, -2i64 as u64), (3, -2i64 as u64));
+
+        // word variants leave the upper word alone and compare 32-bit values
+        assert_eq!(
+            amo(amoswap_w, 0x8000_0000),
+            (-2i64 as u64, 0xffff_ffff_8000_0000)
+        );
+        assert_eq!(
+            amo(amomin_w, 1),
+            (0xffff_ffff_8000_0000, 0xffff_ffff_8000_0000)
+        );
+        assert_eq!(
+            amo(amominu_w, 1),
+            (0xffff_ffff_8000_0000, 0xffff_ffff_0000_0001)
+        );
+    }
+}
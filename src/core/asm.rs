@@ -0,0 +1,500 @@
+//! A tiny single-line RV64IMA assembler.
+//!
+//! Supports the base integer, M and A extension instructions, the Zicsr,
+//! Zifencei and Zihintpause instructions, `fence.tso` and a handful of
+//! single-instruction pseudo instructions (`nop`, `mv`, `li`, `not`, `neg`,
+//! `j`, `jr`, `ret`, `rdcycle`, `rdtime`, `rdinstret`). Branch and jump targets are given as offsets relative
+//! to the instruction itself.
+
+use crate::{
+    core::{insts::inst_64_opcode::*, reg::REGNAME},
+    csr::csr_name,
+    error::{Error, Result},
+};
+
+/// Assemble one line of assembly into a 32-bit instruction.
+pub fn assemble(line: &str) -> Result<u32> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    let (mnemonic, rest) = match line.split_once(char::is_whitespace) {
+        Some((m, r)) => (m, r.trim()),
+        None => (line, ""),
+    };
+    let ops: Vec<&str> = if rest.is_empty() {
+        vec![]
+    } else {
+        rest.split(',').map(str::trim).collect()
+    };
+    let mnemonic = mnemonic.to_lowercase();
+    let m = mnemonic.as_str();
+
+    let inst = match m {
+        // OP, OP_32
+        "add" | "sub" | "sll" | "slt" | "sltu" | "xor" | "srl" | "sra" | "or" | "and" | "mul"
+        | "mulh" | "mulhsu" | "mulhu" | "div" | "divu" | "rem" | "remu" | "addw" | "subw"
+        | "sllw" | "srlw" | "sraw" | "mulw" | "divw" | "divuw" | "remw" | "remuw" => {
+            expect_ops(m, &ops, 3)?;
+            let (opcode, funct3, funct7) = op_funct(m);
+            encode_r(opcode, reg(ops[0])?, funct3, reg(ops[1])?, reg(ops[2])?, funct7)
+        }
+        // OP_IMM, OP_IMM_32
+        "addi" | "slti" | "sltiu" | "xori" | "ori" | "andi" | "addiw" => {
+            expect_ops(m, &ops, 3)?;
+            let (opcode, funct3) = match m {
+                "addi" => (OP_IMM, 0b000),
+                "slti" => (OP_IMM, 0b010),
+                "sltiu" => (OP_IMM, 0b011),
+                "xori" => (OP_IMM, 0b100),
+                "ori" => (OP_IMM, 0b110),
+                "andi" => (OP_IMM, 0b111),
+                _ => (OP_IMM_32, 0b000),
+            };
+            let imm = simm(ops[2], 12)?;
+            encode_i(opcode, reg(ops[0])?, funct3, reg(ops[1])?, imm)
+        }
+        "slli" | "srli" | "srai" | "slliw" | "srliw" | "sraiw" => {
+            expect_ops(m, &ops, 3)?;
+            let (opcode, shamt_bits) = if m.ends_with('w') {
+                (OP_IMM_32, 5)
+            } else {
+                (OP_IMM, 6)
+            };
+            let shamt = uimm(ops[2], shamt_bits)?;
+            let (funct3, high) = match m {
+                "slli" | "slliw" => (0b001, 0),
+                "srli" | "srliw" => (0b101, 0),
+                _ => (0b101, 0b0100000 << 5),
+            };
+            encode_i(opcode, reg(ops[0])?, funct3, reg(ops[1])?, high | shamt)
+        }
+        // LOAD
+        "lb" | "lh" | "lw" | "ld" | "lbu" | "lhu" | "lwu" => {
+            expect_ops(m, &ops, 2)?;
+            let funct3 = match m {
+                "lb" => 0b000,
+                "lh" => 0b001,
+                "lw" => 0b010,
+                "ld" => 0b011,
+                "lbu" => 0b100,
+                "lhu" => 0b101,
+                _ => 0b110,
+            };
+            let (offset, base) = mem_operand(ops[1])?;
+            encode_i(LOAD, reg(ops[0])?, funct3, base, offset)
+        }
+        // STORE
+        "sb" | "sh" | "sw" | "sd" => {
+            expect_ops(m, &ops, 2)?;
+            let funct3 = match m {
+                "sb" => 0b000,
+                "sh" => 0b001,
+                "sw" => 0b010,
+                _ => 0b011,
+            };
+            let (offset, base) = mem_operand(ops[1])?;
+            encode_s(STORE, funct3, base, reg(ops[0])?, offset)
+        }
+        // BRANCH
+        "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => {
+            expect_ops(m, &ops, 3)?;
+            let funct3 = match m {
+                "beq" => 0b000,
+                "bne" => 0b001,
+                "blt" => 0b100,
+                "bge" => 0b101,
+                "bltu" => 0b110,
+                _ => 0b111,
+            };
+            let offset = offset(ops[2], 13)?;
+            encode_b(BRANCH, funct3, reg(ops[0])?, reg(ops[1])?, offset)
+        }
+        "jal" => match ops.len() {
+            1 => encode_j(JAL, 1, offset(ops[0], 21)?),
+            _ => {
+                expect_ops(m, &ops, 2)?;
+                encode_j(JAL, reg(ops[0])?, offset(ops[1], 21)?)
+            }
+        },
+        "jalr" => match ops.len() {
+            1 => encode_i(JALR, 1, 0b000, reg(ops[0])?, 0),
+            _ => {
+                expect_ops(m, &ops, 2)?;
+                let (offset, base) = mem_operand(ops[1])?;
+                encode_i(JALR, reg(ops[0])?, 0b000, base, offset)
+            }
+        },
+        "lui" | "auipc" => {
+            expect_ops(m, &ops, 2)?;
+            let opcode = if m == "lui" { LUI } else { AUIPC };
+            let imm = parse_imm(ops[1])?;
+            if !(-(1 << 19)..(1 << 20)).contains(&imm) {
+                return Err(out_of_range(ops[1]));
+            }
+            encode_u(opcode, reg(ops[0])?, imm as u32)
+        }
+        // AMO
+        m if amo_funct(m).is_some() => {
+            let (funct3, funct5) = amo_funct(m).unwrap_or_default();
+            let (rs2, addr) = if m.starts_with("lr.") {
+                expect_ops(m, &ops, 2)?;
+                (0, ops[1])
+            } else {
+                expect_ops(m, &ops, 3)?;
+                (reg(ops[1])?, ops[2])
+            };
+            let (offset, base) = mem_operand(addr)?;
+            if offset != 0 {
+                return Err(Error::Assemble(format!("`{m}` takes no offset")));
+            }
+            encode_r(AMO, reg(ops[0])?, funct3, base, rs2, funct5 << 2)
+        }
+        // MISC_MEM
+        "fence" => {
+            // only the full fence: fence iorw, iorw
+            expect_ops(m, &ops, 0)?;
+            encode_i(MISC_MEM, 0, 0b000, 0, 0x0FF)
+        }
+        "fence.tso" => {
+            // fm = TSO, fence rw, rw
+            expect_ops(m, &ops, 0)?;
+            encode_i(MISC_MEM, 0, 0b000, 0, 0x833)
+        }
+        "pause" => {
+            // fence w, 0
+            expect_ops(m, &ops, 0)?;
+            encode_i(MISC_MEM, 0, 0b000, 0, 0x010)
+        }
+        "fence.i" => {
+            expect_ops(m, &ops, 0)?;
+            encode_i(MISC_MEM, 0, 0b001, 0, 0)
+        }
+        // SYSTEM
+        "ecall" => {
+            expect_ops(m, &ops, 0)?;
+            encode_i(SYSTEM, 0, 0b000, 0, 0)
+        }
+        "ebreak" => {
+            expect_ops(m, &ops, 0)?;
+            encode_i(SYSTEM, 0, 0b000, 0, 1)
+        }
+        "wfi" => {
+            expect_ops(m, &ops, 0)?;
+            encode_i(SYSTEM, 0, 0b000, 0, 0x105)
+        }
+        "mret" => {
+            expect_ops(m, &ops, 0)?;
+            encode_i(SYSTEM, 0, 0b000, 0, 0x302)
+        }
+        "csrrw" | "csrrs" | "csrrc" | "csrrwi" | "csrrsi" | "csrrci" => {
+            expect_ops(m, &ops, 3)?;
+            let funct3 = match m {
+                "csrrw" => 0b001,
+                "csrrs" => 0b010,
+                "csrrc" => 0b011,
+                "csrrwi" => 0b101,
+                "csrrsi" => 0b110,
+                _ => 0b111,
+            };
+            let src = if m.ends_with('i') {
+                uimm(ops[2], 5)? as u8
+            } else {
+                reg(ops[2])?
+            };
+            encode_i(SYSTEM, reg(ops[0])?, funct3, src, csr(ops[1])?)
+        }
+        // Pseudo instructions
+        "nop" => {
+            expect_ops(m, &ops, 0)?;
+            encode_i(OP_IMM, 0, 0b000, 0, 0)
+        }
+        "mv" => {
+            expect_ops(m, &ops, 2)?;
+            encode_i(OP_IMM, reg(ops[0])?, 0b000, reg(ops[1])?, 0)
+        }
+        "li" => {
+            expect_ops(m, &ops, 2)?;
+            encode_i(OP_IMM, reg(ops[0])?, 0b000, 0, simm(ops[1], 12)?)
+        }
+        "not" => {
+            expect_ops(m, &ops, 2)?;
+            encode_i(OP_IMM, reg(ops[0])?, 0b100, reg(ops[1])?, 0xFFF)
+        }
+        "neg" => {
+            expect_ops(m, &ops, 2)?;
+            let (opcode, funct3, funct7) = op_funct("sub");
+            encode_r(opcode, reg(ops[0])?, funct3, 0, reg(ops[1])?, funct7)
+        }
+        "j" => {
+            expect_ops(m, &ops, 1)?;
+            encode_j(JAL, 0, offset(ops[0], 21)?)
+        }
+        "jr" => {
+            expect_ops(m, &ops, 1)?;
+            encode_i(JALR, 0, 0b000, reg(ops[0])?, 0)
+        }
+        "ret" => {
+            expect_ops(m, &ops, 0)?;
+            encode_i(JALR, 0, 0b000, 1, 0)
+        }
+        // csrrs rd, cycle/time/instret, x0
+        "rdcycle" | "rdtime" | "rdinstret" => {
+            expect_ops(m, &ops, 1)?;
+            let csr = match m {
+                "rdcycle" => 0xc00,
+                "rdtime" => 0xc01,
+                _ => 0xc02,
+            };
+            encode_i(SYSTEM, reg(ops[0])?, 0b010, 0, csr)
+        }
+        _ => return Err(Error::Assemble(format!("unknown instruction `{mnemonic}`"))),
+    };
+
+    Ok(inst)
+}
+
+/// (opcode, funct3, funct7) of OP and OP_32 instructions
+fn op_funct(mnemonic: &str) -> (u32, u32, u32) {
+    match mnemonic {
+        "add" => (OP, 0b000, 0b0000000),
+        "sub" => (OP, 0b000, 0b0100000),
+        "sll" => (OP, 0b001, 0b0000000),
+        "slt" => (OP, 0b010, 0b0000000),
+        "sltu" => (OP, 0b011, 0b0000000),
+        "xor" => (OP, 0b100, 0b0000000),
+        "srl" => (OP, 0b101, 0b0000000),
+        "sra" => (OP, 0b101, 0b0100000),
+        "or" => (OP, 0b110, 0b0000000),
+        "and" => (OP, 0b111, 0b0000000),
+        "mul" => (OP, 0b000, 0b0000001),
+        "mulh" => (OP, 0b001, 0b0000001),
+        "mulhsu" => (OP, 0b010, 0b0000001),
+        "mulhu" => (OP, 0b011, 0b0000001),
+        "div" => (OP, 0b100, 0b0000001),
+        "divu" => (OP, 0b101, 0b0000001),
+        "rem" => (OP, 0b110, 0b0000001),
+        "remu" => (OP, 0b111, 0b0000001),
+        "addw" => (OP_32, 0b000, 0b0000000),
+        "subw" => (OP_32, 0b000, 0b0100000),
+        "sllw" => (OP_32, 0b001, 0b0000000),
+        "srlw" => (OP_32, 0b101, 0b0000000),
+        "sraw" => (OP_32, 0b101, 0b0100000),
+        "mulw" => (OP_32, 0b000, 0b0000001),
+        "divw" => (OP_32, 0b100, 0b0000001),
+        "divuw" => (OP_32, 0b101, 0b0000001),
+        "remw" => (OP_32, 0b110, 0b0000001),
+        "remuw" => (OP_32, 0b111, 0b0000001),
+        _ => unreachable!("Not an OP instruction: {mnemonic}"),
+    }
+}
+
+/// (funct3, funct5) of LR, SC and AMO instructions, e.g. `amoadd.w`
+fn amo_funct(mnemonic: &str) -> Option<(u32, u32)> {
+    let (name, width) = mnemonic.split_once('.')?;
+    let funct3 = match width {
+        "w" => 0b010,
+        "d" => 0b011,
+        _ => return None,
+    };
+    let funct5 = match name {
+        "lr" => 0b00010,
+        "sc" => 0b00011,
+        "amoswap" => 0b00001,
+        "amoadd" => 0b00000,
+        "amoxor" => 0b00100,
+        "amoand" => 0b01100,
+        "amoor" => 0b01000,
+        "amomin" => 0b10000,
+        "amomax" => 0b10100,
+        "amominu" => 0b11000,
+        "amomaxu" => 0b11100,
+        _ => return None,
+    };
+    Some((funct3, funct5))
+}
+
+fn encode_r(opcode: u32, rd: u8, funct3: u32, rs1: u8, rs2: u8, funct7: u32) -> u32 {
+    (funct7 << 25)
+        | ((rs2 as u32) << 20)
+        | ((rs1 as u32) << 15)
+        | (funct3 << 12)
+        | ((rd as u32) << 7)
+        | opcode
+}
+
+fn encode_i(opcode: u32, rd: u8, funct3: u32, rs1: u8, imm: u32) -> u32 {
+    ((imm & 0xFFF) << 20) | ((rs1 as u32) << 15) | (funct3 << 12) | ((rd as u32) << 7) | opcode
+}
+
+fn encode_s(opcode: u32, funct3: u32, rs1: u8, rs2: u8, imm: u32) -> u32 {
+    (((imm >> 5) & 0x7F) << 25)
+        | ((rs2 as u32) << 20)
+        | ((rs1 as u32) << 15)
+        | (funct3 << 12)
+        | ((imm & 0x1F) << 7)
+        | opcode
+}
+
+fn encode_b(opcode: u32, funct3: u32, rs1: u8, rs2: u8, imm: u32) -> u32 {
+    (((imm >> 12) & 0x1) << 31)
+        | (((imm >> 5) & 0x3F) << 25)
+        | ((rs2 as u32) << 20)
+        | ((rs1 as u32) << 15)
+        | (funct3 << 12)
+        | (((imm >> 1) & 0xF) << 8)
+        | (((imm >> 11) & 0x1) << 7)
+        | opcode
+}
+
+fn encode_u(opcode: u32, rd: u8, imm: u32) -> u32 {
+    ((imm & 0xFFFFF) << 12) | ((rd as u32) << 7) | opcode
+}
+
+fn encode_j(opcode: u32, rd: u8, imm: u32) -> u32 {
+    (((imm >> 20) & 0x1) << 31)
+        | (((imm >> 1) & 0x3FF) << 21)
+        | (((imm >> 11) & 0x1) << 20)
+        | (((imm >> 12) & 0xFF) << 12)
+        | ((rd as u32) << 7)
+        | opcode
+}
+
+fn expect_ops(mnemonic: &str, ops: &[&str], n: usize) -> Result<()> {
+    if ops.len() != n {
+        let msg = format!("`{mnemonic}` expects {n} operands, got {}", ops.len());
+        return Err(Error::Assemble(msg));
+    }
+    Ok(())
+}
+
+fn out_of_range(s: &str) -> Error {
+    Error::Assemble(format!("immediate `{s}` out of range"))
+}
+
+/// Parse a register by its ABI name or `xN`.
+fn reg(s: &str) -> Result<u8> {
+    let s = s.to_lowercase();
+    if let Some(idx) = REGNAME.iter().position(|name| *name == s) {
+        return Ok(idx as u8);
+    }
+    if s == "fp" {
+        return Ok(8);
+    }
+    match s.strip_prefix('x').map(str::parse::<u8>) {
+        Some(Ok(idx)) if idx < 32 => Ok(idx),
+        _ => Err(Error::InvalidRegName(s)),
+    }
+}
+
+/// Parse a decimal or `0x` hexadecimal immediate, optionally negative.
+fn parse_imm(s: &str) -> Result<i64> {
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(d) => (true, d),
+        None => (false, s),
+    };
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse::<i64>(),
+    }
+    .map_err(|_| Error::Assemble(format!("invalid immediate `{s}`")))?;
+    Ok(if neg { -value } else { value })
+}
+
+/// Parse a signed immediate of `bits` bits.
+fn simm(s: &str, bits: u32) -> Result<u32> {
+    let imm = parse_imm(s)?;
+    if !(-(1 << (bits - 1))..(1 << (bits - 1))).contains(&imm) {
+        return Err(out_of_range(s));
+    }
+    Ok(imm as u32)
+}
+
+/// Parse an unsigned immediate of `bits` bits.
+fn uimm(s: &str, bits: u32) -> Result<u32> {
+    let imm = parse_imm(s)?;
+    if !(0..(1 << bits)).contains(&imm) {
+        return Err(out_of_range(s));
+    }
+    Ok(imm as u32)
+}
+
+/// Parse an even branch or jump offset of `bits` bits.
+fn offset(s: &str, bits: u32) -> Result<u32> {
+    let imm = simm(s, bits)?;
+    if imm & 1 != 0 {
+        return Err(Error::Assemble(format!("offset `{s}` is not aligned")));
+    }
+    Ok(imm)
+}
+
+/// Parse `offset(base)` memory operand.
+fn mem_operand(s: &str) -> Result<(u32, u8)> {
+    let err = || Error::Assemble(format!("invalid memory operand `{s}`"));
+    let (offset, rest) = s.split_once('(').ok_or_else(err)?;
+    let base = rest.strip_suffix(')').ok_or_else(err)?;
+    let offset = if offset.trim().is_empty() {
+        0
+    } else {
+        simm(offset.trim(), 12)?
+    };
+    Ok((offset, reg(base.trim())?))
+}
+
+/// Parse a CSR by its address or its name.
+fn csr(s: &str) -> Result<u32> {
+    if let Ok(addr) = uimm(s, 12) {
+        return Ok(addr);
+    }
+    let name = s.to_lowercase();
+    (0..0x1000u16)
+        .find(|addr| csr_name(*addr).is_some_and(|n| n == name))
+        .map(|addr| addr as u32)
+        .ok_or_else(|| Error::Assemble(format!("unknown CSR `{s}`")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn assemble_test() {
+        // Expected encodings are cross-checked with llvm-mc
+        assert_eq!(assemble("addi a0, a0, 1").unwrap(), 0x00150513);
+        assert_eq!(assemble("srai x1, x2, 3").unwrap(), 0x40315093);
+        assert_eq!(assemble("sd ra, 8(sp)").unwrap(), 0x00113423);
+        assert_eq!(assemble("beq a0,a1,-4").unwrap(), 0xfeb50ee3);
+        assert_eq!(assemble("jal ra, 16").unwrap(), 0x010000ef);
+        assert_eq!(assemble("lui a0, 0x12345").unwrap(), 0x12345537);
+        assert_eq!(assemble("csrrw t0, mscratch, t1").unwrap(), 0x340312f3);
+        assert_eq!(assemble("csrrsi zero, 0x300, 8").unwrap(), 0x30046073);
+        assert_eq!(assemble("sraiw a0, a1, 31").unwrap(), 0x41f5d51b);
+        assert_eq!(assemble("mulhsu a0,a1,a2").unwrap(), 0x02c5a533);
+        assert_eq!(assemble("lbu t0, -1(s0)").unwrap(), 0xfff44283);
+        assert_eq!(assemble("ret").unwrap(), 0x00008067);
+        assert_eq!(assemble("rdcycle a0").unwrap(), 0xc0002573);
+        assert_eq!(assemble("rdinstret t0").unwrap(), 0xc02022f3);
+        assert_eq!(assemble("rdtime a1").unwrap(), 0xc01025f3);
+        assert_eq!(assemble("lr.w a2, (a0)").unwrap(), 0x1005262f);
+        assert_eq!(assemble("sc.w a3, a1, (a0)").unwrap(), 0x18b526af);
+        assert_eq!(assemble("amoadd.w a6, a1, (a0)").unwrap(), 0x00b5282f);
+        assert_eq!(assemble("amomaxu.d t0, t1, (t2)").unwrap(), 0xe063b2af);
+        assert_eq!(assemble("lr.d s1, 0(sp)").unwrap(), 0x100134af);
+        assert_eq!(assemble("fence").unwrap(), 0x0ff0000f);
+        assert_eq!(assemble("fence.i").unwrap(), 0x0000100f);
+        assert_eq!(assemble("fence.tso").unwrap(), 0x8330000f);
+        assert_eq!(assemble("pause").unwrap(), 0x0100000f);
+        assert_eq!(assemble("wfi").unwrap(), 0x10500073);
+        assert_eq!(assemble("mret").unwrap(), 0x30200073);
+    }
+
+    #[test]
+    fn assemble_error_test() {
+        assert!(assemble("addi a0, a0, 2048").is_err());
+        assert!(assemble("add a0, a1").is_err());
+        assert!(assemble("add a0, a1, x32").is_err());
+        assert!(assemble("beq a0, a1, 3").is_err());
+        assert!(assemble("foo a0").is_err());
+        assert!(assemble("amoadd.w a0, a1, 4(a2)").is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! Conditions of conditional breakpoints, shared by both debuggers.
+
+use crate::error::{Error, Result};
+use clap_num::maybe_hex;
+
+/// Comparison of a breakpoint condition
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl CmpOp {
+    // two-character operators first so that `<=` is not taken as `<`
+    const ALL: [(&'static str, CmpOp); 6] = [
+        ("==", CmpOp::Eq),
+        ("!=", CmpOp::Ne),
+        ("<=", CmpOp::Le),
+        (">=", CmpOp::Ge),
+        ("<", CmpOp::Lt),
+        (">", CmpOp::Gt),
+    ];
+
+    fn as_str(self) -> &'static str {
+        CmpOp::ALL.iter().find(|(_, op)| *op == self).unwrap().0
+    }
+}
+
+/// Condition of a conditional breakpoint, `REG OP VALUE`, where registers
+/// and values are compared as signed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub reg: String,
+    op: CmpOp,
+    value: i64,
+}
+
+impl Condition {
+    /// Parse `REG OP VALUE` with `OP` in `== != < > <= >=` and `VALUE` a
+    /// decimal or hex literal. Spaces around `OP` are optional.
+    pub fn parse(cond: &str) -> Result<Condition> {
+        let err = || Error::DbgParse(format!("invalid condition `{cond}`, expect REG OP VALUE"));
+        let (idx, sym, op) = CmpOp::ALL
+            .iter()
+            .filter_map(|&(sym, op)| cond.find(sym).map(|idx| (idx, sym, op)))
+            .min_by_key(|&(idx, _, _)| idx)
+            .ok_or_else(err)?;
+        let reg = cond[..idx].trim();
+        let value = cond[idx + sym.len()..].trim();
+        if reg.is_empty() || value.is_empty() {
+            return Err(err());
+        }
+        let value = match value.strip_prefix('-') {
+            Some(abs) => maybe_hex::<u64>(abs).map(|v| (v as i64).wrapping_neg()),
+            None => maybe_hex::<u64>(value).map(|v| v as i64),
+        }
+        .map_err(|_| err())?;
+        Ok(Condition {
+            reg: reg.to_string(),
+            op,
+            value,
+        })
+    }
+
+    /// Whether the condition holds when the register reads `val`.
+    pub fn holds(&self, val: u64) -> bool {
+        let val = val as i64;
+        match self.op {
+            CmpOp::Eq => val == self.value,
+            CmpOp::Ne => val != self.value,
+            CmpOp::Lt => val < self.value,
+            CmpOp::Gt => val > self.value,
+            CmpOp::Le => val <= self.value,
+            CmpOp::Ge => val >= self.value,
+        }
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.reg, self.op.as_str(), self.value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn condition_test() {
+        let cond = Condition::parse("a0 == 5").unwrap();
+        assert_eq!(cond.reg, "a0");
+        assert_eq!(cond.op, CmpOp::Eq);
+        assert!(cond.holds(5));
+        assert!(!cond.holds(6));
+
+        // `<=` is not taken as `<`, spaces are optional
+        let cond = Condition::parse("t0<=0x10").unwrap();
+        assert_eq!(
+            (cond.reg.as_str(), cond.op, cond.value),
+            ("t0", CmpOp::Le, 16)
+        );
+        assert!(cond.holds(16));
+        assert!(!cond.holds(17));
+
+        // signed comparison
+        let cond = Condition::parse("a1 < -1").unwrap();
+        assert!(cond.holds(-2i64 as u64));
+        assert!(!cond.holds(0));
+        assert_eq!(cond.to_string(), "a1 < -1");
+
+        assert!(Condition::parse("a0 5").is_err());
+        assert!(Condition::parse("== 5").is_err());
+        assert!(Condition::parse("a0 >= x").is_err());
+    }
+}
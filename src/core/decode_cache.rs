@@ -0,0 +1,85 @@
+//! Cache of decoded instructions, so that instructions run again, e.g. in
+//! loops, are not decoded again.
+
+use crate::error::Result;
+
+const DECODE_CACHE_ENTRIES: usize = 4096; // a power of two
+
+/// Direct-mapped cache of instructions decoded into `T`, indexed by pc.
+/// An entry keeps the raw instruction it was decoded from and only hits on
+/// the same one, so that a store into the text, which changes what is
+/// fetched, is decoded anew instead of running stale code.
+#[derive(Debug, Clone)]
+pub struct DecodeCache<T> {
+    entries: Vec<Option<(u64, u32, T)>>, // (pc, raw instruction, decoded)
+}
+
+impl<T: Clone> Default for DecodeCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> DecodeCache<T> {
+    pub fn new() -> DecodeCache<T> {
+        DecodeCache {
+            entries: vec![None; DECODE_CACHE_ENTRIES],
+        }
+    }
+
+    /// The instruction `inst` fetched at `pc`, decoded by `decode` unless it
+    /// is cached. Instructions which fail to decode are not cached.
+    #[inline(always)]
+    pub fn decode(&mut self, pc: u64, inst: u32, decode: impl FnOnce() -> Result<T>) -> Result<T> {
+        // instructions are 2-byte aligned
+        let entry = &mut self.entries[(pc >> 1) as usize & (DECODE_CACHE_ENTRIES - 1)];
+        match entry {
+            Some((tag_pc, tag_inst, decoded)) if *tag_pc == pc && *tag_inst == inst => {
+                Ok(decoded.clone())
+            }
+            _ => {
+                let decoded = decode()?;
+                *entry = Some((pc, inst, decoded.clone()));
+                Ok(decoded)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn decode_cache_test() {
+        let mut cache = DecodeCache::new();
+        let mut decoded = 0;
+        let mut decode = |pc: u64, inst: u32| {
+            cache.decode(pc, inst, || {
+                decoded += 1;
+                Ok(inst + 1)
+            })
+        };
+        assert_eq!(decode(0x8000_0000, 0x13).unwrap(), 0x14);
+        assert_eq!(decode(0x8000_0000, 0x13).unwrap(), 0x14);
+        // a store changed the instruction
+        assert_eq!(decode(0x8000_0000, 0x93).unwrap(), 0x94);
+        // another pc on the same entry
+        let aliased = 0x8000_0000 + 2 * DECODE_CACHE_ENTRIES as u64;
+        assert_eq!(decode(aliased, 0x93).unwrap(), 0x94);
+        assert_eq!(decode(0x8000_0000, 0x93).unwrap(), 0x94);
+        assert_eq!(decoded, 4);
+
+        // failures are decoded again
+        let mut failed = 0;
+        for _ in 0..2 {
+            let result = cache.decode(0x8000_0004, 0, || {
+                failed += 1;
+                Err(Error::Decode("unknown opcode".to_string()))
+            });
+            assert!(result.is_err());
+        }
+        assert_eq!(failed, 2);
+    }
+}
@@ -0,0 +1,21 @@
+//! Instruction mix of a run, printed by every CPU at its end.
+
+use std::collections::HashMap;
+
+use log::info;
+
+use super::insts::Inst64;
+
+/// Print the executed count of each instruction in `histogram` of `total`
+/// instructions, the most frequent first.
+pub fn print_inst_histogram(histogram: &HashMap<Inst64, u64>, total: u64) {
+    let mut histogram: Vec<_> = histogram.iter().collect();
+    histogram.sort_by(|(op_a, a), (op_b, b)| {
+        b.cmp(a).then_with(|| op_a.mnemonic().cmp(op_b.mnemonic()))
+    });
+    info!("Instruction mix:");
+    for (op, &count) in histogram {
+        let percent = 100.0 * count as f64 / total as f64;
+        info!("{:<10} {:>10} {:>6.2}%", op.mnemonic(), count, percent);
+    }
+}
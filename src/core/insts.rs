@@ -163,6 +163,9 @@ pub mod inst_64_opcode {
     pub const inst80b: u32 = 0b11_111_11; /* reserved for 80 bits inst */
 }
 
+/// `pause` of Zihintpause, the fence hint `fence w, 0`
+pub const PAUSE: u32 = 0x0100000f;
+
 pub enum Inst64Format {
     R,
     R4,
@@ -175,7 +178,7 @@ pub enum Inst64Format {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Inst64 {
     noop,
 
@@ -183,6 +186,24 @@ pub enum Inst64 {
     addi,
     addiw,
     addw,
+    amoadd_d,
+    amoadd_w,
+    amoand_d,
+    amoand_w,
+    amomax_d,
+    amomax_w,
+    amomaxu_d,
+    amomaxu_w,
+    amomin_d,
+    amomin_w,
+    amominu_d,
+    amominu_w,
+    amoor_d,
+    amoor_w,
+    amoswap_d,
+    amoswap_w,
+    amoxor_d,
+    amoxor_w,
     and,
     andi,
     auipc,
@@ -207,8 +228,34 @@ pub enum Inst64 {
     ebreak,
     ecall,
 
+    fadd_s,
+    fclass_s,
+    fcvt_s_w,
+    fcvt_s_wu,
+    fcvt_w_s,
+    fcvt_wu_s,
+    fdiv_s,
     fence,
     fence_i,
+    feq_s,
+    fle_s,
+    flt_s,
+    flw,
+    fmadd_s,
+    fmax_s,
+    fmin_s,
+    fmsub_s,
+    fmul_s,
+    fmv_w_x,
+    fmv_x_w,
+    fnmadd_s,
+    fnmsub_s,
+    fsgnj_s,
+    fsgnjn_s,
+    fsgnjx_s,
+    fsqrt_s,
+    fsub_s,
+    fsw,
 
     jal,
     jalr,
@@ -218,6 +265,8 @@ pub enum Inst64 {
     ld,
     lh,
     lhu,
+    lr_d,
+    lr_w,
     lui,
     lw,
     lwu,
@@ -230,12 +279,15 @@ pub enum Inst64 {
 
     or,
     ori,
+    pause,
     rem,
     remu,
     remuw,
     remw,
 
     sb,
+    sc_d,
+    sc_w,
     sd,
     sh,
     sll,
@@ -265,17 +317,241 @@ pub enum Inst64 {
     xori,
 }
 
+impl Inst64 {
+    /// The assembler name of the instruction, e.g. `amoadd.w` of
+    /// [`Inst64::amoadd_w`]. [`Inst64::noop`] is the bubble of the pipeline,
+    /// not the `nop` pseudo-instruction.
+    pub fn mnemonic(&self) -> &'static str {
+        use Inst64::*;
+        match self {
+            noop => "noop",
+            add => "add",
+            addi => "addi",
+            addiw => "addiw",
+            addw => "addw",
+            amoadd_d => "amoadd.d",
+            amoadd_w => "amoadd.w",
+            amoand_d => "amoand.d",
+            amoand_w => "amoand.w",
+            amomax_d => "amomax.d",
+            amomax_w => "amomax.w",
+            amomaxu_d => "amomaxu.d",
+            amomaxu_w => "amomaxu.w",
+            amomin_d => "amomin.d",
+            amomin_w => "amomin.w",
+            amominu_d => "amominu.d",
+            amominu_w => "amominu.w",
+            amoor_d => "amoor.d",
+            amoor_w => "amoor.w",
+            amoswap_d => "amoswap.d",
+            amoswap_w => "amoswap.w",
+            amoxor_d => "amoxor.d",
+            amoxor_w => "amoxor.w",
+            and => "and",
+            andi => "andi",
+            auipc => "auipc",
+            beq => "beq",
+            bge => "bge",
+            bgeu => "bgeu",
+            blt => "blt",
+            bltu => "bltu",
+            bne => "bne",
+            csrrc => "csrrc",
+            csrrci => "csrrci",
+            csrrs => "csrrs",
+            csrrsi => "csrrsi",
+            csrrw => "csrrw",
+            csrrwi => "csrrwi",
+            div => "div",
+            divu => "divu",
+            divuw => "divuw",
+            divw => "divw",
+            ebreak => "ebreak",
+            ecall => "ecall",
+            fadd_s => "fadd.s",
+            fclass_s => "fclass.s",
+            fcvt_s_w => "fcvt.s.w",
+            fcvt_s_wu => "fcvt.s.wu",
+            fcvt_w_s => "fcvt.w.s",
+            fcvt_wu_s => "fcvt.wu.s",
+            fdiv_s => "fdiv.s",
+            fence => "fence",
+            fence_i => "fence.i",
+            feq_s => "feq.s",
+            fle_s => "fle.s",
+            flt_s => "flt.s",
+            flw => "flw",
+            fmadd_s => "fmadd.s",
+            fmax_s => "fmax.s",
+            fmin_s => "fmin.s",
+            fmsub_s => "fmsub.s",
+            fmul_s => "fmul.s",
+            fmv_w_x => "fmv.w.x",
+            fmv_x_w => "fmv.x.w",
+            fnmadd_s => "fnmadd.s",
+            fnmsub_s => "fnmsub.s",
+            fsgnj_s => "fsgnj.s",
+            fsgnjn_s => "fsgnjn.s",
+            fsgnjx_s => "fsgnjx.s",
+            fsqrt_s => "fsqrt.s",
+            fsub_s => "fsub.s",
+            fsw => "fsw",
+            jal => "jal",
+            jalr => "jalr",
+            lb => "lb",
+            lbu => "lbu",
+            ld => "ld",
+            lh => "lh",
+            lhu => "lhu",
+            lr_d => "lr.d",
+            lr_w => "lr.w",
+            lui => "lui",
+            lw => "lw",
+            lwu => "lwu",
+            mret => "mret",
+            mul => "mul",
+            mulh => "mulh",
+            mulhsu => "mulhsu",
+            mulhu => "mulhu",
+            mulw => "mulw",
+            or => "or",
+            ori => "ori",
+            pause => "pause",
+            rem => "rem",
+            remu => "remu",
+            remuw => "remuw",
+            remw => "remw",
+            sb => "sb",
+            sc_d => "sc.d",
+            sc_w => "sc.w",
+            sd => "sd",
+            sh => "sh",
+            sll => "sll",
+            slli => "slli",
+            slliw => "slliw",
+            sllw => "sllw",
+            slt => "slt",
+            slti => "slti",
+            sltiu => "sltiu",
+            sltu => "sltu",
+            sra => "sra",
+            srai => "srai",
+            sraiw => "sraiw",
+            sraw => "sraw",
+            sret => "sret",
+            srl => "srl",
+            srli => "srli",
+            srliw => "srliw",
+            srlw => "srlw",
+            sub => "sub",
+            subw => "subw",
+            sw => "sw",
+            wfi => "wfi",
+            xor => "xor",
+            xori => "xori",
+        }
+    }
+}
+
+impl std::fmt::Display for Inst64 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.mnemonic())
+    }
+}
+
 #[macro_export]
 macro_rules! pinst {
+    // `$inst` is an Inst64 variant, or a variable holding one
+    (@mnemonic $inst:tt) => {{
+        #[allow(unused_imports)]
+        use $crate::core::insts::Inst64::*;
+        $inst.mnemonic()
+    }};
+    // Registers marked with `f` are float registers
+    // OP_FP
+    ($pc:ident, $inst:tt, f $rd:ident, f $rs1:ident, f $rs2:ident) => {
+        format!(
+            "{:8x}:\t{}\t{},{},{}",
+            $pc,
+            pinst!(@mnemonic $inst),
+            crate::core::reg::FREGNAME[$rd as usize],
+            crate::core::reg::FREGNAME[$rs1 as usize],
+            crate::core::reg::FREGNAME[$rs2 as usize]
+        )
+    };
+    // MADD, MSUB, NMSUB, NMADD
+    ($pc:ident, $inst:tt, f $rd:ident, f $rs1:ident, f $rs2:ident, f $rs3:ident) => {
+        format!(
+            "{:8x}:\t{}\t{},{},{},{}",
+            $pc,
+            pinst!(@mnemonic $inst),
+            crate::core::reg::FREGNAME[$rd as usize],
+            crate::core::reg::FREGNAME[$rs1 as usize],
+            crate::core::reg::FREGNAME[$rs2 as usize],
+            crate::core::reg::FREGNAME[$rs3 as usize]
+        )
+    };
+    // FEQ, FLT, FLE
+    ($pc:ident, $inst:tt, $rd:ident, f $rs1:ident, f $rs2:ident) => {
+        format!(
+            "{:8x}:\t{}\t{},{},{}",
+            $pc,
+            pinst!(@mnemonic $inst),
+            crate::core::reg::REGNAME[$rd as usize],
+            crate::core::reg::FREGNAME[$rs1 as usize],
+            crate::core::reg::FREGNAME[$rs2 as usize]
+        )
+    };
+    // FSQRT
+    ($pc:ident, $inst:tt, f $rd:ident, f $rs1:ident) => {
+        format!(
+            "{:8x}:\t{}\t{},{}",
+            $pc,
+            pinst!(@mnemonic $inst),
+            crate::core::reg::FREGNAME[$rd as usize],
+            crate::core::reg::FREGNAME[$rs1 as usize]
+        )
+    };
+    // FCVT, FMV from float to integer
+    ($pc:ident, $inst:tt, $rd:ident, f $rs1:ident) => {
+        format!(
+            "{:8x}:\t{}\t{},{}",
+            $pc,
+            pinst!(@mnemonic $inst),
+            crate::core::reg::REGNAME[$rd as usize],
+            crate::core::reg::FREGNAME[$rs1 as usize]
+        )
+    };
+    // FCVT, FMV from integer to float
+    ($pc:ident, $inst:tt, f $rd:ident, $rs1:ident) => {
+        format!(
+            "{:8x}:\t{}\t{},{}",
+            $pc,
+            pinst!(@mnemonic $inst),
+            crate::core::reg::FREGNAME[$rd as usize],
+            crate::core::reg::REGNAME[$rs1 as usize]
+        )
+    };
+    // LOAD_FP, STORE_FP
+    ($pc:ident, $inst:tt, f $t1:ident, $imm:ident($t2:ident)) => {
+        format!(
+            "{:8x}:\t{}\t{},{}({})",
+            $pc,
+            pinst!(@mnemonic $inst),
+            crate::core::reg::FREGNAME[$t1 as usize],
+            $imm,
+            crate::core::reg::REGNAME[$t2 as usize],
+        )
+    };
     // SYSTEM
     ($pc:ident, $inst:tt) => {
-        format!("{:8x}:\t{}", $pc, stringify!($inst))
+        format!("{:8x}:\t{}", $pc, pinst!(@mnemonic $inst))
     };
     ($pc:ident, $inst:tt, $t1:ident) => {
         format!(
             "{:8x}:\t{}\t{}",
             $pc,
-            stringify!($inst),
+            pinst!(@mnemonic $inst),
             crate::core::reg::REGNAME[$t1 as usize]
         )
     };
@@ -283,7 +559,7 @@ macro_rules! pinst {
         format!(
             "{:8x}:\t{}\t{},{}",
             $pc,
-            stringify!($inst),
+            pinst!(@mnemonic $inst),
             crate::core::reg::REGNAME[$t1 as usize],
             crate::core::reg::REGNAME[$t2 as usize]
         )
@@ -293,7 +569,7 @@ macro_rules! pinst {
         format!(
             "{:8x}:\t{}\t{},{},{}",
             $pc,
-            stringify!($inst),
+            pinst!(@mnemonic $inst),
             crate::core::reg::REGNAME[$rd as usize],
             crate::core::reg::REGNAME[$rs1 as usize],
             crate::core::reg::REGNAME[$rs2 as usize]
@@ -302,22 +578,30 @@ macro_rules! pinst {
     // BRANCH
     ($pc:ident, $inst:tt, $rs1:ident, $rs2:ident, $offset:ident=>offset) => {
         format!(
-            "{:8x}:\t{}\t{},{},{:x}",
+            "{:8x}:\t{}\t{},{},{}",
             $pc,
-            stringify!($inst),
+            pinst!(@mnemonic $inst),
             crate::core::reg::REGNAME[$rs1 as usize],
             crate::core::reg::REGNAME[$rs2 as usize],
-            $offset
+            crate::core::insts::jump_target(
+                $pc,
+                $offset,
+                crate::core::insts::B_TYPE_IMM_BITWIDTH
+            )
         )
     };
     // JAL
     ($pc:ident, $inst:tt, $rd:ident, $offset:ident=>offset) => {
         format!(
-            "{:8x}:\t{}\t{},{:x}",
+            "{:8x}:\t{}\t{},{}",
             $pc,
-            stringify!($inst),
+            pinst!(@mnemonic $inst),
             crate::core::reg::REGNAME[$rd as usize],
-            $offset
+            crate::core::insts::jump_target(
+                $pc,
+                $offset,
+                crate::core::insts::J_TYPE_IMM_BITWIDTH
+            )
         )
     };
     // AUIPC
@@ -325,7 +609,7 @@ macro_rules! pinst {
         format!(
             "{:8x}:\t{}\t{},{:#x}",
             $pc,
-            stringify!($inst),
+            pinst!(@mnemonic $inst),
             crate::core::reg::REGNAME[$t1 as usize],
             $imm
         )
@@ -335,7 +619,7 @@ macro_rules! pinst {
         format!(
             "{:8x}:\t{}\t{},{},{}",
             $pc,
-            stringify!($inst),
+            pinst!(@mnemonic $inst),
             crate::core::reg::REGNAME[$t1 as usize],
             crate::core::reg::REGNAME[$t2 as usize],
             $imm
@@ -346,26 +630,140 @@ macro_rules! pinst {
         format!(
             "{:8x}:\t{}\t{},{}({})",
             $pc,
-            stringify!($inst),
+            pinst!(@mnemonic $inst),
             crate::core::reg::REGNAME[$t1 as usize],
             $imm,
             crate::core::reg::REGNAME[$t2 as usize],
         )
     };
+    // LR
+    ($pc:ident, $inst:tt, $rd:ident, ($rs1:ident)) => {
+        format!(
+            "{:8x}:\t{}\t{},({})",
+            $pc,
+            pinst!(@mnemonic $inst),
+            crate::core::reg::REGNAME[$rd as usize],
+            crate::core::reg::REGNAME[$rs1 as usize],
+        )
+    };
+    // SC, AMO
+    ($pc:ident, $inst:tt, $rd:ident, $rs2:ident, ($rs1:ident)) => {
+        format!(
+            "{:8x}:\t{}\t{},{},({})",
+            $pc,
+            pinst!(@mnemonic $inst),
+            crate::core::reg::REGNAME[$rd as usize],
+            crate::core::reg::REGNAME[$rs2 as usize],
+            crate::core::reg::REGNAME[$rs1 as usize],
+        )
+    };
+    // CSR
+    ($pc:ident, $inst:tt, $rd:ident, $csr:ident=>csr, $rs1:ident) => {
+        format!(
+            "{:8x}:\t{}\t{},{:#x},{}",
+            $pc,
+            pinst!(@mnemonic $inst),
+            crate::core::reg::REGNAME[$rd as usize],
+            $csr,
+            crate::core::reg::REGNAME[$rs1 as usize]
+        )
+    };
+    // CSR immediate
+    ($pc:ident, $inst:tt, $rd:ident, $csr:ident=>csr, $zimm:ident=>imm) => {
+        format!(
+            "{:8x}:\t{}\t{},{:#x},{}",
+            $pc,
+            pinst!(@mnemonic $inst),
+            crate::core::reg::REGNAME[$rd as usize],
+            $csr,
+            $zimm
+        )
+    };
+}
+
+/// Disassemble `op` with its decoded operands, as printed by itrace.
+pub fn disasm(pc: u64, op: Inst64, rd: u8, rs1: u8, rs2: u8, rs3: u8, imm: u64) -> String {
+    use Inst64::*;
+    // the mnemonic comes from `op`, so only the operands differ
+    match op {
+        noop | ebreak | ecall | fence | fence_i | mret | pause | sret | wfi => pinst!(pc, op),
+        add | addw | and | div | divu | divuw | divw | mul | mulh | mulhsu | mulhu | mulw | or
+        | rem | remu | remuw | remw | sll | sllw | slt | sltu | sra | sraw | srl | srlw | sub
+        | subw | xor => pinst!(pc, op, rd, rs1, rs2),
+        addi | addiw | andi | ori | slli | slliw | slti | sltiu | srai | sraiw | srli | srliw
+        | xori => pinst!(pc, op, rd, rs1, imm=>imm),
+        amoadd_d | amoadd_w | amoand_d | amoand_w | amomax_d | amomax_w | amomaxu_d | amomaxu_w
+        | amomin_d | amomin_w | amominu_d | amominu_w | amoor_d | amoor_w | amoswap_d
+        | amoswap_w | amoxor_d | amoxor_w | sc_d | sc_w => pinst!(pc, op, rd, rs2, (rs1)),
+        auipc | lui => pinst!(pc, op, rd, imm=>imm),
+        beq | bge | bgeu | blt | bltu | bne => pinst!(pc, op, rs1, rs2, imm=>offset),
+        csrrc | csrrs | csrrw => pinst!(pc, op, rd, imm=>csr, rs1),
+        csrrci | csrrsi | csrrwi => pinst!(pc, op, rd, imm=>csr, rs1=>imm),
+        fadd_s | fdiv_s | fmax_s | fmin_s | fmul_s | fsgnj_s | fsgnjn_s | fsgnjx_s | fsub_s => {
+            pinst!(pc, op, f rd, f rs1, f rs2)
+        }
+        feq_s | fle_s | flt_s => pinst!(pc, op, rd, f rs1, f rs2),
+        fcvt_s_w | fcvt_s_wu | fmv_w_x => pinst!(pc, op, f rd, rs1),
+        fclass_s | fcvt_w_s | fcvt_wu_s | fmv_x_w => pinst!(pc, op, rd, f rs1),
+        flw => pinst!(pc, op, f rd, imm(rs1)),
+        fmadd_s | fmsub_s | fnmadd_s | fnmsub_s => pinst!(pc, op, f rd, f rs1, f rs2, f rs3),
+        fsqrt_s => pinst!(pc, op, f rd, f rs1),
+        fsw => pinst!(pc, op, f rs2, imm(rs1)),
+        jal => pinst!(pc, op, rd, imm=>offset),
+        jalr | lb | lbu | ld | lh | lhu | lw | lwu => pinst!(pc, op, rd, imm(rs1)),
+        lr_d | lr_w => pinst!(pc, op, rd, (rs1)),
+        sb | sd | sh | sw => pinst!(pc, op, rs2, imm(rs1)),
+    }
+}
+
+/// Symbols of `--follow-jumps`, once set the targets of branches and jumps
+/// are disassembled as absolute addresses instead of offsets.
+static FOLLOW_JUMPS: std::sync::OnceLock<std::collections::HashMap<u64, String>> =
+    std::sync::OnceLock::new();
+
+/// Disassemble the targets of branches and jumps as absolute addresses,
+/// followed by their names in `symbols` if any.
+pub fn follow_jumps(symbols: std::collections::HashMap<u64, String>) {
+    let _ = FOLLOW_JUMPS.set(symbols);
 }
 
+/// Target operand of a branch or jump at `pc` with `offset` of `bit_width`
+/// bits, the offset unless jumps are followed.
+pub fn jump_target(pc: u64, offset: u64, bit_width: u8) -> String {
+    jump_target_with(pc, offset, bit_width, FOLLOW_JUMPS.get())
+}
+
+fn jump_target_with(
+    pc: u64,
+    offset: u64,
+    bit_width: u8,
+    symbols: Option<&std::collections::HashMap<u64, String>>,
+) -> String {
+    let Some(symbols) = symbols else {
+        return format!("{offset:x}");
+    };
+    // idempotent for offsets already sign-extended
+    let target = pc.wrapping_add(sext(offset, bit_width) as u64);
+    match symbols.get(&target) {
+        Some(name) => format!("{target:#x} <{name}>"),
+        None => format!("{target:#x}"),
+    }
+}
+
+#[derive(Clone)]
 pub struct ExecInternal {
     pub raw_inst: u32,
     pub inst: Inst64,
-    pub rs1: u8,   // source register 1 index
-    pub rs2: u8,   // source register 2 index
-    pub rs3: u8,   // source register 3 index
-    pub rd: u8,    // destination register index
-    pub imm: u64,  // immediate number, which is real number
-    pub pc: u64,   // new pc calculated
-    pub src1: u64, // oprand 1
-    pub src2: u64, // oprand 2
-    pub src3: u64, // oprand 3
+    pub rs1: u8,       // source register 1 index
+    pub rs2: u8,       // source register 2 index
+    pub rs3: u8,       // source register 3 index
+    pub rd: u8,        // destination register index
+    pub imm: u64,      // immediate number, which is real number
+    pub pc: u64,       // new pc calculated
+    pub src1: u64,     // oprand 1
+    pub src2: u64,     // oprand 2
+    pub src3: u64,     // oprand 3
+    pub inst_len: u64, // 2 for compressed instructions, otherwise 4
 }
 
 impl Default for ExecInternal {
@@ -382,6 +780,7 @@ impl Default for ExecInternal {
             src1: 0,
             src2: 0,
             src3: 0,
+            inst_len: 4,
         }
     }
 }
@@ -429,6 +828,20 @@ pub fn sext(imm: u64, bit_width: u8) -> i64 {
     ((imm as i64) & mask) | extended_bits
 }
 
+/// Value of lui: the U-type `imm` shifted to imm[31:12], sign-extended from
+/// 32 bits. `imm` may be the raw 20-bit field or it sign-extended, as the
+/// bits above imm[31] are dropped either way.
+#[inline(always)]
+pub fn upper_imm(imm: u64) -> u64 {
+    sext(imm << 12, WORD_BITWIDTH) as u64
+}
+
+/// Value of auipc at `pc`: pc plus [`upper_imm`] of the U-type `imm`.
+#[inline(always)]
+pub fn pc_upper_imm(pc: u64, imm: u64) -> u64 {
+    pc.wrapping_add(upper_imm(imm))
+}
+
 #[inline(always)]
 pub fn trunc_to_32_bit(x: u64) -> u64 {
     const MASK: u64 = 0x00000000_FFFFFFFF;
@@ -472,6 +885,313 @@ pub fn get_high_64_bit(x: u128) -> u64 {
     ((x & MASK) >> 64) as u64
 }
 
+/// High 64 bits of the signed x signed product (`mulh`).
+///
+/// Both operands must be sign-extended through `i64` before widening:
+/// `u64 as i128` zero-extends and would treat negative operands as huge
+/// positive values. The full product of two `i64` always fits in `i128`,
+/// so the multiplication never overflows.
+#[inline(always)]
+pub fn mulh_64(src1: u64, src2: u64) -> u64 {
+    let result = (src1 as i64 as i128) * (src2 as i64 as i128);
+    get_high_64_bit(result as u128)
+}
+
+/// High 64 bits of the signed x unsigned product (`mulhsu`).
+///
+/// `src1` is sign-extended and `src2` zero-extended into `i128`. The product
+/// lies in `[-2^127 + 2^64, 2^127 - 2^64]`, so it cannot overflow `i128`.
+#[inline(always)]
+pub fn mulhsu_64(src1: u64, src2: u64) -> u64 {
+    let result = (src1 as i64 as i128) * (src2 as i128);
+    get_high_64_bit(result as u128)
+}
+
+/// High 64 bits of the unsigned x unsigned product (`mulhu`).
+///
+/// The product of two `u64` is below `2^128`, so `u128` never overflows.
+#[inline(always)]
+pub fn mulhu_64(src1: u64, src2: u64) -> u64 {
+    let result = (src1 as u128) * (src2 as u128);
+    get_high_64_bit(result)
+}
+
+/// High 32 bits of the signed x signed product of the low 32 bits (RV32 `mulh`).
+///
+/// Operands are narrowed to 32 bits, so the product always fits in `i64`.
+#[inline(always)]
+pub fn mulh_32(src1: u64, src2: u64) -> u64 {
+    let result = (src1 as i32 as i64) * (src2 as i32 as i64);
+    (result >> 32) as u64
+}
+
+/// High 32 bits of the signed x unsigned product of the low 32 bits (RV32 `mulhsu`).
+#[inline(always)]
+pub fn mulhsu_32(src1: u64, src2: u64) -> u64 {
+    let result = (src1 as i32 as i64) * (src2 as u32 as i64);
+    (result >> 32) as u64
+}
+
+/// High 32 bits of the unsigned x unsigned product of the low 32 bits (RV32 `mulhu`).
+#[inline(always)]
+pub fn mulhu_32(src1: u64, src2: u64) -> u64 {
+    let result = (src1 as u32 as u64) * (src2 as u32 as u64);
+    result >> 32
+}
+
+/// Whether the divisor of division or remainder `op` is zero. Word variants
+/// only look at the low 32 bits.
+pub fn div_by_zero(op: Inst64, src2: u64) -> bool {
+    use Inst64::*;
+    match op {
+        divw | divuw | remw | remuw => src2 as u32 == 0,
+        _ => src2 == 0,
+    }
+}
+
+/// Result of division or remainder `op` as the spec defines it, without
+/// trapping: division by zero gives all ones for div and the dividend for
+/// rem, and the signed overflow `MIN / -1` gives MIN for div and 0 for rem.
+/// Operands are sign-extended from XLEN bits. Word variants work on the low
+/// 32 bits and sign-extend the result.
+pub fn div_rem(op: Inst64, src1: u64, src2: u64, xlen: Xlen) -> u64 {
+    use Inst64::*;
+    let (w1, w2) = (src1 as u32, src2 as u32);
+    let sext_w = |w: u32| w as i32 as i64 as u64;
+    match op {
+        div if src2 == 0 => u64::MAX,
+        div => (src1 as i64).wrapping_div(src2 as i64) as u64,
+        divu => xlen.trunc(src1).checked_div(xlen.trunc(src2)).unwrap_or(u64::MAX),
+        rem if src2 == 0 => src1,
+        rem => (src1 as i64).wrapping_rem(src2 as i64) as u64,
+        remu => {
+            let dividend = xlen.trunc(src1);
+            dividend.checked_rem(xlen.trunc(src2)).unwrap_or(dividend)
+        }
+        divw if w2 == 0 => u64::MAX,
+        divw => sext_w((w1 as i32).wrapping_div(w2 as i32) as u32),
+        divuw => w1.checked_div(w2).map_or(u64::MAX, sext_w),
+        remw if w2 == 0 => sext_w(w1),
+        remw => sext_w((w1 as i32).wrapping_rem(w2 as i32) as u32),
+        remuw => sext_w(w1.checked_rem(w2).unwrap_or(w1)),
+        _ => unreachable!("{op:?} is not a division"),
+    }
+}
+
+/// Exception flags accrued in fflags: invalid operation, divide by zero,
+/// overflow and inexact. Underflow, bit 1, is never raised.
+pub const FFLAG_NV: u64 = 1 << 4;
+pub const FFLAG_DZ: u64 = 1 << 3;
+pub const FFLAG_OF: u64 = 1 << 2;
+pub const FFLAG_NX: u64 = 1;
+
+/// The canonical NaN, which operations producing a NaN give.
+pub const CANONICAL_NAN: u32 = 0x7fc0_0000;
+
+/// Whether `x` is a signaling NaN, i.e. a NaN with the quiet bit cleared.
+pub fn is_snan(x: f32) -> bool {
+    x.is_nan() && x.to_bits() & (1 << 22) == 0
+}
+
+/// Flags raised by an arithmetic operation on `operands` giving `result`:
+/// NV on signaling NaN operands or a NaN made out of numbers, OF and NX on
+/// an infinity made out of finite numbers. Rounding is not tracked, so NX
+/// is not raised otherwise, and UF never is.
+pub fn arith_flags(operands: &[f32], result: f32) -> u64 {
+    if operands.iter().any(|&x| is_snan(x))
+        || (result.is_nan() && !operands.iter().any(|x| x.is_nan()))
+    {
+        FFLAG_NV
+    } else if result.is_infinite() && operands.iter().all(|x| x.is_finite()) {
+        FFLAG_OF | FFLAG_NX
+    } else {
+        0
+    }
+}
+
+/// `x` rounded to an integral value with rounding mode `rm`. The dynamic
+/// mode is resolved by the caller, other modes round to nearest even.
+fn round_by(x: f32, rm: u8) -> f32 {
+    match rm {
+        0b001 => x.trunc(), // RTZ
+        0b010 => x.floor(), // RDN
+        0b011 => x.ceil(),  // RUP
+        0b100 => x.round(), // RMM
+        _ => x.round_ties_even(),
+    }
+}
+
+/// Convert `x` to an integer in `min..=max` with rounding mode `rm`. Out of
+/// range values saturate and NaN converts to `max`, raising NV, as the spec
+/// requires. Inexact conversions raise NX.
+fn fcvt_int(x: f32, rm: u8, min: i64, max: i64) -> (i64, u64) {
+    let rounded = round_by(x, rm) as f64;
+    if x.is_nan() || rounded > max as f64 {
+        (max, FFLAG_NV)
+    } else if rounded < min as f64 {
+        (min, FFLAG_NV)
+    } else {
+        let flags = if rounded != x as f64 { FFLAG_NX } else { 0 };
+        (rounded as i64, flags)
+    }
+}
+
+/// Convert single-precision `x` to a signed word (`fcvt.w.s`) with rounding
+/// mode `rm`. Returns the word sign-extended, and the flags raised.
+pub fn fcvt_w_s(x: f32, rm: u8) -> (u64, u64) {
+    let (word, flags) = fcvt_int(x, rm, i32::MIN as i64, i32::MAX as i64);
+    (word as u64, flags)
+}
+
+/// Convert single-precision `x` to an unsigned word (`fcvt.wu.s`) with
+/// rounding mode `rm`. Returns the word sign-extended, as the spec requires
+/// on RV64, and the flags raised.
+pub fn fcvt_wu_s(x: f32, rm: u8) -> (u64, u64) {
+    let (word, flags) = fcvt_int(x, rm, 0, u32::MAX as i64);
+    (word as u32 as i32 as i64 as u64, flags)
+}
+
+/// Convert the integer `x` to single-precision (`fcvt.s.w`, `fcvt.s.wu`),
+/// rounding to nearest even. Returns the float, and NX if inexact.
+pub fn fcvt_s_int(x: i64) -> (f32, u64) {
+    let result = x as f32;
+    let flags = if result as f64 != x as f64 { FFLAG_NX } else { 0 };
+    (result, flags)
+}
+
+/// The smaller (`fmin.s`) or larger (`fmax.s`) of `a` and `b`, where -0.0
+/// is smaller than +0.0. A NaN operand gives the other one, or the
+/// canonical NaN if both are NaN. Signaling NaNs raise NV.
+pub fn fmin_max_s(a: f32, b: f32, max: bool) -> (f32, u64) {
+    let flags = if is_snan(a) || is_snan(b) { FFLAG_NV } else { 0 };
+    let result = match (a.is_nan(), b.is_nan()) {
+        (true, true) => f32::from_bits(CANONICAL_NAN),
+        (true, false) => b,
+        (false, true) => a,
+        // total_cmp orders -0.0 before +0.0
+        _ if (a.total_cmp(&b) == std::cmp::Ordering::Less) != max => a,
+        _ => b,
+    };
+    (result, flags)
+}
+
+/// Compare `a` and `b` by `feq.s`, `flt.s` or `fle.s`, giving 1 if true.
+/// Any NaN compares false. feq.s raises NV on signaling NaNs only, flt.s
+/// and fle.s on any NaN.
+pub fn fcmp_s(op: Inst64, a: f32, b: f32) -> (u64, u64) {
+    let (result, nv) = match op {
+        Inst64::feq_s => (a == b, is_snan(a) || is_snan(b)),
+        Inst64::flt_s => (a < b, a.is_nan() || b.is_nan()),
+        Inst64::fle_s => (a <= b, a.is_nan() || b.is_nan()),
+        _ => unreachable!("{op:?} is not a float comparison"),
+    };
+    (result as u64, if nv { FFLAG_NV } else { 0 })
+}
+
+/// Class of `x` for `fclass.s`, one bit set out of:
+/// -inf, -normal, -subnormal, -0, +0, +subnormal, +normal, +inf, sNaN, qNaN.
+pub fn fclass_s(x: f32) -> u64 {
+    use std::num::FpCategory::*;
+    let bit = match (x.classify(), x.is_sign_negative()) {
+        (Infinite, true) => 0,
+        (Normal, true) => 1,
+        (Subnormal, true) => 2,
+        (Zero, true) => 3,
+        (Zero, false) => 4,
+        (Subnormal, false) => 5,
+        (Normal, false) => 6,
+        (Infinite, false) => 7,
+        (Nan, _) if is_snan(x) => 8,
+        (Nan, _) => 9,
+    };
+    1 << bit
+}
+
+/// Width of the integer registers.
+///
+/// RV32 runs on the same 64-bit datapath: registers hold XLEN-bit values
+/// zero-extended, ALU operands are sign-extended from XLEN bits and results
+/// are truncated back when written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Xlen {
+    Rv32,
+    Rv64,
+}
+
+impl Xlen {
+    pub fn bits(self) -> u32 {
+        match self {
+            Xlen::Rv32 => 32,
+            Xlen::Rv64 => 64,
+        }
+    }
+
+    /// Keep the low XLEN bits of `x`.
+    #[inline(always)]
+    pub fn trunc(self, x: u64) -> u64 {
+        match self {
+            Xlen::Rv32 => trunc_to_32_bit(x),
+            Xlen::Rv64 => x,
+        }
+    }
+
+    /// Sign-extend the low XLEN bits of `x` to 64 bits.
+    #[inline(always)]
+    pub fn sext(self, x: u64) -> u64 {
+        match self {
+            Xlen::Rv32 => sext(x, WORD_BITWIDTH) as u64,
+            Xlen::Rv64 => x,
+        }
+    }
+
+    /// Shift amount taken from `x`: low 5 bits on RV32, low 6 bits on RV64.
+    #[inline(always)]
+    pub fn shamt(self, x: u64) -> u32 {
+        match self {
+            Xlen::Rv32 => trunc_to_5_bit(x) as u32,
+            Xlen::Rv64 => trunc_to_6_bit(x) as u32,
+        }
+    }
+
+    /// Whether `inst` exists in this base ISA. Word instructions and
+    /// double-word memory accesses are RV64 only.
+    pub fn supports(self, inst: Inst64) -> bool {
+        use Inst64::*;
+        self == Xlen::Rv64
+            || !matches!(
+                inst,
+                addiw
+                    | slliw
+                    | srliw
+                    | sraiw
+                    | addw
+                    | subw
+                    | sllw
+                    | srlw
+                    | sraw
+                    | mulw
+                    | divw
+                    | divuw
+                    | remw
+                    | remuw
+                    | ld
+                    | sd
+                    | lwu
+                    | lr_d
+                    | sc_d
+                    | amoswap_d
+                    | amoadd_d
+                    | amoxor_d
+                    | amoand_d
+                    | amoor_d
+                    | amomin_d
+                    | amomax_d
+                    | amominu_d
+                    | amomaxu_d
+            )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -481,4 +1201,239 @@ mod tests {
         let get: u32 = (num & FUNCT7_MASK) >> FUNCT7_SHIFT;
         assert_eq!(get, 0b0000000_00000_00000_000_00000_1011011);
     }
+
+    #[test]
+    fn test_mulh() {
+        assert_eq!(mulh_64(2, 3), 0);
+        assert_eq!(mulh_64(-1i64 as u64, 2), u64::MAX);
+        assert_eq!(mulh_64(-1i64 as u64, -1i64 as u64), 0);
+        assert_eq!(mulh_64(i64::MIN as u64, i64::MIN as u64), 0x40000000_00000000);
+        assert_eq!(mulh_64(i64::MIN as u64, i64::MAX as u64), 0xC0000000_00000000);
+        assert_eq!(mulh_64(i64::MAX as u64, i64::MAX as u64), 0x3FFFFFFF_FFFFFFFF);
+        assert_eq!(mulh_64(1 << 32, 1 << 32), 1);
+    }
+
+    #[test]
+    fn test_mulhsu() {
+        assert_eq!(mulhsu_64(2, 3), 0);
+        assert_eq!(mulhsu_64(-1i64 as u64, 2), u64::MAX);
+        // -1 * (2^64 - 1) = -(2^64 - 1): high word is -1
+        assert_eq!(mulhsu_64(-1i64 as u64, u64::MAX), u64::MAX);
+        // 2 * (2^64 - 1) = 2^65 - 2: high word is 1
+        assert_eq!(mulhsu_64(2, u64::MAX), 1);
+        // -2^63 * (2^64 - 1) = -2^127 + 2^63: high word is -2^63
+        assert_eq!(mulhsu_64(i64::MIN as u64, u64::MAX), 0x80000000_00000000);
+        assert_eq!(mulhsu_64(i64::MAX as u64, u64::MAX), 0x7FFFFFFF_FFFFFFFE);
+    }
+
+    #[test]
+    fn test_mulh_32() {
+        assert_eq!(mulh_32(0xFFFF_FFFF, 0xFFFF_FFFF), 0); // -1 * -1
+        assert_eq!(mulh_32(0x8000_0000, 0x8000_0000) as u32, 0x4000_0000);
+        assert_eq!(mulhsu_32(0xFFFF_FFFF, 0xFFFF_FFFF) as u32, 0xFFFF_FFFF);
+        assert_eq!(mulhu_32(0xFFFF_FFFF, 0xFFFF_FFFF), 0xFFFF_FFFE);
+    }
+
+    #[test]
+    fn test_div_rem() {
+        let min = i64::MIN as u64;
+        let neg1 = u64::MAX;
+        let rv64 = |op, a, b| div_rem(op, a, b, Xlen::Rv64);
+        // signed overflow
+        assert_eq!(rv64(Inst64::div, min, neg1), min);
+        assert_eq!(rv64(Inst64::rem, min, neg1), 0);
+        assert_eq!(rv64(Inst64::divw, 0x8000_0000, neg1), i32::MIN as i64 as u64);
+        assert_eq!(rv64(Inst64::remw, 0x8000_0000, neg1), 0);
+        // division by zero
+        for op in [Inst64::div, Inst64::divu, Inst64::divw, Inst64::divuw] {
+            assert_eq!(rv64(op, 7, 0), u64::MAX);
+            assert!(div_by_zero(op, 0));
+        }
+        assert_eq!(rv64(Inst64::rem, -7i64 as u64, 0), -7i64 as u64);
+        assert_eq!(rv64(Inst64::remu, -7i64 as u64, 0), -7i64 as u64);
+        // word variants look at the low 32 bits only
+        assert!(div_by_zero(Inst64::remuw, 1 << 32));
+        assert!(!div_by_zero(Inst64::remu, 1 << 32));
+        assert_eq!(rv64(Inst64::remw, 0x1_ffff_fff9, 1 << 32), -7i64 as u64);
+        assert_eq!(rv64(Inst64::remuw, 0x8000_0007, 1 << 32), 0xffff_ffff_8000_0007);
+        // ordinary results
+        assert_eq!(rv64(Inst64::div, -7i64 as u64, 2), -3i64 as u64);
+        assert_eq!(rv64(Inst64::rem, -7i64 as u64, 2), -1i64 as u64);
+        assert_eq!(rv64(Inst64::remw, -7i64 as u64, 2), -1i64 as u64);
+        assert_eq!(rv64(Inst64::divuw, -8i64 as u64, 2), 0x7fff_fffc);
+        // RV32 divides XLEN-bit values
+        assert_eq!(div_rem(Inst64::divu, -8i64 as u64, 2, Xlen::Rv32), 0x7fff_fffc);
+        assert_eq!(div_rem(Inst64::remu, -7i64 as u64, 0, Xlen::Rv32), 0xffff_fff9);
+    }
+
+    #[test]
+    fn test_fcvt_w_s() {
+        assert_eq!(fcvt_w_s(2.5, 0b000), (2, FFLAG_NX));
+        assert_eq!(fcvt_w_s(3.5, 0b111), (4, FFLAG_NX));
+        assert_eq!(fcvt_w_s(-1.5, 0b000), (-2i64 as u64, FFLAG_NX));
+        assert_eq!(fcvt_w_s(-1.5, 0b001), (-1i64 as u64, FFLAG_NX));
+        assert_eq!(fcvt_w_s(-1.5, 0b011), (-1i64 as u64, FFLAG_NX));
+        assert_eq!(fcvt_w_s(2.5, 0b100), (3, FFLAG_NX));
+        assert_eq!(fcvt_w_s(-7.0, 0b000), (-7i64 as u64, 0));
+        assert_eq!(fcvt_w_s(1e10, 0b000), (i32::MAX as u64, FFLAG_NV));
+        assert_eq!(
+            fcvt_w_s(f32::NEG_INFINITY, 0b000),
+            (i32::MIN as i64 as u64, FFLAG_NV)
+        );
+        assert_eq!(fcvt_w_s(f32::NAN, 0b000), (i32::MAX as u64, FFLAG_NV));
+    }
+
+    #[test]
+    fn test_float_helpers() {
+        // unsigned words are sign-extended, negatives saturate at 0
+        assert_eq!(fcvt_wu_s(4e9, 0b001), (4_000_000_000u32 as i32 as i64 as u64, 0));
+        assert_eq!(fcvt_wu_s(-1.0, 0b000), (0, FFLAG_NV));
+        assert_eq!(fcvt_wu_s(-0.5, 0b001), (0, FFLAG_NX));
+        assert_eq!(fcvt_s_int(16_777_217), (16_777_216.0, FFLAG_NX));
+        assert_eq!(fcvt_s_int(-3), (-3.0, 0));
+
+        let snan = f32::from_bits(0x7f80_0001);
+        assert_eq!(fmin_max_s(-0.0, 0.0, false).0.to_bits(), (-0.0f32).to_bits());
+        assert_eq!(fmin_max_s(-0.0, 0.0, true).0.to_bits(), 0.0f32.to_bits());
+        assert_eq!(fmin_max_s(f32::NAN, 2.0, false), (2.0, 0));
+        assert_eq!(fmin_max_s(snan, 2.0, true), (2.0, FFLAG_NV));
+        assert_eq!(fmin_max_s(f32::NAN, snan, true).0.to_bits(), CANONICAL_NAN);
+
+        assert_eq!(fcmp_s(Inst64::feq_s, 1.0, 1.0), (1, 0));
+        assert_eq!(fcmp_s(Inst64::feq_s, f32::NAN, 1.0), (0, 0));
+        assert_eq!(fcmp_s(Inst64::feq_s, snan, 1.0), (0, FFLAG_NV));
+        assert_eq!(fcmp_s(Inst64::flt_s, f32::NAN, 1.0), (0, FFLAG_NV));
+        assert_eq!(fcmp_s(Inst64::fle_s, -0.0, 0.0), (1, 0));
+
+        assert_eq!(fclass_s(f32::NEG_INFINITY), 1 << 0);
+        assert_eq!(fclass_s(-0.0), 1 << 3);
+        assert_eq!(fclass_s(f32::from_bits(1)), 1 << 5);
+        assert_eq!(fclass_s(1.0), 1 << 6);
+        assert_eq!(fclass_s(snan), 1 << 8);
+        assert_eq!(fclass_s(f32::NAN), 1 << 9);
+
+        assert_eq!(arith_flags(&[1.0, 2.0], 3.0), 0);
+        assert_eq!(arith_flags(&[f32::INFINITY, f32::NEG_INFINITY], f32::NAN), FFLAG_NV);
+        assert_eq!(arith_flags(&[f32::NAN, 1.0], f32::NAN), 0);
+        assert_eq!(arith_flags(&[f32::MAX, f32::MAX], f32::INFINITY), FFLAG_OF | FFLAG_NX);
+    }
+
+    #[test]
+    fn test_xlen() {
+        assert_eq!(Xlen::Rv32.trunc(u64::MAX), 0xFFFF_FFFF);
+        assert_eq!(Xlen::Rv32.sext(0x8000_0000), 0xFFFF_FFFF_8000_0000);
+        assert_eq!(Xlen::Rv32.shamt(0x3F), 0x1F);
+        assert_eq!(Xlen::Rv64.shamt(0x3F), 0x3F);
+        assert!(!Xlen::Rv32.supports(Inst64::addiw));
+        assert!(Xlen::Rv32.supports(Inst64::addi));
+        assert!(Xlen::Rv64.supports(Inst64::ld));
+    }
+
+    #[test]
+    fn test_mulhu() {
+        assert_eq!(mulhu_64(2, 3), 0);
+        assert_eq!(mulhu_64(-1i64 as u64, 2), 1);
+        assert_eq!(mulhu_64(u64::MAX, u64::MAX), 0xFFFFFFFF_FFFFFFFE);
+        assert_eq!(mulhu_64(1 << 63, 2), 1);
+        assert_eq!(mulhu_64(1 << 32, 1 << 32), 1);
+    }
+
+    #[test]
+    fn test_mnemonic() {
+        assert_eq!(Inst64::addi.to_string(), "addi");
+        assert_eq!(Inst64::amoadd_w.to_string(), "amoadd.w");
+        assert_eq!(Inst64::fcvt_s_w.to_string(), "fcvt.s.w");
+        assert_eq!(Inst64::fence_i.mnemonic(), "fence.i");
+        assert_eq!(Inst64::sc_d.mnemonic(), "sc.d");
+        assert_eq!(Inst64::noop.mnemonic(), "noop");
+        // disassembly takes the name from the same place
+        assert_eq!(
+            disasm(0x80, Inst64::lr_w, 10, 11, 0, 0, 0),
+            "      80:\tlr.w\ta0,(a1)"
+        );
+    }
+
+    /// Bits `hi..=lo` of `inst`, moved to bit `to`.
+    fn bits(inst: u32, hi: u32, lo: u32, to: u32) -> u64 {
+        (((inst >> lo) & ((1 << (hi - lo + 1)) - 1)) as u64) << to
+    }
+
+    // imm[12|10:5] rs2 rs1 funct3 imm[4:1|11] opcode
+    fn reference_imm_sb(inst: u32) -> u64 {
+        bits(inst, 31, 31, 12) | bits(inst, 30, 25, 5) | bits(inst, 11, 8, 1) | bits(inst, 7, 7, 11)
+    }
+
+    // imm[20|10:1|11|19:12] rd opcode
+    fn reference_imm_uj(inst: u32) -> u64 {
+        bits(inst, 31, 31, 20)
+            | bits(inst, 30, 21, 1)
+            | bits(inst, 20, 20, 11)
+            | bits(inst, 19, 12, 12)
+    }
+
+    #[test]
+    fn test_imm_sb_uj() {
+        // every immediate, in words of random other fields
+        let mut rng = crate::core::rng::Rng::new(0);
+        for imm in (0..1u32 << B_TYPE_IMM_BITWIDTH).step_by(2) {
+            let other = rng.next_u64() as u32 & 0x01ff_f07f; // rs2 rs1 funct3 opcode
+            let inst = (imm >> 12) << 31
+                | ((imm >> 5) & 0x3f) << 25
+                | ((imm >> 1) & 0xf) << 8
+                | ((imm >> 11) & 1) << 7
+                | other;
+            assert_eq!(imm_SB(inst), imm as u64, "{inst:#010x}");
+        }
+        for imm in (0..1u32 << J_TYPE_IMM_BITWIDTH).step_by(2) {
+            let other = rng.next_u64() as u32 & 0xfff; // rd opcode
+            let inst = (imm >> 20) << 31
+                | ((imm >> 1) & 0x3ff) << 21
+                | ((imm >> 11) & 1) << 20
+                | ((imm >> 12) & 0xff) << 12
+                | other;
+            assert_eq!(imm_UJ(inst), imm as u64, "{inst:#010x}");
+        }
+        // and random words against the reference
+        for _ in 0..100_000 {
+            let inst = rng.next_u64() as u32;
+            assert_eq!(imm_SB(inst), reference_imm_sb(inst), "{inst:#010x}");
+            assert_eq!(imm_UJ(inst), reference_imm_uj(inst), "{inst:#010x}");
+        }
+
+        // sign bits
+        assert_eq!(sext(imm_SB(0x8000_0063), B_TYPE_IMM_BITWIDTH), -4096);
+        assert_eq!(sext(imm_UJ(0x8000_006f), J_TYPE_IMM_BITWIDTH), -(1 << 20));
+        // beq zero, zero, -2 and jal zero, -2: every bit set but bit 0
+        assert_eq!(imm_SB(0xfe00_0fe3), 0x1ffe);
+        assert_eq!(imm_UJ(0xffff_f06f), 0x1f_fffe);
+    }
+
+    #[test]
+    fn test_jump_target() {
+        let symbols = std::collections::HashMap::from([(0x8000_0000, "main".to_string())]);
+        // jal ra, -16 at 0x80000010, with the offset as decoded
+        let offset = (-16i64 as u64) & ((1 << J_TYPE_IMM_BITWIDTH) - 1);
+        assert_eq!(
+            jump_target_with(0x8000_0010, offset, J_TYPE_IMM_BITWIDTH, Some(&symbols)),
+            "0x80000000 <main>"
+        );
+        // already sign-extended, and to no symbol
+        assert_eq!(
+            jump_target_with(0x8000_0010, 0x20, J_TYPE_IMM_BITWIDTH, Some(&symbols)),
+            "0x80000030"
+        );
+        assert_eq!(
+            jump_target_with(0x8000_0010, -16i64 as u64, B_TYPE_IMM_BITWIDTH, Some(&symbols)),
+            "0x80000000 <main>"
+        );
+        // offsets unless jumps are followed
+        assert_eq!(
+            jump_target_with(0x8000_0010, offset, J_TYPE_IMM_BITWIDTH, None),
+            "1ffff0"
+        );
+        assert_eq!(
+            disasm(0x80, Inst64::jal, 1, 0, 0, 0, 0x10),
+            "      80:\tjal\tra,10"
+        );
+    }
 }
@@ -1,4 +1,14 @@
+pub mod amo;
+pub mod asm;
+pub mod cond;
+pub mod decode_cache;
+pub mod histogram;
 pub mod insts;
 pub mod reg;
+pub mod rng;
+pub mod rvc;
+pub mod scan;
+pub mod uart;
+pub mod uninit;
 pub mod vm;
 pub mod utils;
@@ -1,8 +1,17 @@
 use core::ptr::{read_volatile, write_volatile};
 
-use crate::elf::LoadElfInfo;
+use clap::ValueEnum;
+
+use crate::{
+    core::{
+        insts::{Xlen, CANONICAL_NAN},
+        uninit::UninitFill,
+    },
+    elf::LoadElfInfo,
+};
 
 /// General purpose register file with machine word = 64 bits.
+/// On RV32 the registers hold 32-bit values zero-extended.
 #[derive(Debug, PartialEq, Eq)]
 #[repr(C)]
 pub struct RegisterFile {
@@ -38,6 +47,8 @@ pub struct RegisterFile {
     t4: u64,   // x29 Temporary
     t5: u64,   // x30 Temporary
     t6: u64,   // x31 Temporary
+
+    xlen: Xlen, // writes are truncated to XLEN bits
 }
 
 pub const REGNAME: [&'static str; 32] = [
@@ -75,29 +86,199 @@ pub const REGNAME: [&'static str; 32] = [
     "t6",   // 31
 ];
 
+pub const FREGNAME: [&str; 32] = [
+    "ft0",  // 0
+    "ft1",  // 1
+    "ft2",  // 2
+    "ft3",  // 3
+    "ft4",  // 4
+    "ft5",  // 5
+    "ft6",  // 6
+    "ft7",  // 7
+    "fs0",  // 8
+    "fs1",  // 9
+    "fa0",  // 10
+    "fa1",  // 11
+    "fa2",  // 12
+    "fa3",  // 13
+    "fa4",  // 14
+    "fa5",  // 15
+    "fa6",  // 16
+    "fa7",  // 17
+    "fs2",  // 18
+    "fs3",  // 19
+    "fs4",  // 20
+    "fs5",  // 21
+    "fs6",  // 22
+    "fs7",  // 23
+    "fs8",  // 24
+    "fs9",  // 25
+    "fs10", // 26
+    "fs11", // 27
+    "ft8",  // 28
+    "ft9",  // 29
+    "ft10", // 30
+    "ft11", // 31
+];
+
+/// Index of the general purpose register `name`, either an ABI name like
+/// `a0` or an architectural name like `x10`.
+pub fn reg_index(name: &str) -> Option<u8> {
+    if let Some(idx) = REGNAME.iter().position(|&r| r == name) {
+        return Some(idx as u8);
+    }
+    name.strip_prefix('x')
+        .and_then(|n| n.parse::<u8>().ok())
+        .filter(|&idx| idx < 32 && name == format!("x{idx}"))
+}
+
+/// Registers logged by `--trace-regs` after every retired instruction, as a
+/// commit log to diff against a reference simulator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TraceRegs {
+    All,     // the whole register file
+    Changed, // the register written, with its old and new values
+}
+
+impl TraceRegs {
+    /// Line of the instruction at `pc` retiring with `reg_file`, which wrote
+    /// `write = Some((rd, old value))` if it writes a register.
+    pub fn line(self, pc: u64, write: Option<(u8, u64)>, reg_file: &RegisterFile) -> String {
+        let mut line = format!("{pc:#x}");
+        match self {
+            TraceRegs::All => {
+                for i in 0..32 {
+                    line += &format!(" x{i}={:#x}", reg_file.read(i));
+                }
+            }
+            TraceRegs::Changed => {
+                if let Some((rd, old)) = write.filter(|&(rd, _)| rd != 0) {
+                    line += &format!(" x{rd} {old:#x}->{:#x}", reg_file.read(rd));
+                }
+            }
+        }
+        line
+    }
+}
+
+/// Floating point register file of the F extension (FLEN = 32).
+/// Registers hold raw IEEE 754 single-precision bits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FRegisterFile {
+    regs: [u32; 32],
+}
+
+impl FRegisterFile {
+    /// Get an empty float register file, all registers are +0.0
+    pub fn empty() -> FRegisterFile {
+        FRegisterFile { regs: [0; 32] }
+    }
+
+    /// Read from a float register
+    #[inline]
+    pub fn read(&self, reg_index: u8) -> f32 {
+        f32::from_bits(self.read_bits(reg_index))
+    }
+
+    /// Write into a float register, a NaN as the canonical NaN
+    #[inline]
+    pub fn write(&mut self, reg_index: u8, value: f32) {
+        let bits = if value.is_nan() {
+            CANONICAL_NAN
+        } else {
+            value.to_bits()
+        };
+        self.write_bits(reg_index, bits);
+    }
+
+    /// Read the raw bits of a float register
+    #[inline]
+    pub fn read_bits(&self, reg_index: u8) -> u32 {
+        self.regs[reg_index as usize]
+    }
+
+    /// Write raw bits into a float register, no canonicalization is done
+    #[inline]
+    pub fn write_bits(&mut self, reg_index: u8, bits: u32) {
+        self.regs[reg_index as usize] = bits;
+    }
+}
+
 pub struct ProgramCounter {
     inner: u64,
+    xlen: Xlen,
+}
+
+impl Default for ProgramCounter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ProgramCounter {
     pub fn new() -> ProgramCounter {
-        ProgramCounter { inner: 0 }
+        ProgramCounter {
+            inner: 0,
+            xlen: Xlen::Rv64,
+        }
+    }
+
+    pub fn set_xlen(&mut self, xlen: Xlen) {
+        self.xlen = xlen;
     }
-    /// For now, only simulate 64-bit machine
+
     pub fn read(&self) -> u64 {
         self.inner
     }
 
-    /// For now, only simulate 64-bit machine
+    /// Write pc, wrapping around at XLEN bits
     pub fn write(&mut self, value: u64) {
-        self.inner = value;
+        self.inner = self.xlen.trunc(value);
     }
 }
 
 impl RegisterFile {
     /// Get an empty register file
     pub fn empty() -> RegisterFile {
-        unsafe { std::mem::zeroed() }
+        RegisterFile {
+            zero: 0,
+            ra: 0,
+            sp: 0,
+            gp: 0,
+            tp: 0,
+            t0: 0,
+            t1: 0,
+            t2: 0,
+            s0: 0,
+            s1: 0,
+            a0: 0,
+            a1: 0,
+            a2: 0,
+            a3: 0,
+            a4: 0,
+            a5: 0,
+            a6: 0,
+            a7: 0,
+            s2: 0,
+            s3: 0,
+            s4: 0,
+            s5: 0,
+            s6: 0,
+            s7: 0,
+            s8: 0,
+            s9: 0,
+            s10: 0,
+            s11: 0,
+            t3: 0,
+            t4: 0,
+            t5: 0,
+            t6: 0,
+            xlen: Xlen::Rv64,
+        }
+    }
+
+    pub fn set_xlen(&mut self, xlen: Xlen) {
+        self.xlen = xlen;
     }
 
     /// Read from a register
@@ -109,21 +290,53 @@ impl RegisterFile {
         value
     }
 
-    /// Write into a register
+    /// Write into a register, truncated to XLEN bits.
+    /// Writes to x0 are ignored, so it always reads zero.
     #[inline]
     pub fn write(&mut self, reg_index: u8, value: u64) {
+        if reg_index == 0 {
+            return;
+        }
+        let value = self.xlen.trunc(value);
         let ptr = self as *mut RegisterFile as *mut u64;
         // Pointer add safe because of RISC-V ISA 5 bits register index
         unsafe { write_volatile(ptr.add(reg_index.into()), value) };
     }
+
+    /// Give every register but x0 an initial value from `fill`
+    pub fn fill_uninit(&mut self, fill: &mut UninitFill) {
+        for i in 1..32 {
+            self.write(i, fill.next_u64());
+        }
+    }
+
+    /// Values of all registers, x0 first
+    pub fn to_array(&self) -> [u64; 32] {
+        std::array::from_fn(|i| self.read(i as u8))
+    }
+
+    /// Register file holding `regs`, x0 stays hard-wired zero
+    pub fn from_array(regs: &[u64; 32], xlen: Xlen) -> RegisterFile {
+        let mut reg_file = RegisterFile::empty();
+        reg_file.set_xlen(xlen);
+        for (i, &value) in regs.iter().enumerate() {
+            reg_file.write(i as u8, value);
+        }
+        reg_file
+    }
 }
 
 impl RegisterFile {
-    /// Initialize register file with ELF info
-    pub fn init_elfinfo_64(&mut self, info: &LoadElfInfo) {
-        assert!(info.is_64_bit());
+    /// Initialize register file with ELF info and the initial stack pointer
+    pub fn init_elfinfo_64(&mut self, info: &LoadElfInfo, sp: u64) {
+        assert_eq!(
+            info.is_64_bit(),
+            self.xlen == Xlen::Rv64,
+            "ELF class does not match XLEN"
+        );
 
         self.zero = 0;
+        self.sp = sp;
     }
 }
 
@@ -184,4 +397,77 @@ mod test {
         }
         assert_eq!(reg_file, empty_reg);
     }
+
+    #[test]
+    fn write_x0_test() {
+        let mut reg_file = RegisterFile::empty();
+        reg_file.write(0, 0xdead);
+        assert_eq!(reg_file.read(0), 0);
+    }
+
+    #[test]
+    fn array_test() {
+        let reg_file = demo_reg_file();
+        let regs = reg_file.to_array();
+        assert_eq!(regs[31], 31);
+        assert_eq!(RegisterFile::from_array(&regs, Xlen::Rv64), reg_file);
+
+        let mut regs = [u64::MAX; 32];
+        regs[0] = 1;
+        let reg_file = RegisterFile::from_array(&regs, Xlen::Rv32);
+        assert_eq!(reg_file.read(0), 0);
+        assert_eq!(reg_file.read(1), 0xffff_ffff);
+    }
+
+    #[test]
+    fn reg_index_test() {
+        assert_eq!(reg_index("zero"), Some(0));
+        assert_eq!(reg_index("x0"), Some(0));
+        assert_eq!(reg_index("s11"), Some(27));
+        assert_eq!(reg_index("x31"), Some(31));
+        assert_eq!(reg_index("x32"), None);
+        assert_eq!(reg_index("x05"), None);
+        assert_eq!(reg_index("pc"), None);
+    }
+
+    #[test]
+    fn trace_regs_test() {
+        // li a0, 5; addi a0, a0, 1; sd a0, 0(sp)
+        let mut reg_file = RegisterFile::empty();
+        let mut lines = Vec::new();
+        for (pc, write) in [(0x1000, Some(5)), (0x1004, Some(6)), (0x1008, None)] {
+            let old = reg_file.read(10);
+            if let Some(value) = write {
+                reg_file.write(10, value);
+            }
+            let write = write.map(|_| (10, old));
+            lines.push(TraceRegs::Changed.line(pc, write, &reg_file));
+        }
+        assert_eq!(
+            lines,
+            ["0x1000 x10 0x0->0x5", "0x1004 x10 0x5->0x6", "0x1008"]
+        );
+        // writes to x0 are dropped
+        assert_eq!(
+            TraceRegs::Changed.line(0x100c, Some((0, 0)), &reg_file),
+            "0x100c"
+        );
+
+        let all = TraceRegs::All.line(0x1008, None, &reg_file);
+        assert!(all.starts_with("0x1008 x0=0x0 x1=0x0"), "{all}");
+        assert!(all.contains(" x10=0x6 "), "{all}");
+        assert!(all.ends_with(" x31=0x0"), "{all}");
+    }
+
+    #[test]
+    fn freg_test() {
+        let mut freg_file = FRegisterFile::empty();
+        // f0 is not hard-wired
+        freg_file.write(0, 1.5);
+        freg_file.write(31, -2.0);
+        assert_eq!(freg_file.read(0), 1.5);
+        assert_eq!(freg_file.read_bits(31), 0xc000_0000);
+        freg_file.write_bits(1, 0x7fc0_0000);
+        assert!(freg_file.read(1).is_nan());
+    }
 }
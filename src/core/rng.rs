@@ -0,0 +1,65 @@
+//! The pseudo-random generator behind every randomized feature, so that runs
+//! with the same `--seed`, program and options are repeatable.
+
+/// xorshift64 generator
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Generator of `seed`, which may be any value. The seed is scrambled
+    /// first, as xorshift64 is stuck at a zero state.
+    pub fn new(seed: u64) -> Rng {
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        Rng {
+            state: if z == 0 { 1 } else { z },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+
+    /// A value in `[0, n)`, `n` is not zero.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// A generator seeded from this one, for a feature drawing on its own.
+    /// Features forked in the same order get the same values.
+    pub fn fork(&mut self) -> Rng {
+        Rng::new(self.next_u64())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rng_test() {
+        let mut a = Rng::new(0);
+        let mut b = Rng::new(0);
+        let first = a.next_u64();
+        assert_ne!(first, 0);
+        assert_eq!(first, b.next_u64());
+        assert_ne!(first, a.next_u64());
+        assert_ne!(Rng::new(1).next_u64(), Rng::new(0).next_u64());
+
+        // forks in the same order draw the same values
+        let mut b = Rng::new(0);
+        b.next_u64();
+        b.next_u64();
+        assert_eq!(a.fork().next_u64(), b.fork().next_u64());
+        assert!((0..100).all(|_| a.below(3) < 3));
+    }
+}
@@ -0,0 +1,341 @@
+//! Compressed instructions (C extension).
+//! Every compressed instruction is expanded into its 32-bit equivalent,
+//! which then goes through the normal decoder.
+
+use log::error;
+
+use crate::{
+    core::insts::{inst_64_opcode::*, Xlen},
+    error::{Error, Result},
+};
+
+/// Length in bytes of the instruction starting with the halfword in the low
+/// bits of `inst`. Only 16-bit and 32-bit instructions are supported.
+pub fn inst_len(inst: u32) -> u64 {
+    if inst & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Bits `[hi:lo]` of `inst`, shifted to bit 0
+fn bits(inst: u16, hi: u32, lo: u32) -> u32 {
+    (inst as u32 >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+/// Bit `pos` of `inst`, moved to bit `to`
+fn bit(inst: u16, pos: u32, to: u32) -> u32 {
+    ((inst as u32 >> pos) & 1) << to
+}
+
+/// Sign-extend the low `width` bits of `imm`
+fn sext(imm: u32, width: u32) -> u32 {
+    (((imm << (32 - width)) as i32) >> (32 - width)) as u32
+}
+
+/// Full register index from a 3-bit compressed register field `[hi:hi-2]`
+fn creg(inst: u16, hi: u32) -> u32 {
+    8 + bits(inst, hi, hi - 2)
+}
+
+fn enc_i(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn enc_r(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn enc_s(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | STORE
+}
+
+fn enc_b(imm: u32, rs1: u32, funct3: u32) -> u32 {
+    (imm >> 12 & 1) << 31
+        | (imm >> 5 & 0x3f) << 25
+        | rs1 << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xf) << 8
+        | (imm >> 11 & 1) << 7
+        | BRANCH
+}
+
+fn enc_j(imm: u32, rd: u32) -> u32 {
+    (imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm >> 12 & 0xff) << 12
+        | rd << 7
+        | JAL
+}
+
+/// CI-format 6-bit immediate: imm[5] at bit 12, imm[4:0] at bits 6:2
+fn imm_ci(inst: u16) -> u32 {
+    sext(bit(inst, 12, 5) | bits(inst, 6, 2), 6)
+}
+
+/// CB-format branch offset
+fn imm_cb(inst: u16) -> u32 {
+    let imm = bit(inst, 12, 8)
+        | bits(inst, 11, 10) << 3
+        | bits(inst, 6, 5) << 6
+        | bits(inst, 4, 3) << 1
+        | bit(inst, 2, 5);
+    sext(imm, 9)
+}
+
+/// CJ-format jump offset
+fn imm_cj(inst: u16) -> u32 {
+    let imm = bit(inst, 12, 11)
+        | bit(inst, 11, 4)
+        | bits(inst, 10, 9) << 8
+        | bit(inst, 8, 10)
+        | bit(inst, 7, 6)
+        | bit(inst, 6, 7)
+        | bits(inst, 5, 3) << 1
+        | bit(inst, 2, 5);
+    sext(imm, 12)
+}
+
+/// Expand a compressed instruction into the 32-bit instruction it stands for.
+/// Reserved encodings and floating point loads/stores are decode errors.
+pub fn expand(inst: u16, xlen: Xlen) -> Result<u32> {
+    let rv64 = xlen == Xlen::Rv64;
+    let funct3 = bits(inst, 15, 13);
+    // full register fields of CR/CI/CSS formats
+    let rd = bits(inst, 11, 7);
+    let rs2 = bits(inst, 6, 2);
+    let illegal = || {
+        let msg = format!("Illegal compressed instruction {:#06x}", inst);
+        error!("{msg}");
+        Err(Error::Decode(msg))
+    };
+
+    let expanded = match (inst & 0b11, funct3) {
+        // Quadrant 0
+        (0b00, 0b000) => {
+            // c.addi4spn: addi rd', x2, nzuimm
+            let nzuimm = bits(inst, 12, 11) << 4
+                | bits(inst, 10, 7) << 6
+                | bit(inst, 6, 2)
+                | bit(inst, 5, 3);
+            if nzuimm == 0 {
+                return illegal();
+            }
+            enc_i(nzuimm, 2, 0b000, creg(inst, 4), OP_IMM)
+        }
+        (0b00, 0b010) => {
+            // c.lw: lw rd', uimm(rs1')
+            let uimm = bits(inst, 12, 10) << 3 | bit(inst, 6, 2) | bit(inst, 5, 6);
+            enc_i(uimm, creg(inst, 9), 0b010, creg(inst, 4), LOAD)
+        }
+        (0b00, 0b011) if rv64 => {
+            // c.ld: ld rd', uimm(rs1')
+            let uimm = bits(inst, 12, 10) << 3 | bits(inst, 6, 5) << 6;
+            enc_i(uimm, creg(inst, 9), 0b011, creg(inst, 4), LOAD)
+        }
+        (0b00, 0b110) => {
+            // c.sw: sw rs2', uimm(rs1')
+            let uimm = bits(inst, 12, 10) << 3 | bit(inst, 6, 2) | bit(inst, 5, 6);
+            enc_s(uimm, creg(inst, 4), creg(inst, 9), 0b010)
+        }
+        (0b00, 0b111) if rv64 => {
+            // c.sd: sd rs2', uimm(rs1')
+            let uimm = bits(inst, 12, 10) << 3 | bits(inst, 6, 5) << 6;
+            enc_s(uimm, creg(inst, 4), creg(inst, 9), 0b011)
+        }
+
+        // Quadrant 1
+        // c.addi (c.nop if rd == 0): addi rd, rd, imm
+        (0b01, 0b000) => enc_i(imm_ci(inst), rd, 0b000, rd, OP_IMM),
+        (0b01, 0b001) if rv64 => {
+            // c.addiw: addiw rd, rd, imm
+            if rd == 0 {
+                return illegal();
+            }
+            enc_i(imm_ci(inst), rd, 0b000, rd, OP_IMM_32)
+        }
+        // c.jal (RV32 only): jal x1, offset
+        (0b01, 0b001) => enc_j(imm_cj(inst), 1),
+        // c.li: addi rd, x0, imm
+        (0b01, 0b010) => enc_i(imm_ci(inst), 0, 0b000, rd, OP_IMM),
+        (0b01, 0b011) if rd == 2 => {
+            // c.addi16sp: addi x2, x2, nzimm
+            let nzimm = bit(inst, 12, 9)
+                | bit(inst, 6, 4)
+                | bit(inst, 5, 6)
+                | bits(inst, 4, 3) << 7
+                | bit(inst, 2, 5);
+            if nzimm == 0 {
+                return illegal();
+            }
+            enc_i(sext(nzimm, 10), 2, 0b000, 2, OP_IMM)
+        }
+        (0b01, 0b011) => {
+            // c.lui: lui rd, nzimm
+            let nzimm = imm_ci(inst);
+            if nzimm == 0 {
+                return illegal();
+            }
+            (nzimm & 0xfffff) << 12 | rd << 7 | LUI
+        }
+        (0b01, 0b100) => {
+            let rd = creg(inst, 9);
+            let shamt = bit(inst, 12, 5) | bits(inst, 6, 2);
+            match bits(inst, 11, 10) {
+                // shamt[5] is reserved on RV32
+                0b00 | 0b01 if !rv64 && shamt >= 32 => return illegal(),
+                // c.srli: srli rd', rd', shamt
+                0b00 => enc_i(shamt, rd, 0b101, rd, OP_IMM),
+                // c.srai: srai rd', rd', shamt
+                0b01 => enc_i(0b0100000 << 5 | shamt, rd, 0b101, rd, OP_IMM),
+                // c.andi: andi rd', rd', imm
+                0b10 => enc_i(imm_ci(inst), rd, 0b111, rd, OP_IMM),
+                _ => {
+                    let rs2 = creg(inst, 4);
+                    match (bit(inst, 12, 0), bits(inst, 6, 5)) {
+                        // c.sub: sub rd', rd', rs2'
+                        (0, 0b00) => enc_r(0b0100000, rs2, rd, 0b000, rd, OP),
+                        // c.xor: xor rd', rd', rs2'
+                        (0, 0b01) => enc_r(0, rs2, rd, 0b100, rd, OP),
+                        // c.or: or rd', rd', rs2'
+                        (0, 0b10) => enc_r(0, rs2, rd, 0b110, rd, OP),
+                        // c.and: and rd', rd', rs2'
+                        (0, 0b11) => enc_r(0, rs2, rd, 0b111, rd, OP),
+                        // c.subw: subw rd', rd', rs2'
+                        (1, 0b00) if rv64 => enc_r(0b0100000, rs2, rd, 0b000, rd, OP_32),
+                        // c.addw: addw rd', rd', rs2'
+                        (1, 0b01) if rv64 => enc_r(0, rs2, rd, 0b000, rd, OP_32),
+                        _ => return illegal(),
+                    }
+                }
+            }
+        }
+        // c.j: jal x0, offset
+        (0b01, 0b101) => enc_j(imm_cj(inst), 0),
+        // c.beqz: beq rs1', x0, offset
+        (0b01, 0b110) => enc_b(imm_cb(inst), creg(inst, 9), 0b000),
+        // c.bnez: bne rs1', x0, offset
+        (0b01, 0b111) => enc_b(imm_cb(inst), creg(inst, 9), 0b001),
+
+        // Quadrant 2
+        (0b10, 0b000) => {
+            // c.slli: slli rd, rd, shamt
+            let shamt = bit(inst, 12, 5) | bits(inst, 6, 2);
+            if !rv64 && shamt >= 32 {
+                return illegal();
+            }
+            enc_i(shamt, rd, 0b001, rd, OP_IMM)
+        }
+        (0b10, 0b010) => {
+            // c.lwsp: lw rd, uimm(x2)
+            if rd == 0 {
+                return illegal();
+            }
+            let uimm = bit(inst, 12, 5) | bits(inst, 6, 4) << 2 | bits(inst, 3, 2) << 6;
+            enc_i(uimm, 2, 0b010, rd, LOAD)
+        }
+        (0b10, 0b011) if rv64 => {
+            // c.ldsp: ld rd, uimm(x2)
+            if rd == 0 {
+                return illegal();
+            }
+            let uimm = bit(inst, 12, 5) | bits(inst, 6, 5) << 3 | bits(inst, 4, 2) << 6;
+            enc_i(uimm, 2, 0b011, rd, LOAD)
+        }
+        (0b10, 0b100) => match (bit(inst, 12, 0), rd, rs2) {
+            // c.jr with rs1 == 0 is reserved
+            (0, 0, 0) => return illegal(),
+            // c.jr: jalr x0, 0(rs1)
+            (0, rs1, 0) => enc_i(0, rs1, 0b000, 0, JALR),
+            // c.mv: add rd, x0, rs2
+            (0, rd, rs2) => enc_r(0, rs2, 0, 0b000, rd, OP),
+            // c.ebreak
+            (_, 0, 0) => 0x0010_0073,
+            // c.jalr: jalr x1, 0(rs1)
+            (_, rs1, 0) => enc_i(0, rs1, 0b000, 1, JALR),
+            // c.add: add rd, rd, rs2
+            (_, rd, rs2) => enc_r(0, rs2, rd, 0b000, rd, OP),
+        },
+        (0b10, 0b110) => {
+            // c.swsp: sw rs2, uimm(x2)
+            let uimm = bits(inst, 12, 9) << 2 | bits(inst, 8, 7) << 6;
+            enc_s(uimm, rs2, 2, 0b010)
+        }
+        (0b10, 0b111) if rv64 => {
+            // c.sdsp: sd rs2, uimm(x2)
+            let uimm = bits(inst, 12, 10) << 3 | bits(inst, 9, 7) << 6;
+            enc_s(uimm, rs2, 2, 0b011)
+        }
+
+        // c.fld, c.flw, c.fsd, c.fsw and their sp-relative forms
+        (0b00 | 0b10, 0b001 | 0b011 | 0b101 | 0b111) => {
+            let msg = format!("Unsupported compressed instruction {:#06x}", inst);
+            error!("{msg}");
+            return Err(Error::Decode(msg));
+        }
+        _ => return illegal(),
+    };
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expand_test() {
+        // (compressed, expanded) pairs from an assembler
+        let cases: [(u16, u32); 32] = [
+            (0x1141, 0xff01_0113), // c.addi sp, -16
+            (0x0808, 0x0101_0513), // c.addi4spn a0, sp, 16
+            (0x414c, 0x0045_2583), // c.lw a1, 4(a0)
+            (0x6690, 0x0086_b603), // c.ld a2, 8(a3)
+            (0xc14c, 0x00b5_2223), // c.sw a1, 4(a0)
+            (0xfef0, 0x0ec6_bc23), // c.sd a2, 248(a3)
+            (0x557d, 0xfff0_0513), // c.li a0, -1
+            (0x7785, 0xfffe_17b7), // c.lui a5, 0xfffe1
+            (0x7139, 0xfc01_0113), // c.addi16sp sp, -64
+            (0x810d, 0x0035_5513), // c.srli a0, 3
+            (0x95fd, 0x43f5_d593), // c.srai a1, 63
+            (0x9a71, 0xffc6_7613), // c.andi a2, -4
+            (0x8d0d, 0x40b5_0533), // c.sub a0, a1
+            (0x8d2d, 0x00b5_4533), // c.xor a0, a1
+            (0x8d4d, 0x00b5_6533), // c.or a0, a1
+            (0x8d6d, 0x00b5_7533), // c.and a0, a1
+            (0x9d0d, 0x40b5_053b), // c.subw a0, a1
+            (0x9d2d, 0x00b5_053b), // c.addw a0, a1
+            (0xb001, 0x801f_f06f), // c.j -2048
+            (0xd101, 0xf005_00e3), // c.beqz a0, -256
+            (0xedfd, 0x0e05_9f63), // c.bnez a1, 254
+            (0x0532, 0x00c5_1513), // c.slli a0, 12
+            (0x40b2, 0x00c1_2083), // c.lwsp ra, 12(sp)
+            (0x747e, 0x1f81_3403), // c.ldsp s0, 504(sp)
+            (0x8082, 0x0000_8067), // c.jr ra
+            (0x852e, 0x00b0_0533), // c.mv a0, a1
+            (0x9002, 0x0010_0073), // c.ebreak
+            (0x9282, 0x0002_80e7), // c.jalr t0
+            (0x952e, 0x00b5_0533), // c.add a0, a1
+            (0xdf86, 0x0e11_2e23), // c.swsp ra, 252(sp)
+            (0xffa2, 0x1e81_3c23), // c.sdsp s0, 504(sp)
+            (0x357d, 0xfff5_051b), // c.addiw a0, -1
+        ];
+        for (inst, expanded) in cases {
+            assert_eq!(expand(inst, Xlen::Rv64).unwrap(), expanded, "{inst:#06x}");
+        }
+
+        // c.jal 2046 on RV32
+        assert_eq!(expand(0x2ffd, Xlen::Rv32).unwrap(), 0x7fe0_00ef);
+        assert!(expand(0x2ffd, Xlen::Rv64).is_ok()); // c.addiw on RV64
+
+        // all-zero halfword, c.ld on RV32, c.srli with shamt[5] on RV32
+        assert!(expand(0x0000, Xlen::Rv64).is_err());
+        assert!(expand(0x6690, Xlen::Rv32).is_err());
+        assert!(expand(0x95fd, Xlen::Rv32).is_err());
+
+        assert_eq!(inst_len(0x1141), 2);
+        assert_eq!(inst_len(0xff01_0113), 4);
+    }
+}
@@ -0,0 +1,206 @@
+//! Memory scans of `x N/FMT ADDR`, and watched bytes, shared by both
+//! debuggers.
+
+use crate::error::{Error, Result};
+
+/// Unit of a memory scan, as GDB's `x/FMT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanFormat {
+    Byte,  // b, u8
+    Half,  // h, u16
+    Word,  // w, u32
+    Giant, // g, u64
+    Inst,  // i, disassembled instruction
+}
+
+impl ScanFormat {
+    fn from_char(c: char) -> Option<ScanFormat> {
+        match c {
+            'b' => Some(ScanFormat::Byte),
+            'h' => Some(ScanFormat::Half),
+            'w' => Some(ScanFormat::Word),
+            'g' => Some(ScanFormat::Giant),
+            'i' => Some(ScanFormat::Inst),
+            _ => None,
+        }
+    }
+
+    /// Bytes of a data unit
+    pub fn size(self) -> u64 {
+        match self {
+            ScanFormat::Byte => 1,
+            ScanFormat::Half => 2,
+            ScanFormat::Word | ScanFormat::Inst => 4,
+            ScanFormat::Giant => 8,
+        }
+    }
+}
+
+/// Bytes of data shown on one line
+const LINE_BYTES: u64 = 16;
+
+/// `N` units of `format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scan {
+    pub n: u64,
+    pub format: ScanFormat,
+}
+
+impl Scan {
+    /// Parse `N` or `N/FMT` with `FMT` in `b h w g i`. Quad-words without
+    /// `FMT`.
+    pub fn parse(spec: &str) -> Result<Scan> {
+        let err = || Error::DbgParse(format!("invalid scan `{spec}`, expect N or N/FMT"));
+        let (n, format) = match spec.split_once('/') {
+            Some((n, fmt)) => {
+                let mut chars = fmt.chars();
+                let format = match (chars.next(), chars.next()) {
+                    (Some(c), None) => ScanFormat::from_char(c).ok_or_else(err)?,
+                    _ => return Err(err()),
+                };
+                (n, format)
+            }
+            None => (spec, ScanFormat::Giant),
+        };
+        let n = n.parse().map_err(|_| err())?;
+        Ok(Scan { n, format })
+    }
+
+    /// Lines of the scan from `vaddr`. `read(vaddr, size)` reads a data unit,
+    /// `disasm(vaddr)` disassembles the instruction at `vaddr` and gives its
+    /// length. The scan stops at the first error.
+    pub fn lines(
+        &self,
+        vaddr: u64,
+        read: impl Fn(u64, u64) -> Result<u64>,
+        disasm: impl Fn(u64) -> Result<(String, u64)>,
+    ) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut vaddr = vaddr;
+        if self.format == ScanFormat::Inst {
+            for _ in 0..self.n {
+                match disasm(vaddr) {
+                    Ok((line, len)) => {
+                        lines.push(line);
+                        vaddr += len;
+                    }
+                    Err(e) => {
+                        lines.push(format!("{vaddr:#x}: {e}"));
+                        break;
+                    }
+                }
+            }
+            return lines;
+        }
+
+        let size = self.format.size();
+        let width = 2 * size as usize;
+        let per_line = LINE_BYTES / size;
+        let mut line = String::new();
+        for i in 0..self.n {
+            if i % per_line == 0 {
+                if !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                }
+                line = format!("{vaddr:#x}:");
+            }
+            match read(vaddr, size) {
+                Ok(value) => line += &format!(" {value:0width$x}"),
+                Err(e) => {
+                    line += &format!(" {e}");
+                    break;
+                }
+            }
+            vaddr += size;
+        }
+        if !line.is_empty() {
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+/// Bytes in address order, as watchpoints show them, e.g. `2a 00 ff`
+pub fn hex_bytes(bytes: &[u8]) -> String {
+    let bytes: Vec<_> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    bytes.join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Exception;
+
+    #[test]
+    fn scan_test() {
+        assert_eq!(
+            Scan::parse("3/h").unwrap(),
+            Scan {
+                n: 3,
+                format: ScanFormat::Half
+            }
+        );
+        assert_eq!(Scan::parse("10").unwrap().format, ScanFormat::Giant);
+        assert!(Scan::parse("3/x").is_err());
+        assert!(Scan::parse("3/bh").is_err());
+        assert!(Scan::parse("/b").is_err());
+
+        // bytes 0x00, 0x01, ... 0x1f from 0x1000
+        const BASE: u64 = 0x1000;
+        let read = |vaddr: u64, size: u64| {
+            if vaddr + size > BASE + 0x20 {
+                return Err(Exception::LoadAccessFault(vaddr).into());
+            }
+            let bytes: Vec<u8> = (vaddr - BASE..vaddr - BASE + size)
+                .map(|b| b as u8)
+                .collect();
+            let mut value = [0; 8];
+            value[..size as usize].copy_from_slice(&bytes);
+            Ok(u64::from_le_bytes(value))
+        };
+        let disasm = |vaddr: u64| {
+            if vaddr >= BASE + 6 {
+                return Err(Exception::IllegalInstruction(vaddr, 0).into());
+            }
+            // a compressed instruction at BASE + 4
+            let len = if vaddr == BASE + 4 { 2 } else { 4 };
+            Ok((format!("{vaddr:8x}:\tinst"), len))
+        };
+        let lines = |spec: &str, vaddr: u64| Scan::parse(spec).unwrap().lines(vaddr, read, disasm);
+
+        assert_eq!(
+            lines("18/b", BASE),
+            [
+                "0x1000: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f",
+                "0x1010: 10 11"
+            ]
+        );
+        assert_eq!(lines("3/h", BASE + 2), ["0x1002: 0302 0504 0706"]);
+        assert_eq!(
+            lines("5/w", BASE),
+            [
+                "0x1000: 03020100 07060504 0b0a0908 0f0e0d0c",
+                "0x1010: 13121110"
+            ]
+        );
+        assert_eq!(
+            lines("3", BASE),
+            [
+                "0x1000: 0706050403020100 0f0e0d0c0b0a0908",
+                "0x1010: 1716151413121110"
+            ]
+        );
+        assert_eq!(
+            lines("2/g", BASE + 0x18),
+            ["0x1018: 1f1e1d1c1b1a1918 LoadAccessFault at 0x1020"]
+        );
+        assert_eq!(
+            lines("3/i", BASE),
+            [
+                "    1000:\tinst",
+                "    1004:\tinst",
+                "0x1006: Illegal instruction 0x00000000 at 0x1006"
+            ]
+        );
+    }
+}
@@ -0,0 +1,63 @@
+//! Memory-mapped UART, a minimal 16550 for console output.
+//! Only the transmitter is modeled: bytes written to THR go straight to the
+//! host, and LSR always reports the transmitter as empty.
+
+use std::io::Write;
+
+/// Size of the register window in bytes
+pub const UART_SIZE: u64 = 8;
+/// Transmitter holding register
+const THR: u64 = 0;
+/// Line status register
+const LSR: u64 = 5;
+/// LSR: THR empty and transmitter empty, ready for the next byte
+const LSR_TX_READY: u8 = 0x60;
+
+pub struct Uart {
+    base: u64,
+    out: Box<dyn Write>,
+}
+
+impl std::fmt::Debug for Uart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Uart")
+            .field("base", &self.base)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Uart {
+    /// A UART at `base` which prints to the host stdout.
+    pub fn new(base: u64) -> Uart {
+        Uart::with_output(base, Box::new(std::io::stdout()))
+    }
+
+    /// A UART at `base` which prints to `out`.
+    pub fn with_output(base: u64, out: Box<dyn Write>) -> Uart {
+        Uart { base, out }
+    }
+
+    /// Whether `[vaddr, vaddr + len)` lies inside the register window.
+    pub fn contains(&self, vaddr: u64, len: u64) -> bool {
+        vaddr >= self.base && vaddr - self.base + len <= UART_SIZE
+    }
+
+    /// Read the register at `vaddr`. Unmodeled registers read as zero.
+    pub fn read(&self, vaddr: u64) -> u8 {
+        match vaddr - self.base {
+            LSR => LSR_TX_READY,
+            _ => 0,
+        }
+    }
+
+    /// Write the register at `vaddr`. Writes to unmodeled registers are
+    /// ignored.
+    pub fn write(&mut self, vaddr: u64, value: u8) {
+        if vaddr - self.base == THR {
+            self.out
+                .write_all(&[value])
+                .and_then(|_| self.out.flush())
+                .expect("Fail to write UART output");
+        }
+    }
+}
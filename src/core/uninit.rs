@@ -0,0 +1,70 @@
+//! Initial values of memory and registers the program has not written yet.
+
+use clap::ValueEnum;
+
+use super::rng::Rng;
+
+/// Bytes of every word filled in pattern mode
+pub const UNINIT_PATTERN: u64 = 0xaaaa_aaaa_aaaa_aaaa;
+
+/// How memory and registers start out before the program writes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Uninit {
+    Zero,    // all zero, which hides reads of uninitialized values
+    Pattern, // every byte 0xaa
+    Random,  // seeded pseudo-random values
+}
+
+/// Source of initial values, drawn from `rng` in random mode.
+#[derive(Debug)]
+pub struct UninitFill {
+    uninit: Uninit,
+    rng: Rng,
+}
+
+impl UninitFill {
+    pub fn new(uninit: Uninit, rng: Rng) -> UninitFill {
+        UninitFill { uninit, rng }
+    }
+
+    pub fn uninit(&self) -> Uninit {
+        self.uninit
+    }
+
+    /// The next initial value of a register or 8 bytes of memory.
+    pub fn next_u64(&mut self) -> u64 {
+        match self.uninit {
+            Uninit::Zero => 0,
+            Uninit::Pattern => UNINIT_PATTERN,
+            Uninit::Random => self.rng.next_u64(),
+        }
+    }
+
+    /// Fill `bytes` with initial values.
+    pub fn fill(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            let value = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uninit_fill_test() {
+        let mut bytes = [0u8; 12];
+        UninitFill::new(Uninit::Pattern, Rng::new(0)).fill(&mut bytes);
+        assert_eq!(bytes, [0xaa; 12]);
+
+        let mut a = UninitFill::new(Uninit::Random, Rng::new(7));
+        let mut b = UninitFill::new(Uninit::Random, Rng::new(7));
+        let mut c = UninitFill::new(Uninit::Random, Rng::new(8));
+        let first = a.next_u64();
+        assert_eq!(first, b.next_u64());
+        assert_ne!(first, c.next_u64());
+        assert_ne!(first, a.next_u64());
+    }
+}
@@ -1,17 +1,104 @@
 use std::{
-    fmt::Display,
-    ptr::{read_volatile, write_volatile},
+    cell::RefCell,
+    fmt::{Display, LowerHex},
+    fs::File,
+    io::{BufWriter, Write},
+    ops::Range,
+    path::Path,
+    ptr::{read_unaligned, write_unaligned},
 };
 
-use log::trace;
+use clap::ValueEnum;
+use goblin::elf::program_header::{PF_R, PF_W, PF_X};
+use log::{error, trace, warn};
 
 use crate::{
+    core::{
+        insts::Xlen,
+        rvc::inst_len,
+        uart::Uart,
+        uninit::{Uninit, UninitFill},
+    },
     elf::LoadElfInfo,
-    error::{Error, Result},
+    error::{Exception, Result},
+    trace::{MemEvent, MemKind, TraceFile},
 };
 
 const PROTECT_SIZE: usize = 1 * 1024 * 1024; // 1 MiB, for separation of stack
 const STACK_SIZE: usize = 8 * 1024 * 1024; // 8 MiB, for the stack
+const GUARD_SIZE: usize = 4 * 1024; // 4 KiB, the guard page below the stack
+const AT_NULL: u64 = 0; // end of the auxiliary vector
+
+/// Byte order of data loads and stores.
+/// Instruction fetch is always little-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/// Values which could be loaded from or stored into memory.
+pub trait MemData: Sized + Copy + Display + LowerHex + Into<u64> {
+    fn swap_bytes(self) -> Self;
+    /// Truncate `value` into the type.
+    fn truncate(value: u64) -> Self;
+}
+
+macro_rules! impl_mem_data {
+    ($($t:ty),*) => {
+        $(impl MemData for $t {
+            #[inline(always)]
+            fn swap_bytes(self) -> Self {
+                <$t>::swap_bytes(self)
+            }
+            #[inline(always)]
+            fn truncate(value: u64) -> Self {
+                value as $t
+            }
+        })*
+    };
+}
+
+impl_mem_data!(u8, u16, u32, u64);
+
+/// Raise an address-misaligned exception if `vaddr` is not aligned to
+/// `bytes`, which is a power of two.
+/// Memory itself allows misaligned access, CPUs call it with --strict-align.
+pub fn check_align(vaddr: u64, bytes: u64, write: bool) -> Result<()> {
+    if vaddr & (bytes - 1) == 0 {
+        Ok(())
+    } else if write {
+        Err(Exception::StoreAddressMisaligned(vaddr).into())
+    } else {
+        Err(Exception::LoadAddressMisaligned(vaddr).into())
+    }
+}
+
+/// Access permissions of a range of memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Perms {
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+}
+
+impl Perms {
+    /// Of memory out of the loaded segments, i.e. the heap and the stack
+    pub const RW: Perms = Perms {
+        read: true,
+        write: true,
+        exec: false,
+    };
+
+    /// Permissions of a segment with ELF `p_flags`.
+    pub fn from_p_flags(p_flags: u32) -> Perms {
+        Perms {
+            read: p_flags & PF_R != 0,
+            write: p_flags & PF_W != 0,
+            exec: p_flags & PF_X != 0,
+        }
+    }
+}
 
 /// For now, we view virtual memory as a continuous bytes array.
 #[derive(Debug)]
@@ -19,6 +106,23 @@ pub struct VirtualMemory {
     ld_start: usize, // vaddr where the code starts
     mm: Vec<u8>,
     mtrace: bool,
+    data_endian: Endian,
+    store_log: Option<BufWriter<File>>, // log of committed stores in order
+    store_count: u64,
+    mtrace_file: Option<RefCell<TraceFile>>, // mtrace records, also of loads
+    mem_log: Option<RefCell<TraceFile>>,     // every access, also fetches
+    access_pc: u64,                          // pc of the accesses, for mem_log
+    xlen: Xlen,                              // addresses wrap around at XLEN bits
+    // memory-mapped UART, shadows memory at its addresses
+    uart: Option<Uart>,
+    // [min_vaddr, text_end), stores into it are warned
+    text: Option<Range<usize>>,
+    // permissions of the loaded segments, enforced if set, the rest is RW
+    perms: Option<Vec<(Range<usize>, Perms)>>,
+    // loads and stores into it fault, catching stack overflows
+    guard: Option<Range<usize>>,
+    // (start, bytes) of the text as loaded, fetched from instead of memory
+    imem: Option<(usize, Vec<u8>)>,
 }
 
 impl VirtualMemory {
@@ -29,7 +133,199 @@ impl VirtualMemory {
             ld_start: 0, // default to 0, but usually not what the case is.
             mm,
             mtrace,
+            data_endian: Endian::Little,
+            store_log: None,
+            store_count: 0,
+            mtrace_file: None,
+            mem_log: None,
+            access_pc: 0,
+            xlen: Xlen::Rv64,
+            uart: None,
+            text: None,
+            perms: None,
+            guard: None,
+            imem: None,
+        }
+    }
+
+    /// Create a blank virtual memory starting at `ld_start`.
+    pub fn with_base(ld_start: usize, size: usize, mtrace: bool) -> VirtualMemory {
+        let mut vm = VirtualMemory::new(size, mtrace);
+        vm.ld_start = ld_start;
+        vm
+    }
+
+    /// Set byte order of data loads and stores.
+    pub fn set_data_endian(&mut self, data_endian: Endian) {
+        self.data_endian = data_endian;
+    }
+
+    /// Byte order of data loads and stores.
+    pub fn data_endian(&self) -> Endian {
+        self.data_endian
+    }
+
+    /// Set width of addresses.
+    pub fn set_xlen(&mut self, xlen: Xlen) {
+        self.xlen = xlen;
+    }
+
+    /// Log every store into file at `path`, one line per store in program
+    /// order: `<seq> <vaddr> <bytes> <value>`.
+    /// Logs of different CPU models running the same program should be equal.
+    pub fn set_store_log(&mut self, path: &Path) -> Result<()> {
+        self.store_log = Some(BufWriter::new(File::create(path)?));
+        Ok(())
+    }
+
+    /// Write mtrace records of every data access into file at `path`.
+    pub fn set_mtrace_file(&mut self, path: &Path) -> Result<()> {
+        self.mtrace_file = Some(RefCell::new(TraceFile::create(path)?));
+        Ok(())
+    }
+
+    /// Log every load, store and instruction fetch into file at `path`,
+    /// with the pc set by [`Self::set_access_pc`].
+    pub fn set_mem_log(&mut self, path: &Path) -> Result<()> {
+        self.mem_log = Some(RefCell::new(TraceFile::create(path)?));
+        Ok(())
+    }
+
+    /// Set pc of the instruction making the next accesses, for the memory
+    /// log.
+    pub fn set_access_pc(&mut self, pc: u64) {
+        self.access_pc = pc;
+    }
+
+    /// Record an access into the memory log, if any.
+    fn log_access(&self, kind: MemKind, vaddr: usize, size: usize, value: u64) {
+        if let Some(mem_log) = &self.mem_log {
+            mem_log.borrow_mut().mem_event(&MemEvent {
+                kind,
+                addr: vaddr as u64,
+                size: size as u64,
+                value,
+                pc: self.access_pc,
+            });
+        }
+    }
+
+    /// Map `uart` into the address space. Accesses to its registers no
+    /// longer touch memory.
+    pub fn set_uart(&mut self, uart: Uart) {
+        self.uart = Some(uart);
+    }
+
+    /// Warn on stores of the program into `text`. Fetch reads the same
+    /// memory, but the pipeline does not see stores into instructions it
+    /// has already fetched.
+    pub fn set_text_range(&mut self, text: Range<usize>) {
+        self.text = Some(text);
+    }
+
+    /// Separate instruction memory from data memory, as in a Harvard
+    /// architecture: fetches within `text` read a copy of it taken now, so
+    /// that later stores into it only change data memory. Fetches out of
+    /// it still read memory.
+    pub fn set_harvard(&mut self, text: Range<usize>) {
+        let bytes = self.mm[text.start - self.ld_start..text.end - self.ld_start].to_vec();
+        self.imem = Some((text.start, bytes));
+    }
+
+    /// Whether instruction memory is separate from data memory.
+    pub fn harvard(&self) -> bool {
+        self.imem.is_some()
+    }
+
+    /// Read the instruction memory at `pc`, [`None`] if it is not there.
+    fn imem_read<T: Sized>(&self, pc: usize) -> Option<T> {
+        let (start, bytes) = self.imem.as_ref()?;
+        let pos = pc.checked_sub(*start)?;
+        if pos + std::mem::size_of::<T>() > bytes.len() {
+            return None;
+        }
+        Some(unsafe { read_unaligned(bytes.as_ptr().add(pos) as *const T) })
+    }
+
+    /// Enforce the permissions of the loadable segments of `info`: fetching
+    /// from non-executable memory and storing into read-only memory fault.
+    /// The heap and stack out of them are RW.
+    pub fn enforce_perms(&mut self, info: &LoadElfInfo) {
+        let segments = std::iter::zip(info.vm_ranges(), info.seg_flags())
+            .map(|(vm_range, &p_flags)| (vm_range.clone(), Perms::from_p_flags(p_flags)))
+            .collect();
+        self.set_perms(segments);
+    }
+
+    /// Enforce `segments` of (range, permissions), the rest of memory is RW.
+    pub fn set_perms(&mut self, segments: Vec<(Range<usize>, Perms)>) {
+        self.perms = Some(segments);
+    }
+
+    /// Fault loads and stores into the page below the stack of memory
+    /// created by [`Self::from_elf_info`], so that a stack overflow stops
+    /// the program. The heap grows up to the page.
+    pub fn guard_stack(&mut self) {
+        let stack_bottom = (self.ld_start + self.mm.len()).saturating_sub(STACK_SIZE);
+        self.set_guard(stack_bottom.saturating_sub(GUARD_SIZE)..stack_bottom);
+    }
+
+    /// Fault loads and stores into `guard`.
+    pub fn set_guard(&mut self, guard: Range<usize>) {
+        self.guard = Some(guard);
+    }
+
+    /// Whether `[vaddr, vaddr + len)` overlaps the guard page.
+    pub fn guarded(&self, vaddr: usize, len: usize) -> bool {
+        self.guard
+            .as_ref()
+            .is_some_and(|guard| vaddr < guard.end && guard.start < vaddr + len)
+    }
+
+    /// Log an access into the guard page, which faults.
+    fn guard_hit(&self, vaddr: usize) {
+        error!(
+            "Stack overflow: guard page hit at {:#x}, pc {:#x}",
+            vaddr, self.access_pc
+        );
+    }
+
+    /// Whether permissions are enforced and `[vaddr, vaddr + len)` lacks
+    /// those `allowed` checks.
+    fn denied(&self, vaddr: usize, len: usize, allowed: impl Fn(Perms) -> bool) -> bool {
+        let Some(segments) = &self.perms else {
+            return false;
+        };
+        let perms_at = |vaddr: usize| {
+            segments
+                .iter()
+                .find(|(range, _)| range.contains(&vaddr))
+                .map_or(Perms::RW, |(_, perms)| *perms)
+        };
+        !allowed(perms_at(vaddr)) || !allowed(perms_at(vaddr + len - 1))
+    }
+
+    /// Warn if `[vaddr, vaddr + len)` overlaps the text range.
+    /// Returns whether it does.
+    fn warn_smc(&self, vaddr: usize, len: usize) -> bool {
+        let Some(text) = &self.text else {
+            return false;
+        };
+        if vaddr < text.end && text.start < vaddr + len {
+            warn!(
+                "Self-modifying code: store to {:#x} in text [{:#x}, {:#x})",
+                vaddr, text.start, text.end
+            );
+            return true;
         }
+        false
+    }
+
+    /// The UART which owns `[vaddr, vaddr + len)`, if any.
+    fn uart_at(&self, vaddr: usize, len: usize) -> Option<&Uart> {
+        self.uart
+            .as_ref()
+            .filter(|uart| uart.contains(vaddr as u64, len as u64))
     }
 
     #[allow(unused)]
@@ -45,44 +341,86 @@ impl VirtualMemory {
         let mut vm = VirtualMemory::new(tot_size, mtrace);
         vm.ld_start = info.min_vaddr();
         // debug!("vm.ld_start = {:#x}", vm.ld_start);
+        vm.load_segments(info);
+        vm
+    }
 
+    fn load_segments(&mut self, info: &LoadElfInfo) {
         for (vm_range, file_range) in std::iter::zip(info.vm_ranges(), info.file_ranges()) {
             // copy all bytes into the virtual memory
             let mut load_range = vm_range.clone();
             let load_length = file_range.end - file_range.start;
-            load_range.start -= vm.ld_start;
-            // load_range.end -= vm.ld_start;
+            load_range.start -= self.ld_start;
+            // load_range.end -= self.ld_start;
             load_range.end = load_range.start + load_length;
             // debug!("load {:#x?} from {:#x?}", load_range, file_range);
-            vm.mm[load_range].copy_from_slice(&info.raw_data()[file_range.clone()]);
+            self.mm[load_range].copy_from_slice(&info.raw_data()[file_range.clone()]);
         }
+    }
 
-        vm
+    /// Fill the memory loaded from `info` with initial values from `fill`,
+    /// except for the loaded content and the BSS, which stays zeroed.
+    pub fn fill_uninit(&mut self, info: &LoadElfInfo, fill: &mut UninitFill) {
+        if fill.uninit() == Uninit::Zero {
+            return;
+        }
+        fill.fill(&mut self.mm);
+        self.load_segments(info);
+        for bss in info.bss_ranges() {
+            let start = bss.start - self.ld_start;
+            self.mm[start..start + bss.len()].fill(0);
+        }
     }
 
     /// Read a value from a position
     #[inline(always)]
     fn host_read<T: Sized>(&self, pos: usize) -> T {
         // a raw pointer to the vector's buffer
+        // guest addresses need not be aligned for T on the host
         let mem_0 = self.mm.as_ptr();
-        unsafe { read_volatile(mem_0.add(pos) as *const T) }
+        unsafe { read_unaligned(mem_0.add(pos) as *const T) }
     }
 
     // Internal implementation for mread.
+    // Reading outside the memory raises a load access fault.
     #[inline(always)]
-    fn _mread<T: Sized>(&self, vaddr: usize) -> T {
-        self.host_read(vaddr - self.ld_start)
+    fn _mread<T: Sized>(&self, vaddr: usize) -> Result<T> {
+        if !self.contains(vaddr, std::mem::size_of::<T>()) {
+            return Err(Exception::LoadAccessFault(vaddr as u64).into());
+        }
+        Ok(self.host_read(vaddr - self.ld_start))
     }
 
     /// Read a value from a virtual memory address.
     #[inline(always)]
-    pub fn mread<T: Sized + Display>(&self, vaddr: usize) -> T {
+    pub fn mread<T: MemData>(&self, vaddr: usize) -> Result<T> {
+        let vaddr = self.xlen.trunc(vaddr as u64) as usize;
         // self.host_read(vaddr - self.ld_start)
-        let ret = self._mread::<T>(vaddr);
+        let ret = match self.uart_at(vaddr, std::mem::size_of::<T>()) {
+            Some(uart) => T::truncate(uart.read(vaddr as u64) as u64),
+            None => {
+                if self.guarded(vaddr, std::mem::size_of::<T>()) {
+                    self.guard_hit(vaddr);
+                    return Err(Exception::LoadAccessFault(vaddr as u64).into());
+                }
+                let ret = self._mread::<T>(vaddr)?;
+                match self.data_endian {
+                    Endian::Little => ret,
+                    Endian::Big => ret.swap_bytes(),
+                }
+            }
+        };
         if self.mtrace {
             trace!("mread {:#x}\t{}", vaddr, ret);
         }
-        ret
+        if let Some(mtrace_file) = &self.mtrace_file {
+            let bytes = std::mem::size_of::<T>();
+            mtrace_file
+                .borrow_mut()
+                .mem(false, vaddr as u64, bytes, ret.into());
+        }
+        self.log_access(MemKind::Read, vaddr, std::mem::size_of::<T>(), ret.into());
+        Ok(ret)
     }
 
     /// Write a value into a position
@@ -90,29 +428,180 @@ impl VirtualMemory {
     fn host_write<T: Sized>(&mut self, pos: usize, value: T) {
         // an unsafe mutable pointer to the vector's buffer
         let mem_0 = self.mm.as_mut_ptr();
-        unsafe { write_volatile(mem_0.add(pos) as *mut T, value) };
+        unsafe { write_unaligned(mem_0.add(pos) as *mut T, value) };
     }
 
-    // Internal implementation for mread.
+    // Internal implementation for mwrite.
+    // Writing outside the memory raises a store access fault.
     #[inline(always)]
-    fn _mwrite<T: Sized>(&mut self, vaddr: usize, value: T) {
+    fn _mwrite<T: Sized>(&mut self, vaddr: usize, value: T) -> Result<()> {
+        if !self.contains(vaddr, std::mem::size_of::<T>()) {
+            return Err(Exception::StoreAccessFault(vaddr as u64).into());
+        }
         self.host_write(vaddr - self.ld_start, value);
+        Ok(())
     }
 
     /// Write a value into a virtual memory address.
     #[inline(always)]
-    pub fn mwrite<T: Sized + Display>(&mut self, vaddr: usize, value: T) {
+    pub fn mwrite<T: MemData>(&mut self, vaddr: usize, value: T) -> Result<()> {
+        let vaddr = self.xlen.trunc(vaddr as u64) as usize;
         // self.host_write(vaddr - self.ld_start, value);
+        // a faulting store is neither traced nor logged
+        let is_uart = self.uart_at(vaddr, std::mem::size_of::<T>()).is_some();
+        if !is_uart
+            && (!self.contains(vaddr, std::mem::size_of::<T>())
+                || self.denied(vaddr, std::mem::size_of::<T>(), |perms| perms.write))
+        {
+            return Err(Exception::StoreAccessFault(vaddr as u64).into());
+        }
+        if !is_uart && self.guarded(vaddr, std::mem::size_of::<T>()) {
+            self.guard_hit(vaddr);
+            return Err(Exception::StoreAccessFault(vaddr as u64).into());
+        }
         if self.mtrace {
             trace!("mwrite {:#x}\t{}", vaddr, value);
         }
-        self._mwrite::<T>(vaddr, value);
+        if !is_uart {
+            self.warn_smc(vaddr, std::mem::size_of::<T>());
+        }
+        if let Some(mtrace_file) = &self.mtrace_file {
+            let bytes = std::mem::size_of::<T>();
+            mtrace_file
+                .borrow_mut()
+                .mem(true, vaddr as u64, bytes, value.into());
+        }
+        self.log_access(
+            MemKind::Write,
+            vaddr,
+            std::mem::size_of::<T>(),
+            value.into(),
+        );
+        if let Some(store_log) = self.store_log.as_mut() {
+            let bytes = std::mem::size_of::<T>();
+            writeln!(store_log, "{} {:#x} {} {:#x}", self.store_count, vaddr, bytes, value)
+                .expect("Fail to write store log");
+            self.store_count += 1;
+        }
+        if is_uart {
+            let uart = self.uart.as_mut().unwrap();
+            uart.write(vaddr as u64, value.into() as u8);
+            return Ok(());
+        }
+        let value = match self.data_endian {
+            Endian::Little => value,
+            Endian::Big => value.swap_bytes(),
+        };
+        self._mwrite::<T>(vaddr, value)
+    }
+
+    /// Write `count` consecutive copies of `value` starting at `vaddr`.
+    pub fn fill<T: MemData>(&mut self, vaddr: usize, count: usize, value: T) -> Result<()> {
+        let width = std::mem::size_of::<T>();
+        for i in 0..count {
+            self.mwrite::<T>(vaddr + i * width, value)?;
+        }
+        Ok(())
+    }
+
+    /// Write an instruction into memory, always little-endian.
+    pub fn write_inst(&mut self, pc: usize, inst: u32) -> Result<()> {
+        self._mwrite::<u32>(pc, inst)
+    }
+
+    /// Whether `[vaddr, vaddr + len)` lies inside the virtual memory.
+    pub fn contains(&self, vaddr: usize, len: usize) -> bool {
+        vaddr >= self.ld_start
+            && (vaddr - self.ld_start)
+                .checked_add(len)
+                .is_some_and(|end| end <= self.mm.len())
+    }
+
+    /// The whole memory, for snapshots.
+    pub fn memory(&self) -> &[u8] {
+        &self.mm
+    }
+
+    /// Overwrite the whole memory with a snapshot taken by `memory`.
+    pub fn restore_memory(&mut self, mm: &[u8]) {
+        assert_eq!(mm.len(), self.mm.len(), "Memory snapshot size mismatch");
+        self.mm.copy_from_slice(mm);
+    }
+
+    /// Borrow `len` bytes starting at `vaddr`, [`None`] if out of memory.
+    pub fn read_bytes(&self, vaddr: usize, len: usize) -> Option<&[u8]> {
+        if !self.contains(vaddr, len) {
+            return None;
+        }
+        let pos = vaddr - self.ld_start;
+        Some(&self.mm[pos..pos + len])
+    }
+
+    /// Copy `bytes` into memory starting at `vaddr` as they are.
+    pub fn write_bytes(&mut self, vaddr: usize, bytes: &[u8]) -> Result<()> {
+        if !self.contains(vaddr, bytes.len()) {
+            return Err(Exception::StoreAccessFault(vaddr as u64).into());
+        }
+        let pos = vaddr - self.ld_start;
+        self.mm[pos..pos + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Top of the stack at the end of memory, 16-byte aligned.
+    pub fn stack_top(&self) -> usize {
+        (self.ld_start + self.mm.len() - 16) & !0xf
+    }
+
+    /// Lay out what crt0 expects at sp on entry below the stack top: argc,
+    /// argv, an empty envp and an auxv holding only AT_NULL. The strings of
+    /// `args` are copied above them. Returns the initial sp, which points
+    /// at argc.
+    pub fn push_args(&mut self, args: &[String]) -> Result<u64> {
+        let ptr_bytes = if self.xlen == Xlen::Rv64 { 8 } else { 4 };
+        let mut pos = self.stack_top();
+        let mut argv = Vec::with_capacity(args.len());
+        for arg in args.iter().rev() {
+            pos -= arg.len() + 1;
+            self.write_bytes(pos, arg.as_bytes())?;
+            self.write_bytes(pos + arg.len(), &[0])?;
+            argv.push(pos as u64);
+        }
+        argv.reverse();
+
+        // argc, argv, argv NULL, envp NULL, AT_NULL
+        let mut words = vec![args.len() as u64];
+        words.extend(argv);
+        words.extend([0, 0, AT_NULL, 0]);
+        let sp = (pos - words.len() * ptr_bytes) & !0xf;
+        for (i, word) in words.iter().enumerate() {
+            self.write_bytes(sp + i * ptr_bytes, &word.to_le_bytes()[..ptr_bytes])?;
+        }
+        Ok(sp as u64)
+    }
+
+    /// Load the raw content of file at `path` into memory at `vaddr`.
+    pub fn load_file(&mut self, vaddr: usize, path: &Path) -> Result<()> {
+        let bytes = std::fs::read(path)?;
+        self.write_bytes(vaddr, &bytes)
+    }
+
+    /// Write `len` bytes of memory starting at `vaddr` into file at `path`
+    /// as they are.
+    pub fn dump_file(&self, vaddr: usize, len: usize, path: &Path) -> Result<()> {
+        let bytes = self
+            .read_bytes(vaddr, len)
+            .ok_or(Exception::LoadAccessFault(vaddr as u64))?;
+        std::fs::write(path, bytes)?;
+        Ok(())
     }
 
     /// Fetch instruction from memory.
     /// T should be u32 or u16 (C-extension)
+    /// Fetching outside the memory, including an instruction straddling its
+    /// end, is an error rather than an out-of-bounds read. Fetching from
+    /// non-executable memory faults if permissions are enforced.
     #[inline(always)]
-    pub fn fetch_inst<T: Sized>(&self, pc: usize) -> T {
+    pub fn fetch_inst<T: Sized>(&self, pc: usize) -> Result<T> {
         let type_name = std::any::type_name::<T>();
         let type_u32 = std::any::type_name::<u32>();
         let type_u16 = std::any::type_name::<u16>();
@@ -121,24 +610,237 @@ impl VirtualMemory {
             "T must be either u32 or u16, but got {}",
             type_name
         );
-        self._mread::<T>(pc)
+
+        let inst = match self.imem_read::<T>(pc) {
+            Some(inst) => inst,
+            None => self
+                ._mread::<T>(pc)
+                .map_err(|_| Exception::InstructionAccessFault(pc as u64))?,
+        };
+        if self.denied(pc, std::mem::size_of::<T>(), |perms| perms.exec) {
+            return Err(Exception::InstructionAccessFault(pc as u64).into());
+        }
+        Ok(inst)
     }
 
-    /// Pipeline fetch instruction from memory.
-    pub fn fetch_inst_pipeline<T: Sized>(&self, pc: usize) -> Result<T> {
-        let type_name = std::any::type_name::<T>();
-        let type_u32 = std::any::type_name::<u32>();
-        let type_u16 = std::any::type_name::<u16>();
-        assert!(
-            type_name == type_u32 || type_name == type_u16,
-            "T must be either u32 or u16, but got {}",
-            type_name
-        );
+    /// Fetch the instruction at `pc` halfword by halfword, so that a 32-bit
+    /// instruction only needs 2-byte alignment. A compressed instruction is
+    /// returned zero-extended; check its length with [`inst_len`].
+    pub fn fetch(&self, pc: usize) -> Result<u32> {
+        let low = self.fetch_inst::<u16>(pc)? as u32;
+        if inst_len(low) == 2 {
+            self.log_access(MemKind::Fetch, pc, 2, low as u64);
+            return Ok(low);
+        }
+        let high = self.fetch_inst::<u16>(pc + 2)? as u32;
+        let inst = high << 16 | low;
+        self.log_access(MemKind::Fetch, pc, 4, inst as u64);
+        Ok(inst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Error;
+    use crate::temp_path;
+
+    #[test]
+    fn data_endian_test() {
+        let mut vm = VirtualMemory::with_base(0x1000, 0x100, false);
+        vm.mwrite::<u32>(0x1000, 0x11223344).unwrap();
+        assert_eq!(vm.mread::<u8>(0x1000).unwrap(), 0x44);
+
+        vm.set_data_endian(Endian::Big);
+        vm.mwrite::<u32>(0x1000, 0x11223344).unwrap();
+        assert_eq!(vm.mread::<u8>(0x1000).unwrap(), 0x11);
+        assert_eq!(vm.mread::<u8>(0x1003).unwrap(), 0x44);
+        assert_eq!(vm.mread::<u32>(0x1000).unwrap(), 0x11223344);
+        assert_eq!(vm.mread::<u16>(0x1002).unwrap(), 0x3344);
+
+        // instruction fetch stays little-endian
+        assert_eq!(vm.fetch_inst::<u32>(0x1000).unwrap(), 0x44332211);
+        assert_eq!(vm.fetch(0x1000).unwrap(), 0x2211);
+    }
+
+    #[test]
+    fn fill_test() {
+        let mut vm = VirtualMemory::with_base(0x1000, 0x100, false);
+        vm.fill::<u16>(0x1002, 3, 0xbeef).unwrap();
+        assert_eq!(vm.mread::<u16>(0x1000).unwrap(), 0);
+        assert_eq!(vm.mread::<u16>(0x1002).unwrap(), 0xbeef);
+        assert_eq!(vm.mread::<u16>(0x1006).unwrap(), 0xbeef);
+        assert_eq!(vm.mread::<u16>(0x1008).unwrap(), 0);
+    }
+
+    #[test]
+    fn store_log_test() {
+        let path = temp_path("store-log.log");
+        let mut vm = VirtualMemory::with_base(0x1000, 0x100, false);
+        vm.set_store_log(&path).unwrap();
+        vm.mwrite::<u64>(0x1008, 0xdead_beef).unwrap();
+        vm.mwrite::<u8>(0x1000, 0x7f).unwrap();
+        vm.write_inst(0x1010, 0x13).unwrap(); // not a store of the program
+        vm.mread::<u32>(0x1008).unwrap();
+        drop(vm);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(log, "0 0x1008 8 0xdeadbeef\n1 0x1000 1 0x7f\n");
+    }
+
+    #[test]
+    fn load_dump_file_test() {
+        let path = temp_path("load-dump-file.bin");
+        let mut vm = VirtualMemory::with_base(0x1000, 0x100, false);
+        std::fs::write(&path, [1, 2, 3, 4, 5]).unwrap();
+        vm.load_file(0x1010, &path).unwrap();
+        assert_eq!(vm.mread::<u32>(0x1010).unwrap(), 0x04030201);
+        assert!(vm.load_file(0x10fc, &path).is_err());
+
+        vm.dump_file(0x100f, 4, &path).unwrap();
+        let dumped = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dumped, [0, 1, 2, 3]);
+        assert!(vm.dump_file(0x10fc, 8, &path).is_err());
+    }
+
+    #[test]
+    fn push_args_test() {
+        let mut vm = VirtualMemory::with_base(0x1000, 0x100, false);
+        assert_eq!(vm.stack_top(), 0x10f0);
+        let args = ["prog".to_string(), "-v".to_string()];
+        let sp = vm.push_args(&args).unwrap() as usize;
+        assert_eq!(sp % 16, 0);
 
-        if pc < self.ld_start {
-            Err(Error::Fetch("Pipeline fail".into()))
-        } else {
-            Ok(self._mread::<T>(pc))
+        // argc, argv, NULL, envp NULL, AT_NULL
+        assert_eq!(vm.mread::<u64>(sp).unwrap(), 2);
+        let argv0 = vm.mread::<u64>(sp + 8).unwrap() as usize;
+        let argv1 = vm.mread::<u64>(sp + 16).unwrap() as usize;
+        assert_eq!(vm.read_bytes(argv0, 5), Some(&b"prog\0"[..]));
+        assert_eq!(vm.read_bytes(argv1, 3), Some(&b"-v\0"[..]));
+        for i in 3..7 {
+            assert_eq!(vm.mread::<u64>(sp + 8 * i).unwrap(), 0);
         }
+
+        // pointers are 32 bits on RV32
+        vm.set_xlen(Xlen::Rv32);
+        let sp = vm.push_args(&args).unwrap() as usize;
+        assert_eq!(vm.mread::<u32>(sp).unwrap(), 2);
+        assert_eq!(vm.mread::<u32>(sp + 4).unwrap() as usize, argv0);
+    }
+
+    #[test]
+    fn access_fault_test() {
+        let mut vm = VirtualMemory::with_base(0x1000, 0x100, false);
+        assert_eq!(vm.mread::<u8>(0x10ff).unwrap(), 0);
+        assert!(matches!(
+            vm.mread::<u8>(0x1100),
+            Err(Error::Exception(Exception::LoadAccessFault(0x1100)))
+        ));
+        // straddling the end or below the start
+        assert!(matches!(
+            vm.mread::<u32>(0x10fe),
+            Err(Error::Exception(Exception::LoadAccessFault(0x10fe)))
+        ));
+        assert!(vm.mread::<u8>(0xfff).is_err());
+        assert!(matches!(
+            vm.mwrite::<u16>(0x10ff, 0xbeef),
+            Err(Error::Exception(Exception::StoreAccessFault(0x10ff)))
+        ));
+        assert_eq!(vm.mread::<u8>(0x10ff).unwrap(), 0);
+    }
+
+    #[test]
+    fn perms_test() {
+        let mut vm = VirtualMemory::with_base(0x1000, 0x100, false);
+        let text = Perms::from_p_flags(PF_R | PF_X);
+        let rodata = Perms::from_p_flags(PF_R);
+        vm.write_inst(0x1000, 0x13).unwrap();
+        vm.write_inst(0x1040, 0x13).unwrap();
+        vm.mwrite::<u32>(0x1020, 1).unwrap();
+
+        vm.set_perms(vec![(0x1000..0x1020, text), (0x1020..0x1030, rodata)]);
+        assert_eq!(vm.fetch(0x1000).unwrap(), 0x13);
+        assert!(matches!(
+            vm.fetch(0x1040),
+            Err(Error::Exception(Exception::InstructionAccessFault(0x1040)))
+        ));
+        // straddling into read-only memory
+        assert!(matches!(
+            vm.mwrite::<u32>(0x101e, 0),
+            Err(Error::Exception(Exception::StoreAccessFault(0x101e)))
+        ));
+        assert!(vm.mwrite::<u32>(0x1024, 0).is_err());
+        assert_eq!(vm.mread::<u32>(0x1020).unwrap(), 1);
+        // the rest of memory is RW
+        vm.mwrite::<u32>(0x1040, 0).unwrap();
+    }
+
+    #[test]
+    fn smc_test() {
+        const ENTRY: usize = 0x1000;
+        let mut vm = VirtualMemory::with_base(ENTRY, 0x100, false);
+        vm.write_inst(ENTRY, 0x13).unwrap();
+        assert!(!vm.warn_smc(ENTRY, 4));
+
+        // the store into the entry point is warned but still done
+        vm.set_text_range(ENTRY..ENTRY + 0x40);
+        assert!(vm.warn_smc(ENTRY, 4));
+        assert!(vm.warn_smc(ENTRY + 0x3c, 8));
+        assert!(!vm.warn_smc(ENTRY + 0x40, 8));
+        vm.mwrite::<u32>(ENTRY, 0x0010_0073).unwrap();
+        assert_eq!(vm.fetch(ENTRY).unwrap(), 0x0010_0073);
+    }
+
+    #[test]
+    fn harvard_test() {
+        const ENTRY: usize = 0x1000;
+        let mut vm = VirtualMemory::with_base(ENTRY, 0x100, false);
+        vm.write_inst(ENTRY, 0x13).unwrap();
+        vm.set_harvard(ENTRY..ENTRY + 0x40);
+        assert!(vm.harvard());
+
+        // the store reaches data memory, but not the instruction fetched
+        vm.mwrite::<u32>(ENTRY, 0x0010_0073).unwrap();
+        assert_eq!(vm.mread::<u32>(ENTRY).unwrap(), 0x0010_0073);
+        assert_eq!(vm.fetch(ENTRY).unwrap(), 0x13);
+        // out of the text, fetch reads memory
+        vm.mwrite::<u32>(ENTRY + 0x40, 0x0010_0073).unwrap();
+        assert_eq!(vm.fetch(ENTRY + 0x40).unwrap(), 0x0010_0073);
+    }
+
+    #[test]
+    fn uart_test() {
+        use std::{cell::RefCell, io, rc::Rc};
+
+        #[derive(Clone, Default)]
+        struct Sink(Rc<RefCell<Vec<u8>>>);
+        impl Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        const UART_BASE: usize = 0x1000_0000;
+        let sink = Sink::default();
+        let mut vm = VirtualMemory::with_base(0x1000, 0x100, false);
+        vm.set_uart(Uart::with_output(UART_BASE as u64, Box::new(sink.clone())));
+
+        for &c in b"Hi\n" {
+            // wait until the transmitter is ready
+            assert_eq!(vm.mread::<u8>(UART_BASE + 5).unwrap() & 0x20, 0x20);
+            vm.mwrite::<u8>(UART_BASE, c).unwrap();
+        }
+        assert_eq!(sink.0.borrow().as_slice(), b"Hi\n");
+
+        // other registers are not memory, out of the window is still a fault
+        vm.mwrite::<u8>(UART_BASE + 1, 0xff).unwrap();
+        assert_eq!(vm.mread::<u8>(UART_BASE + 1).unwrap(), 0);
+        assert!(vm.mread::<u8>(UART_BASE + 8).is_err());
     }
 }
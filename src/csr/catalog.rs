@@ -0,0 +1,102 @@
+//! Catalog of CSR addresses defined by the privileged specification, and
+//! the custom ones of this emulator.
+//!
+//! Only used to give accesses to CSRs that are not modeled yet a readable
+//! name. Being listed here does not mean a CSR is implemented.
+
+/// Single CSRs: (address, name)
+pub static CSR_CATALOG: &[(u16, &str)] = &[
+    // Unprivileged floating-point CSRs
+    (0x001, "fflags"),
+    (0x002, "frm"),
+    (0x003, "fcsr"),
+    // Unprivileged counters/timers
+    (0xC00, "cycle"),
+    (0xC01, "time"),
+    (0xC02, "instret"),
+    (0xC80, "cycleh"),
+    (0xC81, "timeh"),
+    (0xC82, "instreth"),
+    // Supervisor trap setup
+    (0x100, "sstatus"),
+    (0x104, "sie"),
+    (0x105, "stvec"),
+    (0x106, "scounteren"),
+    (0x10A, "senvcfg"),
+    // Supervisor trap handling
+    (0x140, "sscratch"),
+    (0x141, "sepc"),
+    (0x142, "scause"),
+    (0x143, "stval"),
+    (0x144, "sip"),
+    // Supervisor protection and translation
+    (0x180, "satp"),
+    // Machine information registers
+    (0xF11, "mvendorid"),
+    (0xF12, "marchid"),
+    (0xF13, "mimpid"),
+    (0xF14, "mhartid"),
+    (0xF15, "mconfigptr"),
+    // Machine trap setup
+    (0x300, "mstatus"),
+    (0x301, "misa"),
+    (0x302, "medeleg"),
+    (0x303, "mideleg"),
+    (0x304, "mie"),
+    (0x305, "mtvec"),
+    (0x306, "mcounteren"),
+    (0x310, "mstatush"),
+    // Machine trap handling
+    (0x340, "mscratch"),
+    (0x341, "mepc"),
+    (0x342, "mcause"),
+    (0x343, "mtval"),
+    (0x344, "mip"),
+    (0x34A, "mtinst"),
+    (0x34B, "mtval2"),
+    // Machine configuration
+    (0x30A, "menvcfg"),
+    (0x747, "mseccfg"),
+    // Machine counters/timers
+    (0xB00, "mcycle"),
+    (0xB02, "minstret"),
+    (0xB80, "mcycleh"),
+    (0xB82, "minstreth"),
+    (0x320, "mcountinhibit"),
+    // Debug/trace registers
+    (0x7A0, "tselect"),
+    (0x7A1, "tdata1"),
+    (0x7A2, "tdata2"),
+    (0x7A3, "tdata3"),
+    (0x7B0, "dcsr"),
+    (0x7B1, "dpc"),
+    (0x7B2, "dscratch0"),
+    (0x7B3, "dscratch1"),
+    // Custom machine timer compare, memory-mapped on real platforms
+    (0x7C0, "mtimecmp"),
+    (0x7C1, "mtimecmph"),
+];
+
+/// Numbered CSR families: (first address, first index, count, name prefix, name suffix)
+pub static CSR_FAMILY_CATALOG: &[(u16, u16, u16, &str, &str)] = &[
+    (0xC03, 3, 29, "hpmcounter", ""),
+    (0xC83, 3, 29, "hpmcounter", "h"),
+    (0x3A0, 0, 16, "pmpcfg", ""),
+    (0x3B0, 0, 64, "pmpaddr", ""),
+    (0xB03, 3, 29, "mhpmcounter", ""),
+    (0xB83, 3, 29, "mhpmcounter", "h"),
+    (0x323, 3, 29, "mhpmevent", ""),
+];
+
+/// Look up the name of a CSR address, [`None`] if it is not a known CSR.
+pub fn csr_name(addr: u16) -> Option<String> {
+    if let Some((_, name)) = CSR_CATALOG.iter().find(|(a, _)| *a == addr) {
+        return Some(name.to_string());
+    }
+    CSR_FAMILY_CATALOG
+        .iter()
+        .find(|(base, _, count, _, _)| (*base..*base + *count).contains(&addr))
+        .map(|(base, first, _, prefix, suffix)| {
+            format!("{}{}{}", prefix, addr - base + first, suffix)
+        })
+}
@@ -0,0 +1,616 @@
+//! Control and status registers
+
+mod catalog;
+
+use std::collections::HashSet;
+
+use clap::ValueEnum;
+use log::{error, warn};
+
+use crate::{
+    core::insts::{Inst64, Xlen},
+    error::{Error, Exception, Result},
+};
+
+pub use catalog::csr_name;
+
+/// What the CPU does on an exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TrapMode {
+    /// Stop the program with the exception
+    Fatal,
+    /// Jump to the handler at mtvec
+    Vectored,
+}
+
+pub const FFLAGS: u16 = 0x001;
+pub const FRM: u16 = 0x002;
+pub const FCSR: u16 = 0x003;
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSTATUSH: u16 = 0x310;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const MCYCLE: u16 = 0xB00;
+pub const MINSTRET: u16 = 0xB02;
+pub const MCYCLEH: u16 = 0xB80;
+pub const MINSTRETH: u16 = 0xB82;
+pub const CYCLE: u16 = 0xC00;
+pub const TIME: u16 = 0xC01;
+pub const INSTRET: u16 = 0xC02;
+pub const CYCLEH: u16 = 0xC80;
+pub const TIMEH: u16 = 0xC81;
+pub const INSTRETH: u16 = 0xC82;
+/// Custom CSRs of mtimecmp, which real platforms map into memory
+pub const MTIMECMP: u16 = 0x7C0;
+pub const MTIMECMPH: u16 = 0x7C1;
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+
+/// mstatus fields which could be written: MIE, MPIE, MPP
+const MSTATUS_MASK: u64 = MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP;
+const MSTATUS_MIE: u64 = 1 << 3;
+const MSTATUS_MPIE: u64 = 1 << 7;
+const MSTATUS_MPP_SHIFT: u32 = 11;
+const MSTATUS_MPP: u64 = 0b11 << MSTATUS_MPP_SHIFT;
+/// Big-endian data accesses of U and M modes, fixed by --data-endian. MBE is
+/// in mstatush on RV32.
+const MSTATUS_UBE: u64 = 1 << 6;
+const MSTATUS_MBE: u64 = 1 << 37;
+/// Privilege levels
+const PRIV_U: u64 = 0;
+const PRIV_M: u64 = 3;
+/// Machine software, timer and external interrupts
+const MIE_MASK: u64 = (1 << 3) | (1 << 7) | (1 << 11);
+/// Machine timer interrupt: its bit in mie and mip, and its cause
+const MIP_MTIP: u64 = 1 << 7;
+const CAUSE_MTI: u64 = 7;
+/// Extensions in misa: A, C, I and M, and F where floats are run
+const MISA_EXTENSIONS: u64 = 1
+    | (1 << (b'C' - b'A'))
+    | (1 << (b'I' - b'A'))
+    | (1 << (b'M' - b'A'));
+const MISA_F: u64 = 1 << (b'F' - b'A');
+/// fcsr: frm above the accrued exception flags
+const FCSR_MASK: u64 = 0xff;
+const FFLAGS_MASK: u64 = 0x1f;
+const FRM_SHIFT: u32 = 5;
+
+#[derive(Clone)]
+pub struct Csr {
+    // Warn once and read zero on unmodeled CSRs instead of failing
+    warn_on_unknown: bool,
+
+    // Unmodeled CSR addresses which have already been warned
+    warned: HashSet<u16>,
+
+    // Width of the registers, reported in misa.MXL
+    xlen: Xlen,
+
+    // Data accesses are big-endian, reported in mstatus.UBE and MBE
+    big_endian: bool,
+
+    // The F extension is run, reported in misa.F
+    float: bool,
+
+    // Float rounding mode and accrued exception flags
+    fcsr: u64,
+
+    // Current privilege level, set by mret
+    privilege: u64,
+
+    // Machine trap setup
+    mstatus: u64,
+    mie: u64,
+    mtvec: u64,
+
+    // Machine trap handling
+    mscratch: u64,
+    mepc: u64,
+    mcause: u64,
+    mtval: u64,
+    mip: u64,
+
+    // Machine counters
+    mcycle: u64,
+    minstret: u64,
+
+    // Machine timer, which ticks with the clock. The timer interrupt is
+    // pending while mtime >= mtimecmp.
+    mtime: u64,
+    mtimecmp: u64,
+
+    // Cycles from a timer interrupt to the next one, if the timer rearms
+    // itself
+    timer_interval: Option<u64>,
+
+    // Instructions older than the executing one which retire after it, and
+    // so are not counted in minstret yet
+    pending_retire: u64,
+}
+
+impl Csr {
+    pub fn new(warn_on_unknown: bool, xlen: Xlen) -> Csr {
+        Csr {
+            warn_on_unknown,
+            warned: HashSet::new(),
+            xlen,
+            big_endian: false,
+            float: false,
+            fcsr: 0,
+            privilege: PRIV_M,
+            mstatus: 0,
+            mie: 0,
+            mtvec: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            mip: 0,
+            mcycle: 0,
+            minstret: 0,
+            mtime: 0,
+            mtimecmp: u64::MAX,
+            timer_interval: None,
+            pending_retire: 0,
+        }
+    }
+
+    /// Read a CSR.
+    pub fn read(&mut self, addr: u16) -> Result<u64> {
+        let val = match addr {
+            FFLAGS => self.fcsr & FFLAGS_MASK,
+            FRM => self.fcsr >> FRM_SHIFT,
+            FCSR => self.fcsr,
+            MSTATUS => self.xlen.trunc(self.mstatus | self.endian_bits()),
+            MSTATUSH if self.xlen == Xlen::Rv32 => self.endian_bits() >> 32,
+            MISA => {
+                let mxl = match self.xlen {
+                    Xlen::Rv32 => 1 << 30,
+                    Xlen::Rv64 => 2 << 62,
+                };
+                let float = if self.float { MISA_F } else { 0 };
+                mxl | MISA_EXTENSIONS | float
+            }
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip(),
+            MCYCLE | CYCLE => self.xlen.trunc(self.mcycle),
+            MINSTRET | INSTRET => self.xlen.trunc(self.instret()),
+            TIME => self.xlen.trunc(self.mtime),
+            MTIMECMP => self.xlen.trunc(self.mtimecmp),
+            // high halves of the counters on RV32
+            MCYCLEH | CYCLEH if self.xlen == Xlen::Rv32 => self.mcycle >> 32,
+            MINSTRETH | INSTRETH if self.xlen == Xlen::Rv32 => self.instret() >> 32,
+            TIMEH if self.xlen == Xlen::Rv32 => self.mtime >> 32,
+            MTIMECMPH if self.xlen == Xlen::Rv32 => self.mtimecmp >> 32,
+            MVENDORID | MARCHID | MIMPID | MHARTID => 0,
+            _ => {
+                self.unmodeled(addr)?;
+                0
+            }
+        };
+        Ok(val)
+    }
+
+    /// Write a CSR. Unwritable fields keep their values (WARL).
+    pub fn write(&mut self, addr: u16, val: u64) -> Result<()> {
+        // csr[11:10] == 0b11 marks read-only CSRs
+        if addr >> 10 == 0b11 {
+            let msg = format!("write to read-only CSR {:#05X}", addr);
+            error!("{msg}");
+            return Err(Exception::IllegalInstruction(0, 0).into()); // filled in by the CPU
+        }
+        match addr {
+            FFLAGS => self.fcsr = (self.fcsr & !FFLAGS_MASK) | (val & FFLAGS_MASK),
+            FRM => self.fcsr = (self.fcsr & FFLAGS_MASK) | ((val & 0b111) << FRM_SHIFT),
+            FCSR => self.fcsr = val & FCSR_MASK,
+            MSTATUS => self.mstatus = val & MSTATUS_MASK,
+            MISA => {}
+            MSTATUSH if self.xlen == Xlen::Rv32 => {}
+            MIE => self.mie = val & MIE_MASK,
+            // only direct and vectored modes
+            MTVEC => {
+                if val & 0b11 < 2 {
+                    self.mtvec = val
+                }
+            }
+            MSCRATCH => self.mscratch = val,
+            MEPC => self.mepc = val & !0b11,
+            MCAUSE => self.mcause = val,
+            MTVAL => self.mtval = val,
+            // pending bits are set by the platform only
+            MIP => {}
+            MCYCLE => self.mcycle = self.set_low(self.mcycle, val),
+            MINSTRET => {
+                let instret = self.set_low(self.instret(), val);
+                self.set_instret(instret)
+            }
+            MCYCLEH if self.xlen == Xlen::Rv32 => {
+                self.mcycle = (self.mcycle & 0xffff_ffff) | (val << 32)
+            }
+            MINSTRETH if self.xlen == Xlen::Rv32 => {
+                let instret = (self.instret() & 0xffff_ffff) | (val << 32);
+                self.set_instret(instret)
+            }
+            MTIMECMP => self.mtimecmp = self.set_low(self.mtimecmp, val),
+            MTIMECMPH if self.xlen == Xlen::Rv32 => {
+                self.mtimecmp = (self.mtimecmp & 0xffff_ffff) | (val << 32)
+            }
+            _ => self.unmodeled(addr)?,
+        }
+        Ok(())
+    }
+
+    /// Report data accesses of every privilege level as big-endian in
+    /// mstatus.UBE and MBE. Both bits are read-only.
+    pub fn set_big_endian(&mut self, big_endian: bool) {
+        self.big_endian = big_endian;
+    }
+
+    /// Report the F extension in misa.F.
+    pub fn set_float(&mut self, float: bool) {
+        self.float = float;
+    }
+
+    /// The dynamic rounding mode in frm.
+    pub fn frm(&self) -> u8 {
+        (self.fcsr >> FRM_SHIFT) as u8
+    }
+
+    /// Accrue the float exception `flags` in fflags.
+    pub fn accrue_fflags(&mut self, flags: u64) {
+        self.fcsr |= flags & FFLAGS_MASK;
+    }
+
+    fn endian_bits(&self) -> u64 {
+        if self.big_endian {
+            MSTATUS_UBE | MSTATUS_MBE
+        } else {
+            0
+        }
+    }
+
+    /// Advance the counters and the timer by elapsed `cycles` and retired
+    /// `insts`.
+    pub fn tick(&mut self, cycles: u64, insts: u64) {
+        self.mcycle = self.mcycle.wrapping_add(cycles);
+        self.minstret = self.minstret.wrapping_add(insts);
+        self.mtime = self.mtime.wrapping_add(cycles);
+    }
+
+    /// Raise a timer interrupt `interval` cycles from now, and again
+    /// `interval` cycles after each one taken, unless the handler writes
+    /// mtimecmp itself.
+    pub fn set_timer_interval(&mut self, interval: u64) {
+        self.timer_interval = Some(interval);
+        self.mtimecmp = self.mtime.wrapping_add(interval);
+    }
+
+    /// Cycles until an interrupt enabled in mie is pending, which wfi sleeps
+    /// through, or [`None`] if no interrupt could ever wake the hart up.
+    /// The interrupt is then taken only if mstatus.MIE allows, otherwise the
+    /// hart goes on after the wfi.
+    pub fn wfi_cycles(&self) -> Option<u64> {
+        if self.mip() & self.mie != 0 {
+            return Some(0);
+        }
+        // mtimecmp is all ones until the timer is armed
+        let armed = self.mie & MIP_MTIP != 0 && self.mtimecmp != u64::MAX;
+        armed.then(|| self.mtimecmp - self.mtime)
+    }
+
+    /// mip with the timer interrupt pending bit
+    fn mip(&self) -> u64 {
+        if self.mtime >= self.mtimecmp {
+            self.mip | MIP_MTIP
+        } else {
+            self.mip
+        }
+    }
+
+    /// Set the number of instructions older than the next one executed which
+    /// have not been ticked yet, as in later stages of a pipeline. instret
+    /// reads count them, so that they give the instructions retired before
+    /// the reading one, as in program order.
+    pub fn set_pending_retire(&mut self, insts: u64) {
+        self.pending_retire = insts;
+    }
+
+    fn instret(&self) -> u64 {
+        self.minstret.wrapping_add(self.pending_retire)
+    }
+
+    fn set_instret(&mut self, instret: u64) {
+        self.minstret = instret.wrapping_sub(self.pending_retire);
+    }
+
+    /// `counter` with its low bits, or all of it on RV64, replaced by `val`.
+    fn set_low(&self, counter: u64, val: u64) -> u64 {
+        match self.xlen {
+            Xlen::Rv32 => (counter & !0xffff_ffff) | (val & 0xffff_ffff),
+            Xlen::Rv64 => val,
+        }
+    }
+
+    /// Execute a Zicsr instruction and return the old CSR value for rd.
+    /// `src1` is x[rs1] for register forms, ignored for immediate forms which
+    /// use the `rs1` field as zimm.
+    pub fn exec(&mut self, op: Inst64, addr: u16, rd: u8, rs1: u8, src1: u64) -> Result<u64> {
+        use crate::core::insts::Inst64::*;
+        let operand = match op {
+            csrrwi | csrrsi | csrrci => rs1 as u64,
+            _ => src1,
+        };
+        match op {
+            csrrw | csrrwi => {
+                // csrrw with rd=x0 shall not read the CSR
+                let old = if rd != 0 { self.read(addr)? } else { 0 };
+                self.write(addr, operand)?;
+                Ok(old)
+            }
+            csrrs | csrrsi | csrrc | csrrci => {
+                let old = self.read(addr)?;
+                // csrrs/csrrc with rs1=x0 (or zimm=0) shall not write the CSR
+                if rs1 != 0 {
+                    let new = match op {
+                        csrrs | csrrsi => old | operand,
+                        _ => old & !operand,
+                    };
+                    self.write(addr, new)?;
+                }
+                Ok(old)
+            }
+            _ => unreachable!("Not a CSR instruction: {:?}", op),
+        }
+    }
+
+    /// Take a trap into machine mode on `exception` raised by the
+    /// instruction at `epc`, and return the handler address in mtvec.
+    /// Exceptions always go to the base address, even in vectored mode.
+    pub fn trap(&mut self, exception: &Exception, epc: u64) -> Option<u64> {
+        let (cause, tval) = exception.trap_cause()?;
+        self.enter_trap(cause, tval, epc);
+        Some(self.mtvec & !0b11)
+    }
+
+    /// Take the timer interrupt, if it is pending and enabled, before the
+    /// instruction at `epc`, and return the handler address in mtvec.
+    /// Machine mode takes interrupts only with mstatus.MIE set.
+    pub fn interrupt(&mut self, epc: u64) -> Option<u64> {
+        let enabled = self.privilege < PRIV_M || self.mstatus & MSTATUS_MIE != 0;
+        if !enabled || self.mip() & self.mie & MIP_MTIP == 0 {
+            return None;
+        }
+        if let Some(interval) = self.timer_interval {
+            self.mtimecmp = self.mtime.wrapping_add(interval);
+        }
+        let interrupt = 1 << (self.xlen.bits() - 1);
+        self.enter_trap(interrupt | CAUSE_MTI, 0, epc);
+        // vectored mode jumps to base + 4 * cause
+        let base = self.mtvec & !0b11;
+        if self.mtvec & 0b11 == 1 {
+            Some(base + 4 * CAUSE_MTI)
+        } else {
+            Some(base)
+        }
+    }
+
+    /// Save the state of the interrupted program and enter machine mode.
+    fn enter_trap(&mut self, cause: u64, tval: u64, epc: u64) {
+        self.mepc = epc;
+        self.mcause = cause;
+        self.mtval = tval;
+        let mie = self.mstatus & MSTATUS_MIE != 0;
+        self.mstatus &= !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+        if mie {
+            self.mstatus |= MSTATUS_MPIE;
+        }
+        self.mstatus |= self.privilege << MSTATUS_MPP_SHIFT;
+        self.privilege = PRIV_M;
+    }
+
+    /// Return from a machine-mode trap: MIE gets MPIE, MPIE is set, the
+    /// privilege level drops to MPP and MPP becomes U.
+    /// Returns mepc to jump to.
+    pub fn mret(&mut self) -> Result<u64> {
+        if self.privilege != PRIV_M {
+            let msg = format!("mret at privilege level {}", self.privilege);
+            error!("{msg}");
+            return Err(Exception::IllegalInstruction(0, 0).into()); // filled in by the CPU
+        }
+        let mpie = self.mstatus & MSTATUS_MPIE != 0;
+        self.privilege = (self.mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT;
+        self.mstatus &= !(MSTATUS_MIE | MSTATUS_MPP);
+        if mpie {
+            self.mstatus |= MSTATUS_MIE;
+        }
+        self.mstatus |= MSTATUS_MPIE | (PRIV_U << MSTATUS_MPP_SHIFT);
+        Ok(self.mepc)
+    }
+
+    fn unmodeled(&mut self, addr: u16) -> Result<()> {
+        let msg = match csr_name(addr) {
+            Some(name) => format!("unsupported CSR {} ({:#05X})", name, addr),
+            None => format!("unknown CSR {:#05X}", addr),
+        };
+        if !self.warn_on_unknown {
+            return Err(Error::Execute(msg));
+        }
+        if self.warned.insert(addr) {
+            warn!("{msg}, reads as zero and ignores writes");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn catalog_test() {
+        assert_eq!(csr_name(0x300).as_deref(), Some("mstatus"));
+        assert_eq!(csr_name(0x3B0).as_deref(), Some("pmpaddr0"));
+        assert_eq!(csr_name(0x3EF).as_deref(), Some("pmpaddr63"));
+        assert_eq!(csr_name(0xC83).as_deref(), Some("hpmcounter3h"));
+        assert_eq!(csr_name(0x7FF), None);
+    }
+
+    #[test]
+    fn machine_csr_test() {
+        use crate::core::insts::Inst64::*;
+        let mut csr = Csr::new(false, Xlen::Rv64);
+        assert_eq!(csr.exec(csrrs, MHARTID, 5, 0, 0).unwrap(), 0);
+        assert_eq!(csr.read(MISA).unwrap() >> 62, 2);
+
+        // toggle bit 4 of mscratch
+        csr.exec(csrrw, MSCRATCH, 0, 6, 0xf0).unwrap();
+        assert_eq!(csr.exec(csrrci, MSCRATCH, 5, 0x10, 0).unwrap(), 0xf0);
+        assert_eq!(csr.exec(csrrsi, MSCRATCH, 5, 0x10, 0).unwrap(), 0xe0);
+        assert_eq!(csr.read(MSCRATCH).unwrap(), 0xf0);
+
+        // WARL fields and read-only CSRs
+        csr.write(MSTATUS, u64::MAX).unwrap();
+        assert_eq!(csr.read(MSTATUS).unwrap(), MSTATUS_MASK);
+        csr.write(MEPC, 0x8000_0003).unwrap();
+        assert_eq!(csr.read(MEPC).unwrap(), 0x8000_0000);
+        assert!(csr.exec(csrrw, MHARTID, 0, 6, 1).is_err());
+        assert!(csr.exec(csrrs, CYCLE, 5, 0, 0).is_ok());
+
+        csr.tick(3, 2);
+        assert_eq!(csr.read(CYCLE).unwrap(), 3);
+        assert_eq!(csr.read(MINSTRET).unwrap(), 2);
+        assert!(csr.read(CYCLEH).is_err());
+
+        // 2 older instructions still in the pipeline
+        csr.set_pending_retire(2);
+        assert_eq!(csr.read(INSTRET).unwrap(), 4);
+        csr.write(MINSTRET, 10).unwrap();
+        csr.set_pending_retire(0);
+        csr.tick(1, 2);
+        assert_eq!(csr.read(INSTRET).unwrap(), 10);
+    }
+
+    #[test]
+    fn fcsr_test() {
+        let mut csr = Csr::new(false, Xlen::Rv64);
+        assert_eq!(csr.read(MISA).unwrap() & MISA_F, 0);
+        csr.set_float(true);
+        assert_eq!(csr.read(MISA).unwrap() & MISA_F, MISA_F);
+
+        // frm and fflags are views of fcsr
+        csr.write(FRM, 0b001).unwrap();
+        csr.accrue_fflags(0b10000);
+        csr.accrue_fflags(0b00001);
+        assert_eq!(csr.read(FCSR).unwrap(), 0b001_10001);
+        assert_eq!(csr.read(FFLAGS).unwrap(), 0b10001);
+        assert_eq!(csr.frm(), 0b001);
+        csr.write(FFLAGS, 0).unwrap();
+        assert_eq!(csr.read(FCSR).unwrap(), 0b001_00000);
+        csr.write(FCSR, u64::MAX).unwrap();
+        assert_eq!(csr.read(FRM).unwrap(), 0b111);
+        assert_eq!(csr.read(FFLAGS).unwrap(), 0x1f);
+    }
+
+    #[test]
+    fn endian_test() {
+        let mut csr = Csr::new(false, Xlen::Rv64);
+        csr.set_big_endian(true);
+        csr.write(MSTATUS, 0).unwrap();
+        assert_eq!(csr.read(MSTATUS).unwrap(), MSTATUS_UBE | MSTATUS_MBE);
+        assert!(csr.read(MSTATUSH).is_err());
+
+        let mut csr = Csr::new(false, Xlen::Rv32);
+        csr.set_big_endian(true);
+        assert_eq!(csr.read(MSTATUS).unwrap(), MSTATUS_UBE);
+        assert_eq!(csr.read(MSTATUSH).unwrap(), 1 << 5);
+        csr.set_big_endian(false);
+        assert_eq!(csr.read(MSTATUSH).unwrap(), 0);
+    }
+
+    #[test]
+    fn rv32_counter_test() {
+        let mut csr = Csr::new(false, Xlen::Rv32);
+        csr.write(MCYCLE, 0xffff_ffff).unwrap();
+        csr.tick(1, 0);
+        assert_eq!(csr.read(CYCLE).unwrap(), 0);
+        assert_eq!(csr.read(CYCLEH).unwrap(), 1);
+        csr.write(MINSTRETH, 5).unwrap();
+        csr.write(MINSTRET, 7).unwrap();
+        assert_eq!(csr.read(INSTRETH).unwrap(), 5);
+        assert_eq!(csr.read(INSTRET).unwrap(), 7);
+    }
+
+    #[test]
+    fn timer_test() {
+        let mut csr = Csr::new(false, Xlen::Rv64);
+        csr.write(MTVEC, 0x8000_0001).unwrap();
+        csr.write(MIE, MIP_MTIP).unwrap();
+        csr.write(MSTATUS, MSTATUS_MIE).unwrap();
+        // never pending without a timer interval
+        csr.tick(100, 0);
+        assert_eq!(csr.read(TIME).unwrap(), 100);
+        assert_eq!(csr.interrupt(0x8000_0100), None);
+
+        csr.set_timer_interval(10);
+        csr.tick(9, 0);
+        assert_eq!(csr.read(MIP).unwrap(), 0);
+        csr.tick(1, 0);
+        assert_eq!(csr.read(MIP).unwrap(), MIP_MTIP);
+        assert_eq!(csr.interrupt(0x8000_0100), Some(0x8000_001c));
+        assert_eq!(csr.read(MCAUSE).unwrap(), (1 << 63) | CAUSE_MTI);
+        assert_eq!(csr.read(MEPC).unwrap(), 0x8000_0100);
+        // rearmed, and masked in the handler
+        assert_eq!(csr.read(MTIMECMP).unwrap(), 120);
+        csr.tick(20, 0);
+        assert_eq!(csr.interrupt(0x8000_0000), None);
+        csr.mret().unwrap();
+        assert!(csr.interrupt(0x8000_0100).is_some());
+
+        // masked by mie
+        csr.mret().unwrap();
+        csr.write(MTIMECMP, 0).unwrap();
+        csr.write(MIE, 0).unwrap();
+        assert_eq!(csr.read(MIP).unwrap(), MIP_MTIP);
+        assert_eq!(csr.interrupt(0x8000_0100), None);
+    }
+
+    #[test]
+    fn mret_test() {
+        let mut csr = Csr::new(false, Xlen::Rv64);
+        csr.write(MEPC, 0x8000_0100).unwrap();
+        csr.write(MSTATUS, MSTATUS_MPIE | (PRIV_M << MSTATUS_MPP_SHIFT))
+            .unwrap();
+        assert_eq!(csr.mret().unwrap(), 0x8000_0100);
+        assert_eq!(csr.read(MSTATUS).unwrap(), MSTATUS_MIE | MSTATUS_MPIE);
+        assert_eq!(csr.privilege, PRIV_M);
+
+        // now MPP is U, the next mret leaves machine mode
+        csr.mret().unwrap();
+        assert_eq!(csr.privilege, PRIV_U);
+        assert!(csr.mret().is_err());
+    }
+
+    #[test]
+    fn unmodeled_test() {
+        let mut csr = Csr::new(false, Xlen::Rv64);
+        assert!(csr.read(0x3B0).is_err());
+
+        let mut csr = Csr::new(true, Xlen::Rv64);
+        assert_eq!(csr.exec(Inst64::csrrs, 0x3B0, 5, 0, 0).unwrap(), 0);
+        assert_eq!(csr.exec(Inst64::csrrw, 0x7FF, 5, 6, 42).unwrap(), 0);
+        assert!(csr.warned.contains(&0x3B0) && csr.warned.contains(&0x7FF));
+    }
+}
@@ -0,0 +1,156 @@
+//! Disassembly of the whole program for tooling: every instruction of the
+//! executable segments, decoded into JSON.
+
+use std::path::Path;
+
+use goblin::elf::program_header::PF_X;
+
+use crate::{
+    core::{insts::Xlen, rvc::inst_len},
+    elf::LoadElfInfo,
+    error::Result,
+    multi_stage::fetch::inst_interpret,
+};
+
+/// A decoded instruction of the program, or a halfword which does not
+/// decode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmEntry {
+    pub addr: u64,
+    pub raw: u32,         // 16 bits of compressed instructions and unknown halfwords
+    pub mnemonic: String, // `unknown` if it does not decode
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub imm: u64, // the immediate field as decoded, not sign-extended
+}
+
+impl DisasmEntry {
+    fn unknown(addr: u64, raw: u16) -> DisasmEntry {
+        DisasmEntry {
+            addr,
+            raw: raw as u32,
+            mnemonic: "unknown".to_string(),
+            rd: 0,
+            rs1: 0,
+            rs2: 0,
+            imm: 0,
+        }
+    }
+
+    /// Bytes the entry covers.
+    fn len(&self) -> usize {
+        if self.mnemonic == "unknown" {
+            2
+        } else {
+            inst_len(self.raw) as usize
+        }
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"addr\": {}, \"raw\": {}, \"mnemonic\": \"{}\", \"rd\": {}, \"rs1\": {}, \"rs2\": {}, \"imm\": {}}}",
+            self.addr, self.raw, self.mnemonic, self.rd, self.rs1, self.rs2, self.imm
+        )
+    }
+}
+
+/// Decode the instruction at the start of `bytes`, loaded at `addr`.
+fn decode(addr: u64, bytes: &[u8], xlen: Xlen) -> DisasmEntry {
+    let halfword = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+    let low = halfword(0);
+    let inst = match inst_len(low as u32) {
+        2 => low as u32,
+        _ if bytes.len() >= 4 => (halfword(2) as u32) << 16 | low as u32,
+        _ => return DisasmEntry::unknown(addr, low), // cut off at the end
+    };
+    match inst_interpret(addr, inst, xlen) {
+        Ok(itl) => DisasmEntry {
+            addr,
+            raw: inst,
+            mnemonic: itl.exec_flags.alu_op.to_string(),
+            rd: itl.rd,
+            rs1: itl.rs1,
+            rs2: itl.rs2,
+            imm: itl.imm,
+        },
+        Err(_) => DisasmEntry::unknown(addr, low),
+    }
+}
+
+/// Decode the executable segments of `info` from their start to their end.
+/// What does not decode is skipped a halfword at a time, so that every byte
+/// is covered.
+pub fn disasm_program(info: &LoadElfInfo) -> Vec<DisasmEntry> {
+    let xlen = if info.is_64_bit() {
+        Xlen::Rv64
+    } else {
+        Xlen::Rv32
+    };
+    let mut entries = Vec::new();
+    let segments = std::iter::zip(info.vm_ranges(), info.file_ranges())
+        .zip(info.seg_flags())
+        .filter(|(_, &flags)| flags & PF_X != 0);
+    for ((vm_range, file_range), _) in segments {
+        let bytes = &info.raw_data()[file_range.clone()];
+        let mut offset = 0;
+        while offset + 2 <= bytes.len() {
+            let entry = decode((vm_range.start + offset) as u64, &bytes[offset..], xlen);
+            offset += entry.len();
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+/// Write the disassembly of `info` into file at `path` as a JSON array of
+/// `{addr, raw, mnemonic, rd, rs1, rs2, imm}`, one object per line.
+pub fn write_disasm_json(info: &LoadElfInfo, path: &Path) -> Result<()> {
+    let objects: Vec<_> = disasm_program(info)
+        .iter()
+        .map(|entry| format!("  {}", entry.to_json()))
+        .collect();
+    std::fs::write(path, format!("[\n{}\n]\n", objects.join(",\n")))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{core::asm::assemble, elf::read_flat_binary, temp_path};
+
+    #[test]
+    fn disasm_whole_text() {
+        // 3 instructions, a compressed c.nop and a zero word, which is not
+        // an instruction
+        let mut image: Vec<u8> = ["addi a0, zero, -1", "add a1, a0, a0", "ecall"]
+            .iter()
+            .flat_map(|line| assemble(line).unwrap().to_le_bytes())
+            .collect();
+        image.extend([0x01, 0x00]);
+        image.extend([0; 4]);
+        let path = temp_path("disasm-whole-text.bin");
+        std::fs::write(&path, &image).unwrap();
+        let info = read_flat_binary(&path, 0x8000_0000, 0).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let entries = disasm_program(&info);
+        assert_eq!(
+            entries.iter().map(DisasmEntry::len).sum::<usize>(),
+            image.len()
+        );
+        assert_eq!(entries.len(), 6);
+        assert_eq!(entries[0].mnemonic, "addi");
+        assert_eq!((entries[0].rd, entries[0].imm), (10, 0xfff));
+        assert_eq!(entries[1].addr, 0x8000_0004);
+        assert_eq!((entries[1].rs1, entries[1].rs2), (10, 10));
+        assert_eq!(entries[3].raw, 0x0001);
+        assert_eq!(entries[3].addr, 0x8000_000c);
+        assert!(entries[4..].iter().all(|entry| entry.mnemonic == "unknown"));
+        assert_eq!(
+            entries[0].to_json(),
+            "{\"addr\": 2147483648, \"raw\": 4293920019, \"mnemonic\": \"addi\", \
+             \"rd\": 10, \"rs1\": 0, \"rs2\": 0, \"imm\": 4095}"
+        );
+    }
+}
@@ -1,19 +1,30 @@
 use std::{collections::HashMap, fs, ops::Range, path::PathBuf};
 
-use goblin::elf::{header, program_header, Elf};
+use goblin::elf::{header, program_header, sym, Elf};
 use log::error;
 
 use crate::error::{Error, Result};
 
+/// A function symbol covering `[addr, addr + size)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuncSymbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+}
+
 pub struct LoadElfInfo {
     raw_data: Vec<u8>,
     is_64_bit: bool,
     entry_point: u64,
     vm_ranges: Vec<Range<usize>>,
     file_ranges: Vec<Range<usize>>,
+    seg_flags: Vec<u32>,           // p_flags of each loadable range
+    bss_ranges: Vec<Range<usize>>, // [p_filesz, p_memsz) of each loadable range
     min_vaddr: usize,
     max_vaddr: usize,
     symbol_map: HashMap<u64, String>,
+    func_symbols: Vec<FuncSymbol>, // sorted by address
 }
 
 impl LoadElfInfo {
@@ -37,6 +48,32 @@ impl LoadElfInfo {
         &self.file_ranges
     }
 
+    /// p_flags of each loadable range, in the order of `vm_ranges`.
+    pub fn seg_flags(&self) -> &Vec<u32> {
+        &self.seg_flags
+    }
+
+    /// Parts of the loadable ranges beyond the file content, which start out
+    /// zeroed.
+    pub fn bss_ranges(&self) -> &Vec<Range<usize>> {
+        &self.bss_ranges
+    }
+
+    /// End of the executable loadable ranges, [`None`] if there are none.
+    pub fn text_end(&self) -> Option<usize> {
+        std::iter::zip(&self.vm_ranges, &self.seg_flags)
+            .filter(|(_, flags)| *flags & program_header::PF_X != 0)
+            .map(|(vm_range, _)| vm_range.end)
+            .max()
+    }
+
+    /// Whether `vaddr` lies in an executable loadable range.
+    pub fn is_executable(&self, vaddr: usize) -> bool {
+        std::iter::zip(&self.vm_ranges, &self.seg_flags).any(|(vm_range, flags)| {
+            *flags & program_header::PF_X != 0 && vm_range.contains(&vaddr)
+        })
+    }
+
     pub fn min_vaddr(&self) -> usize {
         self.min_vaddr
     }
@@ -48,11 +85,38 @@ impl LoadElfInfo {
     pub fn symbol_map(&self) -> &HashMap<u64, String> {
         &self.symbol_map
     }
+
+    /// Function symbols sorted by address.
+    pub fn func_symbols(&self) -> &[FuncSymbol] {
+        &self.func_symbols
+    }
+
+    /// Look up the address of a symbol by its name.
+    pub fn symbol_addr(&self, name: &str) -> Option<u64> {
+        self.symbol_map
+            .iter()
+            .find(|(_, sym)| *sym == name)
+            .map(|(addr, _)| *addr)
+    }
 }
 
-pub fn read_elf(path: &PathBuf) -> Result<LoadElfInfo> {
+/// Load bias of position-independent executables unless given: where Linux
+/// puts them on RV64, and past the first pages on RV32.
+pub const DEFAULT_LOAD_BIAS_64: u64 = 0x5555_5555_4000;
+pub const DEFAULT_LOAD_BIAS_32: u64 = 0x1_0000;
+
+/// Load an ELF file. Position-independent executables (ET_DYN) are moved up
+/// by `load_bias`, or the default of their ELF class, other files are loaded
+/// at their own addresses. Relocations are not applied.
+pub fn read_elf(path: &PathBuf, load_bias: Option<u64>) -> Result<LoadElfInfo> {
     let raw_data = fs::read(path)?;
     let elf = Elf::parse(&raw_data)?;
+    let bias = match load_bias {
+        _ if elf.header.e_type != header::ET_DYN => 0,
+        Some(bias) => bias,
+        None if elf.is_64 => DEFAULT_LOAD_BIAS_64,
+        None => DEFAULT_LOAD_BIAS_32,
+    };
 
     /*
     {
@@ -113,13 +177,23 @@ pub fn read_elf(path: &PathBuf) -> Result<LoadElfInfo> {
 
     // Symbol table
     let mut symbol_map = HashMap::new();
+    let mut func_symbols = Vec::new();
     for sym in elf.syms.iter() {
         if let Some(name) = elf.strtab.get_at(sym.st_name) {
             // maybe we could add elf-trace?
             // info!("Symbol: {}, address: {:#x}", name, sym.st_value);
-            symbol_map.insert(sym.st_value, name.to_string());
+            let addr = sym.st_value.wrapping_add(bias);
+            symbol_map.insert(addr, name.to_string());
+            if sym.st_type() == sym::STT_FUNC {
+                func_symbols.push(FuncSymbol {
+                    name: name.to_string(),
+                    addr,
+                    size: sym.st_size,
+                });
+            }
         }
     }
+    func_symbols.sort_by_key(|func| func.addr);
 
     // Validity check
     if elf.header.e_machine != header::EM_RISCV {
@@ -140,10 +214,12 @@ pub fn read_elf(path: &PathBuf) -> Result<LoadElfInfo> {
     // );
 
     // entry point
-    let entry_point = elf.header.e_entry;
+    let entry_point = elf.header.e_entry.wrapping_add(bias);
     // fetch loadable ranges
     let mut vm_ranges = Vec::new();
     let mut file_ranges = Vec::new();
+    let mut seg_flags = Vec::new();
+    let mut bss_ranges = Vec::new();
     let mut min_vaddr = usize::MAX;
     let mut max_vaddr = usize::MIN;
     let mut min_offset = usize::MAX;
@@ -155,6 +231,7 @@ pub fn read_elf(path: &PathBuf) -> Result<LoadElfInfo> {
                 continue;
             }
             let vm_range = ph.vm_range();
+            let vm_range = vm_range.start + bias as usize..vm_range.end + bias as usize;
             let file_range = ph.file_range();
             // debug!("vm_range: {:#x?}", vm_range);
             // debug!("file_range: {:#x?}", file_range);
@@ -164,8 +241,12 @@ pub fn read_elf(path: &PathBuf) -> Result<LoadElfInfo> {
             let start_offset = file_range.start;
             let end_offset = file_range.end;
 
+            if ph.p_memsz > ph.p_filesz {
+                bss_ranges.push(start_vaddr + ph.p_filesz as usize..end_vaddr);
+            }
             vm_ranges.push(vm_range);
             file_ranges.push(file_range);
+            seg_flags.push(ph.p_flags);
             if start_vaddr < min_vaddr {
                 min_vaddr = start_vaddr;
             }
@@ -180,6 +261,13 @@ pub fn read_elf(path: &PathBuf) -> Result<LoadElfInfo> {
             }
         }
     }
+    if !is_64_bit && max_vaddr > 1 << 32 {
+        let msg = format!(
+            "segments end at {:#x} with load bias {:#x}, past the 32-bit address space",
+            max_vaddr, bias
+        );
+        return Err(Error::InvalidElf(msg));
+    }
 
     let info = LoadElfInfo {
         raw_data: raw_data.clone(),
@@ -187,9 +275,216 @@ pub fn read_elf(path: &PathBuf) -> Result<LoadElfInfo> {
         entry_point,
         vm_ranges,
         file_ranges,
+        seg_flags,
+        bss_ranges,
         min_vaddr,
         max_vaddr,
         symbol_map,
+        func_symbols,
+    };
+    Ok(info)
+}
+
+/// Load a raw image (e.g. `objcopy -O binary` output) at `base_addr` as one
+/// loadable range, and start at `base_addr + entry_offset`.
+/// Flat images carry neither class nor symbols, they are taken as RV64.
+pub fn read_flat_binary(path: &PathBuf, base_addr: u64, entry_offset: u64) -> Result<LoadElfInfo> {
+    let raw_data = fs::read(path)?;
+    let size = raw_data.len();
+    if entry_offset >= size as u64 {
+        let msg = format!(
+            "Entry offset {:#x} is out of the flat binary of {:#x} bytes",
+            entry_offset, size
+        );
+        error!("{msg}");
+        return Err(Error::InvalidElf(msg));
+    }
+
+    let base_addr = base_addr as usize;
+    let vm_range = base_addr..base_addr + size;
+    let file_range = 0..size;
+    let info = LoadElfInfo {
+        raw_data,
+        is_64_bit: true,
+        entry_point: base_addr as u64 + entry_offset,
+        vm_ranges: vec![vm_range],
+        file_ranges: vec![file_range],
+        // code and data are not told apart
+        seg_flags: vec![program_header::PF_R | program_header::PF_W | program_header::PF_X],
+        bss_ranges: Vec::new(),
+        min_vaddr: base_addr,
+        max_vaddr: base_addr + size,
+        symbol_map: HashMap::new(),
+        func_symbols: Vec::new(),
     };
     Ok(info)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::{
+        rng::Rng,
+        uninit::{Uninit, UninitFill, UNINIT_PATTERN},
+        vm::VirtualMemory,
+    };
+    use crate::temp_path;
+
+    #[test]
+    fn func_symbols_test() {
+        // test/data/funcs.o is assembled from test/data/funcs.s by
+        // `llvm-mc -triple=riscv64 -filetype=obj`
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test/data/funcs.o");
+        let info = read_elf(&path, None).unwrap();
+        let func = |name: &str, addr, size| FuncSymbol {
+            name: name.to_string(),
+            addr,
+            size,
+        };
+        assert_eq!(
+            info.func_symbols(),
+            [func("main", 0, 24), func("add_one", 24, 8)]
+        );
+    }
+
+    // A minimal RV64 ELF with one PT_LOAD segment of the whole file at
+    // `vaddr`: ELF header, program header, then `li a0, 42; ebreak` at
+    // `vaddr + ENTRY`, followed by `bss` bytes of BSS. Returns the image
+    // and the file size.
+    const ENTRY: u64 = 0x78;
+    fn minimal_elf(e_type: u16, vaddr: u64, bss: u64) -> (Vec<u8>, u64) {
+        let code = [0x13, 0x05, 0xa0, 0x02, 0x73, 0x00, 0x10, 0x00];
+        let size = ENTRY + code.len() as u64;
+        let mut image = Vec::new();
+        image.extend_from_slice(b"\x7fELF\x02\x01\x01");
+        image.resize(16, 0);
+        image.extend_from_slice(&e_type.to_le_bytes());
+        image.extend_from_slice(&header::EM_RISCV.to_le_bytes());
+        image.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        image.extend_from_slice(&(vaddr + ENTRY).to_le_bytes());
+        image.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+        image.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        image.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        for half in [64u16, 56, 1, 64, 0, 0] {
+            // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+            image.extend_from_slice(&half.to_le_bytes());
+        }
+        image.extend_from_slice(&program_header::PT_LOAD.to_le_bytes());
+        let flags = program_header::PF_R | program_header::PF_W | program_header::PF_X;
+        image.extend_from_slice(&flags.to_le_bytes());
+        for word in [0u64, vaddr, vaddr, size, size + bss, 0x1000] {
+            // p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_align
+            image.extend_from_slice(&word.to_le_bytes());
+        }
+        image.extend_from_slice(&code);
+        (image, size)
+    }
+
+    #[test]
+    fn pie_load_bias_test() {
+        const BIAS: u64 = 0x5555_5555_4000;
+        let (image, size) = minimal_elf(header::ET_DYN, 0, 0);
+        let path = temp_path("pie-load-bias.elf");
+        fs::write(&path, &image).unwrap();
+        let info = read_elf(&path, Some(BIAS));
+        fs::remove_file(&path).unwrap();
+
+        let info = info.unwrap();
+        assert_eq!(info.entry_point(), BIAS + ENTRY);
+        assert_eq!(info.min_vaddr(), BIAS as usize);
+        assert_eq!(info.max_vaddr(), (BIAS + size) as usize);
+
+        let vm = VirtualMemory::from_elf_info(&info, false);
+        assert_eq!(vm.fetch(info.entry_point() as usize).unwrap(), 0x02a00513);
+    }
+
+    // The RV32 counterpart of `minimal_elf`, without BSS
+    fn minimal_elf32(e_type: u16, vaddr: u32) -> Vec<u8> {
+        let code = [0x13, 0x05, 0xa0, 0x02, 0x73, 0x00, 0x10, 0x00];
+        let size = ENTRY as u32 + code.len() as u32;
+        let mut image = Vec::new();
+        image.extend_from_slice(b"\x7fELF\x01\x01\x01");
+        image.resize(16, 0);
+        image.extend_from_slice(&e_type.to_le_bytes());
+        image.extend_from_slice(&header::EM_RISCV.to_le_bytes());
+        for word in [1, vaddr + ENTRY as u32, 52, 0, 0] {
+            // e_version, e_entry, e_phoff, e_shoff, e_flags
+            image.extend_from_slice(&word.to_le_bytes());
+        }
+        for half in [52u16, 32, 1, 40, 0, 0] {
+            // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+            image.extend_from_slice(&half.to_le_bytes());
+        }
+        let flags = program_header::PF_R | program_header::PF_X;
+        for word in [program_header::PT_LOAD, 0, vaddr, vaddr, size, size, flags, 0x1000] {
+            // p_type, p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_flags, p_align
+            image.extend_from_slice(&word.to_le_bytes());
+        }
+        image.resize(ENTRY as usize, 0);
+        image.extend_from_slice(&code);
+        image
+    }
+
+    #[test]
+    fn pie_load_bias_by_class_test() {
+        let path = temp_path("pie-load-bias-by-class.elf");
+        fs::write(&path, minimal_elf(header::ET_DYN, 0, 0).0).unwrap();
+        let pie64 = read_elf(&path, None);
+        fs::write(&path, minimal_elf32(header::ET_DYN, 0)).unwrap();
+        let pie32 = read_elf(&path, None);
+        let pie32_high = read_elf(&path, Some(DEFAULT_LOAD_BIAS_64));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(pie64.unwrap().entry_point(), DEFAULT_LOAD_BIAS_64 + ENTRY);
+        let pie32 = pie32.unwrap();
+        assert!(!pie32.is_64_bit());
+        assert_eq!(pie32.entry_point(), DEFAULT_LOAD_BIAS_32 + ENTRY);
+        // a bias past 4 GiB does not fit RV32
+        assert!(matches!(pie32_high, Err(Error::InvalidElf(_))));
+    }
+
+    #[test]
+    fn bss_uninit_test() {
+        const BASE: u64 = 0x8000_0000;
+        const BSS: u64 = 0x20;
+        let (image, size) = minimal_elf(header::ET_EXEC, BASE, BSS);
+        let path = temp_path("bss-uninit.elf");
+        fs::write(&path, &image).unwrap();
+        let info = read_elf(&path, None);
+        fs::remove_file(&path).unwrap();
+
+        let info = info.unwrap();
+        let bss = (BASE + size) as usize..(BASE + size + BSS) as usize;
+        assert_eq!(info.bss_ranges(), std::slice::from_ref(&bss));
+
+        // the BSS reads zero and the code stays, whatever else is filled in
+        let mut vm = VirtualMemory::from_elf_info(&info, false);
+        vm.fill_uninit(&info, &mut UninitFill::new(Uninit::Pattern, Rng::new(0)));
+        assert_eq!(vm.fetch(info.entry_point() as usize).unwrap(), 0x02a00513);
+        assert_eq!(vm.mread::<u64>(bss.start).unwrap(), 0);
+        assert_eq!(vm.mread::<u64>(bss.end - 8).unwrap(), 0);
+        assert_eq!(vm.mread::<u64>(bss.end).unwrap(), UNINIT_PATTERN);
+    }
+
+    #[test]
+    fn flat_binary_test() {
+        let path = temp_path("flat-binary.bin");
+        // li a0, 42; ebreak
+        let image = [0x13, 0x05, 0xa0, 0x02, 0x73, 0x00, 0x10, 0x00];
+        fs::write(&path, image).unwrap();
+        let info = read_flat_binary(&path, 0x8000_0000, 4);
+        let out_of_image = read_flat_binary(&path, 0x8000_0000, 8);
+        fs::remove_file(&path).unwrap();
+
+        let info = info.unwrap();
+        assert!(out_of_image.is_err());
+        assert_eq!(info.entry_point(), 0x8000_0004);
+        assert_eq!(info.min_vaddr(), 0x8000_0000);
+        assert_eq!(info.max_vaddr(), 0x8000_0008);
+        assert_eq!(info.text_end(), Some(0x8000_0008));
+
+        let vm = VirtualMemory::from_elf_info(&info, false);
+        assert_eq!(vm.fetch(0x8000_0000).unwrap(), 0x02a00513);
+        assert_eq!(vm.fetch(0x8000_0004).unwrap(), 0x00100073);
+    }
+}
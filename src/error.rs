@@ -1,3 +1,5 @@
+use crate::core::insts::Inst64;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Error when performing I/O: {0}")]
@@ -10,23 +12,105 @@ pub enum Error {
     DbgParse(String),
     #[error("Unknown register name: {0}")]
     InvalidRegName(String),
-    #[error("Error when fetch instruction: {0}")]
-    Fetch(String),
     #[error("Error when decoding: {0}")]
     Decode(String),
     #[error("Error when executing: {0}")]
     Execute(String),
+    #[error("Error when assembling: {0}")]
+    Assemble(String),
+    #[error("Unsupported instruction {inst} at {pc:#x}")]
+    Unsupported { inst: Inst64, pc: u64, raw_inst: u32 }, // valid, but not run by the CPU
+    #[error("Cycle limit exceeded at pc {0:#x}")]
+    CycleLimitExceeded(u64),
+    #[error("Interrupted at pc {0:#x}")]
+    Interrupted(u64),
+    #[error("Invalid reference log: {0}")]
+    InvalidReference(String),
+    #[error("Mismatch with the reference log at pc {0:#x}:\n{1}")]
+    Mismatch(u64, String),
     #[error("{0}")]
     Exception(#[from] Exception),
 }
 
 /// CPU raised exceptions
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, Clone, Copy, thiserror::Error)]
 pub enum Exception {
     #[error("DividedByZero")]
     DividedByZero,
-    #[error("IllegalInstruction")]
-    IllegalInstruction,
+    #[error("Illegal instruction {1:#010x} at {0:#x}")]
+    IllegalInstruction(u64, u32), // pc, raw instruction
+    #[error("InstructionAccessFault at {0:#x}")]
+    InstructionAccessFault(u64),
+    #[error("LoadAccessFault at {0:#x}")]
+    LoadAccessFault(u64),
+    #[error("StoreAccessFault at {0:#x}")]
+    StoreAccessFault(u64),
+    #[error("LoadAddressMisaligned at {0:#x}")]
+    LoadAddressMisaligned(u64),
+    #[error("StoreAddressMisaligned at {0:#x}")]
+    StoreAddressMisaligned(u64),
+    #[error("Breakpoint at {0:#x}")]
+    Breakpoint(u64),
+}
+
+impl Exception {
+    /// mcause and mtval of the trap taken on the exception.
+    /// Breakpoints are left to the debugger and taken as no trap.
+    pub fn trap_cause(&self) -> Option<(u64, u64)> {
+        match *self {
+            // not an exception on RISC-V, taken as the first custom cause
+            Exception::DividedByZero => Some((24, 0)),
+            Exception::InstructionAccessFault(vaddr) => Some((1, vaddr)),
+            Exception::IllegalInstruction(_, inst) => Some((2, inst as u64)),
+            Exception::Breakpoint(_) => None,
+            Exception::LoadAddressMisaligned(vaddr) => Some((4, vaddr)),
+            Exception::LoadAccessFault(vaddr) => Some((5, vaddr)),
+            Exception::StoreAddressMisaligned(vaddr) => Some((6, vaddr)),
+            Exception::StoreAccessFault(vaddr) => Some((7, vaddr)),
+        }
+    }
+}
+
+impl Error {
+    /// Give an illegal or unsupported instruction raised where the
+    /// instruction is not known, e.g. by the CSR file or the decoder, its
+    /// `pc` and raw `inst`. Other errors are returned as they are.
+    pub fn at_inst(self, pc: u64, inst: u32) -> Error {
+        match self {
+            Error::Exception(Exception::IllegalInstruction(..)) => {
+                Exception::IllegalInstruction(pc, inst).into()
+            }
+            Error::Unsupported { inst: op, .. } => Error::Unsupported {
+                inst: op,
+                pc,
+                raw_inst: inst,
+            },
+            err => err,
+        }
+    }
+
+    /// The exception a trap handler sees for the error, if any. An
+    /// instruction the CPU does not run is illegal to the handler, which
+    /// may emulate it.
+    pub fn as_exception(&self) -> Option<Exception> {
+        match *self {
+            Error::Exception(exception) => Some(exception),
+            Error::Unsupported { pc, raw_inst, .. } => {
+                Some(Exception::IllegalInstruction(pc, raw_inst))
+            }
+            _ => None,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// Stop with [`Error::CycleLimitExceeded`] once `clock` reaches
+/// `max_cycles`, before running the cycle at `pc`.
+pub fn check_cycle_limit(max_cycles: Option<u64>, clock: u64, pc: u64) -> Result<()> {
+    if max_cycles.is_some_and(|max| clock >= max) {
+        log::error!("Cycle limit exceeded at pc {:#x}, clock {}", pc, clock);
+        return Err(Error::CycleLimitExceeded(pc));
+    }
+    Ok(())
+}
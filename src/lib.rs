@@ -0,0 +1,35 @@
+//! RISC-V emulator with single-cycle, multi-cycle and pipeline CPUs.
+//!
+//! Scripts run a program to its end with [`batch::run_elf`]; the
+//! `riscv-emulator` binary adds the debugger and the command line on top.
+
+use clap::ValueEnum;
+
+pub mod batch;
+pub mod bench;
+pub mod callstack;
+pub mod core;
+pub mod csr;
+pub mod disasm;
+pub mod elf;
+pub mod error;
+pub mod logger;
+pub mod multi_stage;
+pub mod single_cycle;
+pub mod syscall;
+pub mod trace;
+pub mod verify;
+
+/// A path in the temporary directory unique to this test process, for
+/// tests which write files.
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("riscv-emulator-{}-{name}", std::process::id()))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, ValueEnum)]
+pub enum CPUMode {
+    Single,
+    Multi,
+    Pipeline,
+}
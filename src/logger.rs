@@ -1,7 +1,23 @@
 #![allow(unused)]
+use clap::ValueEnum;
 use log::*;
+use std::{
+    fs::File,
+    io::{LineWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
 
-struct Logger;
+// Set by --quiet
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+struct Logger {
+    // Write logs into this file instead of stdout
+    file: Option<Mutex<LineWriter<File>>>,
+}
 
 impl Log for Logger {
     fn enabled(&self, _metadata: &Metadata) -> bool {
@@ -11,6 +27,12 @@ impl Log for Logger {
         if !self.enabled(record.metadata()) {
             return;
         }
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            writeln!(file, "[{:>5}] {}", record.level(), record.args())
+                .expect("Fail to write log file");
+            return;
+        }
         let color = match record.level() {
             Level::Error => 31, // Red
             Level::Warn => 93,  // BrightYellow
@@ -26,18 +48,65 @@ impl Log for Logger {
             record.args(),
         );
     }
-    fn flush(&self) {}
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            file.lock().unwrap().flush().expect("Fail to flush log file");
+        }
+    }
+}
+
+/// Most verbose level logged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> LevelFilter {
+        match level {
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
 }
 
-pub fn init() {
-    static LOGGER: Logger = Logger;
-    log::set_logger(&LOGGER).unwrap();
-    log::set_max_level(match option_env!("LOG") {
+/// Initialize the logger. Logs go to `log_file` if given, otherwise stdout.
+/// Records up to `level` are logged, or up to the level of `LOG` at build
+/// time if not given.
+pub fn init(log_file: Option<&Path>, level: Option<LogLevel>) -> std::io::Result<()> {
+    let file = match log_file {
+        Some(path) => Some(Mutex::new(LineWriter::new(File::create(path)?))),
+        None => None,
+    };
+    let logger: &'static Logger = Box::leak(Box::new(Logger { file }));
+    log::set_logger(logger).unwrap();
+    let default = match option_env!("LOG") {
         Some("ERROR") => LevelFilter::Error,
         Some("WARN") => LevelFilter::Warn,
         Some("INFO") => LevelFilter::Info,
         Some("DEBUG") => LevelFilter::Debug,
         Some("TRACE") => LevelFilter::Trace,
         _ => LevelFilter::Trace,
-    });
+    };
+    log::set_max_level(level.map_or(default, LevelFilter::from));
+    Ok(())
+}
+
+/// Log nothing, and leave how the program ended untold, for a one-line
+/// summary instead.
+pub fn set_quiet() {
+    QUIET.store(true, Ordering::Relaxed);
+    log::set_max_level(LevelFilter::Off);
+}
+
+/// Whether the run is quiet.
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
 }
@@ -1,38 +1,129 @@
-use callstack::CallStack;
-use clap::{Parser, ValueEnum};
-use core::vm::VirtualMemory;
-use elf::read_elf;
-use log::info;
-use multi_stage::cpu::{ControlPolicy, DataHazardPolicy, PredictPolicy};
-use std::path;
-
-mod callstack;
-mod core;
-mod elf;
-mod error;
-mod logger;
-mod multi_stage;
-mod single_cycle;
+use clap::{CommandFactory, Parser};
+use clap_num::maybe_hex;
+use log::{error, info, warn};
+use riscv_emulator::batch::{run_elf, RunConfig, RunResult};
+use riscv_emulator::bench::Benchmark;
+use riscv_emulator::callstack::CallStack;
+use riscv_emulator::core::{
+    insts::{follow_jumps, Xlen},
+    reg::TraceRegs,
+    rng::Rng,
+    uart::Uart,
+    uninit::{Uninit, UninitFill},
+    vm::{Endian, VirtualMemory},
+};
+use riscv_emulator::csr::TrapMode;
+use riscv_emulator::elf::{read_elf, read_flat_binary, LoadElfInfo};
+use riscv_emulator::error::{Error, Result};
+use riscv_emulator::logger::LogLevel;
+use riscv_emulator::multi_stage::{
+    branch_predict::{DEFAULT_BHT_BITS, DEFAULT_BTB_ENTRIES},
+    cache::{
+        Cache, Replacement, DEFAULT_CACHE_LINE, DEFAULT_CACHE_MISS_PENALTY, DEFAULT_CACHE_WAYS,
+    },
+    cpu::{
+        ControlPolicy, DataHazardPolicy, PipelineConfig, PredictPolicy, StatsFormat,
+        DEFAULT_DIV_LATENCY, DEFAULT_MUL_LATENCY, DEFAULT_NO_PROGRESS_CYCLES,
+    },
+};
+use riscv_emulator::syscall::SyscallAbi;
+use riscv_emulator::CPUMode;
+use std::{collections::HashMap, path, process::ExitCode};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about)]
 struct Args {
     /// Path to the program to be loaded
-    #[arg(short, long)]
-    input: String,
+    #[arg(short, long, required_unless_present = "repl")]
+    input: Option<String>,
+
+    /// Load the input as a flat binary image instead of an ELF file.
+    #[arg(long)]
+    flat: bool,
+
+    /// Address where the flat binary image is loaded.
+    #[arg(long, value_name = "ADDR", default_value = "0x80000000", value_parser = maybe_hex::<u64>)]
+    base: u64,
+
+    /// Offset of the entry point into the flat binary image.
+    #[arg(long, value_name = "OFFSET", default_value = "0", value_parser = maybe_hex::<u64>)]
+    entry_offset: u64,
+
+    /// Start running at ADDR instead of the entry point of the program,
+    /// e.g. at a function to test on its own.
+    #[arg(long, value_name = "ADDR", value_parser = maybe_hex::<u64>)]
+    entry: Option<u64>,
+
+    /// Load bias of position-independent executables (ET_DYN), 0x555555554000
+    /// for RV64 and 0x10000 for RV32 by default. Other ELF files are loaded at
+    /// their own addresses.
+    #[arg(long, value_name = "ADDR", value_parser = maybe_hex::<u64>)]
+    load_bias: Option<u64>,
 
     /// CPU mode
-    #[arg(short, long)]
-    cpu_mode: CPUMode,
+    #[arg(short, long, required_unless_present_any = ["repl", "disasm_json"])]
+    cpu_mode: Option<CPUMode>,
+
+    /// Start an assembly-level REPL on a blank single-cycle machine.
+    #[arg(long)]
+    repl: bool,
 
     /// Enable debug mode. Not set to enable batch mode.
     #[arg(short, long)]
     debug: bool,
 
+    /// Run the program to its end for scripts: print
+    /// `exit_code=N clock=N retired=N` on stdout and exit with the program's
+    /// exit code. Only the CPU mode and policies, --syscall-abi, --load-bias,
+    /// --max-cycles and the program arguments apply.
+    #[arg(long, conflicts_with_all = ["debug", "flat"])]
+    headless: bool,
+
+    /// Write the disassembly of the executable segments into PATH and exit
+    /// without running: a JSON array of `{addr, raw, mnemonic, rd, rs1, rs2,
+    /// imm}`, with halfwords which do not decode as `unknown`.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["debug", "headless"])]
+    disasm_json: Option<String>,
+
+    /// Preload a breakpoint at ADDR, can be repeated.
+    /// Without --debug, the program runs to the end and dumps state at each breakpoint.
+    #[arg(long = "break", value_name = "ADDR", value_parser = maybe_hex::<u64>)]
+    breakpoints: Vec<u64>,
+
+    /// Preload a breakpoint at the symbol NAME, can be repeated.
+    #[arg(long = "break-symbol", value_name = "NAME")]
+    break_symbols: Vec<String>,
+
     /// Enable itrace.
     #[arg(long)]
     itrace: bool,
 
+    /// Enable itrace with source register values and results, and whether
+    /// branches are taken. Ignored by the multi-cycle and pipeline CPUs.
+    #[arg(long)]
+    itrace_verbose: bool,
+
+    /// Disassemble the targets of branches and jumps as absolute addresses
+    /// and their symbols, e.g. `beq a0,a1,0x80001234 <loop>`, instead of
+    /// offsets.
+    #[arg(long)]
+    follow_jumps: bool,
+
+    /// Log the registers after every retired instruction, as a commit log to
+    /// diff against a reference simulator: all of them, or the one written
+    /// as `x<n> <old>-><new>`.
+    #[arg(long, value_name = "MODE")]
+    trace_regs: Option<TraceRegs>,
+
+    /// Write the commit log of `--trace-regs` into PATH instead of stdout.
+    #[arg(long, value_name = "PATH", requires = "trace_regs")]
+    trace_regs_file: Option<String>,
+
+    /// Check every retired instruction against the reference commit log at
+    /// PATH, e.g. of `spike --log-commits`, stopping at the first mismatch.
+    #[arg(long, value_name = "PATH")]
+    verify: Option<String>,
+
     /// Enable mtrace.
     #[arg(long)]
     mtrace: bool,
@@ -53,6 +144,107 @@ struct Args {
     #[arg(long)]
     predict_policy: Option<PredictPolicy>,
 
+    /// Index bits of the branch history table, which has `1 << n` counters.
+    #[arg(
+        long,
+        value_name = "n",
+        default_value_t = DEFAULT_BHT_BITS,
+        value_parser = clap::value_parser!(u32).range(1..=24)
+    )]
+    bht_bits: u32,
+
+    /// Number of branch target buffer entries.
+    #[arg(
+        long,
+        value_name = "n",
+        default_value_t = DEFAULT_BTB_ENTRIES as u64,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    btb_entries: u64,
+
+    /// Model a single memory port shared by IF and MEM, so that fetch stalls
+    /// for a cycle while a load or store is in MEM.
+    /// Ignored by the single-cycle and multi-cycle CPUs.
+    #[arg(long)]
+    single_mem_port: bool,
+
+    /// Split MEM into MEM1 and MEM2, delaying writeback by a cycle, with
+    /// results forwarded from MEM1/MEM2 as well.
+    /// Ignored by the single-cycle and multi-cycle CPUs.
+    #[arg(long)]
+    extra_mem_stage: bool,
+
+    /// Extra cycles a multiplication spends in EX.
+    /// Ignored by the single-cycle CPU.
+    #[arg(long, value_name = "CYCLES", default_value_t = DEFAULT_MUL_LATENCY)]
+    mul_latency: u64,
+
+    /// Extra cycles a division or remainder spends in EX. A rem right after
+    /// the div of the same operands costs nothing.
+    /// Ignored by the single-cycle CPU.
+    #[arg(long, value_name = "CYCLES", default_value_t = DEFAULT_DIV_LATENCY)]
+    div_latency: u64,
+
+    /// Put an instruction cache of this many sets in front of IF, charging a
+    /// miss penalty to the clock on every fetch missing it. The cache only
+    /// affects timing. Ignored by the single-cycle and multi-cycle CPUs.
+    #[arg(long, value_name = "SETS", value_parser = clap::value_parser!(u64).range(1..))]
+    icache_sets: Option<u64>,
+
+    /// Ways of each instruction cache set, 1 for a direct mapped cache.
+    #[arg(
+        long,
+        value_name = "WAYS",
+        default_value_t = DEFAULT_CACHE_WAYS as u64,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    icache_ways: u64,
+
+    /// Bytes of an instruction cache line.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = DEFAULT_CACHE_LINE,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    icache_line: u64,
+
+    /// Extra cycles IF spends on an instruction cache miss.
+    #[arg(long, value_name = "CYCLES", default_value_t = DEFAULT_CACHE_MISS_PENALTY)]
+    icache_miss_penalty: u64,
+
+    /// Put a data cache of this many sets in front of MEM, charging a miss
+    /// penalty to the clock on every load or store missing it. The cache only
+    /// affects timing. Ignored by the single-cycle and multi-cycle CPUs.
+    #[arg(long, value_name = "SETS", value_parser = clap::value_parser!(u64).range(1..))]
+    dcache_sets: Option<u64>,
+
+    /// Ways of each data cache set, 1 for a direct mapped cache.
+    #[arg(
+        long,
+        value_name = "WAYS",
+        default_value_t = DEFAULT_CACHE_WAYS as u64,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    dcache_ways: u64,
+
+    /// Bytes of a data cache line.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = DEFAULT_CACHE_LINE,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    dcache_line: u64,
+
+    /// Extra cycles MEM spends on a data cache miss.
+    #[arg(long, value_name = "CYCLES", default_value_t = DEFAULT_CACHE_MISS_PENALTY)]
+    dcache_miss_penalty: u64,
+
+    /// Line evicted from a full cache set on a miss, of both caches.
+    #[arg(long, default_value = "lru")]
+    cache_replacement: Replacement,
+
     // Pre-execution pipeline register info
     #[arg(long)]
     pre_pipeline_info: bool,
@@ -69,30 +261,287 @@ struct Args {
     #[arg(long)]
     control_hazard_info: bool,
 
+    /// Print the pipeline diagram of the last N instructions (50 if not
+    /// given) at the end: one row per instruction, one column per cycle.
+    /// Ignored by the single-cycle and multi-cycle CPUs.
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "50")]
+    pipeline_diagram: Option<usize>,
+
+    /// Write every hazard detected by the pipeline CPU
+    /// (`<clock> <kind> <producer> -> <consumer> <stages>`) into PATH.
+    #[arg(long, value_name = "PATH")]
+    trace_hazards: Option<String>,
+
     // Data hazard info
     #[arg(long)]
     data_hazard_info: bool,
-}
 
-#[derive(Debug, Clone, PartialEq, Eq, ValueEnum)]
-enum CPUMode {
-    Single,
-    Multi,
-    Pipeline,
+    /// Byte order of data loads and stores. Instruction fetch is always little-endian.
+    #[arg(long, default_value = "little")]
+    data_endian: Endian,
+
+    /// Write logs (itrace, mtrace, ftrace, ...) into PATH instead of stdout.
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<String>,
+
+    /// Most verbose level logged, by default the level of `LOG` at build time,
+    /// or trace. How the program ended is printed at any level.
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<LogLevel>,
+
+    /// Log nothing and print only `exit_code=N clock=N retired=N` on stdout
+    /// once the program has exited, besides the program's own output.
+    #[arg(long, conflicts_with_all = ["debug", "headless"])]
+    quiet: bool,
+
+    /// Print the summary of the run (clock, hazards, CPI, ...) on stdout at
+    /// any log level, on every CPU mode.
+    #[arg(long, conflicts_with = "headless")]
+    summary: bool,
+
+    /// Write itrace records (`<pc> <inst>` per retired instruction) into PATH.
+    /// Independent of the log level, for comparing traces of CPU modes.
+    #[arg(long, value_name = "PATH")]
+    itrace_file: Option<String>,
+
+    /// Write mtrace records (`R|W <vaddr> <bytes> <value>` per data access) into PATH.
+    #[arg(long, value_name = "PATH")]
+    mtrace_file: Option<String>,
+
+    /// Write every memory access (`R|W|F <pc> <vaddr> <bytes> <value>` per
+    /// load, store and instruction fetch) into PATH.
+    #[arg(long, value_name = "PATH")]
+    mem_log: Option<String>,
+
+    /// Write ftrace records (function calls and returns) into PATH.
+    #[arg(long, value_name = "PATH")]
+    ftrace_file: Option<String>,
+
+    /// Map a 16550 UART at ADDR, bytes written to its THR are printed to stdout.
+    #[arg(long, value_name = "ADDR", value_parser = maybe_hex::<u64>)]
+    uart_addr: Option<u64>,
+
+    /// Warn on stores into the text, i.e. from the lowest loaded address to
+    /// the end of the executable segments. The pipeline does not see such
+    /// stores into instructions it has already fetched.
+    #[arg(long)]
+    warn_smc: bool,
+
+    /// Fetch the text from a copy taken at load time, as if instruction and
+    /// data memory were separate: stores into the text do not change what
+    /// is fetched, and fetch never waits for MEM under --single-mem-port.
+    #[arg(long)]
+    harvard: bool,
+
+    /// Enforce the R/W/X flags of the loadable segments: fetching from
+    /// non-executable memory raises InstructionAccessFault and storing into
+    /// read-only memory StoreAccessFault. The heap and stack are RW.
+    #[arg(long)]
+    enforce_perms: bool,
+
+    /// Make loads and stores into the page below the stack fault, so that a
+    /// stack overflow stops the program rather than corrupting the heap.
+    #[arg(long)]
+    guard_stack: bool,
+
+    /// Load the raw content of PATH into memory at ADDR before running, e.g.
+    /// input data of a kernel.
+    #[arg(long, num_args = 2, value_names = ["ADDR", "PATH"])]
+    load_memory: Option<Vec<String>>,
+
+    /// Write LEN bytes of memory starting at ADDR into PATH as they are after
+    /// running, e.g. a result array.
+    #[arg(long, num_args = 3, value_names = ["ADDR", "LEN", "PATH"])]
+    dump_memory: Option<Vec<String>>,
+
+    /// Log every committed store of the program into PATH in order.
+    /// Diff logs of different CPU modes to check they write memory identically.
+    #[arg(long, value_name = "PATH")]
+    store_log: Option<String>,
+
+    /// Treat ebreak as a breakpoint trap which drops into REDB, instead of
+    /// halting the program.
+    #[arg(long)]
+    ebreak_as_trap: bool,
+
+    /// Serve ebreak between `slli x0, x0, 0x1f` and `srai x0, x0, 7` as a
+    /// semihosting call. Any other ebreak traps into REDB as with
+    /// --ebreak-as-trap.
+    #[arg(long)]
+    semihosting: bool,
+
+    /// Raise address-misaligned exceptions on misaligned loads and stores.
+    #[arg(long)]
+    strict_align: bool,
+
+    /// Stop with DividedByZero on division by zero, as older versions did,
+    /// instead of giving the results the RISC-V spec defines.
+    #[arg(long)]
+    div_trap: bool,
+
+    /// Initial value of memory and registers before the program writes them,
+    /// to surface reads of uninitialized values. The BSS is zeroed anyway.
+    #[arg(long, default_value = "zero")]
+    uninit: Uninit,
+
+    /// Seed of every randomized feature, `--uninit random` and
+    /// `--cache-replacement random`. Runs with the same seed, program and
+    /// options are repeatable.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Stop with an error once the CPU has run CYCLES clock cycles, to catch
+    /// programs which loop forever.
+    #[arg(long, value_name = "CYCLES")]
+    max_cycles: Option<u64>,
+
+    /// Stop with an error once the pipeline has run CYCLES clock cycles in a
+    /// row without retiring any instruction, as it is stuck.
+    /// Ignored by the single-cycle and multi-cycle CPUs.
+    #[arg(long, value_name = "CYCLES", default_value_t = DEFAULT_NO_PROGRESS_CYCLES)]
+    no_progress_cycles: u64,
+
+    /// Measure the host time of the run and print the guest instructions and
+    /// cycles run per second at the end, e.g. `Retired 10000000 insts in
+    /// 0.42s (23.8 MIPS)`. The run may end at --max-cycles. Ignored with the
+    /// debugger and breakpoints.
+    #[arg(long, conflicts_with_all = ["debug", "headless"])]
+    benchmark: bool,
+
+    /// Print how many times each instruction is executed at the end.
+    #[arg(long)]
+    inst_histogram: bool,
+
+    /// Print calls and cycles spent in each function at the end, for calls
+    /// of function symbols. Cycles of a function include its callees.
+    #[arg(long)]
+    profile: bool,
+
+    /// Write the run statistics (clock, CPI, hazard counts) into PATH at the
+    /// end. Ignored by the single-cycle CPU.
+    #[arg(long, value_name = "PATH")]
+    stats_output: Option<String>,
+
+    /// File format of `--stats-output`.
+    #[arg(long, default_value = "json")]
+    stats_format: StatsFormat,
+
+    /// Syscall ABI served on ecall.
+    #[arg(long, default_value = "linux")]
+    syscall_abi: SyscallAbi,
+
+    /// Back `read` of stdin by the contents of file at PATH. Without it,
+    /// stdin is empty.
+    #[arg(long, value_name = "PATH")]
+    stdin: Option<String>,
+
+    /// On exceptions, stop the program (fatal), or set mepc, mcause and
+    /// mtval and jump to the handler at mtvec (vectored).
+    #[arg(long, default_value = "fatal")]
+    trap_mode: TrapMode,
+
+    /// Raise a machine timer interrupt every CYCLES cycles: the initial
+    /// mtimecmp, and how far it moves after each interrupt taken.
+    #[arg(long, value_name = "CYCLES")]
+    timer_interval: Option<u64>,
+
+    /// Warn once and read zero on unmodeled CSRs instead of stopping.
+    #[arg(long)]
+    warn_on_unknown_csr: bool,
+
+    /// Arguments of the program, given after `--`. argv[0] is the input
+    /// path.
+    #[arg(last = true, value_name = "ARGS")]
+    program_args: Vec<String>,
 }
 
-fn main() {
+fn main() -> ExitCode {
     // log4rs::init_file("config/log4rs.yaml", Default::default())
     //     .expect("Fail to load logger configuration");
-    logger::init();
-
     let args = Args::parse();
-    let file_path = path::PathBuf::from(&args.input);
-    let enable_debug_mode = args.debug;
+    riscv_emulator::logger::init(
+        args.log_file.as_deref().map(path::Path::new),
+        args.log_level,
+    )
+    .expect("Fail to create log file");
+    if args.quiet {
+        riscv_emulator::logger::set_quiet();
+    }
+
+    // everything run has been dropped, and its output flushed, by now
+    match run(args) {
+        Ok(code) => ExitCode::from(code as u8),
+        Err(err) => {
+            report_error(&err);
+            match err {
+                // as if killed by SIGINT
+                Error::Interrupted(_) => ExitCode::from(130),
+                _ => ExitCode::FAILURE,
+            }
+        }
+    }
+}
+
+/// Run as told by `args`. Returns the exit code of the emulator, or the
+/// error which stopped the program once everything asked for at the end,
+/// e.g. statistics and memory dumps, is done.
+fn run(args: Args) -> Result<i32> {
     let itrace = args.itrace;
+    let itrace_verbose = args.itrace_verbose;
+    let trace_regs = args.trace_regs;
     let mtrace = args.mtrace;
     let ftrace = args.ftrace;
-    let cpu_mode = args.cpu_mode;
+    let warn_on_unknown_csr = args.warn_on_unknown_csr;
+    let data_endian = args.data_endian;
+    let ebreak_as_trap = args.ebreak_as_trap;
+    let semihosting = args.semihosting;
+    let syscall_abi = args.syscall_abi;
+    let stdin = args
+        .stdin
+        .as_deref()
+        .map(|path| std::fs::read(path).expect("Fail to read stdin file"))
+        .unwrap_or_default();
+    let strict_align = args.strict_align;
+    let div_trap = args.div_trap;
+    let max_cycles = args.max_cycles;
+    let trap_mode = args.trap_mode;
+    let timer_interval = args.timer_interval;
+    let inst_histogram = args.inst_histogram;
+    let profile = args.profile;
+    let benchmark = args.benchmark;
+    let summary = args.summary;
+    let stats_format = args.stats_format;
+
+    if args.repl {
+        run_repl(
+            itrace,
+            mtrace,
+            data_endian,
+            warn_on_unknown_csr,
+            ebreak_as_trap,
+            syscall_abi,
+        );
+        return Ok(0);
+    }
+
+    let file_path = path::PathBuf::from(args.input.expect("Must give input file"));
+    if let Some(disasm_json) = &args.disasm_json {
+        let elf_info = load_program(
+            &file_path,
+            args.flat,
+            args.base,
+            args.entry_offset,
+            args.load_bias,
+        );
+        riscv_emulator::disasm::write_disasm_json(&elf_info, path::Path::new(disasm_json))
+            .expect("Fail to write disassembly");
+        return Ok(0);
+    }
+    let program_args: Vec<String> = std::iter::once(file_path.display().to_string())
+        .chain(args.program_args)
+        .collect();
+    let enable_debug_mode = args.debug;
+    let cpu_mode = args.cpu_mode.expect("Must give CPU mode");
     let data_hazard_policy = if cpu_mode == CPUMode::Pipeline {
         args.data_hazard_policy
             .expect("Must give data hazard policy if pipeline CPU is used")
@@ -114,71 +563,496 @@ fn main() {
         None
     };
 
+    if args.headless {
+        let config = RunConfig {
+            cpu_mode,
+            data_hazard_policy,
+            control_policy,
+            predict_policy,
+            syscall_abi,
+            load_bias: args.load_bias,
+            uninit: args.uninit,
+            seed: args.seed,
+            max_cycles,
+            args: program_args[1..].to_vec(),
+            stdin,
+        };
+        let result = run_elf(&file_path, &config)?;
+        println!("{result}");
+        return Ok(result.exit_code as i32);
+    }
+
+    let bht_bits = args.bht_bits;
+    let btb_entries = args.btb_entries as usize;
+    let single_mem_port = args.single_mem_port;
+    let extra_mem_stage = args.extra_mem_stage;
+    let mul_latency = args.mul_latency;
+    let div_latency = args.div_latency;
+    // randomized features fork their generators from the seed in this order
+    let mut rng = Rng::new(args.seed);
+    let mut new_cache = |sets: u64, ways: u64, line: u64| {
+        let mut cache = Cache::new(sets as usize, ways as usize, line);
+        if args.cache_replacement == Replacement::Random {
+            cache.set_random_replacement(rng.fork());
+        }
+        cache
+    };
+    let icache = args
+        .icache_sets
+        .map(|sets| new_cache(sets, args.icache_ways, args.icache_line));
+    let icache_miss_penalty = args.icache_miss_penalty;
+    let dcache = args
+        .dcache_sets
+        .map(|sets| new_cache(sets, args.dcache_ways, args.dcache_line));
+    let dcache_miss_penalty = args.dcache_miss_penalty;
+
     let pre_pipeline_info = args.pre_pipeline_info;
     let pipeline_info = args.pipeline_info;
     let post_pipeline_info = args.post_pipeline_info;
+    let pipeline_diagram = args.pipeline_diagram;
     let control_hazard_info = args.control_hazard_info;
     let data_hazard_info = args.data_hazard_info;
     info!("Loading file: {file_path:?}");
 
-    // Parse ELF file
-    let elf_info = read_elf(&file_path).expect("Fail to load ELF");
+    // Parse ELF file, or take a flat binary image as is
+    let elf_info = load_program(
+        &file_path,
+        args.flat,
+        args.base,
+        args.entry_offset,
+        args.load_bias,
+    );
+
+    // Resolve preloaded breakpoints
+    let mut breakpoints = args.breakpoints;
+    for name in &args.break_symbols {
+        let Some(addr) = elf_info.symbol_addr(name) else {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::InvalidValue,
+                    format!("unknown symbol '{name}' for '--break-symbol <NAME>'"),
+                )
+                .exit();
+        };
+        breakpoints.push(addr);
+    }
+
+    // RV32 or RV64 follows the ELF class
+    let xlen = if elf_info.is_64_bit() {
+        Xlen::Rv64
+    } else {
+        Xlen::Rv32
+    };
 
     // Load the file into virtual memory
     let mut vm = VirtualMemory::from_elf_info(&elf_info, mtrace);
+    let mut uninit_fill = UninitFill::new(args.uninit, rng.fork());
+    vm.fill_uninit(&elf_info, &mut uninit_fill);
+    vm.set_data_endian(data_endian);
+    if let Some(store_log) = &args.store_log {
+        vm.set_store_log(path::Path::new(store_log))
+            .expect("Fail to create store log");
+    }
+    if let Some(mtrace_file) = &args.mtrace_file {
+        vm.set_mtrace_file(path::Path::new(mtrace_file))
+            .expect("Fail to create mtrace file");
+    }
+    if let Some(mem_log) = &args.mem_log {
+        vm.set_mem_log(path::Path::new(mem_log))
+            .expect("Fail to create memory log");
+    }
+    if let Some(uart_addr) = args.uart_addr {
+        vm.set_uart(Uart::new(uart_addr));
+    }
+    if let Some(load_memory) = &args.load_memory {
+        let addr = maybe_hex::<u64>(&load_memory[0]).expect("Invalid address of --load-memory");
+        vm.load_file(addr as usize, path::Path::new(&load_memory[1]))
+            .expect("Fail to load memory");
+    }
+    if args.warn_smc {
+        match elf_info.text_end() {
+            Some(text_end) => vm.set_text_range(elf_info.min_vaddr()..text_end),
+            None => warn!("No executable segment, --warn-smc ignored"),
+        }
+    }
+    if args.harvard {
+        match elf_info.text_end() {
+            Some(text_end) => vm.set_harvard(elf_info.min_vaddr()..text_end),
+            None => warn!("No executable segment, --harvard ignored"),
+        }
+    }
+    if args.enforce_perms {
+        vm.enforce_perms(&elf_info);
+    }
+    if args.guard_stack {
+        vm.guard_stack();
+    }
+    if let Some(entry) = args.entry {
+        if !elf_info.is_executable(entry as usize) {
+            warn!("Entry {entry:#x} is not in an executable segment");
+        }
+    }
+
+    if args.follow_jumps {
+        follow_jumps(elf_info.symbol_map().clone());
+    }
 
     // Create call stack for the running process on the CPU
     let mut callstack = CallStack::from_elf_info(&elf_info, ftrace);
+    callstack.set_profile(profile);
+    if let Some(ftrace_file) = &args.ftrace_file {
+        callstack
+            .set_ftrace_file(path::Path::new(ftrace_file))
+            .expect("Fail to create ftrace file");
+    }
+    let itrace_file = args.itrace_file.as_deref().map(path::Path::new);
+    let trace_regs_file = args.trace_regs_file.as_deref().map(path::Path::new);
+    let stats_output = args.stats_output.as_deref().map(path::Path::new);
 
-    match cpu_mode {
+    // the error which stopped the program is returned once done with the rest
+    let result = match cpu_mode {
         CPUMode::Single => {
-            use single_cycle::{cpu::CPU, debug::REDB};
-            let mut cpu = CPU::new(&mut vm, &mut callstack, itrace);
+            use riscv_emulator::single_cycle::{cpu::CPU, debug::REDB};
+            let mut cpu = CPU::new(
+                &mut vm,
+                &mut callstack,
+                itrace,
+                warn_on_unknown_csr,
+                ebreak_as_trap,
+                syscall_abi,
+                xlen,
+            );
 
-            cpu.init_elfinfo_64(&elf_info);
+            cpu.fill_uninit_regs(&mut uninit_fill);
+            cpu.init_elfinfo_64(&elf_info, &program_args)
+                .expect("Fail to set up program arguments");
+            if let Some(entry) = args.entry {
+                cpu.set_entry(entry);
+            }
+            cpu.set_strict_align(strict_align);
+            cpu.set_div_trap(div_trap);
+            cpu.set_max_cycles(max_cycles);
+            cpu.set_trap_mode(trap_mode);
+            if let Some(interval) = timer_interval {
+                cpu.set_timer_interval(interval);
+            }
+            cpu.set_semihosting(semihosting);
+            cpu.set_stdin(stdin);
+            cpu.set_itrace_verbose(itrace_verbose);
+            if let Some(trace_regs) = trace_regs {
+                cpu.set_trace_regs(trace_regs, trace_regs_file)
+                    .expect("Fail to create commit log file");
+            }
+            if let Some(verify) = &args.verify {
+                cpu.set_verify_file(path::Path::new(verify))
+                    .expect("Fail to read reference log");
+            }
+            if let Some(itrace_file) = itrace_file {
+                cpu.set_itrace_file(itrace_file)
+                    .expect("Fail to create itrace file");
+            }
 
-            if !enable_debug_mode {
-                cpu.cpu_exec(None).expect("Failed to execute the program");
+            if !enable_debug_mode && breakpoints.is_empty() && !ebreak_as_trap && !semihosting {
+                let benchmark = benchmark.then(Benchmark::start);
+                let result = cpu.cpu_exec(None);
+                let result = riscv_emulator::bench::finish(
+                    benchmark,
+                    "single-cycle",
+                    cpu.retired(),
+                    cpu.cycles(),
+                    result,
+                );
+                print_summary(cpu.summary_lines(), summary);
+                print_result(cpu.exit_code(), cpu.cycles(), cpu.retired(), xlen);
+                if inst_histogram {
+                    cpu.print_inst_histogram();
+                }
+                if profile {
+                    cpu.print_profile();
+                }
+                result
             } else {
                 let mut redb = REDB::new(&mut cpu);
-                redb.run();
+                breakpoints.iter().for_each(|addr| redb.add_breakpoint(*addr));
+                if enable_debug_mode {
+                    redb.run();
+                    Ok(())
+                } else {
+                    redb.run_batch()
+                }
             }
         }
         CPUMode::Multi => {
-            use multi_stage::cpu::MultistageCPU;
-            let mut cpu = MultistageCPU::new(&mut vm, &mut callstack, itrace);
-            cpu.init_elfinfo_64(&elf_info);
-            cpu.cpu_exec(None).expect("Failed to execute the program");
-            cpu.print_info();
+            use riscv_emulator::multi_stage::cpu::MultistageCPU;
+            // The multi-cycle CPU has no REDB, it always runs in batch mode
+            if enable_debug_mode {
+                warn!("Debug mode is not supported on multi-stage CPU, running in batch mode");
+            }
+            if !breakpoints.is_empty() {
+                warn!("Breakpoints are not supported on multi-stage CPU, ignored");
+            }
+            if ebreak_as_trap {
+                warn!("--ebreak-as-trap is not supported on multi-stage CPU, ignored");
+            }
+            let mut cpu = MultistageCPU::new(
+                &mut vm,
+                &mut callstack,
+                itrace,
+                warn_on_unknown_csr,
+                syscall_abi,
+                xlen,
+            );
+            cpu.fill_uninit_regs(&mut uninit_fill);
+            cpu.init_elfinfo_64(&elf_info, &program_args)
+                .expect("Fail to set up program arguments");
+            if let Some(entry) = args.entry {
+                cpu.set_entry(entry);
+            }
+            cpu.set_strict_align(strict_align);
+            cpu.set_div_trap(div_trap);
+            cpu.set_max_cycles(max_cycles);
+            cpu.set_trap_mode(trap_mode);
+            if let Some(interval) = timer_interval {
+                cpu.set_timer_interval(interval);
+            }
+            cpu.set_semihosting(semihosting);
+            cpu.set_stdin(stdin);
+            cpu.set_mul_div_latency(mul_latency, div_latency);
+            if let Some(trace_regs) = trace_regs {
+                cpu.set_trace_regs(trace_regs, trace_regs_file)
+                    .expect("Fail to create commit log file");
+            }
+            if let Some(verify) = &args.verify {
+                cpu.set_verify_file(path::Path::new(verify))
+                    .expect("Fail to read reference log");
+            }
+            if let Some(itrace_file) = itrace_file {
+                cpu.set_itrace_file(itrace_file)
+                    .expect("Fail to create itrace file");
+            }
+            let benchmark = benchmark.then(Benchmark::start);
+            let result = cpu.cpu_exec(None);
+            let result = riscv_emulator::bench::finish(
+                benchmark,
+                "multi-cycle",
+                cpu.retired(),
+                cpu.cycles(),
+                result,
+            );
+            print_summary(cpu.summary_lines(), summary);
+            if inst_histogram {
+                cpu.print_inst_histogram();
+            }
+            if profile {
+                cpu.print_profile();
+            }
+            if let Some(stats_output) = stats_output {
+                cpu.write_stats(stats_output, stats_format)
+                    .expect("Fail to write statistics");
+            }
+            print_result(cpu.exit_code(), cpu.cycles(), cpu.retired(), xlen);
+            result
         }
         CPUMode::Pipeline => {
-            use multi_stage::{cpu::CPU, debug::REDB};
+            use riscv_emulator::multi_stage::{cpu::CPU, debug::REDB};
             let mut cpu = CPU::new(
                 &mut vm,
                 &mut callstack,
-                data_hazard_policy,
-                control_policy,
-                predict_policy,
-                pre_pipeline_info,
-                pipeline_info,
-                post_pipeline_info,
-                control_hazard_info,
-                data_hazard_info,
+                PipelineConfig {
+                    data_hazard_policy,
+                    control_policy,
+                    predict_policy,
+                    pre_pipeline_info,
+                    pipeline_info,
+                    post_pipeline_info,
+                    control_hazard_info,
+                    data_hazard_info,
+                    warn_on_unknown_csr,
+                    ebreak_as_trap,
+                    syscall_abi,
+                    xlen,
+                    bht_bits,
+                    btb_entries,
+                },
             );
 
-            cpu.init_elfinfo_64(&elf_info);
+            cpu.fill_uninit_regs(&mut uninit_fill);
+            cpu.init_elfinfo_64(&elf_info, &program_args)
+                .expect("Fail to set up program arguments");
+            if let Some(entry) = args.entry {
+                cpu.set_entry(entry);
+            }
+            cpu.set_strict_align(strict_align);
+            cpu.set_div_trap(div_trap);
+            cpu.set_max_cycles(max_cycles);
+            cpu.set_trap_mode(trap_mode);
+            if let Some(interval) = timer_interval {
+                cpu.set_timer_interval(interval);
+            }
+            cpu.set_semihosting(semihosting);
+            cpu.set_stdin(stdin);
+            cpu.set_mul_div_latency(mul_latency, div_latency);
+            cpu.set_no_progress_cycles(args.no_progress_cycles);
+            cpu.set_single_mem_port(single_mem_port);
+            cpu.set_extra_mem_stage(extra_mem_stage);
+            cpu.set_icache(icache, icache_miss_penalty);
+            cpu.set_dcache(dcache, dcache_miss_penalty);
+            cpu.set_pipeline_diagram(pipeline_diagram);
+            if let Some(trace_regs) = trace_regs {
+                cpu.set_trace_regs(trace_regs, trace_regs_file)
+                    .expect("Fail to create commit log file");
+            }
+            if let Some(verify) = &args.verify {
+                cpu.set_verify_file(path::Path::new(verify))
+                    .expect("Fail to read reference log");
+            }
+            if let Some(itrace_file) = itrace_file {
+                cpu.set_itrace_file(itrace_file)
+                    .expect("Fail to create itrace file");
+            }
+            if let Some(trace_hazards) = &args.trace_hazards {
+                cpu.set_hazard_trace(path::Path::new(trace_hazards))
+                    .expect("Fail to create hazard trace");
+            }
 
-            if !enable_debug_mode {
-                cpu.cpu_exec(None).expect("Failed to execute the program");
-                cpu.print_info();
+            if !enable_debug_mode && breakpoints.is_empty() && !ebreak_as_trap && !semihosting {
+                let benchmark = benchmark.then(Benchmark::start);
+                let result = cpu.cpu_exec(None);
+                let result = riscv_emulator::bench::finish(
+                    benchmark,
+                    "pipeline",
+                    cpu.retired(),
+                    cpu.cycles(),
+                    result,
+                );
+                print_summary(cpu.summary_lines(), summary);
+                print_result(cpu.exit_code(), cpu.cycles(), cpu.retired(), xlen);
+                cpu.print_pipeline_diagram();
+                if inst_histogram {
+                    cpu.print_inst_histogram();
+                }
+                if profile {
+                    cpu.print_profile();
+                }
+                if let Some(stats_output) = stats_output {
+                    cpu.write_stats(stats_output, stats_format)
+                        .expect("Fail to write statistics");
+                }
+                result
             } else {
                 let mut redb = REDB::new(&mut cpu);
-                redb.run();
+                breakpoints.iter().for_each(|addr| redb.add_breakpoint(*addr));
+                let result = if enable_debug_mode {
+                    redb.run()
+                } else {
+                    redb.run_batch()
+                };
+                redb.print_pipeline_diagram();
+                result
             }
         }
+    };
+
+    if let Some(dump_memory) = &args.dump_memory {
+        let addr = maybe_hex::<u64>(&dump_memory[0]).expect("Invalid address of --dump-memory");
+        let len = maybe_hex::<u64>(&dump_memory[1]).expect("Invalid length of --dump-memory");
+        vm.dump_file(
+            addr as usize,
+            len as usize,
+            path::Path::new(&dump_memory[2]),
+        )
+        .expect("Fail to dump memory");
     }
 
     // Atomatically drop all resources
+    result.map(|()| 0)
+}
+
+/// Print the summary `lines` of the run: logged, or on stdout at any log
+/// level with --summary.
+fn print_summary(lines: Vec<String>, summary: bool) {
+    for line in lines {
+        if summary {
+            println!("{line}");
+        } else {
+            info!("{line}");
+        }
+    }
+}
+
+/// Report the error which stopped the program, e.g. an unsupported
+/// instruction. It is told on stderr with --quiet, which logs nothing.
+fn report_error(err: &Error) {
+    if riscv_emulator::logger::quiet() {
+        eprintln!("{err}");
+    } else {
+        error!("{err}");
+    }
+}
+
+/// Print how the program ended in one line with --quiet, once it has exited.
+fn print_result(exit_code: Option<u64>, clock: u64, retired: u64, xlen: Xlen) {
+    let Some(exit_code) = exit_code.filter(|_| riscv_emulator::logger::quiet()) else {
+        return;
+    };
+    let result = RunResult {
+        exit_code: xlen.sext(exit_code) as i64,
+        clock,
+        retired,
+    };
+    println!("{result}");
+}
+
+/// Parse the ELF file at `file_path`, or take it as a flat binary image
+/// loaded at `base`.
+fn load_program(
+    file_path: &path::PathBuf,
+    flat: bool,
+    base: u64,
+    entry_offset: u64,
+    load_bias: Option<u64>,
+) -> LoadElfInfo {
+    if flat {
+        read_flat_binary(file_path, base, entry_offset).expect("Fail to load flat binary")
+    } else {
+        read_elf(file_path, load_bias).expect("Fail to load ELF")
+    }
+}
+
+/// Run the assembly REPL on a blank single-cycle machine.
+fn run_repl(
+    itrace: bool,
+    mtrace: bool,
+    data_endian: Endian,
+    warn_on_unknown_csr: bool,
+    ebreak_as_trap: bool,
+    syscall_abi: SyscallAbi,
+) {
+    use riscv_emulator::single_cycle::{
+        cpu::CPU,
+        repl::{REPL, REPL_MEM_BASE, REPL_MEM_SIZE},
+    };
+    let mut vm = VirtualMemory::with_base(
+        REPL_MEM_BASE as usize,
+        REPL_MEM_SIZE as usize,
+        mtrace,
+    );
+    vm.set_data_endian(data_endian);
+    let symbol_map = HashMap::new();
+    let mut callstack = CallStack::new(&symbol_map, false);
+    let mut cpu = CPU::new(
+        &mut vm,
+        &mut callstack,
+        itrace,
+        warn_on_unknown_csr,
+        ebreak_as_trap,
+        syscall_abi,
+        Xlen::Rv64,
+    );
+    cpu.init_blank(REPL_MEM_BASE, REPL_MEM_BASE + REPL_MEM_SIZE);
+
+    let mut repl = REPL::new(&mut cpu);
+    repl.run();
 }
 
 #[macro_export]
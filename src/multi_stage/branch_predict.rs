@@ -1,59 +1,97 @@
-use std::collections::HashMap;
+use crate::core::insts::Inst64;
 
 use super::cpu::PredictPolicy;
 
+/// Default number of index bits of BHT
+pub const DEFAULT_BHT_BITS: u32 = 12;
+
+/// Default number of BTB entries
+pub const DEFAULT_BTB_ENTRIES: usize = 512;
+
 /// Branch history table
+#[derive(Clone)]
 pub struct BHT {
-    inner: HashMap<u64, u8>, // pc -> taken
+    inner: Vec<u8>, // index -> taken
     predict_policy: PredictPolicy,
+    // Global history of conditional branches, the latest outcome in bit 0,
+    // as long as the index of the table. Only used by gshare.
+    history: u64,
 }
 
-/// Branch target buffer
+/// Branch target buffer, direct mapped
+#[derive(Clone)]
 pub struct BTB {
-    inner: HashMap<u64, u64>, // pc -> branch target address
+    inner: Vec<Option<(u64, u64)>>, // index -> (tag pc, branch target address)
 }
 
 /// Return address stack
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct RAS {
     inner: Vec<u64>,
 }
 
 impl BHT {
-    pub fn new(predict_policy: PredictPolicy) -> Self {
-        Self {
-            inner: HashMap::new(),
-            predict_policy,
-        }
-    }
-
-    pub fn init_pc_predict(&mut self, pc: u64) -> u8 {
-        let init_predict = match self.predict_policy {
+    /// BHT of `1 << bht_bits` counters.
+    pub fn new(predict_policy: PredictPolicy, bht_bits: u32) -> Self {
+        let init_predict = match predict_policy {
             PredictPolicy::OneBitPredict => {
                 0 // Initially not taken
             }
-            PredictPolicy::TwoBitsPredict => {
+            PredictPolicy::TwoBitsPredict | PredictPolicy::Gshare => {
                 0b01 // Initially not taken but in an unstable FSM state
             }
         };
-        self.inner.insert(pc, init_predict);
-        init_predict
+        Self {
+            inner: vec![init_predict; 1 << bht_bits],
+            predict_policy,
+            history: 0,
+        }
+    }
+
+    /// Global history before the branch being fetched is predicted.
+    /// Carried along with the branch so that it could be trained and
+    /// recovered with the same history.
+    pub fn history(&self) -> u64 {
+        self.history
+    }
+
+    /// Speculatively shift the predicted outcome of a conditional branch
+    /// into the global history.
+    pub fn speculate(&mut self, taken: bool) {
+        if self.predict_policy == PredictPolicy::Gshare {
+            let mask = self.inner.len() as u64 - 1;
+            self.history = ((self.history << 1) | taken as u64) & mask;
+        }
+    }
+
+    /// Roll back the global history to a snapshot taken by `history`.
+    pub fn restore_history(&mut self, history: u64) {
+        self.history = history;
+    }
+
+    /// Counters are indexed by the low bits of pc, XORed with the global
+    /// history for gshare. pc[0] is always 0 and is dropped.
+    fn index(&self, pc: u64, history: u64) -> usize {
+        let mask = self.inner.len() as u64 - 1;
+        match self.predict_policy {
+            PredictPolicy::OneBitPredict | PredictPolicy::TwoBitsPredict => {
+                ((pc >> 1) & mask) as usize
+            }
+            PredictPolicy::Gshare => (((pc >> 1) ^ history) & mask) as usize,
+        }
     }
 
     /// Called in Fetch phase
     pub fn predict(&mut self, pc: u64) -> bool {
-        let result = self
-            .inner
-            .get(&pc)
-            .cloned()
-            .unwrap_or_else(|| self.init_pc_predict(pc));
+        let index = self.index(pc, self.history);
+        let result = self.inner[index];
 
         match self.predict_policy {
             PredictPolicy::OneBitPredict => {
                 assert!(result == 0 || result == 1);
                 result == 1
             }
-            PredictPolicy::TwoBitsPredict => {
+            PredictPolicy::TwoBitsPredict | PredictPolicy::Gshare => {
                 assert!(result == 0b00 || result == 0b01 || result == 0b10 || result == 0b11);
                 match result {
                     0b00 | 0b01 => false, // branch not taken
@@ -64,17 +102,16 @@ impl BHT {
         }
     }
 
-    /// Called by CPU with Exec phase result
-    pub fn update_with_result(&mut self, pc: u64, taken: bool) {
+    /// Called by CPU with Exec phase result.
+    /// `history` is the global history the branch was predicted with.
+    pub fn update_with_result(&mut self, pc: u64, history: u64, taken: bool) {
+        let index = self.index(pc, history);
         match self.predict_policy {
             PredictPolicy::OneBitPredict => {
-                self.inner.insert(pc, if taken { 1 } else { 0 });
+                self.inner[index] = if taken { 1 } else { 0 };
             }
-            PredictPolicy::TwoBitsPredict => {
-                let original_state = *self
-                    .inner
-                    .get(&pc)
-                    .expect(format!("Not initialized: {pc:#x}").as_str());
+            PredictPolicy::TwoBitsPredict | PredictPolicy::Gshare => {
+                let original_state = self.inner[index];
                 let new_state = match (original_state, taken) {
                     (0b00, false) => 0b00,
                     (0b00, true) => 0b01,
@@ -86,38 +123,61 @@ impl BHT {
                     (0b10, true) => 0b11,
                     _ => unreachable!(),
                 };
-                self.inner.insert(pc, new_state);
+                self.inner[index] = new_state;
             }
         }
     }
 }
 
 impl BTB {
-    pub fn new() -> Self {
+    /// BTB of `entries` slots.
+    pub fn new(entries: usize) -> Self {
+        assert!(entries > 0, "BTB must have at least one entry");
         Self {
-            inner: HashMap::new(),
+            inner: vec![None; entries],
         }
     }
 
-    /// Called in Fetch phase
+    /// pc[0] is always 0 and is dropped.
+    fn index(&self, pc: u64) -> usize {
+        ((pc >> 1) % self.inner.len() as u64) as usize
+    }
+
+    /// Called in Fetch phase.
+    /// Only hits if the full pc matches the tag, aliasing pcs miss.
     pub fn query_target(&self, pc: u64) -> Option<u64> {
-        self.inner.get(&pc).cloned()
+        match self.inner[self.index(pc)] {
+            Some((tag, target)) if tag == pc => Some(target),
+            _ => None,
+        }
     }
 
-    /// Called by CPU with Exec phase result
+    /// Called by CPU with Exec phase result.
+    /// Evicts the entry of an aliasing pc.
     pub fn add_entry(&mut self, pc: u64, target: u64, is_jalr: bool) {
-        let old_target = self.inner.insert(pc, target);
+        let index = self.index(pc);
+        let old_entry = self.inner[index].replace((pc, target));
         // sanity check
         if is_jalr {
             /* Do nothing */
-        } else {
-            if let Some(old_target) = old_target {
-                assert!(old_target == target)
-            }
+        } else if let Some((tag, old_target)) = old_entry {
+            assert!(tag != pc || old_target == target)
         }
     }
 }
 
+/// Whether `op` is a conditional branch, whose direction is predicted by BHT.
+pub fn is_conditional_branch(op: Inst64) -> bool {
+    use Inst64::*;
+    matches!(op, beq | bne | blt | bge | bltu | bgeu)
+}
+
+/// Whether `op` is a branch or a jump, whose next pc is predicted at fetch.
+pub fn is_control(op: Inst64) -> bool {
+    use Inst64::*;
+    is_conditional_branch(op) || matches!(op, jal | jalr)
+}
+
 impl RAS {
     pub fn new() -> Self {
         Self { inner: Vec::new() }
@@ -131,3 +191,43 @@ impl RAS {
         self.inner.pop()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gshare_history_test() {
+        // the history is as long as the index, whatever the table size
+        for bht_bits in [4, 12, 16] {
+            let mut bht = BHT::new(PredictPolicy::Gshare, bht_bits);
+            for _ in 0..20 {
+                bht.speculate(true);
+            }
+            assert_eq!(bht.history(), (1 << bht_bits) - 1, "{bht_bits}");
+        }
+    }
+
+    #[test]
+    fn btb_alias_test() {
+        let mut btb = BTB::new(4);
+        // 0x1000 and 0x1008 both map to entry 0
+        btb.add_entry(0x1000, 0x2000, false);
+        assert_eq!(btb.query_target(0x1000), Some(0x2000));
+        assert_eq!(btb.query_target(0x1008), None);
+
+        btb.add_entry(0x1008, 0x3000, false);
+        assert_eq!(btb.query_target(0x1000), None);
+        assert_eq!(btb.query_target(0x1008), Some(0x3000));
+
+        // and the first one evicts the second back
+        btb.add_entry(0x1000, 0x2000, false);
+        assert_eq!(btb.query_target(0x1000), Some(0x2000));
+        assert_eq!(btb.query_target(0x1008), None);
+
+        // other entries are not affected
+        btb.add_entry(0x1002, 0x4000, false);
+        assert_eq!(btb.query_target(0x1002), Some(0x4000));
+        assert_eq!(btb.query_target(0x1000), Some(0x2000));
+    }
+}
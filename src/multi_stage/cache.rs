@@ -0,0 +1,120 @@
+//! Caches in front of the memory accessed by IF and MEM. They model timing
+//! only: they keep the tags of cached lines, while instructions and data
+//! always come from [`crate::core::vm::VirtualMemory`].
+
+use clap::ValueEnum;
+
+use crate::core::rng::Rng;
+
+/// Default number of ways, direct mapped
+pub const DEFAULT_CACHE_WAYS: usize = 1;
+
+/// Default bytes of a cache line
+pub const DEFAULT_CACHE_LINE: u64 = 64;
+
+/// Default extra cycles IF or MEM spends on a miss
+pub const DEFAULT_CACHE_MISS_PENALTY: u64 = 10;
+
+/// Line evicted from a full set on a miss
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Replacement {
+    Lru,    // the least recently used
+    Random, // any, drawn from the seeded generator
+}
+
+/// Set-associative cache, with LRU replacement unless given a generator
+/// for random replacement
+#[derive(Debug, Clone)]
+pub struct Cache {
+    line: u64,
+    ways: usize,
+    sets: Vec<Vec<u64>>, // set -> tags of its lines, the most recently used first
+    rng: Option<Rng>,    // of random replacement
+}
+
+impl Cache {
+    /// Cache of `sets` sets of `ways` lines of `line` bytes.
+    pub fn new(sets: usize, ways: usize, line: u64) -> Cache {
+        assert!(sets > 0 && ways > 0 && line > 0);
+        Cache {
+            line,
+            ways,
+            sets: vec![Vec::with_capacity(ways); sets],
+            rng: None,
+        }
+    }
+
+    /// Evict a random line drawn from `rng` instead of the least recently
+    /// used one.
+    pub fn set_random_replacement(&mut self, rng: Rng) {
+        self.rng = Some(rng);
+    }
+
+    /// Access `bytes` bytes at `vaddr`, filling the lines missed.
+    /// Returns whether every line touched was cached.
+    pub fn access(&mut self, vaddr: u64, bytes: u64) -> bool {
+        let first = vaddr / self.line;
+        let last = (vaddr + bytes.max(1) - 1) / self.line;
+        let mut hit = true;
+        for line in first..=last {
+            hit &= self.access_line(line);
+        }
+        hit
+    }
+
+    /// Access the line of number `line`, tagged by the line number itself.
+    fn access_line(&mut self, line: u64) -> bool {
+        let index = (line % self.sets.len() as u64) as usize;
+        let set = &mut self.sets[index];
+        let hit = match set.iter().position(|&tag| tag == line) {
+            Some(way) => {
+                set.remove(way);
+                true
+            }
+            None => {
+                if set.len() == self.ways {
+                    match self.rng.as_mut() {
+                        Some(rng) => set.remove(rng.below(self.ways as u64) as usize),
+                        None => set.pop().unwrap(),
+                    };
+                }
+                false
+            }
+        };
+        set.insert(0, line);
+        hit
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cache_test() {
+        // 2 sets of 2 ways of 16 bytes
+        let mut cache = Cache::new(2, 2, 16);
+        assert!(!cache.access(0x00, 4));
+        assert!(cache.access(0x0c, 4));
+        // lines 0, 2 and 4 map to set 0
+        assert!(!cache.access(0x20, 8));
+        assert!(cache.access(0x00, 1));
+        // evicts line 2, the least recently used
+        assert!(!cache.access(0x40, 8));
+        assert!(cache.access(0x00, 8));
+        assert!(!cache.access(0x20, 8));
+        // across lines 1 and 2, of which only 2 is cached
+        assert!(!cache.access(0x1e, 4));
+        assert!(cache.access(0x1e, 4));
+
+        // the same evictions with the same seed
+        let evictions = |seed| {
+            let mut cache = Cache::new(1, 2, 16);
+            cache.set_random_replacement(Rng::new(seed));
+            (0..64)
+                .map(|i| cache.access(16 * (i * 7 % 5), 1))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(evictions(3), evictions(3));
+    }
+}
@@ -1,28 +1,48 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
 use log::{debug, error, info, warn};
 
 use crate::{
-    callstack::CallStack,
+    callstack::{CallStack, CallStackSnapshot},
     core::{
-        insts::Inst64,
-        reg::{ProgramCounter, RegisterFile, REGNAME},
-        vm::VirtualMemory,
+        amo::{is_atomic, Reservation},
+        cond::Condition,
+        decode_cache::DecodeCache,
+        histogram::print_inst_histogram,
+        insts::{disasm, Inst64, Xlen},
+        reg::{reg_index, ProgramCounter, RegisterFile, TraceRegs, REGNAME},
+        uninit::UninitFill,
+        vm::{Endian, MemData, VirtualMemory},
     },
+    csr::{Csr, TrapMode},
     elf::LoadElfInfo,
-    error::{Error, Result},
+    error::{check_cycle_limit, Error, Exception, Result},
+    logger,
+    syscall::{is_semihosting_call, new_syscall, Syscall, SyscallAbi},
+    trace::{HazardEvent, HazardKind, TraceFile},
+    verify::Verifier,
 };
 
 use super::{
-    branch_predict::{BHT, BTB, RAS},
+    branch_predict::{
+        is_conditional_branch, is_control, BHT, BTB, DEFAULT_BHT_BITS, DEFAULT_BTB_ENTRIES, RAS,
+    },
+    cache::Cache,
     decode::decode,
+    diagram::PipelineDiagram,
     exec::exec,
-    fetch::fetch,
+    fetch::{decode_at, fetch},
     mem::mem,
     phases::*,
-    writeback::writeback,
+    writeback::{writeback, Retire},
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum PipelineState {
+pub enum PipelineState {
     Stall,
     Bubble,
     Normal,
@@ -68,6 +88,17 @@ impl Ord for PipelineState {
 
 const PIPELINE_STATES_DEPTH: usize = 5;
 
+/// Default clocks without any instruction retiring before the pipeline is
+/// considered stuck. Far above the longest legitimate stall, as the extra
+/// cycles of a functional unit or a cache miss take a single clock.
+pub const DEFAULT_NO_PROGRESS_CYCLES: u64 = 1000;
+
+/// Default extra cycles a multiplication spends in EX
+pub const DEFAULT_MUL_LATENCY: u64 = 1;
+
+/// Default extra cycles a division or remainder spends in EX
+pub const DEFAULT_DIV_LATENCY: u64 = 39;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
 pub enum DataHazardPolicy {
     NaiveStall,  // just stall
@@ -78,7 +109,7 @@ pub enum DataHazardPolicy {
 pub enum ControlPolicy {
     AllStall,       // stall when branch instructions encountered
     AlwaysNotTaken, // static branch prediction: always not taken
-    // AlwaysTaken,    // static branch prediction: always taken
+    AlwaysTaken,    // static branch prediction: always taken
     DynamicPredict, // dynamic branch prediction
 }
 
@@ -86,26 +117,272 @@ pub enum ControlPolicy {
 pub enum PredictPolicy {
     OneBitPredict,
     TwoBitsPredict,
+    Gshare, // two-bit counters indexed by pc XOR global history
+}
+
+/// How the pipeline CPU is built: its hazard policies and what it logs
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineConfig {
+    pub data_hazard_policy: DataHazardPolicy,
+    pub control_policy: ControlPolicy,
+    pub predict_policy: Option<PredictPolicy>, // with dynamic prediction
+    pub bht_bits: u32,                         // index bits of BHT
+    pub btb_entries: usize,
+    pub pre_pipeline_info: bool,
+    pub pipeline_info: bool,
+    pub post_pipeline_info: bool,
+    pub control_hazard_info: bool,
+    pub data_hazard_info: bool,
+    pub warn_on_unknown_csr: bool,
+    pub ebreak_as_trap: bool, // breaks into the debugger instead of halting
+    pub syscall_abi: SyscallAbi,
+    pub xlen: Xlen,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            data_hazard_policy: DataHazardPolicy::DataForward,
+            control_policy: ControlPolicy::AlwaysNotTaken,
+            predict_policy: None,
+            bht_bits: DEFAULT_BHT_BITS,
+            btb_entries: DEFAULT_BTB_ENTRIES,
+            pre_pipeline_info: false,
+            pipeline_info: false,
+            post_pipeline_info: false,
+            control_hazard_info: false,
+            data_hazard_info: false,
+            warn_on_unknown_csr: false,
+            ebreak_as_trap: false,
+            syscall_abi: SyscallAbi::Linux,
+            xlen: Xlen::Rv64,
+        }
+    }
+}
+
+/// File format of `--stats-output`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StatsFormat {
+    Json, // one object
+    Csv,  // a header line and a value line
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct CPUStatistics {
     data_hazard_count: u64,
     control_hazard_count: u64,
     data_hazard_delayed_cycles: u64,
     control_hazard_delayed_cycles: u64,
+    structural_hazard_count: u64, // fetches delayed by MEM on a single memory port
     executed_inst_count: u64,
+    executed_branch_count: u64,
+    pause_count: u64,            // spin-wait hints executed
+    direction_hit_count: u64,    // branches taken or not as predicted
+    target_predicted_count: u64, // branches taken as predicted
+    target_hit_count: u64,       // of which to the predicted target
+    btb_hit_count: u64,
+    btb_miss_count: u64,
+    ras_hit_count: u64, // returns to the target popped from the RAS
+    ras_miss_count: u64,
+    icache_hit_count: u64, // fetches hitting the instruction cache
+    icache_miss_count: u64,
+    dcache_hit_count: u64, // loads and stores hitting the data cache
+    dcache_miss_count: u64,
+    inst_histogram: HashMap<Inst64, u64>, // executed count of each instruction
 }
 
-impl Default for CPUStatistics {
-    fn default() -> Self {
-        Self {
-            data_hazard_count: 0,
-            control_hazard_count: 0,
-            data_hazard_delayed_cycles: 0,
-            control_hazard_delayed_cycles: 0,
-            executed_inst_count: 0,
+/// `part` of `total` in percent, n/a if there is nothing to count.
+fn percent(part: u64, total: u64) -> String {
+    if total == 0 {
+        "n/a".to_string()
+    } else {
+        format!("{:.2}%", part as f64 * 100.0 / total as f64)
+    }
+}
+
+/// `part / total`, or n/a if there is nothing to divide by.
+fn ratio(part: u64, total: u64) -> String {
+    if total == 0 {
+        "n/a".to_string()
+    } else {
+        (part as f64 / total as f64).to_string()
+    }
+}
+
+impl CPUStatistics {
+    /// Count a retired non-noop instruction.
+    fn count_inst(&mut self, op: Inst64) {
+        self.executed_inst_count += 1;
+        if op == Inst64::pause {
+            self.pause_count += 1;
+        }
+        *self.inst_histogram.entry(op).or_insert(0) += 1;
+    }
+
+    /// Count the prediction of an executed branch or jump `itl`, which went
+    /// to `target` if taken.
+    fn count_prediction(&mut self, itl: &InternalExecMem, target: u64, dynamic: bool) {
+        let flags = &itl.branch_flags;
+        self.executed_branch_count += 1;
+        if flags.pc_src == flags.predicted_src {
+            self.direction_hit_count += 1;
+        }
+        // the target only matters if taken as predicted
+        if flags.pc_src && flags.predicted_src {
+            self.target_predicted_count += 1;
+            if flags.predicted_target == target {
+                self.target_hit_count += 1;
+            }
+        }
+        if !dynamic {
+            return;
         }
+        if flags.btb_hit {
+            self.btb_hit_count += 1;
+            // returns found in the BTB take their target from the RAS
+            if itl.is_ret() {
+                if flags.predicted_target == target {
+                    self.ras_hit_count += 1;
+                } else {
+                    self.ras_miss_count += 1;
+                }
+            }
+        } else {
+            self.btb_miss_count += 1;
+        }
+    }
+
+    /// Cycles lost on average by a mispredicted branch or jump, [`None`] if
+    /// nothing was mispredicted.
+    fn mispredict_penalty(&self) -> Option<f64> {
+        (self.control_hazard_count > 0)
+            .then(|| self.control_hazard_delayed_cycles as f64 / self.control_hazard_count as f64)
+    }
+
+    /// Write the statistics of a run of `clock` cycles into `path`.
+    fn write(&self, path: &Path, format: StatsFormat, clock: u64) -> Result<()> {
+        let cpi = clock as f64 / self.executed_inst_count as f64;
+        let fields = [
+            ("clock", clock.to_string()),
+            // NaN is not a JSON number
+            (
+                "cpi",
+                if cpi.is_finite() {
+                    cpi.to_string()
+                } else {
+                    "null".to_string()
+                },
+            ),
+            ("data_hazard_count", self.data_hazard_count.to_string()),
+            (
+                "data_hazard_delayed_cycles",
+                self.data_hazard_delayed_cycles.to_string(),
+            ),
+            (
+                "control_hazard_count",
+                self.control_hazard_count.to_string(),
+            ),
+            (
+                "control_hazard_delayed_cycles",
+                self.control_hazard_delayed_cycles.to_string(),
+            ),
+            (
+                "structural_hazard_count",
+                self.structural_hazard_count.to_string(),
+            ),
+            ("executed_inst_count", self.executed_inst_count.to_string()),
+            (
+                "executed_branch_count",
+                self.executed_branch_count.to_string(),
+            ),
+            ("pause_count", self.pause_count.to_string()),
+            ("direction_hit_count", self.direction_hit_count.to_string()),
+            (
+                "target_predicted_count",
+                self.target_predicted_count.to_string(),
+            ),
+            ("target_hit_count", self.target_hit_count.to_string()),
+            ("btb_hit_count", self.btb_hit_count.to_string()),
+            ("btb_miss_count", self.btb_miss_count.to_string()),
+            ("ras_hit_count", self.ras_hit_count.to_string()),
+            ("ras_miss_count", self.ras_miss_count.to_string()),
+            ("icache_hit_count", self.icache_hit_count.to_string()),
+            ("icache_miss_count", self.icache_miss_count.to_string()),
+            ("dcache_hit_count", self.dcache_hit_count.to_string()),
+            ("dcache_miss_count", self.dcache_miss_count.to_string()),
+            (
+                "mispredict_penalty",
+                match self.mispredict_penalty() {
+                    Some(penalty) => penalty.to_string(),
+                    None => "null".to_string(),
+                },
+            ),
+        ];
+        let content = match format {
+            StatsFormat::Json => {
+                let pairs: Vec<_> = fields
+                    .iter()
+                    .map(|(key, val)| format!("  \"{key}\": {val}"))
+                    .collect();
+                format!("{{\n{}\n}}\n", pairs.join(",\n"))
+            }
+            StatsFormat::Csv => {
+                let keys: Vec<_> = fields.iter().map(|(key, _)| *key).collect();
+                let vals: Vec<_> = fields.iter().map(|(_, val)| val.as_str()).collect();
+                format!("{}\n{}\n", keys.join(","), vals.join(","))
+            }
+        };
+        std::fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// Print executed instructions, the most frequent first.
+    fn print_inst_histogram(&self) {
+        print_inst_histogram(&self.inst_histogram, self.executed_inst_count);
+    }
+}
+
+/// Architectural and micro-architectural state of the pipeline CPU, taken by
+/// [`CPU::snapshot`] and put back by [`CPU::restore`] to re-run from a point.
+/// Output of the program and trace records already written stay written.
+pub struct CpuSnapshot {
+    running: bool,
+    wfi: bool,
+    exit_code: Option<u64>,
+    last_store: Option<(u64, u64)>,
+    last_retire: Option<u64>,
+    regs: [u64; 32],
+    pc: u64,
+    clock: u64,
+    itl_f_d: InternalFetchDecode,
+    itl_d_e: InternalDecodeExec,
+    itl_e_m: InternalExecMem,
+    itl_m_m: InternalMemWb,
+    itl_m_w: InternalMemWb,
+    m_w_pipeline_states: [PipelineState; PIPELINE_STATES_DEPTH],
+    e_m_pipeline_states: [PipelineState; PIPELINE_STATES_DEPTH],
+    d_e_pipeline_states: [PipelineState; PIPELINE_STATES_DEPTH],
+    f_d_pipeline_states: [PipelineState; PIPELINE_STATES_DEPTH],
+    pc_next_states: [PipelineState; PIPELINE_STATES_DEPTH],
+    cpu_statistics: CPUStatistics,
+    bht: Option<BHT>,
+    btb: Option<BTB>,
+    ras: RAS,
+    icache: Option<Cache>,
+    dcache: Option<Cache>,
+    csr: Csr,
+    reservation: Reservation,
+    cycles_since_retire: u64,
+    call_depth: u64,
+    syscall: Box<dyn Syscall>,
+    callstack: CallStackSnapshot,
+    mm: Vec<u8>,
+}
+
+impl CpuSnapshot {
+    /// Cycles run when the snapshot was taken.
+    pub fn cycles(&self) -> u64 {
+        self.clock
     }
 }
 
@@ -140,6 +417,9 @@ pub struct CPU<'a> {
     // Exec / Mem
     itl_e_m: InternalExecMem,
 
+    // Mem1 / Mem2, empty unless with the extra MEM stage
+    itl_m_m: InternalMemWb,
+
     // Mem / Wb
     itl_m_w: InternalMemWb,
 
@@ -193,32 +473,140 @@ pub struct CPU<'a> {
 
     // Return address stack
     ras: RAS,
+
+    // Instruction cache in front of IF, timing only
+    icache: Option<Cache>,
+
+    // Extra cycles of an instruction cache miss
+    icache_miss_penalty: u64,
+
+    // Data cache in front of MEM, timing only
+    dcache: Option<Cache>,
+
+    // Extra cycles of a data cache miss
+    dcache_miss_penalty: u64,
+
+    // Control and status registers
+    csr: Csr,
+
+    // Raise breakpoint exception on ebreak instead of halting
+    ebreak_as_trap: bool,
+
+    // Serve ebreak in the semihosting sequence as a semihosting call
+    semihosting: bool,
+
+    // Stopped by a retired wfi, resumes after it on the next run
+    wfi: bool,
+
+    // Exit code of the program once it has ended
+    exit_code: Option<u64>,
+
+    // (vaddr, bytes) of the store committed by MEM in the last clock
+    last_store: Option<(u64, u64)>,
+
+    // pc of the instruction retired in the last clock
+    last_retire: Option<u64>,
+
+    // Executed calls minus executed returns
+    call_depth: u64,
+
+    // Consecutive cycles in which no instruction retired
+    cycles_since_retire: u64,
+
+    // Cycles without a retire before the pipeline is considered stuck
+    no_progress_cycles: u64,
+
+    // Syscall handler for ecall
+    syscall: Box<dyn Syscall>,
+
+    // LR/SC reservation set
+    reservation: Reservation,
+
+    // Itrace records of retired instructions
+    itrace_file: Option<TraceFile>,
+
+    // Hazards detected, and the stages held by the detector recording next
+    hazard_trace: Option<TraceFile>,
+    hazard_stages: Vec<String>,
+
+    // Registers logged after every retired instruction
+    trace_regs: Option<(TraceRegs, TraceFile)>,
+
+    // Reference commit log checked against every retired instruction
+    verifier: Option<Verifier>,
+
+    // Width of the integer registers
+    xlen: Xlen,
+
+    // Decoded instructions, not decoded again while unchanged
+    decode_cache: DecodeCache<InternalFetchDecode>,
+
+    // Raise address-misaligned exceptions on misaligned loads and stores
+    strict_align: bool,
+
+    // Stop with DividedByZero on division by zero instead of the spec results
+    div_trap: bool,
+
+    // Stop with an error once the clock reaches it
+    max_cycles: Option<u64>,
+
+    // Stop on exceptions, or jump to the handler at mtvec
+    trap_mode: TrapMode,
+
+    // IF and MEM share one memory port, so IF waits while MEM accesses memory
+    single_mem_port: bool,
+
+    // MEM takes two stages, MEM1 accessing memory and MEM2 delaying WB
+    extra_mem_stage: bool,
+
+    // Extra cycles of mul and div/rem in EX
+    mul_latency: u64,
+    div_latency: u64,
+
+    // Pipeline diagram of the last instructions
+    pipeline_diagram: Option<PipelineDiagram>,
 }
 
 impl<'a> CPU<'a> {
     pub fn new(
         vm: &'a mut VirtualMemory,
         callstack: &'a mut CallStack<'a>,
-        data_hazard_policy: DataHazardPolicy,
-        control_policy: ControlPolicy,
-        predict_policy: Option<PredictPolicy>,
-        pre_pipeline_info: bool,
-        pipeline_info: bool,
-        post_pipeline_info: bool,
-        control_hazard_info: bool,
-        data_hazard_info: bool,
+        config: PipelineConfig,
     ) -> CPU<'a> {
+        let PipelineConfig {
+            data_hazard_policy,
+            control_policy,
+            predict_policy,
+            bht_bits,
+            btb_entries,
+            pre_pipeline_info,
+            pipeline_info,
+            post_pipeline_info,
+            control_hazard_info,
+            data_hazard_info,
+            warn_on_unknown_csr,
+            ebreak_as_trap,
+            syscall_abi,
+            xlen,
+        } = config;
+        // mstatus.UBE and MBE show the byte order of data accesses
+        let mut csr = Csr::new(warn_on_unknown_csr, xlen);
+        csr.set_big_endian(vm.data_endian() == Endian::Big);
+
         // x0 already set to 0
-        let reg_file = RegisterFile::empty();
-        let pc = ProgramCounter::new();
+        let mut reg_file = RegisterFile::empty();
+        reg_file.set_xlen(xlen);
+        let mut pc = ProgramCounter::new();
+        pc.set_xlen(xlen);
+        vm.set_xlen(xlen);
 
         let bht = if let Some(predict_policy) = predict_policy {
-            Some(BHT::new(predict_policy))
+            Some(BHT::new(predict_policy, bht_bits))
         } else {
             None
         };
         let btb = if let Some(_) = predict_policy {
-            Some(BTB::new())
+            Some(BTB::new(btb_entries))
         } else {
             None
         };
@@ -234,6 +622,7 @@ impl<'a> CPU<'a> {
             itl_f_d: InternalFetchDecode::default(),
             itl_d_e: InternalDecodeExec::default(),
             itl_e_m: InternalExecMem::default(),
+            itl_m_m: InternalMemWb::default(),
             itl_m_w: InternalMemWb::default(),
             m_w_pipeline_states: [PipelineState::Normal; PIPELINE_STATES_DEPTH],
             e_m_pipeline_states: [PipelineState::Normal; PIPELINE_STATES_DEPTH],
@@ -256,18 +645,66 @@ impl<'a> CPU<'a> {
             bht,
             btb,
             ras: RAS::new(),
+            icache: None,
+            icache_miss_penalty: 0,
+            dcache: None,
+            dcache_miss_penalty: 0,
+            csr,
+            ebreak_as_trap,
+            semihosting: false,
+            wfi: false,
+            exit_code: None,
+            last_store: None,
+            last_retire: None,
+            call_depth: 0,
+            cycles_since_retire: 0,
+            no_progress_cycles: DEFAULT_NO_PROGRESS_CYCLES,
+            syscall: new_syscall(syscall_abi),
+            reservation: Reservation::default(),
+            itrace_file: None,
+            hazard_trace: None,
+            hazard_stages: Vec::new(),
+            trace_regs: None,
+            verifier: None,
+            xlen,
+            decode_cache: DecodeCache::new(),
+            strict_align: false,
+            div_trap: false,
+            max_cycles: None,
+            trap_mode: TrapMode::Fatal,
+            single_mem_port: false,
+            extra_mem_stage: false,
+            mul_latency: DEFAULT_MUL_LATENCY,
+            div_latency: DEFAULT_DIV_LATENCY,
+            pipeline_diagram: None,
         }
     }
 
-    /// Initialize CPU with ELF info
-    pub fn init_elfinfo_64(&mut self, info: &LoadElfInfo) {
-        // make sure we are running a ELF64 executable
-        assert!(info.is_64_bit());
+    /// Give every register but x0 an initial value from `fill`. Call it
+    /// before [`Self::init_elfinfo_64`], which sets sp.
+    pub fn fill_uninit_regs(&mut self, fill: &mut UninitFill) {
+        self.reg_file.fill_uninit(fill);
+    }
+
+    /// Initialize CPU with ELF info, and pass `args` to the program as its
+    /// argv on the stack.
+    pub fn init_elfinfo_64(&mut self, info: &LoadElfInfo, args: &[String]) -> Result<()> {
+        // make sure the ELF class matches XLEN
+        assert_eq!(info.is_64_bit(), self.xlen == Xlen::Rv64);
+
+        let sp = self.vm.push_args(args)?;
+        self.reg_file.init_elfinfo_64(info, sp);
 
-        self.reg_file.init_elfinfo_64(info);
+        self.set_entry(info.entry_point());
 
-        let pc = info.entry_point();
+        // Heap begins right after the program
+        self.syscall.set_brk(info.max_vaddr() as u64);
+        Ok(())
+    }
 
+    /// Start running at `pc` instead of the entry point of the program, e.g.
+    /// at a function to test on its own.
+    pub fn set_entry(&mut self, pc: u64) {
         // Load program counter
         self.pc.write(pc);
 
@@ -275,9 +712,216 @@ impl<'a> CPU<'a> {
         self.itl_f_d.pc = pc;
         self.itl_d_e.pc = pc;
         self.itl_e_m.pc = pc;
+        self.itl_m_m.pc = pc;
         self.itl_m_w.pc = pc;
     }
 
+    /// Write itrace records of every retired instruction into file at `path`.
+    pub fn set_itrace_file(&mut self, path: &Path) -> Result<()> {
+        self.itrace_file = Some(TraceFile::create(path)?);
+        Ok(())
+    }
+
+    /// Write every hazard detected, with the stages it holds, into file at
+    /// `path`.
+    pub fn set_hazard_trace(&mut self, path: &Path) -> Result<()> {
+        self.hazard_trace = Some(TraceFile::create(path)?);
+        Ok(())
+    }
+
+    /// Check every retired instruction against the reference commit log at
+    /// `path`, stopping with [`Error::Mismatch`] at the first difference.
+    pub fn set_verify_file(&mut self, path: &Path) -> Result<()> {
+        self.verifier = Some(Verifier::open(path, self.xlen)?);
+        Ok(())
+    }
+
+    /// Log the registers after every retired instruction, see [`TraceRegs`],
+    /// into file at `path` or else on stdout.
+    pub fn set_trace_regs(&mut self, trace_regs: TraceRegs, path: Option<&Path>) -> Result<()> {
+        let file = match path {
+            Some(path) => TraceFile::create(path)?,
+            None => TraceFile::stdout(),
+        };
+        self.trace_regs = Some((trace_regs, file));
+        Ok(())
+    }
+
+    /// Raise address-misaligned exceptions on misaligned loads and stores.
+    pub fn set_strict_align(&mut self, strict_align: bool) {
+        self.strict_align = strict_align;
+    }
+
+    /// Stop with [`Exception::DividedByZero`] on division by zero instead of
+    /// giving the results the spec defines.
+    pub fn set_div_trap(&mut self, div_trap: bool) {
+        self.div_trap = div_trap;
+    }
+
+    /// Stop with [`Error::CycleLimitExceeded`] once the clock reaches
+    /// `max_cycles`, however the CPU is run.
+    pub fn set_max_cycles(&mut self, max_cycles: Option<u64>) {
+        self.max_cycles = max_cycles;
+    }
+
+    /// Stop on exceptions, or jump to the handler at mtvec.
+    pub fn set_trap_mode(&mut self, trap_mode: TrapMode) {
+        self.trap_mode = trap_mode;
+    }
+
+    /// Raise a timer interrupt every `interval` cycles, taken once enabled
+    /// by mstatus.MIE and mie.MTIE.
+    pub fn set_timer_interval(&mut self, interval: u64) {
+        self.csr.set_timer_interval(interval);
+    }
+
+    /// Extra cycles a multiplication and a division or remainder spend in
+    /// EX. A rem right after the div of the same operands costs nothing.
+    pub fn set_mul_div_latency(&mut self, mul_latency: u64, div_latency: u64) {
+        self.mul_latency = mul_latency;
+        self.div_latency = div_latency;
+    }
+
+    /// Stop with an error once `cycles` clocks in a row retire nothing.
+    pub fn set_no_progress_cycles(&mut self, cycles: u64) {
+        self.no_progress_cycles = cycles;
+    }
+
+    /// Serve ebreak between `slli x0, x0, 0x1f` and `srai x0, x0, 7` as a
+    /// semihosting call when it retires. Any other ebreak raises a
+    /// breakpoint exception as with ebreak as trap.
+    pub fn set_semihosting(&mut self, semihosting: bool) {
+        self.semihosting = semihosting;
+    }
+
+    /// Back `read` of stdin by `input`, which is empty unless set.
+    pub fn set_stdin(&mut self, input: Vec<u8>) {
+        self.syscall.set_stdin(input);
+    }
+
+    /// Whether the ebreak at `pc` raises a breakpoint exception, rather than
+    /// halting or making a semihosting call.
+    fn ebreak_traps(&self, pc: u64) -> bool {
+        if self.semihosting {
+            !is_semihosting_call(self.vm, pc)
+        } else {
+            self.ebreak_as_trap
+        }
+    }
+
+    /// Share one memory port between IF and MEM: fetch stalls for a cycle
+    /// whenever a load or store is in MEM.
+    pub fn set_single_mem_port(&mut self, single_mem_port: bool) {
+        self.single_mem_port = single_mem_port;
+    }
+
+    /// Split MEM into MEM1, which accesses memory, and MEM2, which only
+    /// holds the result for a cycle before WB. Results are forwarded from
+    /// MEM1/MEM2 too. Call it before [`Self::set_pipeline_diagram`].
+    pub fn set_extra_mem_stage(&mut self, extra_mem_stage: bool) {
+        self.extra_mem_stage = extra_mem_stage;
+    }
+
+    /// Put `icache` in front of IF: every fetch missing it costs
+    /// `miss_penalty` extra cycles. Instructions still come from memory.
+    pub fn set_icache(&mut self, icache: Option<Cache>, miss_penalty: u64) {
+        self.icache = icache;
+        self.icache_miss_penalty = miss_penalty;
+    }
+
+    /// Put `dcache` in front of MEM: every load and store missing it costs
+    /// `miss_penalty` extra cycles. Data still comes from memory.
+    pub fn set_dcache(&mut self, dcache: Option<Cache>, miss_penalty: u64) {
+        self.dcache = dcache;
+        self.dcache_miss_penalty = miss_penalty;
+    }
+
+    /// Record the pipeline diagram of the last `rows` instructions, see
+    /// [`Self::print_pipeline_diagram`].
+    pub fn set_pipeline_diagram(&mut self, rows: Option<usize>) {
+        let extra_mem_stage = self.extra_mem_stage;
+        self.pipeline_diagram = rows.map(|rows| PipelineDiagram::new(rows, extra_mem_stage));
+    }
+
+    /// Print the pipeline diagram, if recorded.
+    pub fn print_pipeline_diagram(&self) {
+        if let Some(diagram) = &self.pipeline_diagram {
+            diagram.print();
+        }
+    }
+
+    /// Take a snapshot of the CPU state and the memory.
+    pub fn snapshot(&self) -> CpuSnapshot {
+        CpuSnapshot {
+            running: self.running,
+            wfi: self.wfi,
+            exit_code: self.exit_code,
+            last_store: self.last_store,
+            last_retire: self.last_retire,
+            regs: self.reg_file.to_array(),
+            pc: self.pc.read(),
+            clock: self.clock,
+            itl_f_d: self.itl_f_d,
+            itl_d_e: self.itl_d_e,
+            itl_e_m: self.itl_e_m,
+            itl_m_m: self.itl_m_m,
+            itl_m_w: self.itl_m_w,
+            m_w_pipeline_states: self.m_w_pipeline_states,
+            e_m_pipeline_states: self.e_m_pipeline_states,
+            d_e_pipeline_states: self.d_e_pipeline_states,
+            f_d_pipeline_states: self.f_d_pipeline_states,
+            pc_next_states: self.pc_next_states,
+            cpu_statistics: self.cpu_statistics.clone(),
+            bht: self.bht.clone(),
+            btb: self.btb.clone(),
+            ras: self.ras.clone(),
+            icache: self.icache.clone(),
+            dcache: self.dcache.clone(),
+            csr: self.csr.clone(),
+            reservation: self.reservation.clone(),
+            cycles_since_retire: self.cycles_since_retire,
+            call_depth: self.call_depth,
+            syscall: self.syscall.clone_box(),
+            callstack: self.callstack.snapshot(),
+            mm: self.vm.memory().to_vec(),
+        }
+    }
+
+    /// Put back a snapshot taken by [`CPU::snapshot`].
+    pub fn restore(&mut self, snapshot: &CpuSnapshot) {
+        self.running = snapshot.running;
+        self.wfi = snapshot.wfi;
+        self.exit_code = snapshot.exit_code;
+        self.last_store = snapshot.last_store;
+        self.last_retire = snapshot.last_retire;
+        self.reg_file = RegisterFile::from_array(&snapshot.regs, self.xlen);
+        self.pc.write(snapshot.pc);
+        self.clock = snapshot.clock;
+        self.itl_f_d = snapshot.itl_f_d;
+        self.itl_d_e = snapshot.itl_d_e;
+        self.itl_e_m = snapshot.itl_e_m;
+        self.itl_m_m = snapshot.itl_m_m;
+        self.itl_m_w = snapshot.itl_m_w;
+        self.m_w_pipeline_states = snapshot.m_w_pipeline_states;
+        self.e_m_pipeline_states = snapshot.e_m_pipeline_states;
+        self.d_e_pipeline_states = snapshot.d_e_pipeline_states;
+        self.f_d_pipeline_states = snapshot.f_d_pipeline_states;
+        self.pc_next_states = snapshot.pc_next_states;
+        self.cpu_statistics = snapshot.cpu_statistics.clone();
+        self.bht = snapshot.bht.clone();
+        self.btb = snapshot.btb.clone();
+        self.ras = snapshot.ras.clone();
+        self.icache = snapshot.icache.clone();
+        self.dcache = snapshot.dcache.clone();
+        self.csr = snapshot.csr.clone();
+        self.reservation = snapshot.reservation.clone();
+        self.cycles_since_retire = snapshot.cycles_since_retire;
+        self.call_depth = snapshot.call_depth;
+        self.syscall = snapshot.syscall.clone_box();
+        self.callstack.restore(&snapshot.callstack);
+        self.vm.restore_memory(&snapshot.mm);
+    }
+
     /// Run the cpu.
     /// steps: how many steps should be run, [`None`] means run until end or
     /// exception raised.
@@ -296,43 +940,402 @@ impl<'a> CPU<'a> {
         Ok(())
     }
 
-    pub fn print_info(&self) {
-        info!("CPU run clock: {}", self.clock);
-        info!(
+    /// Run the cpu until it stops, an instruction at one of `breakpoints`
+    /// gets to [`CPU::breakpoint_hit`], a store writes into one of
+    /// `watchpoints`, or a watched register changes from its value in
+    /// `reg_values`.
+    /// Always runs at least one clock, so that a CPU standing on a breakpoint
+    /// makes progress. Returns whether a breakpoint or watchpoint was hit,
+    /// or [`Error::Interrupted`] once `interrupted` is set.
+    pub(super) fn cpu_exec_break(
+        &mut self,
+        breakpoints: &BTreeSet<u64>,
+        conditions: &BTreeMap<u64, Condition>,
+        watchpoints: &BTreeMap<u64, u64>,
+        reg_values: &BTreeMap<u8, u64>,
+        interrupted: &AtomicBool,
+    ) -> Result<bool> {
+        self.running = true;
+        self.clock()?;
+        while self.running {
+            let pc = self.pc.read();
+            if interrupted.load(Ordering::Relaxed) {
+                return Err(Error::Interrupted(pc));
+            }
+            if self.breakpoint_hit(breakpoints, conditions)?.is_some()
+                || self.watch_hit(watchpoints).is_some()
+                || self.reg_watch_hit(reg_values).is_some()
+            {
+                return Ok(true);
+            }
+            self.clock()?;
+        }
+        Ok(false)
+    }
+
+    /// The first register in `values` which no longer holds its value there,
+    /// with that old value.
+    pub(super) fn reg_watch_hit(&self, values: &BTreeMap<u8, u64>) -> Option<(u8, u64)> {
+        values
+            .iter()
+            .find(|&(&idx, &old)| self.reg_file.read(idx) != old)
+            .map(|(&idx, &old)| (idx, old))
+    }
+
+    /// pc of the instruction waiting to write back if it is at one of
+    /// `breakpoints` and its condition in `conditions`, if any, holds. Every
+    /// older instruction has retired then, so registers hold the values it
+    /// reads, and a wrongly fetched instruction never gets there.
+    pub(super) fn breakpoint_hit(
+        &self,
+        breakpoints: &BTreeSet<u64>,
+        conditions: &BTreeMap<u64, Condition>,
+    ) -> Result<Option<u64>> {
+        let itl = &self.itl_m_w;
+        if itl.alu_op == Inst64::noop || !breakpoints.contains(&itl.pc) {
+            return Ok(None);
+        }
+        let Some(cond) = conditions.get(&itl.pc) else {
+            return Ok(Some(itl.pc));
+        };
+        let val = match cond.reg.as_str() {
+            "pc" => itl.pc,
+            reg => self.reg_val_by_name(reg)?,
+        };
+        Ok(cond.holds(val).then_some(itl.pc))
+    }
+
+    /// Return address of the instruction at the fetch pc if it is a call.
+    pub(super) fn call_return_addr(&self) -> Option<u64> {
+        let pc = self.pc.read();
+        let itl = decode_at(pc, self.vm, self.xlen).ok()?;
+        let is_call = matches!(itl.exec_flags.alu_op, Inst64::jal | Inst64::jalr) && itl.rd == 1;
+        is_call.then_some(pc.wrapping_add(itl.inst_len))
+    }
+
+    /// Step over the call at the fetch pc: run the cpu until the instruction
+    /// at `return_addr` is about to execute in the caller's frame, or until
+    /// [`CPU::cpu_exec_break`] would stop. Returns of recursive calls into
+    /// `return_addr` are told apart by the call depth.
+    /// Returns whether it stopped before the cpu stops, or
+    /// [`Error::Interrupted`] once `interrupted` is set.
+    pub(super) fn cpu_exec_next(
+        &mut self,
+        return_addr: u64,
+        breakpoints: &BTreeSet<u64>,
+        conditions: &BTreeMap<u64, Condition>,
+        watchpoints: &BTreeMap<u64, u64>,
+        interrupted: &AtomicBool,
+    ) -> Result<bool> {
+        let depth = self.call_depth;
+        self.running = true;
+        self.clock()?;
+        while self.running {
+            if interrupted.load(Ordering::Relaxed) {
+                return Err(Error::Interrupted(self.pc.read()));
+            }
+            // every older instruction, including the call and its return,
+            // has executed when an instruction waits in ID/EX
+            let returned = self.itl_d_e.exec_flags.alu_op != Inst64::noop
+                && self.itl_d_e.pc == return_addr
+                && self.call_depth == depth;
+            if returned
+                || self.breakpoint_hit(breakpoints, conditions)?.is_some()
+                || self.watch_hit(watchpoints).is_some()
+            {
+                return Ok(true);
+            }
+            self.clock()?;
+        }
+        Ok(false)
+    }
+
+    /// Run the cpu until the current function returns: its ret executes and
+    /// then retires, so that registers hold the return value.
+    /// Also stops where [`CPU::cpu_exec_break`] would. Returns whether it
+    /// stopped before the cpu stops, or [`Error::Interrupted`] once
+    /// `interrupted` is set.
+    pub(super) fn cpu_exec_finish(
+        &mut self,
+        breakpoints: &BTreeSet<u64>,
+        conditions: &BTreeMap<u64, Condition>,
+        watchpoints: &BTreeMap<u64, u64>,
+        interrupted: &AtomicBool,
+    ) -> Result<bool> {
+        let depth = self.call_depth;
+        let mut ret_pc = None;
+        self.running = true;
+        while self.running {
+            let ret_retiring = ret_pc.is_some_and(|pc| self.itl_m_w.pc == pc);
+            self.clock()?;
+            if interrupted.load(Ordering::Relaxed) {
+                return Err(Error::Interrupted(self.pc.read()));
+            }
+            match ret_pc {
+                Some(_) if ret_retiring => return Ok(true),
+                Some(_) => {}
+                // the ret has just executed
+                None if self.call_depth < depth => ret_pc = Some(self.itl_e_m.pc),
+                None => {
+                    if self.breakpoint_hit(breakpoints, conditions)?.is_some()
+                        || self.watch_hit(watchpoints).is_some()
+                    {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// pc of the instruction retired in the last clock, if any.
+    pub(super) fn last_retire(&self) -> Option<u64> {
+        self.last_retire
+    }
+
+    /// Executed calls which have not returned yet.
+    pub(super) fn call_depth(&self) -> u64 {
+        self.call_depth
+    }
+
+    /// The first of `watchpoints`, the bytes watched from each address,
+    /// overlapped by the store committed in the last clock.
+    pub(super) fn watch_hit(&self, watchpoints: &BTreeMap<u64, u64>) -> Option<u64> {
+        let (vaddr, bytes) = self.last_store?;
+        watchpoints
+            .iter()
+            .find(|(&addr, &len)| addr < vaddr + bytes && vaddr < addr + len)
+            .map(|(&addr, _)| addr)
+    }
+
+    /// Jump to the trap handler on an exception of the instruction at `epc`
+    /// in vectored trap mode, flushing the instructions younger than it in
+    /// ID/EX and IF/ID. Other errors are returned as they are.
+    fn take_trap(&mut self, err: Error, epc: u64) -> Result<()> {
+        if self.trap_mode == TrapMode::Vectored {
+            if let Some(exception) = err.as_exception() {
+                if let Some(handler) = self.csr.trap(&exception, epc) {
+                    if self.pipeline_info {
+                        info!("Trap: {} at {:#x}, to {:#x}", exception, epc, handler);
+                    }
+                    self.itl_d_e = InternalDecodeExec::default();
+                    self.itl_f_d = InternalFetchDecode::default();
+                    self.pc.write(handler);
+                    return Ok(());
+                }
+            }
+        }
+        Err(err)
+    }
+
+    /// The pc of an ebreak in MEM or WB, which is older than the instruction
+    /// in EX.
+    fn ebreak_ahead(&self) -> Option<u64> {
+        [
+            (self.itl_e_m.alu_op, self.itl_e_m.pc),
+            (self.itl_m_m.alu_op, self.itl_m_m.pc),
+            (self.itl_m_w.alu_op, self.itl_m_w.pc),
+        ]
+        .into_iter()
+        .find_map(|(op, pc)| (op == Inst64::ebreak).then_some(pc))
+    }
+
+    /// Whether the ebreak at `pc` ends the program, rather than trapping or
+    /// making a semihosting call.
+    fn ebreak_halts(&self, pc: u64) -> bool {
+        !(self.ebreak_traps(pc) || self.semihosting && is_semihosting_call(self.vm, pc))
+    }
+
+    /// Jump to the trap handler on a pending timer interrupt before the
+    /// instruction in ID/EX, flushing it and the younger one in IF/ID.
+    /// Returns whether the interrupt is taken.
+    fn take_interrupt(&mut self) -> bool {
+        if self.itl_d_e.exec_flags.alu_op == Inst64::noop {
+            return false;
+        }
+        let epc = self.itl_d_e.pc;
+        let Some(handler) = self.csr.interrupt(epc) else {
+            return false;
+        };
+        if self.pipeline_info {
+            info!("Interrupt: timer at {:#x}, to {:#x}", epc, handler);
+        }
+        self.itl_d_e = InternalDecodeExec::default();
+        self.itl_f_d = InternalFetchDecode::default();
+        self.pc.write(handler);
+        true
+    }
+
+    /// Look up the fetch of the `len` bytes instruction at `pc` in the
+    /// instruction cache, charging the miss penalty to the clock on a miss.
+    fn access_icache(&mut self, pc: u64, len: u64) {
+        let Some(icache) = self.icache.as_mut() else {
+            return;
+        };
+        if icache.access(pc, len) {
+            self.cpu_statistics.icache_hit_count += 1;
+        } else {
+            self.cpu_statistics.icache_miss_count += 1;
+            self.clock += self.icache_miss_penalty;
+        }
+    }
+
+    /// Look up the access of the load or store in MEM in the data cache,
+    /// charging the miss penalty to the clock on a miss.
+    fn access_dcache(&mut self) {
+        let itl = &self.itl_e_m;
+        let accesses = itl.mem_flags.mem_read || itl.mem_flags.mem_write || is_atomic(itl.alu_op);
+        let Some(dcache) = self.dcache.as_mut().filter(|_| accesses) else {
+            return;
+        };
+        let vaddr = self.xlen.trunc(itl.mem_addr);
+        if dcache.access(vaddr, itl.mem_bitwidth as u64 / 8) {
+            self.cpu_statistics.dcache_hit_count += 1;
+        } else {
+            self.cpu_statistics.dcache_miss_count += 1;
+            self.clock += self.dcache_miss_penalty;
+        }
+    }
+
+    /// Summary of the run, printed at its end.
+    pub fn summary_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        lines.push(format!(
+            "CPU policies: data hazard {:?}, control {:?}",
+            self.data_hazard_policy, self.control_policy
+        ));
+        if self.extra_mem_stage {
+            lines.push("CPU pipeline: IF, ID, EX, MEM1, MEM2, WB".to_string());
+        }
+        lines.push(format!("CPU run clock: {}", self.clock));
+        lines.push(format!(
+            "CPU mul/div latency: {} / {} extra cycles",
+            self.mul_latency, self.div_latency
+        ));
+        lines.push(format!(
             "CPU data hazard count: {}",
             self.cpu_statistics.data_hazard_count
-        );
-        info!(
+        ));
+        lines.push(format!(
             "CPU data hazard delayed cycles: {}",
             self.cpu_statistics.data_hazard_delayed_cycles
-        );
-        info!(
+        ));
+        lines.push(format!(
             "CPU control hazard count: {}",
             self.cpu_statistics.control_hazard_count
-        );
-        info!(
+        ));
+        lines.push(format!(
             "CPU control hazard delayed cycles: {}",
             self.cpu_statistics.control_hazard_delayed_cycles
-        );
-        info!(
+        ));
+        if let Some(penalty) = self.cpu_statistics.mispredict_penalty() {
+            lines.push(format!(
+                "CPU branch misprediction penalty: {penalty} cycles"
+            ));
+        }
+        if self.single_mem_port {
+            lines.push(format!(
+                "CPU structural hazard count: {}",
+                self.cpu_statistics.structural_hazard_count
+            ));
+        }
+        lines.push(format!(
+            "CPU branch misprediction rate: {}",
+            percent(
+                self.cpu_statistics.control_hazard_count,
+                self.cpu_statistics.executed_branch_count
+            )
+        ));
+        let stats = &self.cpu_statistics;
+        lines.push(format!(
+            "CPU branch direction accuracy: {} of {} branches",
+            percent(stats.direction_hit_count, stats.executed_branch_count),
+            stats.executed_branch_count
+        ));
+        lines.push(format!(
+            "CPU branch target accuracy: {} of {} taken as predicted",
+            percent(stats.target_hit_count, stats.target_predicted_count),
+            stats.target_predicted_count
+        ));
+        if self.control_policy == ControlPolicy::DynamicPredict {
+            lines.push(format!(
+                "CPU BTB hits / misses: {} / {}",
+                stats.btb_hit_count, stats.btb_miss_count
+            ));
+            lines.push(format!(
+                "CPU RAS hits / misses: {} / {}",
+                stats.ras_hit_count, stats.ras_miss_count
+            ));
+        }
+        if self.icache.is_some() {
+            let fetches = stats.icache_hit_count + stats.icache_miss_count;
+            lines.push(format!(
+                "CPU icache hits / misses: {} / {}, miss rate {}",
+                stats.icache_hit_count,
+                stats.icache_miss_count,
+                percent(stats.icache_miss_count, fetches)
+            ));
+        }
+        if self.dcache.is_some() {
+            let accesses = stats.dcache_hit_count + stats.dcache_miss_count;
+            lines.push(format!(
+                "CPU dcache hits / misses: {} / {}, miss rate {}",
+                stats.dcache_hit_count,
+                stats.dcache_miss_count,
+                percent(stats.dcache_miss_count, accesses)
+            ));
+        }
+        lines.push(format!(
             "CPU executed valid instructions: {}",
             self.cpu_statistics.executed_inst_count
-        );
-        info!("CPI = {}", {
-            let cycles = self.clock;
-            let insts = self.cpu_statistics.executed_inst_count;
-            (cycles as f64) / (insts as f64)
-        });
+        ));
+        lines.push(format!(
+            "CPI = {}",
+            ratio(self.clock, self.cpu_statistics.executed_inst_count)
+        ));
+        lines
     }
 
-    pub(super) fn clock(&mut self) -> Result<()> {
-        // begin the clock
-        self.clock += 1;
-        if self.clock_info {
-            debug!(
-                "#################### CLOCK: {} ####################",
-                self.clock
-            );
+    /// Print how many times each instruction is executed.
+    pub fn print_inst_histogram(&self) {
+        self.cpu_statistics.print_inst_histogram();
+    }
+
+    /// Print calls and cycles of each function, if profiling.
+    pub fn print_profile(&self) {
+        self.callstack.print_profile();
+    }
+
+    /// Write the run statistics into `path` for scripts.
+    pub fn write_stats(&self, path: &Path, format: StatsFormat) -> Result<()> {
+        self.cpu_statistics.write(path, format, self.clock)
+    }
+
+    /// Exit code of the program, once it has ended by exit or ebreak.
+    pub fn exit_code(&self) -> Option<u64> {
+        self.exit_code
+    }
+
+    /// Cycles run so far.
+    pub fn cycles(&self) -> u64 {
+        self.clock
+    }
+
+    /// Instructions executed so far.
+    pub fn retired(&self) -> u64 {
+        self.cpu_statistics.executed_inst_count
+    }
+
+    pub(super) fn clock(&mut self) -> Result<()> {
+        check_cycle_limit(self.max_cycles, self.clock, self.pc.read())?;
+        // begin the clock
+        let clock_start = self.clock;
+        self.clock += 1;
+        if self.clock_info {
+            debug!(
+                "#################### CLOCK: {} ####################",
+                self.clock
+            );
         }
 
         // detect load-use hazard
@@ -392,12 +1395,20 @@ impl<'a> CPU<'a> {
                             PipelineState::Bubble,
                         ]);
                         self.pc_next_states_set(&mut [PipelineState::Stall, PipelineState::Stall]);
+                        self.trace_hazard(HazardKind::MemToMem, self.itl_d_e.pc, self.itl_f_d.pc);
                     }
                 }
                 DataHazardPolicy::DataForward => {
                     // debug!("Detecting memory-to-memory hazard");
-                    let mem_wb_rd = self.itl_m_w.rd;
-                    let mem_wb_mem_read = self.itl_m_w.mem_read;
+                    // the load is right after MEM, which is in MEM1/MEM2 with
+                    // the extra MEM stage
+                    let mem_out = if self.extra_mem_stage {
+                        self.itl_m_m
+                    } else {
+                        self.itl_m_w
+                    };
+                    let mem_wb_rd = mem_out.rd;
+                    let mem_wb_mem_read = mem_out.mem_read;
                     let exec_mem_rs2 = self.itl_e_m.rs2;
                     let exec_mem_mem_write = self.itl_e_m.mem_flags.mem_write;
                     if (mem_wb_rd != 0)
@@ -418,7 +1429,8 @@ impl<'a> CPU<'a> {
                         }
                         self.cpu_statistics.data_hazard_count += 1;
                         self.itl_e_m.m2m_forward = true;
-                        self.itl_e_m.m2m_forward_val = self.itl_m_w.regval;
+                        self.itl_e_m.m2m_forward_val = mem_out.regval;
+                        self.trace_hazard(HazardKind::MemToMem, mem_out.pc, self.itl_e_m.pc);
                     }
                 }
             }
@@ -446,6 +1458,7 @@ impl<'a> CPU<'a> {
                         PipelineState::Bubble,
                     ]);
                     self.pc_next_states_set(&mut [PipelineState::Stall, PipelineState::Stall]);
+                    self.trace_hazard(HazardKind::ExMem, self.itl_d_e.pc, self.itl_f_d.pc);
                 }
             }
             DataHazardPolicy::DataForward => {
@@ -471,7 +1484,8 @@ impl<'a> CPU<'a> {
                             warn!("  EX/MEM.rd={}({})", ex_mem_rd, REGNAME[ex_mem_rd as usize]);
                             warn!("  ID/EX.rs1={}({})", id_ex_rs2, REGNAME[id_ex_rs2 as usize]);
                         }
-                        // forward B from EX/MEM
+                        // forward B from EX/MEM, which is also the data of a
+                        // store since the immediate does not go through B
                         self.itl_d_e.forward_b = 0b10;
                     }
 
@@ -480,6 +1494,7 @@ impl<'a> CPU<'a> {
                         && ((ex_mem_rd == id_ex_rs1) || (ex_mem_rd == id_ex_rs2))
                     {
                         self.cpu_statistics.data_hazard_count += 1;
+                        self.trace_hazard(HazardKind::ExMem, self.itl_e_m.pc, self.itl_d_e.pc);
                     }
 
                     self.itl_e_m.alu_out
@@ -488,6 +1503,93 @@ impl<'a> CPU<'a> {
             }
         }
 
+        // MEM1/MEM2 is empty unless with the extra MEM stage
+        match self.data_hazard_policy {
+            DataHazardPolicy::NaiveStall => {
+                let mem_mem_regwrite = self.itl_m_m.wb_flags.mem_to_reg;
+                let mem_mem_rd = self.itl_m_m.rd;
+                let ex_mem_rd = self.itl_e_m.rd;
+                let id_ex_rd = self.itl_d_e.rd;
+                let if_id_rs1 = self.itl_f_d.rs1;
+                let if_id_rs2 = self.itl_f_d.rs2;
+                if mem_mem_regwrite
+                    && (mem_mem_rd != 0)
+                    && (((id_ex_rd != if_id_rs1)
+                        && (ex_mem_rd != if_id_rs1)
+                        && (mem_mem_rd == if_id_rs1))
+                        || ((id_ex_rd != if_id_rs2)
+                            && (ex_mem_rd != if_id_rs2)
+                            && (mem_mem_rd == if_id_rs2)))
+                {
+                    if self.data_hazard_info {
+                        warn!("MEM1/MEM2 data hazard detected");
+                    }
+                    self.cpu_statistics.data_hazard_count += 1;
+                    self.cpu_statistics.data_hazard_delayed_cycles += 1;
+
+                    self.d_e_pipeline_states_set(&mut [PipelineState::Bubble]);
+                    self.f_d_pipeline_states_set(&mut [PipelineState::Stall]);
+                    self.pc_next_states_set(&mut [PipelineState::Stall]);
+                    self.trace_hazard(HazardKind::MemMem, self.itl_m_m.pc, self.itl_f_d.pc);
+                }
+            }
+            DataHazardPolicy::DataForward => {
+                let mem_mem_forward = {
+                    // MEM1/MEM2 hazard
+                    let mem_mem_regwrite = self.itl_m_m.wb_flags.mem_to_reg;
+                    let mem_mem_rd = self.itl_m_m.rd;
+                    let ex_mem_rd = self.itl_e_m.rd;
+                    let id_ex_rs1 = self.itl_d_e.rs1;
+                    let id_ex_rs2 = self.itl_d_e.rs2;
+                    if mem_mem_regwrite
+                        && (mem_mem_rd != 0)
+                        && (ex_mem_rd != id_ex_rs1)
+                        && (mem_mem_rd == id_ex_rs1)
+                    {
+                        if self.data_hazard_info {
+                            warn!("MEM1/MEM2 data hazard detected, for ALU SRC A");
+                            warn!(
+                                "  MEM1/MEM2.rd={}({})",
+                                mem_mem_rd, REGNAME[mem_mem_rd as usize]
+                            );
+                            warn!("  ID/EX.rs1={}({})", id_ex_rs1, REGNAME[id_ex_rs1 as usize]);
+                        }
+                        // forward A from MEM1/MEM2
+                        assert_eq!(self.itl_d_e.forward_a, 0);
+                        self.itl_d_e.forward_a = 0b11;
+                    }
+                    if mem_mem_regwrite
+                        && (mem_mem_rd != 0)
+                        && (ex_mem_rd != id_ex_rs2)
+                        && (mem_mem_rd == id_ex_rs2)
+                    {
+                        if self.data_hazard_info {
+                            warn!("MEM1/MEM2 data hazard detected, for ALU SRC B");
+                            warn!(
+                                "  MEM1/MEM2.rd={}({})",
+                                mem_mem_rd, REGNAME[mem_mem_rd as usize]
+                            );
+                            warn!("  ID/EX.rs2={}({})", id_ex_rs2, REGNAME[id_ex_rs2 as usize]);
+                        }
+                        // forward B from MEM1/MEM2
+                        assert_eq!(self.itl_d_e.forward_b, 0);
+                        self.itl_d_e.forward_b = 0b11;
+                    }
+
+                    if mem_mem_regwrite
+                        && (mem_mem_rd != 0)
+                        && ((ex_mem_rd != id_ex_rs1) && (mem_mem_rd == id_ex_rs1)
+                            || (ex_mem_rd != id_ex_rs2) && (mem_mem_rd == id_ex_rs2))
+                    {
+                        self.cpu_statistics.data_hazard_count += 1;
+                        self.trace_hazard(HazardKind::MemMem, self.itl_m_m.pc, self.itl_d_e.pc);
+                    }
+                    self.itl_m_m.regval
+                };
+                self.itl_d_e.mem_mem_forward = mem_mem_forward;
+            }
+        }
+
         match self.data_hazard_policy {
             DataHazardPolicy::NaiveStall => {
                 let ex_mem_regwrite = self.itl_e_m.wb_flags.mem_to_reg;
@@ -509,6 +1611,7 @@ impl<'a> CPU<'a> {
                     self.d_e_pipeline_states_set(&mut [PipelineState::Bubble]); // ?
                     self.f_d_pipeline_states_set(&mut [PipelineState::Stall]);
                     self.pc_next_states_set(&mut [PipelineState::Stall]);
+                    self.trace_hazard(HazardKind::MemWb, self.itl_e_m.pc, self.itl_f_d.pc);
                 }
             }
             DataHazardPolicy::DataForward => {
@@ -517,11 +1620,13 @@ impl<'a> CPU<'a> {
                     let mem_wb_regwrite = self.itl_m_w.wb_flags.mem_to_reg;
                     let mem_wb_rd = self.itl_m_w.rd;
                     let ex_mem_rd = self.itl_e_m.rd;
+                    let mem_mem_rd = self.itl_m_m.rd;
                     let id_ex_rs1 = self.itl_d_e.rs1;
                     let id_ex_rs2 = self.itl_d_e.rs2;
                     if mem_wb_regwrite
                         && (mem_wb_rd != 0)
                         && (ex_mem_rd != id_ex_rs1)
+                        && (mem_mem_rd != id_ex_rs1)
                         && (mem_wb_rd == id_ex_rs1)
                     {
                         if self.data_hazard_info {
@@ -536,6 +1641,7 @@ impl<'a> CPU<'a> {
                     if mem_wb_regwrite
                         && (mem_wb_rd != 0)
                         && (ex_mem_rd != id_ex_rs2)
+                        && (mem_mem_rd != id_ex_rs2)
                         && (mem_wb_rd == id_ex_rs2)
                     {
                         if self.data_hazard_info {
@@ -550,10 +1656,15 @@ impl<'a> CPU<'a> {
 
                     if mem_wb_regwrite
                         && (mem_wb_rd != 0)
-                        && ((ex_mem_rd != id_ex_rs1) && (mem_wb_rd == id_ex_rs1)
-                            || (ex_mem_rd != id_ex_rs2) && (mem_wb_rd == id_ex_rs2))
+                        && ((ex_mem_rd != id_ex_rs1)
+                            && (mem_mem_rd != id_ex_rs1)
+                            && (mem_wb_rd == id_ex_rs1)
+                            || (ex_mem_rd != id_ex_rs2)
+                                && (mem_mem_rd != id_ex_rs2)
+                                && (mem_wb_rd == id_ex_rs2))
                     {
                         self.cpu_statistics.data_hazard_count += 1;
+                        self.trace_hazard(HazardKind::MemWb, self.itl_m_w.pc, self.itl_d_e.pc);
                     }
                     self.itl_m_w.regval
                 };
@@ -564,6 +1675,12 @@ impl<'a> CPU<'a> {
         // function units
         if self.pre_pipeline_info {
             info!("MEM/WB {:#x} {:#?}", self.itl_m_w.pc, self.itl_m_w.alu_op);
+            if self.extra_mem_stage {
+                info!(
+                    "MEM1/MEM2 {:#x} {:#?}",
+                    self.itl_m_m.pc, self.itl_m_m.alu_op
+                );
+            }
             info!("EX/MEM {:#x} {:#?}", self.itl_e_m.pc, self.itl_e_m.alu_op);
             info!(
                 "ID/EX  {:#x} {:#?}",
@@ -574,26 +1691,156 @@ impl<'a> CPU<'a> {
                 self.itl_f_d.pc, self.itl_f_d.exec_flags.alu_op
             );
         }
-        let running = writeback(&self.itl_m_w, &mut self.reg_file, self.pipeline_info);
-        let new_itl_m_w = mem(&self.itl_e_m, &mut self.vm, self.pipeline_info);
-        let (new_itl_e_m, new_pc_0, new_pc_1) = exec(
-            &self.itl_d_e,
+        // ebreak traps when it retires, after finishing this clock
+        let trap_pc = (self.itl_m_w.alu_op == Inst64::ebreak && self.ebreak_traps(self.itl_m_w.pc))
+            .then_some(self.itl_m_w.pc);
+        // a trapping ebreak does not retire, as on the single cycle CPU
+        if self.itl_m_w.alu_op != Inst64::noop && trap_pc.is_none() {
+            if let Some(itrace_file) = self.itrace_file.as_mut() {
+                itrace_file.inst(self.itl_m_w.pc, self.itl_m_w.raw_inst);
+            }
+        }
+        self.vm.set_access_pc(self.itl_m_w.pc);
+        let retire = writeback(
+            &self.itl_m_w,
+            &mut self.reg_file,
+            self.vm,
+            self.syscall.as_mut(),
             self.pipeline_info,
-            &mut self.callstack,
-            Some(&mut self.ras),
+            self.ebreak_as_trap || self.semihosting,
+            self.semihosting,
+            self.xlen,
+            self.trace_regs.as_mut().map(|(mode, file)| (*mode, file)),
+            self.verifier.as_mut(),
         )?;
+        let retire = wait_for_interrupt(retire, &self.csr, &mut self.clock);
+
+        // counted at WB, as an instruction may still trap in MEM
+        let retired = self.itl_m_w.alu_op != Inst64::noop;
+        if retired {
+            self.cpu_statistics.count_inst(self.itl_m_w.alu_op);
+        }
+        self.last_retire = retired.then_some(self.itl_m_w.pc);
+
+        // a fetch failure decodes into noop, so garbage or a stall that
+        // never clears would otherwise keep the clock ticking forever
+        if retired {
+            self.cycles_since_retire = 0;
+        } else {
+            self.cycles_since_retire += 1;
+            if self.cycles_since_retire >= self.no_progress_cycles {
+                return Err(Error::Execute(format!(
+                    "no forward progress in {} cycles at pc {:#x}, possible decode failure or deadlock",
+                    self.cycles_since_retire,
+                    self.pc.read()
+                )));
+            }
+        }
+
+        self.last_store = None;
+        // a trapped instruction goes on as a bubble, and the younger ones in
+        // the pipeline are flushed, so that the handler is fetched next
+        self.vm.set_access_pc(self.itl_e_m.pc);
+        let mem_out = match mem(
+            &self.itl_e_m,
+            self.vm,
+            &mut self.reservation,
+            self.pipeline_info,
+        ) {
+            Ok(mem_out) => {
+                if self.itl_e_m.mem_flags.mem_write {
+                    let vaddr = self.xlen.trunc(self.itl_e_m.mem_addr);
+                    self.last_store = Some((vaddr, self.itl_e_m.mem_bitwidth as u64 / 8));
+                }
+                self.access_dcache();
+                mem_out
+            }
+            Err(err) => {
+                self.take_trap(err, self.itl_e_m.pc)?;
+                InternalMemWb::default()
+            }
+        };
+        // MEM2 passes on what MEM1 gave in the clock before
+        let (new_itl_m_m, new_itl_m_w) = if self.extra_mem_stage {
+            (mem_out, self.itl_m_m)
+        } else {
+            (InternalMemWb::default(), mem_out)
+        };
+        // the instructions in WB and MEM are older than the one in EX, which
+        // counts them in instret
+        let pending = retired as u64
+            + (new_itl_m_m.alu_op != Inst64::noop) as u64
+            + (new_itl_m_w.alu_op != Inst64::noop) as u64;
+        self.csr.set_pending_retire(pending);
+        // a pending interrupt is taken before the instruction in EX, which
+        // is on the right path, as wrong paths are flushed when a branch
+        // leaves EX. It is then flushed like a trapped instruction.
+        // the instruction in EX to fetch again, when it faults behind an
+        // ebreak that does not halt, so that it faults after resuming
+        let mut refetch = None;
+        let (new_itl_e_m, new_pc_0, new_pc_1) = if self.take_interrupt() {
+            (InternalExecMem::default(), 0, 0)
+        } else {
+            match exec(
+                &self.itl_d_e,
+                self.pipeline_info,
+                &mut self.callstack,
+                self.clock,
+                Some(&mut self.ras),
+                &mut self.csr,
+                self.xlen,
+                self.strict_align,
+                self.div_trap,
+            ) {
+                Ok(result) => result,
+                Err(err) => match self.ebreak_ahead() {
+                    // what follows the last ebreak is often not code
+                    Some(pc) if self.ebreak_halts(pc) => (InternalExecMem::default(), 0, 0),
+                    Some(_) => {
+                        refetch = Some(self.itl_d_e.pc);
+                        (InternalExecMem::default(), 0, 0)
+                    }
+                    None => {
+                        log_illegal(&err);
+                        self.take_trap(err, self.itl_d_e.pc)?;
+                        (InternalExecMem::default(), 0, 0)
+                    }
+                },
+            }
+        };
         let new_itl_d_e = decode(&self.reg_file, &self.itl_f_d, self.pipeline_info);
 
+        // structural hazard: the memory port is taken by MEM, so nothing is
+        // fetched, and no prediction is made, in this clock. Separate
+        // instruction memory has a port of its own.
+        let mem_port_busy = self.single_mem_port
+            && !self.vm.harvard()
+            && (self.itl_e_m.mem_flags.mem_read || self.itl_e_m.mem_flags.mem_write);
+
         // fetch code
-        let new_itl_f_d = fetch(
-            &self.pc,
-            &mut self.vm,
-            self.pipeline_info,
-            self.control_policy,
-            self.bht.as_mut(),
-            self.btb.as_ref(),
-            Some(&mut self.ras),
-        );
+        let new_itl_f_d = if mem_port_busy {
+            InternalFetchDecode::default()
+        } else {
+            self.vm.set_access_pc(self.pc.read());
+            fetch(
+                &self.pc,
+                &mut self.vm,
+                &mut self.decode_cache,
+                self.pipeline_info,
+                self.control_policy,
+                self.bht.as_mut(),
+                self.btb.as_ref(),
+                Some(&mut self.ras),
+                self.xlen,
+            )
+        };
+        let fetched = (self.pipeline_diagram.is_some()
+            && new_itl_f_d.exec_flags.alu_op != Inst64::noop)
+            .then(|| {
+                let itl = &new_itl_f_d;
+                let op = itl.exec_flags.alu_op;
+                disasm(itl.pc, op, itl.rd, itl.rs1, itl.rs2, itl.rs3, itl.imm).replace('\t', " ")
+            });
 
         // handle executed branch instruction
         let ex_branch = new_itl_e_m.branch_flags.branch;
@@ -606,8 +1853,9 @@ impl<'a> CPU<'a> {
             //     "UPDATE BTB: PC={:#x}, new_pc_1={:#x}",
             //     new_itl_e_m.pc, new_pc_1
             // );
-            use crate::core::insts::Inst64::jalr;
-            let is_jalr = new_itl_e_m.alu_op == jalr;
+            use crate::core::insts::Inst64::{jalr, mret};
+            // targets of jalr and mret are taken from registers
+            let is_jalr = matches!(new_itl_e_m.alu_op, jalr | mret);
             // fill BTB with potential new entry
             // NOTE: branch target is calculated at EX phase.
             self.btb
@@ -615,10 +1863,13 @@ impl<'a> CPU<'a> {
                 .unwrap()
                 .add_entry(new_itl_e_m.pc, new_pc_1, is_jalr); // new_pc_1 is branch target
                                                                // update BHT
-            self.bht
-                .as_mut()
-                .unwrap()
-                .update_with_result(new_itl_e_m.pc, pc_src);
+            if is_conditional_branch(new_itl_e_m.alu_op) {
+                self.bht.as_mut().unwrap().update_with_result(
+                    new_itl_e_m.pc,
+                    new_itl_e_m.branch_flags.history,
+                    pc_src,
+                );
+            }
         }
 
         // debug!("Before checking misprediction:");
@@ -630,22 +1881,77 @@ impl<'a> CPU<'a> {
         // debug!("new_pc_1={:#x}",new_pc_1);
         // debug!("self.itl_e_m.branch_flags.predicted_target={:#x}",new_itl_e_m.branch_flags.predicted_target);
 
+        if ex_branch {
+            self.cpu_statistics.count_prediction(
+                &new_itl_e_m,
+                new_pc_1,
+                self.control_policy == ControlPolicy::DynamicPredict,
+            );
+        }
+
+        // all-stall: nothing is fetched while a branch or jump is in ID or
+        // EX, so the next fetch follows it once it is resolved at EX
+        if self.control_policy == ControlPolicy::AllStall {
+            let in_ex = is_control(self.itl_d_e.exec_flags.alu_op);
+            let in_id = is_control(self.itl_f_d.exec_flags.alu_op);
+            if in_ex || in_id {
+                let control_pc = if in_ex {
+                    // counted once, when it executes
+                    self.cpu_statistics.control_hazard_count += 1;
+                    self.itl_d_e.pc
+                } else {
+                    self.itl_f_d.pc
+                };
+                if self.control_hazard_info {
+                    warn!("Control hazard detected, fetch waits for EX");
+                }
+                self.cpu_statistics.control_hazard_delayed_cycles += 1;
+                self.f_d_pipeline_states_set(&mut [PipelineState::Bubble]);
+                self.pc_next_states_set(&mut [PipelineState::Stall]);
+                self.trace_hazard(HazardKind::ControlStall, control_pc, self.pc.read());
+            }
+        }
+
         // mispredict
         let mispredict = ex_branch
             && ((pc_src != predicted_src)
                 || (new_itl_e_m.is_ret() && new_pc_1 != new_itl_e_m.branch_flags.predicted_target));
+        // ecall, wfi and ebreak as trap: flush younger instructions and
+        // refetch after it, so that nothing younger runs before it is served
+        // at WB, younger instructions see the syscall's return value, and an
+        // interrupt waking wfi up is taken right after it.
+        let trap_flush = matches!(new_itl_e_m.alu_op, Inst64::ecall | Inst64::wfi)
+            || ((self.ebreak_as_trap || self.semihosting) && new_itl_e_m.alu_op == Inst64::ebreak);
+        // fence.i: younger instructions were fetched before the older stores
+        // were written, so they are flushed and fetched again after it.
+        let fence_flush = new_itl_e_m.alu_op == Inst64::fence_i;
+        if trap_flush || fence_flush {
+            self.d_e_pipeline_states[0] = PipelineState::Bubble;
+            self.f_d_pipeline_states[0] = PipelineState::Bubble;
+            self.pc_next_states[0] = PipelineState::Normal;
+        }
+
         if mispredict {
             // compulsory flush
             // so do not use self.x_y_pipeline_states_set
             // instead, set directly
-            if self.control_hazard_info {
-                warn!("Misprediction detected");
-            }
-            self.cpu_statistics.control_hazard_count += 1;
-            self.cpu_statistics.control_hazard_delayed_cycles += 2;
             self.d_e_pipeline_states[0] = PipelineState::Bubble;
             self.f_d_pipeline_states[0] = PipelineState::Bubble;
             self.pc_next_states[0] = PipelineState::Normal;
+            // with all-stall, nothing was fetched after a branch or jump,
+            // and the jump to its target is charged as its stall
+            if self.control_policy != ControlPolicy::AllStall || !is_control(new_itl_e_m.alu_op) {
+                if self.control_hazard_info {
+                    warn!("Misprediction detected");
+                }
+                self.cpu_statistics.control_hazard_count += 1;
+                self.cpu_statistics.control_hazard_delayed_cycles += 2;
+                self.note_states("ID/EX", &[PipelineState::Bubble]);
+                self.note_states("IF/ID", &[PipelineState::Bubble]);
+                self.note_states("PC", &[PipelineState::Normal]);
+                // the younger instruction flushed is the one in IF/ID
+                self.trace_hazard(HazardKind::Mispredict, new_itl_e_m.pc, self.itl_f_d.pc);
+            }
         }
 
         // handle load-use hazard
@@ -662,6 +1968,45 @@ impl<'a> CPU<'a> {
                     self.cpu_statistics.data_hazard_delayed_cycles += 1;
                 }
             }
+            // held by the EX/MEM stall of NaiveStall
+            self.trace_hazard(HazardKind::LoadUse, self.itl_d_e.pc, self.itl_f_d.pc);
+        }
+
+        // the instruction refetched after a syscall reaches ID while the
+        // syscall is still in MEM2, a cycle before it writes registers at WB
+        let syscall_in_mem2 = self.itl_m_m.alu_op == Inst64::ecall
+            || ((self.ebreak_as_trap || self.semihosting) && self.itl_m_m.alu_op == Inst64::ebreak);
+        if syscall_in_mem2 && self.itl_f_d.exec_flags.alu_op != Inst64::noop {
+            if self.data_hazard_info {
+                warn!("Syscall hazard detected, ID waits for WB");
+            }
+            self.cpu_statistics.data_hazard_count += 1;
+            self.cpu_statistics.data_hazard_delayed_cycles += 1;
+            self.d_e_pipeline_states_set(&mut [PipelineState::Bubble]);
+            self.f_d_pipeline_states_set(&mut [PipelineState::Stall]);
+            self.pc_next_states_set(&mut [PipelineState::Stall]);
+            self.trace_hazard(HazardKind::Syscall, self.itl_m_m.pc, self.itl_f_d.pc);
+        }
+
+        // handle structural hazard, which costs nothing when the fetch would
+        // be discarded anyway by a flush or a stall of IF/ID
+        if mem_port_busy
+            && !(mispredict || trap_flush || fence_flush)
+            && self.f_d_pipeline_states[0] == PipelineState::Normal
+        {
+            if self.data_hazard_info {
+                warn!("Structural hazard detected, fetch waits for MEM");
+            }
+            self.cpu_statistics.structural_hazard_count += 1;
+            self.f_d_pipeline_states_set(&mut [PipelineState::Bubble]);
+            self.pc_next_states_set(&mut [PipelineState::Stall]);
+            self.trace_hazard(HazardKind::Structural, self.itl_e_m.pc, self.pc.read());
+        }
+
+        if refetch.is_some() {
+            self.d_e_pipeline_states[0] = PipelineState::Bubble;
+            self.f_d_pipeline_states[0] = PipelineState::Bubble;
+            self.pc_next_states[0] = PipelineState::Normal;
         }
 
         let m_w_pipeline_state = self.m_w_pipeline_states[0];
@@ -670,6 +2015,34 @@ impl<'a> CPU<'a> {
         let f_d_pipeline_state = self.f_d_pipeline_states[0];
         let pc_next_state = self.pc_next_states[0];
 
+        // Roll back the speculative global history of discarded branches,
+        // and correct it with the outcome of the mispredicted one.
+        if let Some(bht) = self.bht.as_mut() {
+            if d_e_pipeline_state == PipelineState::Bubble
+                && f_d_pipeline_state == PipelineState::Bubble
+                && is_conditional_branch(self.itl_f_d.exec_flags.alu_op)
+            {
+                bht.restore_history(self.itl_f_d.branch_flags.history);
+            } else if f_d_pipeline_state != PipelineState::Normal
+                && is_conditional_branch(new_itl_f_d.exec_flags.alu_op)
+            {
+                bht.restore_history(new_itl_f_d.branch_flags.history);
+            }
+            if mispredict {
+                bht.restore_history(new_itl_e_m.branch_flags.history);
+                if is_conditional_branch(new_itl_e_m.alu_op) {
+                    bht.speculate(pc_src);
+                }
+            }
+        }
+
+        // MEM1/MEM2 moves on with MEM/WB
+        let new_itl_m_m = match m_w_pipeline_state {
+            PipelineState::Normal => new_itl_m_m,
+            PipelineState::Bubble => InternalMemWb::default(),
+            PipelineState::Stall => self.itl_m_m,
+        };
+
         let new_itl_m_w = match m_w_pipeline_state {
             PipelineState::Normal => new_itl_m_w,
             PipelineState::Bubble => InternalMemWb::default(),
@@ -693,36 +2066,77 @@ impl<'a> CPU<'a> {
             PipelineState::Bubble => InternalFetchDecode::default(),
             PipelineState::Stall => self.itl_f_d,
         };
+        // only a fetch moving on into IF/ID is looked up, not one stalled
+        // and fetched again or flushed
+        if f_d_pipeline_state == PipelineState::Normal
+            && new_itl_f_d.exec_flags.alu_op != Inst64::noop
+        {
+            self.access_icache(new_itl_f_d.pc, new_itl_f_d.inst_len);
+        }
 
         // mul/div/rem
         {
             use Inst64::*;
+            // the instruction executed in the clock before
+            let ahead = if self.extra_mem_stage {
+                &new_itl_m_m
+            } else {
+                &new_itl_m_w
+            };
             match new_itl_e_m.alu_op {
                 div | divw | divu | divuw => {
-                    self.clock += 39;
+                    self.clock += self.div_latency;
                 }
-                r @ (rem | remw | remu | remuw) => match (r, new_itl_m_w.alu_op) {
+                r @ (rem | remw | remu | remuw) => match (r, ahead.alu_op) {
                     (rem, div) | (remw, divw) | (remu, divu) | (remuw, divuw)
-                        if new_itl_e_m.rs1 == new_itl_m_w.rs1
-                            && new_itl_e_m.rs2 == new_itl_m_w.rs2 => {}
+                        if new_itl_e_m.rs1 == ahead.rs1 && new_itl_e_m.rs2 == ahead.rs2 => {}
                     _ => {
-                        self.clock += 39;
+                        self.clock += self.div_latency;
                     }
                 },
                 mul | mulh | mulhsu | mulhu | mulw => {
-                    self.clock += 1;
+                    self.clock += self.mul_latency;
                 }
                 _ => {}
             }
         }
 
-        // whether executed a non-noop instruction
-        if new_itl_e_m.alu_op != Inst64::noop {
-            self.cpu_statistics.executed_inst_count += 1;
+        if new_itl_e_m.is_call() {
+            self.call_depth += 1;
+        } else if new_itl_e_m.is_ret() {
+            self.call_depth = self.call_depth.saturating_sub(1);
+        }
+
+        if let Some(diagram) = self.pipeline_diagram.as_mut() {
+            let mut states = vec![
+                pc_next_state,
+                f_d_pipeline_state,
+                d_e_pipeline_state,
+                e_m_pipeline_state,
+                m_w_pipeline_state,
+            ];
+            let mut occupied = vec![
+                new_itl_f_d.exec_flags.alu_op != Inst64::noop,
+                new_itl_d_e.exec_flags.alu_op != Inst64::noop,
+                new_itl_e_m.alu_op != Inst64::noop,
+                new_itl_m_w.alu_op != Inst64::noop,
+            ];
+            if self.extra_mem_stage {
+                states.insert(4, m_w_pipeline_state);
+                occupied.insert(3, new_itl_m_m.alu_op != Inst64::noop);
+            }
+            diagram.clock(
+                clock_start,
+                self.clock - clock_start,
+                fetched,
+                &states,
+                &occupied,
+            );
         }
 
         // push pipeline forward
         self.itl_m_w = new_itl_m_w;
+        self.itl_m_m = new_itl_m_m;
         self.itl_e_m = new_itl_e_m;
         self.itl_d_e = new_itl_d_e;
         self.itl_f_d = new_itl_f_d;
@@ -731,7 +2145,9 @@ impl<'a> CPU<'a> {
             PipelineState::Stall => self.pc.read(),
             PipelineState::Bubble => unreachable!(),
             PipelineState::Normal => {
-                if mispredict {
+                if let Some(pc) = refetch {
+                    pc
+                } else if mispredict || trap_flush || fence_flush {
                     // rollback pc
                     // if new_itl_f_d is a branch inst, don't mind it.
                     // because that's a misfetched instruction.
@@ -747,7 +2163,7 @@ impl<'a> CPU<'a> {
                         // Fetch phase decides that predicted
                         new_itl_f_d.branch_flags.predicted_target
                     } else {
-                        self.pc.read().wrapping_add(4)
+                        self.pc.read().wrapping_add(new_itl_f_d.inst_len)
                     }
                 }
             }
@@ -762,6 +2178,12 @@ impl<'a> CPU<'a> {
 
         if self.post_pipeline_info {
             info!("MEM/WB {:#x} {:#?}", self.itl_m_w.pc, self.itl_m_w.alu_op);
+            if self.extra_mem_stage {
+                info!(
+                    "MEM1/MEM2 {:#x} {:#?}",
+                    self.itl_m_m.pc, self.itl_m_m.alu_op
+                );
+            }
             info!("EX/MEM {:#x} {:#?}", self.itl_e_m.pc, self.itl_e_m.alu_op);
             info!(
                 "ID/EX  {:#x} {:#?}",
@@ -773,11 +2195,12 @@ impl<'a> CPU<'a> {
             );
         }
 
-        // reset x0 to 0
-        self.reg_file.write(0, 0);
-
         // decide whether continue to run
-        self.running = running;
+        self.running = retire == Retire::Running;
+        if let Retire::Exit(code) = retire {
+            self.exit_code = Some(code);
+        }
+        self.wfi = retire == Retire::Wait;
 
         self.m_w_pipeline_states.rotate_left(1);
         self.m_w_pipeline_states[PIPELINE_STATES_DEPTH - 1] = PipelineState::Normal;
@@ -789,11 +2212,18 @@ impl<'a> CPU<'a> {
         self.f_d_pipeline_states[PIPELINE_STATES_DEPTH - 1] = PipelineState::Normal;
         self.pc_next_states.rotate_left(1);
         self.pc_next_states[PIPELINE_STATES_DEPTH - 1] = PipelineState::Normal;
+
+        self.csr.tick(self.clock - clock_start, retired as u64);
+
+        if let Some(pc) = trap_pc {
+            return Err(Exception::Breakpoint(pc).into());
+        }
         Ok(())
     }
 
     #[allow(unused)]
     fn m_w_pipeline_states_set(&mut self, states: &mut [PipelineState]) {
+        self.note_states("MEM/WB", states);
         (0..self.m_w_pipeline_states.len().min(states.len())).for_each(|i| {
             let x = &mut self.m_w_pipeline_states[i];
             *x = *x.max(&mut states[i]);
@@ -802,6 +2232,7 @@ impl<'a> CPU<'a> {
 
     #[allow(unused)]
     fn e_m_pipeline_states_set(&mut self, states: &mut [PipelineState]) {
+        self.note_states("EX/MEM", states);
         (0..self.e_m_pipeline_states.len().min(states.len())).for_each(|i| {
             let x = &mut self.e_m_pipeline_states[i];
             *x = *x.max(&mut states[i]);
@@ -809,6 +2240,7 @@ impl<'a> CPU<'a> {
     }
 
     fn d_e_pipeline_states_set(&mut self, states: &mut [PipelineState]) {
+        self.note_states("ID/EX", states);
         (0..self.d_e_pipeline_states.len().min(states.len())).for_each(|i| {
             let x = &mut self.d_e_pipeline_states[i];
             *x = *x.max(&mut states[i]);
@@ -816,6 +2248,7 @@ impl<'a> CPU<'a> {
     }
 
     fn f_d_pipeline_states_set(&mut self, states: &mut [PipelineState]) {
+        self.note_states("IF/ID", states);
         (0..self.f_d_pipeline_states.len().min(states.len())).for_each(|i| {
             let x = &mut self.f_d_pipeline_states[i];
             *x = *x.max(&mut states[i]);
@@ -823,11 +2256,40 @@ impl<'a> CPU<'a> {
     }
 
     fn pc_next_states_set(&mut self, states: &mut [PipelineState]) {
+        self.note_states("PC", states);
         (0..self.pc_next_states.len().min(states.len())).for_each(|i| {
             let x = &mut self.pc_next_states[i];
             *x = *x.max(&mut states[i]);
         });
     }
+
+    /// Note `states` set on `stage`, for the next hazard recorded.
+    fn note_states(&mut self, stage: &str, states: &[PipelineState]) {
+        if self.hazard_trace.is_some() {
+            let states: Vec<_> = states
+                .iter()
+                .map(|state| format!("{state:?}").to_lowercase())
+                .collect();
+            self.hazard_stages
+                .push(format!("{stage}={}", states.join(",")));
+        }
+    }
+
+    /// Record a hazard of `kind` between the instructions at `producer` and
+    /// `consumer` into the hazard trace, with the states noted since the
+    /// last one.
+    fn trace_hazard(&mut self, kind: HazardKind, producer: u64, consumer: u64) {
+        let stages = std::mem::take(&mut self.hazard_stages);
+        if let Some(hazard_trace) = self.hazard_trace.as_mut() {
+            hazard_trace.hazard(&HazardEvent {
+                kind,
+                clock: self.clock,
+                producer,
+                consumer,
+                stages,
+            });
+        }
+    }
 }
 
 impl<'a> CPU<'a> {
@@ -835,53 +2297,102 @@ impl<'a> CPU<'a> {
         self.pc.read()
     }
 
-    pub(super) fn mread<T: Sized + std::fmt::Display>(&self, vaddr: u64) -> T {
+    pub(super) fn itl_f_d(&self) -> &InternalFetchDecode {
+        &self.itl_f_d
+    }
+
+    pub(super) fn itl_d_e(&self) -> &InternalDecodeExec {
+        &self.itl_d_e
+    }
+
+    pub(super) fn itl_e_m(&self) -> &InternalExecMem {
+        &self.itl_e_m
+    }
+
+    pub(super) fn itl_m_w(&self) -> &InternalMemWb {
+        &self.itl_m_w
+    }
+
+    /// MEM1/MEM2, if with the extra MEM stage.
+    pub(super) fn itl_m_m(&self) -> Option<&InternalMemWb> {
+        self.extra_mem_stage.then_some(&self.itl_m_m)
+    }
+
+    /// States of IF/ID, ID/EX, EX/MEM and MEM/WB in the next clock, as far
+    /// as they are already decided.
+    pub(super) fn pipeline_states(&self) -> [PipelineState; 4] {
+        [
+            self.f_d_pipeline_states[0],
+            self.d_e_pipeline_states[0],
+            self.e_m_pipeline_states[0],
+            self.m_w_pipeline_states[0],
+        ]
+    }
+
+    /// Whether the CPU is stopped by a retired wfi. Running again resumes
+    /// after the wfi.
+    pub(super) fn waiting_for_interrupt(&self) -> bool {
+        self.wfi
+    }
+
+    /// Disassemble the instruction at `pc`, giving its length too.
+    pub(super) fn disasm_at(&self, pc: u64) -> Result<(String, u64)> {
+        let itl = decode_at(pc, self.vm, self.xlen)?;
+        let line = disasm(
+            pc,
+            itl.exec_flags.alu_op,
+            itl.rd,
+            itl.rs1,
+            itl.rs2,
+            itl.rs3,
+            itl.imm,
+        );
+        Ok((line, itl.inst_len))
+    }
+
+    pub(super) fn mread<T: MemData>(&self, vaddr: u64) -> Result<T> {
         self.vm.mread(vaddr as usize)
     }
 
+    pub(super) fn mwrite<T: MemData>(&mut self, vaddr: u64, value: T) -> Result<()> {
+        self.vm.mwrite(vaddr as usize, value)
+    }
+
+    pub(super) fn fill<T: MemData>(&mut self, vaddr: u64, count: u64, value: T) -> Result<()> {
+        self.vm.fill(vaddr as usize, count as usize, value)
+    }
+
+    pub(super) fn mem_contains(&self, vaddr: u64, len: u64) -> bool {
+        self.vm.contains(vaddr as usize, len as usize)
+    }
+
     pub(super) fn backtrace(&self) {
-        self.callstack.backtrace();
+        self.callstack.backtrace(self.pc.read());
     }
 
     pub(super) fn reg_val_by_name(&self, name: &str) -> Result<u64> {
-        let idx = match name {
-            "zero" | "x0" => 0,
-            "ra" | "x1" => 1,
-            "sp" | "x2" => 2,
-            "gp" | "x3" => 3,
-            "tp" | "x4" => 4,
-            "t0" | "x5" => 5,
-            "t1" | "x6" => 6,
-            "t2" | "x7" => 7,
-            "s0" | "x8" => 8,
-            "s1" | "x9" => 9,
-            "a0" | "x10" => 10,
-            "a1" | "x11" => 11,
-            "a2" | "x12" => 12,
-            "a3" | "x13" => 13,
-            "a4" | "x14" => 14,
-            "a5" | "x15" => 15,
-            "a6" | "x16" => 16,
-            "a7" | "x17" => 17,
-            "s2" | "x18" => 18,
-            "s3" | "x19" => 19,
-            "s4" | "x20" => 20,
-            "s5" | "x21" => 21,
-            "s6" | "x22" => 22,
-            "s7" | "x23" => 23,
-            "s8" | "x24" => 24,
-            "s9" | "x25" => 25,
-            "s10" | "x26" => 26,
-            "s11" | "x27" => 27,
-            "t3" | "x28" => 28,
-            "t4" | "x29" => 29,
-            "t5" | "x30" => 30,
-            "t6" | "x31" => 31,
-            "pc" => return Ok(self.pc()),
-            _ => return Err(Error::InvalidRegName(name.into())),
-        };
+        if name == "pc" {
+            return Ok(self.pc());
+        }
+        let idx = reg_index(name).ok_or_else(|| Error::InvalidRegName(name.into()))?;
         Ok(self.reg_file.read(idx))
     }
+
+    /// Write `value` into register `name` and return the old value.
+    /// x0 is hard-wired to zero and refused. Instructions already decoded
+    /// keep the operands they have read.
+    pub(super) fn set_reg_by_name(&mut self, name: &str, value: u64) -> Result<u64> {
+        let old = self.reg_val_by_name(name)?;
+        if name == "pc" {
+            return Err(Error::DbgParse("pc cannot be set on the pipeline CPU".into()));
+        }
+        let idx = reg_index(name).ok_or_else(|| Error::InvalidRegName(name.into()))?;
+        if idx == 0 {
+            return Err(Error::DbgParse("x0 is hard-wired to zero".into()));
+        }
+        self.reg_file.write(idx, value);
+        Ok(old)
+    }
 }
 
 #[allow(unused)]
@@ -897,13 +2408,36 @@ fn input() {
     stdin.read_line(&mut buf).unwrap();
 }
 
+/// Sleep in the wfi which has just retired, if `retire` says so, until an
+/// interrupt is pending, letting `clock` run on. Halts if no interrupt could
+/// ever wake the hart up.
+fn wait_for_interrupt(retire: Retire, csr: &Csr, clock: &mut u64) -> Retire {
+    if retire != Retire::Wait {
+        return retire;
+    }
+    match csr.wfi_cycles() {
+        Some(cycles) => {
+            *clock += cycles;
+            Retire::Running
+        }
+        None => {
+            info!("WFI: no pending interrupts, halting");
+            Retire::Wait
+        }
+    }
+}
+
 pub fn halt(pc: u64, code: u64) {
+    // printed rather than logged, to report the outcome at any log level
+    if logger::quiet() {
+        return;
+    }
     if code != 0 {
-        error!("HIT BAD TRAP!\n");
+        println!("HIT BAD TRAP!");
     } else {
-        info!("HIT GOOD TRAP!\n");
+        println!("HIT GOOD TRAP!");
     }
-    info!("Program ended at pc {:#x}, with exit code {}", pc, code);
+    println!("Program ended at pc {:#x}, with exit code {}", pc, code);
 }
 
 pub struct MultistageCPU<'a> {
@@ -943,6 +2477,52 @@ pub struct MultistageCPU<'a> {
     cpu_statistics: CPUStatistics,
 
     last_inst_info: LastInstInfo,
+
+    // Control and status registers
+    csr: Csr,
+
+    // Syscall handler for ecall
+    syscall: Box<dyn Syscall>,
+
+    // LR/SC reservation set
+    reservation: Reservation,
+
+    // Itrace records of retired instructions
+    itrace_file: Option<TraceFile>,
+
+    // Registers logged after every retired instruction
+    trace_regs: Option<(TraceRegs, TraceFile)>,
+
+    // Reference commit log checked against every retired instruction
+    verifier: Option<Verifier>,
+
+    // Width of the integer registers
+    xlen: Xlen,
+
+    // Decoded instructions, not decoded again while unchanged
+    decode_cache: DecodeCache<InternalFetchDecode>,
+
+    // Raise address-misaligned exceptions on misaligned loads and stores
+    strict_align: bool,
+
+    // Stop with DividedByZero on division by zero instead of the spec results
+    div_trap: bool,
+
+    // Stop with an error once the clock reaches it
+    max_cycles: Option<u64>,
+
+    // Stop on exceptions, or jump to the handler at mtvec
+    trap_mode: TrapMode,
+
+    // Serve ebreak in the semihosting sequence as a semihosting call
+    semihosting: bool,
+
+    // Exit code of the program once it has ended
+    exit_code: Option<u64>,
+
+    // Extra cycles of mul and div/rem in EX
+    mul_latency: u64,
+    div_latency: u64,
 }
 
 struct LastInstInfo {
@@ -970,10 +2550,20 @@ impl<'a> MultistageCPU<'a> {
         vm: &'a mut VirtualMemory,
         callstack: &'a mut CallStack<'a>,
         itrace: bool,
+        warn_on_unknown_csr: bool,
+        syscall_abi: SyscallAbi,
+        xlen: Xlen,
     ) -> MultistageCPU<'a> {
+        // mstatus.UBE and MBE show the byte order of data accesses
+        let mut csr = Csr::new(warn_on_unknown_csr, xlen);
+        csr.set_big_endian(vm.data_endian() == Endian::Big);
+
         // x0 already set to 0
-        let reg_file = RegisterFile::empty();
-        let pc = ProgramCounter::new();
+        let mut reg_file = RegisterFile::empty();
+        reg_file.set_xlen(xlen);
+        let mut pc = ProgramCounter::new();
+        pc.set_xlen(xlen);
+        vm.set_xlen(xlen);
 
         MultistageCPU {
             running: false,
@@ -990,18 +2580,50 @@ impl<'a> MultistageCPU<'a> {
             itl_m_w: InternalMemWb::default(),
             cpu_statistics: CPUStatistics::default(),
             last_inst_info: LastInstInfo::new(),
+            csr,
+            syscall: new_syscall(syscall_abi),
+            reservation: Reservation::default(),
+            itrace_file: None,
+            trace_regs: None,
+            verifier: None,
+            xlen,
+            decode_cache: DecodeCache::new(),
+            strict_align: false,
+            div_trap: false,
+            max_cycles: None,
+            trap_mode: TrapMode::Fatal,
+            semihosting: false,
+            exit_code: None,
+            mul_latency: DEFAULT_MUL_LATENCY,
+            div_latency: DEFAULT_DIV_LATENCY,
         }
     }
 
-    /// Initialize CPU with ELF info
-    pub fn init_elfinfo_64(&mut self, info: &LoadElfInfo) {
-        // make sure we are running a ELF64 executable
-        assert!(info.is_64_bit());
+    /// Give every register but x0 an initial value from `fill`. Call it
+    /// before [`Self::init_elfinfo_64`], which sets sp.
+    pub fn fill_uninit_regs(&mut self, fill: &mut UninitFill) {
+        self.reg_file.fill_uninit(fill);
+    }
+
+    /// Initialize CPU with ELF info, and pass `args` to the program as its
+    /// argv on the stack.
+    pub fn init_elfinfo_64(&mut self, info: &LoadElfInfo, args: &[String]) -> Result<()> {
+        // make sure the ELF class matches XLEN
+        assert_eq!(info.is_64_bit(), self.xlen == Xlen::Rv64);
+
+        let sp = self.vm.push_args(args)?;
+        self.reg_file.init_elfinfo_64(info, sp);
 
-        self.reg_file.init_elfinfo_64(info);
+        self.set_entry(info.entry_point());
 
-        let pc = info.entry_point();
+        // Heap begins right after the program
+        self.syscall.set_brk(info.max_vaddr() as u64);
+        Ok(())
+    }
 
+    /// Start running at `pc` instead of the entry point of the program, e.g.
+    /// at a function to test on its own.
+    pub fn set_entry(&mut self, pc: u64) {
         // Load program counter
         self.pc.write(pc);
 
@@ -1012,6 +2634,77 @@ impl<'a> MultistageCPU<'a> {
         self.itl_m_w.pc = pc;
     }
 
+    /// Write itrace records of every retired instruction into file at `path`.
+    pub fn set_itrace_file(&mut self, path: &Path) -> Result<()> {
+        self.itrace_file = Some(TraceFile::create(path)?);
+        Ok(())
+    }
+
+    /// Check every retired instruction against the reference commit log at
+    /// `path`, stopping with [`Error::Mismatch`] at the first difference.
+    pub fn set_verify_file(&mut self, path: &Path) -> Result<()> {
+        self.verifier = Some(Verifier::open(path, self.xlen)?);
+        Ok(())
+    }
+
+    /// Log the registers after every retired instruction, see [`TraceRegs`],
+    /// into file at `path` or else on stdout.
+    pub fn set_trace_regs(&mut self, trace_regs: TraceRegs, path: Option<&Path>) -> Result<()> {
+        let file = match path {
+            Some(path) => TraceFile::create(path)?,
+            None => TraceFile::stdout(),
+        };
+        self.trace_regs = Some((trace_regs, file));
+        Ok(())
+    }
+
+    /// Raise address-misaligned exceptions on misaligned loads and stores.
+    pub fn set_strict_align(&mut self, strict_align: bool) {
+        self.strict_align = strict_align;
+    }
+
+    /// Stop with [`Exception::DividedByZero`] on division by zero instead of
+    /// giving the results the spec defines.
+    pub fn set_div_trap(&mut self, div_trap: bool) {
+        self.div_trap = div_trap;
+    }
+
+    /// Stop with [`Error::CycleLimitExceeded`] once the clock reaches
+    /// `max_cycles`, however the CPU is run.
+    pub fn set_max_cycles(&mut self, max_cycles: Option<u64>) {
+        self.max_cycles = max_cycles;
+    }
+
+    /// Stop on exceptions, or jump to the handler at mtvec.
+    pub fn set_trap_mode(&mut self, trap_mode: TrapMode) {
+        self.trap_mode = trap_mode;
+    }
+
+    /// Raise a timer interrupt every `interval` cycles, taken once enabled
+    /// by mstatus.MIE and mie.MTIE.
+    pub fn set_timer_interval(&mut self, interval: u64) {
+        self.csr.set_timer_interval(interval);
+    }
+
+    /// Serve ebreak between `slli x0, x0, 0x1f` and `srai x0, x0, 7` as a
+    /// semihosting call. Any other ebreak still halts, as there is no
+    /// debugger to trap into.
+    pub fn set_semihosting(&mut self, semihosting: bool) {
+        self.semihosting = semihosting;
+    }
+
+    /// Back `read` of stdin by `input`, which is empty unless set.
+    pub fn set_stdin(&mut self, input: Vec<u8>) {
+        self.syscall.set_stdin(input);
+    }
+
+    /// Extra cycles a multiplication and a division or remainder spend in
+    /// EX. A rem right after the div of the same operands costs nothing.
+    pub fn set_mul_div_latency(&mut self, mul_latency: u64, div_latency: u64) {
+        self.mul_latency = mul_latency;
+        self.div_latency = div_latency;
+    }
+
     /// Run the cpu.
     /// steps: how many steps should be run, [`None`] means run until end or
     /// exception raised.
@@ -1030,48 +2723,115 @@ impl<'a> MultistageCPU<'a> {
         Ok(())
     }
 
-    pub fn print_info(&self) {
-        info!("CPU run clock: {}", self.clock);
-        info!(
+    /// Jump to the trap handler on an exception of the instruction at `epc`
+    /// in vectored trap mode. Other errors are returned as they are.
+    fn take_trap(&mut self, err: Error, epc: u64) -> Result<()> {
+        if self.trap_mode == TrapMode::Vectored {
+            if let Some(exception) = err.as_exception() {
+                if let Some(handler) = self.csr.trap(&exception, epc) {
+                    if self.itrace {
+                        info!("Trap: {} at {:#x}, to {:#x}", exception, epc, handler);
+                    }
+                    self.pc.write(handler);
+                    return Ok(());
+                }
+            }
+        }
+        Err(err)
+    }
+
+    /// Summary of the run, printed at its end.
+    pub fn summary_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        lines.push(format!("CPU run clock: {}", self.clock));
+        lines.push(format!(
+            "CPU mul/div latency: {} / {} extra cycles",
+            self.mul_latency, self.div_latency
+        ));
+        lines.push(format!(
             "CPU data hazard count: {}",
             self.cpu_statistics.data_hazard_count
-        );
-        info!(
+        ));
+        lines.push(format!(
             "CPU data hazard delayed cycles: {}",
             self.cpu_statistics.data_hazard_delayed_cycles
-        );
-        info!(
+        ));
+        lines.push(format!(
             "CPU control hazard count: {}",
             self.cpu_statistics.control_hazard_count
-        );
-        info!(
+        ));
+        lines.push(format!(
             "CPU control hazard delayed cycles: {}",
             self.cpu_statistics.control_hazard_delayed_cycles
-        );
-        info!(
+        ));
+        lines.push(format!(
             "CPU executed valid instructions: {}",
             self.cpu_statistics.executed_inst_count
-        );
-        info!("CPI = {}", {
-            let cycles = self.clock;
-            let insts = self.cpu_statistics.executed_inst_count;
-            (cycles as f64) / (insts as f64)
-        });
+        ));
+        lines.push(format!(
+            "CPI = {}",
+            ratio(self.clock, self.cpu_statistics.executed_inst_count)
+        ));
+        lines
+    }
+
+    /// Print how many times each instruction is executed.
+    pub fn print_inst_histogram(&self) {
+        self.cpu_statistics.print_inst_histogram();
+    }
+
+    /// Print calls and cycles of each function, if profiling.
+    pub fn print_profile(&self) {
+        self.callstack.print_profile();
+    }
+
+    /// Write the run statistics into `path` for scripts.
+    pub fn write_stats(&self, path: &Path, format: StatsFormat) -> Result<()> {
+        self.cpu_statistics.write(path, format, self.clock)
+    }
+
+    /// Exit code of the program, once it has ended by exit or ebreak.
+    pub fn exit_code(&self) -> Option<u64> {
+        self.exit_code
+    }
+
+    /// Cycles run so far.
+    pub fn cycles(&self) -> u64 {
+        self.clock
+    }
+
+    /// Instructions executed so far.
+    pub fn retired(&self) -> u64 {
+        self.cpu_statistics.executed_inst_count
     }
 
     pub(super) fn exec_once(&mut self) -> Result<()> {
         use crate::core::insts::Inst64::*;
 
+        // a pending interrupt is taken before the instruction at pc
+        let pc = self.pc.read();
+        check_cycle_limit(self.max_cycles, self.clock, pc)?;
+        if let Some(handler) = self.csr.interrupt(pc) {
+            if self.itrace {
+                info!("Interrupt: timer at {:#x}, to {:#x}", pc, handler);
+            }
+            self.pc.write(handler);
+        }
+        self.vm.set_access_pc(self.pc.read());
+
         // fetch code
+        let clock_start = self.clock;
         self.clock += 1;
         let new_itl_f_d = fetch(
             &self.pc,
             &mut self.vm,
+            &mut self.decode_cache,
             self.itrace,
             ControlPolicy::AlwaysNotTaken,
             None,
             None,
             None,
+            self.xlen,
         );
         self.itl_f_d = new_itl_f_d;
 
@@ -1080,8 +2840,26 @@ impl<'a> MultistageCPU<'a> {
         self.itl_d_e = new_itl_d_e;
 
         self.clock += 1;
-        let (new_itl_e_m, new_pc_0, new_pc_1) =
-            exec(&self.itl_d_e, self.itrace, &mut self.callstack, None)?;
+        // the trapped instruction does not retire
+        let (new_itl_e_m, new_pc_0, new_pc_1) = match exec(
+            &self.itl_d_e,
+            self.itrace,
+            &mut self.callstack,
+            self.clock,
+            None,
+            &mut self.csr,
+            self.xlen,
+            self.strict_align,
+            self.div_trap,
+        ) {
+            Ok(result) => result,
+            Err(err) => {
+                log_illegal(&err);
+                self.take_trap(err, self.itl_d_e.pc)?;
+                self.csr.tick(self.clock - clock_start, 0);
+                return Ok(());
+            }
+        };
         self.itl_e_m = new_itl_e_m;
 
         match new_itl_e_m.alu_op {
@@ -1089,7 +2867,7 @@ impl<'a> MultistageCPU<'a> {
                 self.last_inst_info.alu_op = d;
                 self.last_inst_info.rs1 = new_itl_e_m.rs1;
                 self.last_inst_info.rs2 = new_itl_e_m.rs2;
-                self.clock += 39;
+                self.clock += self.div_latency;
             }
             r @ (rem | remu | remuw | remw) => {
                 match (self.last_inst_info.alu_op, r) {
@@ -1097,36 +2875,56 @@ impl<'a> MultistageCPU<'a> {
                         if self.last_inst_info.rs1 == new_itl_e_m.rs1
                             && self.last_inst_info.rs2 == new_itl_e_m.rs2 => {}
                     _ => {
-                        self.clock += 39;
+                        self.clock += self.div_latency;
                     }
                 }
                 self.last_inst_info.clear();
             }
             mul | mulh | mulhsu | mulhu | mulw => {
-                self.clock += 1;
+                self.clock += self.mul_latency;
                 self.last_inst_info.clear();
             }
             _ => {
                 self.last_inst_info.clear();
             }
         }
-        // whether executed a non-noop instruction
-        if new_itl_e_m.alu_op != Inst64::noop {
-            self.cpu_statistics.executed_inst_count += 1;
-        }
 
         if self.itl_e_m.mem_flags.mem_read || self.itl_e_m.mem_flags.mem_write {
             // begin the clock
             self.clock += 1;
         }
-        let new_itl_m_w = mem(&self.itl_e_m, &mut self.vm, self.itrace);
+        let new_itl_m_w = match mem(&self.itl_e_m, self.vm, &mut self.reservation, self.itrace) {
+            Ok(new_itl_m_w) => new_itl_m_w,
+            Err(err) => {
+                self.take_trap(err, self.itl_e_m.pc)?;
+                self.csr.tick(self.clock - clock_start, 0);
+                return Ok(());
+            }
+        };
         self.itl_m_w = new_itl_m_w;
 
         if self.itl_m_w.wb_flags.mem_to_reg {
             // begin the clock
             self.clock += 1;
         }
-        let running = writeback(&self.itl_m_w, &mut self.reg_file, self.itrace);
+        if self.itl_m_w.alu_op != Inst64::noop {
+            if let Some(itrace_file) = self.itrace_file.as_mut() {
+                itrace_file.inst(self.itl_m_w.pc, self.itl_m_w.raw_inst);
+            }
+        }
+        let retire = writeback(
+            &self.itl_m_w,
+            &mut self.reg_file,
+            self.vm,
+            self.syscall.as_mut(),
+            self.itrace,
+            false,
+            self.semihosting,
+            self.xlen,
+            self.trace_regs.as_mut().map(|(mode, file)| (*mode, file)),
+            self.verifier.as_mut(),
+        )?;
+        let retire = wait_for_interrupt(retire, &self.csr, &mut self.clock);
 
         let next_pc = if new_itl_e_m.branch_flags.pc_src {
             new_pc_1
@@ -1136,12 +2934,1883 @@ impl<'a> MultistageCPU<'a> {
 
         self.pc.write(next_pc);
 
-        // reset x0 to 0
-        self.reg_file.write(0, 0);
-
         // decide whether continue to run
-        self.running = running;
+        self.running = retire == Retire::Running;
+        if let Retire::Exit(code) = retire {
+            self.exit_code = Some(code);
+        }
+
+        // counted at WB, as an instruction may still trap in MEM
+        let retired = self.itl_m_w.alu_op != Inst64::noop;
+        if retired {
+            self.cpu_statistics.count_inst(self.itl_m_w.alu_op);
+        }
+        self.csr.tick(self.clock - clock_start, retired as u64);
 
         Ok(())
     }
 }
+
+/// Log an illegal instruction raised at EX, which carries it from IF
+/// without logging, since it may be on a wrong path.
+fn log_illegal(err: &Error) {
+    if let Error::Exception(exception @ Exception::IllegalInstruction(..)) = err {
+        error!("{exception}");
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::core::asm::assemble;
+    use crate::core::vm::Perms;
+    use crate::single_cycle::cpu::test::with_single_cycle;
+    use crate::temp_path;
+    use goblin::elf::program_header::{PF_R, PF_X};
+    use std::collections::HashMap;
+
+    const BASE: u64 = 0x8000_0000;
+    const SIZE: u64 = 0x1000;
+
+    /// Hand `f` a pipeline CPU with `config` at BASE of SIZE bytes of memory
+    /// holding `program`.
+    pub(crate) fn with_pipeline<T>(
+        program: &[&str],
+        config: PipelineConfig,
+        f: impl FnOnce(&mut CPU) -> T,
+    ) -> T {
+        let mut vm = VirtualMemory::with_base(BASE as usize, SIZE as usize, false);
+        for (i, line) in program.iter().enumerate() {
+            vm.write_inst((BASE + 4 * i as u64) as usize, assemble(line).unwrap())
+                .unwrap();
+        }
+        let symbol_map = HashMap::new();
+        let mut callstack = CallStack::new(&symbol_map, false);
+        let mut cpu = CPU::new(&mut vm, &mut callstack, config);
+        cpu.set_entry(BASE);
+        f(&mut cpu)
+    }
+
+    /// Hand `f` a multi-cycle CPU at BASE of SIZE bytes of memory holding
+    /// `program`.
+    fn with_multistage<T>(program: &[&str], f: impl FnOnce(&mut MultistageCPU) -> T) -> T {
+        let mut vm = VirtualMemory::with_base(BASE as usize, SIZE as usize, false);
+        for (i, line) in program.iter().enumerate() {
+            vm.write_inst((BASE + 4 * i as u64) as usize, assemble(line).unwrap())
+                .unwrap();
+        }
+        let symbol_map = HashMap::new();
+        let mut callstack = CallStack::new(&symbol_map, false);
+        let mut cpu = MultistageCPU::new(
+            &mut vm,
+            &mut callstack,
+            false,
+            false,
+            SyscallAbi::Linux,
+            Xlen::Rv64,
+        );
+        cpu.set_entry(BASE);
+        f(&mut cpu)
+    }
+
+    #[test]
+    fn no_forward_progress() {
+        let program = ["addi a0, a0, 1", "ebreak"];
+        let run = |cycles: u64| {
+            with_pipeline(&program, PipelineConfig::default(), |cpu| {
+                cpu.set_no_progress_cycles(cycles);
+                cpu.cpu_exec(None)
+            })
+        };
+
+        assert!(run(DEFAULT_NO_PROGRESS_CYCLES).is_ok());
+        // nothing retires before the first instruction gets to WB
+        let err = run(3).unwrap_err();
+        assert!(err.to_string().contains("no forward progress in 3 cycles"));
+    }
+
+    #[test]
+    fn fetch_out_of_memory() {
+        with_pipeline(&[], PipelineConfig::default(), |cpu| {
+            cpu.pc.write(BASE + SIZE);
+            assert!(matches!(
+                cpu.cpu_exec(None),
+                Err(Error::Exception(Exception::InstructionAccessFault(vaddr))) if vaddr == BASE + SIZE
+            ));
+        });
+    }
+
+    #[test]
+    fn summary_before_run() {
+        with_pipeline(&[], PipelineConfig::default(), |cpu| {
+            // no branch and no instruction to divide by
+            let lines = cpu.summary_lines();
+            assert!(lines.contains(&"CPU branch misprediction rate: n/a".to_string()));
+            assert!(lines.contains(&"CPI = n/a".to_string()));
+        });
+    }
+
+    #[test]
+    fn ecall_syscalls() {
+        let program = [
+            "li a7, 214", // brk(0)
+            "li a0, 0",
+            "ecall",
+            "addi a1, a0, 1", // sees the return value of brk
+            "li a7, 93",      // exit(a1)
+            "mv a0, a1",
+            "ecall",
+            "li a0, 0", // never retires
+        ];
+        for policy in [DataHazardPolicy::NaiveStall, DataHazardPolicy::DataForward] {
+            with_pipeline(
+                &program,
+                PipelineConfig {
+                    data_hazard_policy: policy,
+                    ..Default::default()
+                },
+                |cpu| {
+                    cpu.syscall.set_brk(BASE + 0x800);
+
+                    cpu.cpu_exec(None).unwrap();
+                    assert!(!cpu.running);
+                    assert_eq!(cpu.reg_file.read(10), BASE + 0x801);
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn rv32_execution() {
+        let program = [
+            "li a0, -1",
+            "srli a1, a0, 4",
+            "srai a2, a0, 4",
+            "slt a3, a0, zero",
+            "mulhu a4, a0, a0",
+            "addi a5, a0, 2",
+            "sw a0, -4(sp)", // sp has bit 31 set
+            "lw a6, -4(sp)",
+            "slli a7, a0, 31",
+            "ebreak",
+        ];
+        with_pipeline(
+            &program,
+            PipelineConfig {
+                xlen: Xlen::Rv32,
+                ..Default::default()
+            },
+            |cpu| {
+                cpu.reg_file.write(2, BASE + SIZE);
+
+                cpu.cpu_exec(None).unwrap();
+                let regs: Vec<u64> = (10..=17).map(|i| cpu.reg_file.read(i)).collect();
+                assert_eq!(
+                    regs,
+                    [
+                        0xFFFF_FFFF,
+                        0x0FFF_FFFF,
+                        0xFFFF_FFFF,
+                        1,
+                        0xFFFF_FFFE,
+                        1,
+                        0xFFFF_FFFF,
+                        0x8000_0000
+                    ]
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn compressed_execution() {
+        // sum 5..=1 in a loop, then call a function through a 2-byte aligned jal
+        let program: [u16; 11] = [
+            0x4515, // c.li a0, 5
+            0x4581, // c.li a1, 0
+            0x95aa, // loop: c.add a1, a0
+            0x157d, // c.addi a0, -1
+            0xfd75, // c.bnez a0, loop
+            0x00ef, // jal ra, func
+            0x0080, 0x8606, // c.mv a2, ra
+            0x9002, // c.ebreak
+            0x0585, // func: c.addi a1, 1
+            0x8082, // c.jr ra
+        ];
+        for control_policy in [ControlPolicy::AlwaysNotTaken, ControlPolicy::DynamicPredict] {
+            let predict_policy = match control_policy {
+                ControlPolicy::DynamicPredict => Some(PredictPolicy::TwoBitsPredict),
+                _ => None,
+            };
+            let config = PipelineConfig {
+                control_policy,
+                predict_policy,
+                ..Default::default()
+            };
+            with_pipeline(&[], config, |cpu| {
+                for (i, half) in program.iter().enumerate() {
+                    cpu.vm
+                        .fill::<u16>((BASE + 2 * i as u64) as usize, 1, *half)
+                        .unwrap();
+                }
+                cpu.reg_file.write(2, BASE + SIZE);
+
+                cpu.cpu_exec(None).unwrap();
+                assert_eq!(cpu.reg_file.read(11), 16);
+                assert_eq!(cpu.reg_file.read(12), BASE + 0xe);
+            });
+        }
+    }
+
+    #[test]
+    fn atomic_execution() {
+        let program = [
+            "addi a0, sp, -8",
+            "li a1, 42",
+            "lr.w a2, (a0)",
+            "sc.w a3, a1, (a0)",
+            "sc.w a4, a1, (a0)", // no reservation left
+            "lw a5, 0(a0)",
+            "amoadd.w a6, a1, (a0)",
+            "lw a7, 0(a0)",
+            "ebreak",
+        ];
+        for data_hazard_policy in [DataHazardPolicy::NaiveStall, DataHazardPolicy::DataForward] {
+            with_pipeline(
+                &program,
+                PipelineConfig {
+                    data_hazard_policy,
+                    ..Default::default()
+                },
+                |cpu| {
+                    cpu.reg_file.write(2, BASE + SIZE);
+
+                    cpu.cpu_exec(None).unwrap();
+                    let regs: Vec<u64> = (12..=17).map(|i| cpu.reg_file.read(i)).collect();
+                    assert_eq!(regs, [0, 0, 1, 42, 42, 84]);
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn illegal_instruction() {
+        // opcode 0x7f is reserved for instructions longer than 64 bits
+        const ILLEGAL: u32 = 0xffff_ffff;
+        let program = ["li a0, 1", "jal zero, 8"];
+        let run = |last: u32| {
+            with_pipeline(&program, PipelineConfig::default(), |cpu| {
+                // fetched on the wrong path, but never executed
+                cpu.vm.write_inst(BASE as usize + 8, ILLEGAL).unwrap();
+                cpu.vm.write_inst(BASE as usize + 12, last).unwrap();
+                let result = cpu.cpu_exec(None);
+                (result, cpu.reg_file.read(10))
+            })
+        };
+
+        let (result, a0) = run(assemble("ebreak").unwrap());
+        result.unwrap();
+        assert_eq!(a0, 1);
+
+        let (result, a0) = run(ILLEGAL);
+        let err = result.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::Exception(Exception::IllegalInstruction(pc, ILLEGAL)) if pc == BASE + 12
+            ),
+            "{err:?}"
+        );
+        assert_eq!(a0, 1);
+
+        // a valid instruction the pipeline does not run stops it cleanly
+        let (result, _) = run(0x0001_2007); // flw ft0, 0(sp)
+        let err = result.unwrap_err();
+        assert!(
+            matches!(err, Error::Unsupported { inst: Inst64::flw, pc, .. } if pc == BASE + 12),
+            "{err:?}"
+        );
+        assert_eq!(err.to_string(), "Unsupported instruction flw at 0x8000000c");
+    }
+
+    #[test]
+    fn resume_after_trapping_ebreak() {
+        const ILLEGAL: u32 = 0xffff_ffff;
+        let program = ["li a0, 1", "ebreak"];
+        let config = PipelineConfig {
+            ebreak_as_trap: true,
+            ..Default::default()
+        };
+        for extra_mem_stage in [false, true] {
+            with_pipeline(&program, config, |cpu| {
+                cpu.vm.write_inst(BASE as usize + 8, ILLEGAL).unwrap();
+                cpu.set_extra_mem_stage(extra_mem_stage);
+                let err = cpu.cpu_exec(None).unwrap_err();
+                assert!(
+                    matches!(err, Error::Exception(Exception::Breakpoint(pc)) if pc == BASE + 4),
+                    "{err:?}"
+                );
+                // the word after the ebreak is still executed when resumed
+                let err = cpu.cpu_exec(None).unwrap_err();
+                assert!(
+                    matches!(
+                        err,
+                        Error::Exception(Exception::IllegalInstruction(pc, ILLEGAL)) if pc == BASE + 8
+                    ),
+                    "{err:?}"
+                );
+            });
+        }
+    }
+
+    #[test]
+    fn fence_execution() {
+        let program = [
+            "li a0, 1",
+            "fence",
+            "fence.i",
+            "fence.tso",
+            "pause",
+            "addi a0, a0, 1",
+            "ebreak",
+        ];
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.cpu_exec(None).unwrap();
+            assert_eq!(cpu.reg_file.read(10), 2);
+            assert_eq!(cpu.cpu_statistics.executed_inst_count, 7);
+            assert_eq!(cpu.cpu_statistics.pause_count, 1);
+        });
+    }
+
+    #[test]
+    fn fence_i_refetches() {
+        // the li after fence.i is overwritten by the word at BASE + 24
+        let program = [
+            "auipc t0, 0",
+            "lw t1, 24(t0)",
+            "sw t1, 16(t0)",
+            "fence.i",
+            "li a0, 1",
+            "ebreak",
+            "li a0, 2",
+        ];
+        for control_policy in [ControlPolicy::AllStall, ControlPolicy::AlwaysNotTaken] {
+            let config = PipelineConfig {
+                control_policy,
+                ..Default::default()
+            };
+            with_pipeline(&program, config, |cpu| {
+                cpu.cpu_exec(None).unwrap();
+                assert_eq!(cpu.reg_file.read(10), 2, "{control_policy:?}");
+            });
+        }
+    }
+
+    #[test]
+    fn mret_jumps_to_mepc() {
+        let program = [
+            "auipc t0, 0",
+            "addi t0, t0, 20",
+            "csrrw zero, mepc, t0",
+            "mret",
+            "li a0, 1",
+            "li a1, 7",
+            "ebreak",
+        ];
+        for control_policy in [ControlPolicy::AlwaysNotTaken, ControlPolicy::DynamicPredict] {
+            let predict_policy = (control_policy == ControlPolicy::DynamicPredict)
+                .then_some(PredictPolicy::TwoBitsPredict);
+            with_pipeline(
+                &program,
+                PipelineConfig {
+                    control_policy,
+                    predict_policy,
+                    ..Default::default()
+                },
+                |cpu| {
+                    cpu.cpu_exec(None).unwrap();
+                    // the instruction fetched after mret is flushed
+                    assert_eq!(cpu.reg_file.read(10), 0);
+                    assert_eq!(cpu.reg_file.read(11), 7);
+                    assert_eq!(cpu.cpu_statistics.control_hazard_count, 1);
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn vectored_trap() {
+        // the handler at BASE + 32 counts traps in a0 and returns after
+        // the trapped instruction
+        let program = [
+            "auipc t0, 0",
+            "addi t0, t0, 32",
+            "csrrw zero, mtvec, t0",
+            "div a1, a2, zero",
+            "addi a3, a3, 1",
+            "ebreak",
+            "nop",
+            "nop",
+            "csrrs t1, mepc, zero",
+            "addi t1, t1, 4",
+            "csrrw zero, mepc, t1",
+            "addi a0, a0, 1",
+            "mret",
+        ];
+        // an instruction the pipeline does not run traps as illegal
+        const FLW: u32 = 0x0001_2007; // flw ft0, 0(sp)
+        for (trapped, mcause, mtval) in [(None, 24, 0), (Some(FLW), 2, FLW as u64)] {
+            with_pipeline(&program, PipelineConfig::default(), |cpu| {
+                if let Some(inst) = trapped {
+                    cpu.vm.write_inst(BASE as usize + 12, inst).unwrap();
+                }
+                cpu.set_trap_mode(TrapMode::Vectored);
+                cpu.set_div_trap(true);
+                cpu.cpu_exec(None).unwrap();
+                assert_eq!(cpu.reg_file.read(10), 1);
+                assert_eq!(cpu.reg_file.read(13), 1);
+                assert_eq!(cpu.csr.read(crate::csr::MCAUSE).unwrap(), mcause);
+                assert_eq!(cpu.csr.read(crate::csr::MTVAL).unwrap(), mtval);
+                assert_eq!(cpu.csr.read(crate::csr::MEPC).unwrap(), BASE + 16);
+            });
+        }
+    }
+
+    #[test]
+    fn faulting_load_not_retired() {
+        // the load from unmapped memory traps in MEM to the handler at
+        // BASE + 32, which returns after it
+        let program = [
+            "auipc t0, 0",
+            "addi t0, t0, 32",
+            "csrrw zero, mtvec, t0",
+            "li t1, 8",
+            "ld t2, 0(t1)",
+            "ebreak",
+            "nop",
+            "nop",
+            "csrrs t1, mepc, zero",
+            "addi t1, t1, 4",
+            "csrrw zero, mepc, t1",
+            "mret",
+        ];
+        let single = with_single_cycle(&program, |cpu| {
+            cpu.set_trap_mode(TrapMode::Vectored);
+            assert!(cpu.cpu_exec(None).is_err()); // halted by ebreak
+            cpu.retired()
+        });
+        let multi = with_multistage(&program, |cpu| {
+            cpu.set_trap_mode(TrapMode::Vectored);
+            let _ = cpu.cpu_exec(None);
+            cpu.retired()
+        });
+        let pipeline = with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.set_trap_mode(TrapMode::Vectored);
+            cpu.cpu_exec(None).unwrap();
+            cpu.retired()
+        });
+        assert_eq!(single, 9);
+        assert_eq!(multi, single);
+        assert_eq!(pipeline, single);
+    }
+
+    #[test]
+    fn cycle_limit() {
+        let program = ["addi a0, a0, 1", "j -4"];
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.set_max_cycles(Some(1000));
+
+            let err = cpu.cpu_exec(None).unwrap_err();
+            assert!(matches!(err, Error::CycleLimitExceeded(_)));
+            assert_eq!(cpu.clock, 1000);
+
+            // the debugger's runs are stopped too
+            cpu.set_max_cycles(Some(2000));
+            let err = cpu
+                .cpu_exec_break(
+                    &BTreeSet::new(),
+                    &BTreeMap::new(),
+                    &BTreeMap::new(),
+                    &BTreeMap::new(),
+                    &AtomicBool::new(false),
+                )
+                .unwrap_err();
+            assert!(matches!(err, Error::CycleLimitExceeded(_)));
+            assert_eq!(cpu.clock, 2000);
+        });
+    }
+
+    #[test]
+    fn semihosting() {
+        // SYS_WRITE0 of the string at BASE + 0x100, then SYS_EXIT with the
+        // block at BASE + 0x108
+        let program = [
+            "auipc a1, 0",
+            "addi a1, a1, 256",
+            "li a0, 4",
+            "slli x0, x0, 0x1f",
+            "ebreak",
+            "srai x0, x0, 7",
+            "li a2, 5",
+            "li a0, 0x18",
+            "addi a1, a1, 8",
+            "slli x0, x0, 0x1f",
+            "ebreak",
+            "srai x0, x0, 7",
+            "li a2, 6",
+            "nop",
+            "nop",
+            "ebreak",
+        ];
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.vm
+                .write_bytes((BASE + 0x100) as usize, b"ok\n\0")
+                .unwrap();
+            cpu.vm
+                .mwrite::<u64>((BASE + 0x108) as usize, 0x20026)
+                .unwrap(); // ADP_Stopped_ApplicationExit
+            cpu.vm.mwrite::<u64>((BASE + 0x110) as usize, 0).unwrap();
+            cpu.set_semihosting(true);
+            cpu.cpu_exec(None).unwrap();
+            // exited at the second call, with nothing younger written back
+            assert_eq!(cpu.reg_file.read(12), 5);
+            assert!(!cpu.running);
+        });
+    }
+
+    #[test]
+    fn pipeline_states() {
+        let program = ["li a0, 1", "add a1, a0, a0", "ebreak"];
+        let config = PipelineConfig {
+            data_hazard_policy: DataHazardPolicy::NaiveStall,
+            ..Default::default()
+        };
+        with_pipeline(&program, config, |cpu| {
+            cpu.clock().unwrap();
+            cpu.clock().unwrap();
+            assert_eq!(cpu.itl_f_d().exec_flags.alu_op, Inst64::add);
+            assert_eq!(cpu.itl_d_e().exec_flags.alu_op, Inst64::addi);
+            assert_eq!(cpu.pipeline_states(), [PipelineState::Normal; 4]);
+
+            // add waits in IF/ID for addi, and NaiveStall already decides the
+            // state of IF/ID in the next clock
+            cpu.clock().unwrap();
+            assert_eq!(cpu.itl_f_d().exec_flags.alu_op, Inst64::add);
+            assert_eq!(cpu.itl_d_e().exec_flags.alu_op, Inst64::noop);
+            assert_eq!(cpu.itl_e_m().alu_op, Inst64::addi);
+            assert_eq!(
+                cpu.pipeline_states(),
+                [
+                    PipelineState::Bubble,
+                    PipelineState::Normal,
+                    PipelineState::Normal,
+                    PipelineState::Normal
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn pipeline_diagram() {
+        // add waits a cycle in ID for the load
+        let program = [
+            "auipc s0, 0",
+            "ld t0, 64(s0)",
+            "add t1, t0, t0",
+            "addi t2, zero, 1",
+            "ebreak",
+        ];
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.set_pipeline_diagram(Some(3));
+            cpu.cpu_exec(None).unwrap();
+            // only the last 3 instructions are kept
+            assert_eq!(
+                cpu.pipeline_diagram.as_ref().unwrap().lines(),
+                [
+                    "                          2  3  4  5  6  7  8  9",
+                    "80000008: add t1,t0,t0    F  D  -  E  M  W",
+                    "8000000c: addi t2,zero,1     F  -  D  E  M  W",
+                    "80000010: ebreak                   F  D  E  M  W",
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn single_mem_port() {
+        const DATA: u64 = BASE + 0x400;
+        // sum 5 pairs of quad-words at DATA
+        let program = [
+            "auipc s0, 0",
+            "addi s0, s0, 1024",
+            "li a0, 5",
+            "ld t0, 0(s0)",
+            "ld t1, 8(s0)",
+            "add a1, a1, t0",
+            "add a1, a1, t1",
+            "addi a0, a0, -1",
+            "bne a0, zero, -20",
+            "ebreak",
+        ];
+        let run = |single_mem_port: bool| {
+            with_pipeline(&program, PipelineConfig::default(), |cpu| {
+                cpu.vm.mwrite::<u64>(DATA as usize, 3).unwrap();
+                cpu.vm.mwrite::<u64>(DATA as usize + 8, 4).unwrap();
+                cpu.set_single_mem_port(single_mem_port);
+                cpu.cpu_exec(None).unwrap();
+                (
+                    cpu.reg_file.read(11),
+                    cpu.clock,
+                    cpu.cpu_statistics.structural_hazard_count,
+                )
+            })
+        };
+
+        let (sum, clock, count) = run(false);
+        assert_eq!(sum, 35);
+        assert_eq!(count, 0);
+        let (sum_1, clock_1, count_1) = run(true);
+        assert_eq!(sum_1, 35);
+        // at most one stall per load
+        assert!(count_1 > 0 && count_1 <= 10);
+        assert_eq!(clock_1, clock + count_1);
+    }
+
+    #[test]
+    fn extra_mem_stage() {
+        const DATA: u64 = BASE + 0x400;
+        // loads feeding ALU ops and stores, then brk(0) feeding the next
+        // instruction
+        let program = [
+            "auipc s0, 0",
+            "addi s0, s0, 1024",
+            "li a2, 4",
+            "ld t0, 0(s0)",
+            "add t1, t0, t0",
+            "sd t1, 8(s0)",
+            "ld t2, 8(s0)",
+            "sd t2, 16(s0)",
+            "add a1, a1, t2",
+            "addi t3, a1, 1",
+            "add a1, t3, t1",
+            "addi a2, a2, -1",
+            "bne a2, zero, -36",
+            "li a7, 214",
+            "li a0, 0",
+            "ecall",
+            "addi a3, a0, 1",
+            "ebreak",
+        ];
+        let run = |data_hazard_policy: DataHazardPolicy, extra_mem_stage: bool| {
+            with_pipeline(
+                &program,
+                PipelineConfig {
+                    data_hazard_policy,
+                    ..Default::default()
+                },
+                |cpu| {
+                    cpu.vm.mwrite::<u64>(DATA as usize, 3).unwrap();
+                    cpu.syscall.set_brk(BASE + SIZE);
+                    cpu.set_extra_mem_stage(extra_mem_stage);
+                    cpu.cpu_exec(None).unwrap();
+                    let stored: u64 = cpu.vm.mread(DATA as usize + 16).unwrap();
+                    (
+                        cpu.reg_file.to_array(),
+                        stored,
+                        cpu.clock,
+                        cpu.cpu_statistics.mispredict_penalty(),
+                    )
+                },
+            )
+        };
+
+        for policy in [DataHazardPolicy::NaiveStall, DataHazardPolicy::DataForward] {
+            let (regs, stored, clock, penalty) = run(policy, false);
+            assert_eq!(regs[11], 4 * 13, "{policy:?}");
+            assert_eq!(regs[13], BASE + SIZE + 1, "{policy:?}");
+            assert_eq!(stored, 6, "{policy:?}");
+            let (regs_1, stored_1, clock_1, penalty_1) = run(policy, true);
+            assert_eq!(regs_1, regs, "{policy:?}");
+            assert_eq!(stored_1, stored, "{policy:?}");
+            assert!(clock_1 > clock, "{policy:?}: {clock_1} <= {clock}");
+            // branches still resolve at EX
+            assert_eq!(penalty, Some(2.0), "{policy:?}");
+            assert_eq!(penalty_1, penalty, "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn mul_div_latency() {
+        // 3 divisions and 1 multiplication are charged, the rem is fused
+        // with the div before it
+        let program = [
+            "li a0, 100",
+            "li a1, 3",
+            "div a2, a0, a1",
+            "rem a3, a0, a1",
+            "div a4, a2, a1",
+            "divu a5, a4, a1",
+            "mul a6, a0, a1",
+            "ebreak",
+        ];
+        let pipeline = |mul_latency, div_latency| {
+            with_pipeline(&program, PipelineConfig::default(), |cpu| {
+                cpu.set_mul_div_latency(mul_latency, div_latency);
+                cpu.cpu_exec(None).unwrap();
+                assert_eq!(cpu.reg_file.read(15), 3);
+                cpu.clock
+            })
+        };
+        let multistage = |mul_latency, div_latency| {
+            with_multistage(&program, |cpu| {
+                cpu.set_mul_div_latency(mul_latency, div_latency);
+                cpu.cpu_exec(None).unwrap();
+                assert_eq!(cpu.reg_file.read(15), 3);
+                cpu.clock
+            })
+        };
+
+        assert_eq!(pipeline(2, 5), pipeline(0, 0) + 3 * 5 + 2);
+        assert_eq!(
+            pipeline(DEFAULT_MUL_LATENCY, DEFAULT_DIV_LATENCY),
+            pipeline(0, 0) + 3 * 39 + 1
+        );
+        assert_eq!(multistage(2, 5), multistage(0, 0) + 3 * 5 + 2);
+    }
+
+    #[test]
+    fn dcache_miss_rate() {
+        // two passes loading the 32 words from sp, 128 bytes
+        let program = [
+            "li a3, 2",
+            "addi a0, sp, 0",
+            "li a1, 32",
+            "lw a2, 0(a0)",
+            "addi a0, a0, 4",
+            "addi a1, a1, -1",
+            "bne a1, zero, -12",
+            "addi a3, a3, -1",
+            "bne a3, zero, -28",
+            "ebreak",
+        ];
+        let run = |dcache: Option<Cache>| {
+            with_pipeline(&program, PipelineConfig::default(), |cpu| {
+                cpu.set_dcache(dcache, 10);
+                cpu.reg_file.write(2, BASE + 0x800);
+                cpu.cpu_exec(None).unwrap();
+                let stats = &cpu.cpu_statistics;
+                (stats.dcache_hit_count, stats.dcache_miss_count, cpu.clock)
+            })
+        };
+
+        let (_, _, clock) = run(None);
+        // 64 bytes, smaller than the array: every pass misses on each of the
+        // 8 lines, 1 in 4 loads
+        let (hits, misses, small_clock) = run(Some(Cache::new(4, 1, 16)));
+        assert_eq!((hits, misses), (48, 16));
+        assert_eq!(small_clock, clock + 16 * 10);
+        // 128 bytes: the second pass hits
+        let (hits, misses, large_clock) = run(Some(Cache::new(4, 2, 16)));
+        assert_eq!((hits, misses), (56, 8));
+        assert_eq!(large_clock, clock + 8 * 10);
+    }
+
+    #[test]
+    fn icache_cold_misses() {
+        // 16 instructions and the ebreak span 5 lines of 16 bytes
+        let mut program = vec!["addi a0, a0, 1"; 16];
+        program.push("ebreak");
+        let run = |icache: Option<Cache>| {
+            with_pipeline(&program, PipelineConfig::default(), |cpu| {
+                cpu.set_icache(icache, 10);
+                cpu.cpu_exec(None).unwrap();
+                assert_eq!(cpu.reg_file.read(10), 16);
+                let stats = &cpu.cpu_statistics;
+                (stats.icache_hit_count, stats.icache_miss_count, cpu.clock)
+            })
+        };
+
+        let (_, _, clock) = run(None);
+        // only the first fetch of each line misses
+        let (hits, misses, cached_clock) = run(Some(Cache::new(8, 1, 16)));
+        assert_eq!((hits, misses), (12, 5));
+        assert_eq!(cached_clock, clock + 5 * 10);
+    }
+
+    #[test]
+    fn icache_skips_stalled_fetches() {
+        // the addi waits in IF/ID for the ld, and the ebreak is fetched again
+        let program = ["auipc sp, 0", "ld a1, 0(sp)", "addi a1, a1, 1", "ebreak"];
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.set_icache(Some(Cache::new(8, 1, 16)), 10);
+            cpu.cpu_exec(None).unwrap();
+            assert_eq!(cpu.cpu_statistics.data_hazard_delayed_cycles, 1);
+            let stats = &cpu.cpu_statistics;
+            assert_eq!((stats.icache_hit_count, stats.icache_miss_count), (3, 1));
+        });
+    }
+
+    #[test]
+    fn wfi_halts() {
+        let program = ["li a0, 7", "wfi", "addi a0, a0, 1", "ebreak"];
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            // stops once the wfi retires, younger instructions stay in flight
+            cpu.cpu_exec(None).unwrap();
+            assert!(cpu.waiting_for_interrupt());
+            assert_eq!(cpu.reg_file.read(10), 7);
+
+            // the debugger could resume after the wfi
+            cpu.cpu_exec(None).unwrap();
+            assert!(!cpu.waiting_for_interrupt());
+            assert_eq!(cpu.reg_file.read(10), 8);
+        });
+    }
+
+    #[test]
+    fn watchpoint() {
+        let program = [
+            "li t0, -1",
+            "sw t0, 12(sp)",
+            "sd t0, 0(sp)",
+            "addi a0, a0, 1",
+            "ebreak",
+        ];
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.reg_file.write(2, BASE + 0x800);
+
+            // the sd covers the watched quad-word from its middle, the sw does not
+            let breakpoints = BTreeSet::new();
+            let watchpoints = BTreeMap::from([(BASE + 0x804, 8)]);
+            assert!(cpu
+                .cpu_exec_break(
+                    &breakpoints,
+                    &BTreeMap::new(),
+                    &watchpoints,
+                    &BTreeMap::new(),
+                    &AtomicBool::new(false),
+                )
+                .unwrap());
+            assert_eq!(cpu.watch_hit(&watchpoints), Some(BASE + 0x804));
+            assert_eq!(cpu.last_store, Some((BASE + 0x800, 8)));
+            // only the watched bytes count
+            assert_eq!(
+                cpu.watch_hit(&BTreeMap::from([(BASE + 0x807, 1)])),
+                Some(BASE + 0x807)
+            );
+            assert_eq!(cpu.watch_hit(&BTreeMap::from([(BASE + 0x808, 4)])), None);
+            assert_eq!(cpu.mread::<u32>(BASE + 0x804).unwrap(), u32::MAX);
+
+            assert!(!cpu
+                .cpu_exec_break(
+                    &breakpoints,
+                    &BTreeMap::new(),
+                    &watchpoints,
+                    &BTreeMap::new(),
+                    &AtomicBool::new(false),
+                )
+                .unwrap());
+            assert_eq!(cpu.reg_file.read(10), 1);
+        });
+    }
+
+    #[test]
+    fn breakpoint_after_taken_call() {
+        // the jal is predicted not taken, so the li after it is fetched on
+        // the wrong path before the call, and again after its return
+        let program = [
+            "li a0, 1",
+            "jal ra, 12",
+            "li a1, 2",
+            "ebreak",
+            "addi a0, a0, 4",
+            "ret",
+        ];
+        let config = PipelineConfig {
+            control_policy: ControlPolicy::AlwaysNotTaken,
+            ..Default::default()
+        };
+        with_pipeline(&program, config, |cpu| {
+            let breakpoints = BTreeSet::from([BASE + 8]);
+            let run = |cpu: &mut CPU| {
+                cpu.cpu_exec_break(
+                    &breakpoints,
+                    &BTreeMap::new(),
+                    &BTreeMap::new(),
+                    &BTreeMap::new(),
+                    &AtomicBool::new(false),
+                )
+                .unwrap()
+            };
+            assert!(run(cpu));
+            assert_eq!(
+                cpu.breakpoint_hit(&breakpoints, &BTreeMap::new()).unwrap(),
+                Some(BASE + 8)
+            );
+            // the call has returned, and the li has not written back yet
+            assert_eq!(cpu.reg_file.read(10), 5);
+            assert_eq!(cpu.reg_file.read(11), 0);
+            assert_eq!(cpu.call_depth(), 0);
+
+            assert!(!run(cpu));
+            assert_eq!(cpu.reg_file.read(11), 2);
+        });
+    }
+
+    #[test]
+    fn next_over_recursive_call() {
+        // main calls f(3), f(n) calls f(n - 1) at BASE + 32 until n is 0
+        let program = [
+            "li a0, 3",
+            "jal ra, 12",
+            "addi a1, a1, 1",
+            "ebreak",
+            "addi sp, sp, -16",
+            "sd ra, 8(sp)",
+            "beq a0, zero, 16",
+            "addi a0, a0, -1",
+            "jal ra, -16",
+            "addi a2, a2, 1",
+            "ld ra, 8(sp)",
+            "addi sp, sp, 16",
+            "ret",
+        ];
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.reg_file.write(2, BASE + 0x800);
+
+            // step until the call of f(2) is fetched
+            let watchpoints = BTreeMap::new();
+            while cpu.pc.read() != BASE + 32 {
+                cpu.clock().unwrap();
+            }
+            assert_eq!(cpu.call_return_addr(), Some(BASE + 36));
+            assert_eq!(cpu.call_depth, 1);
+
+            // the nested calls return to BASE + 36 first, in deeper frames
+            let breakpoints = BTreeSet::new();
+            assert!(cpu
+                .cpu_exec_next(
+                    BASE + 36,
+                    &breakpoints,
+                    &BTreeMap::new(),
+                    &watchpoints,
+                    &AtomicBool::new(false)
+                )
+                .unwrap());
+            assert_eq!(cpu.itl_d_e.pc, BASE + 36);
+            assert_eq!(cpu.call_depth, 1);
+            assert_eq!(cpu.reg_file.read(12), 2);
+
+            cpu.cpu_exec(None).unwrap();
+            assert_eq!(cpu.reg_file.read(11), 1);
+            assert_eq!(cpu.reg_file.read(12), 3);
+            assert_eq!(cpu.call_depth, 0);
+        });
+    }
+
+    #[test]
+    fn finish_returns() {
+        // main calls f(5) at BASE + 16, which returns 5 + 11
+        let program = [
+            "li a0, 5",
+            "jal ra, 12",
+            "addi a1, a0, 0",
+            "ebreak",
+            "addi a0, a0, 10",
+            "addi a0, a0, 1",
+            "ret",
+        ];
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            // step until the last addi of f is fetched
+            let watchpoints = BTreeMap::new();
+            while cpu.pc.read() != BASE + 20 || cpu.call_depth() == 0 {
+                cpu.clock().unwrap();
+            }
+
+            // an interrupt stops it after one clock, still inside f
+            let interrupted = AtomicBool::new(true);
+            assert!(matches!(
+                cpu.cpu_exec_finish(
+                    &BTreeSet::new(),
+                    &BTreeMap::new(),
+                    &watchpoints,
+                    &interrupted
+                ),
+                Err(Error::Interrupted(_))
+            ));
+            assert_eq!(cpu.call_depth(), 1);
+
+            let interrupted = AtomicBool::new(false);
+            assert!(cpu
+                .cpu_exec_finish(
+                    &BTreeSet::new(),
+                    &BTreeMap::new(),
+                    &watchpoints,
+                    &interrupted
+                )
+                .unwrap());
+            assert_eq!(cpu.call_depth(), 0);
+            assert_eq!(cpu.reg_file.read(10), 16);
+
+            cpu.cpu_exec(None).unwrap();
+            assert_eq!(cpu.reg_file.read(11), 16);
+        });
+    }
+
+    #[test]
+    fn multistage_add_loop() {
+        // a0 = 10 + 9 + ... + 1
+        let program = [
+            "li a0, 0",
+            "li a1, 10",
+            "add a0, a0, a1",
+            "addi a1, a1, -1",
+            "bne a1, zero, -8",
+            "ebreak",
+        ];
+        with_multistage(&program, |cpu| {
+            cpu.cpu_exec(None).unwrap();
+            assert_eq!(cpu.reg_file.read(10), 55);
+            // 2 + 3 * 10 instructions before ebreak, each takes several clocks
+            assert_eq!(cpu.cpu_statistics.executed_inst_count, 33);
+            assert!(cpu.clock > 3 * 33);
+            assert_eq!(cpu.cpu_statistics.inst_histogram[&Inst64::addi], 12);
+            assert_eq!(cpu.cpu_statistics.inst_histogram[&Inst64::bne], 10);
+        });
+    }
+
+    #[test]
+    fn store_data_forward() {
+        // each stored value is produced 1, 2 and 3 instructions earlier
+        let program = [
+            "auipc sp, 1",
+            "li a0, 3",
+            "li a1, 4",
+            "add t0, a0, a1",
+            "sd t0, -8(sp)",
+            "add t1, t0, a1",
+            "nop",
+            "sd t1, -16(sp)",
+            "add t2, t1, a1",
+            "nop",
+            "nop",
+            "sd t2, -24(sp)",
+            "ld a2, -8(sp)",
+            "ld a3, -16(sp)",
+            "ld a4, -24(sp)",
+            "sd a4, -32(sp)",
+            "ld a5, -32(sp)",
+            "ebreak",
+        ];
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.cpu_exec(None).unwrap();
+            assert_eq!(cpu.reg_file.read(12), 7);
+            assert_eq!(cpu.reg_file.read(13), 11);
+            assert_eq!(cpu.reg_file.read(14), 15);
+            assert_eq!(cpu.reg_file.read(15), 15);
+        });
+    }
+
+    #[test]
+    fn lui_matches_single_cycle() {
+        let program = [
+            "lui a0, 0x80000",
+            "lui a1, 0xfffff",
+            "lui a2, 0x7ffff",
+            "lui a3, 0x12345",
+            "ebreak",
+        ];
+        let expected = [
+            0xffff_ffff_8000_0000,
+            0xffff_ffff_ffff_f000,
+            0x7fff_f000,
+            0x1234_5000,
+        ];
+
+        let single: Vec<u64> = with_single_cycle(&program, |cpu| {
+            assert!(cpu.cpu_exec(None).is_err()); // halted by ebreak
+            (10..14).map(|i| cpu.reg_val(i)).collect()
+        });
+        let pipeline: Vec<u64> = with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.cpu_exec(None).unwrap();
+            (10..14).map(|i| cpu.reg_file.read(i)).collect()
+        });
+
+        assert_eq!(single, expected);
+        assert_eq!(pipeline, expected);
+    }
+
+    #[test]
+    fn loads_match_single_cycle() {
+        // 0xff, 0xffff and 0xffffffff loaded back with every width
+        let program = [
+            "li t0, -1",
+            "sb t0, 0(sp)",
+            "sh t0, 8(sp)",
+            "sw t0, 16(sp)",
+            "lb a0, 0(sp)",
+            "lbu a1, 0(sp)",
+            "lh a2, 8(sp)",
+            "lhu a3, 8(sp)",
+            "lw a4, 16(sp)",
+            "lwu a5, 16(sp)",
+            "ld a6, 16(sp)",
+            "ebreak",
+        ];
+        let expected = [
+            u64::MAX,
+            0xff,
+            u64::MAX,
+            0xffff,
+            u64::MAX,
+            0xffff_ffff,
+            0xffff_ffff,
+        ];
+        let sp = BASE + 0x800;
+
+        let single: Vec<u64> = with_single_cycle(&program, |cpu| {
+            cpu.init_blank(BASE, sp);
+            assert!(cpu.cpu_exec(None).is_err()); // halted by ebreak
+            (10..17).map(|i| cpu.reg_val(i)).collect()
+        });
+        let pipeline: Vec<u64> = with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.reg_file.write(2, sp);
+            cpu.cpu_exec(None).unwrap();
+            (10..17).map(|i| cpu.reg_file.read(i)).collect()
+        });
+        let multistage: Vec<u64> = with_multistage(&program, |cpu| {
+            cpu.reg_file.write(2, sp);
+            cpu.cpu_exec(None).unwrap();
+            (10..17).map(|i| cpu.reg_file.read(i)).collect()
+        });
+
+        assert_eq!(single, expected);
+        assert_eq!(pipeline, expected);
+        assert_eq!(multistage, expected);
+    }
+
+    #[test]
+    fn enforce_perms_on_stack() {
+        const STACK_CODE: u64 = BASE + 0x400;
+        // jr is the last executable instruction, the ones fetched after it
+        // are on a wrong path and must not fault
+        let program = ["auipc t0, 0", "addi t0, t0, 0x400", "jr t0"];
+        let stack_code = ["li a0, 7", "ebreak"];
+
+        for enforce in [false, true] {
+            with_pipeline(&program, PipelineConfig::default(), |cpu| {
+                for (i, line) in stack_code.iter().enumerate() {
+                    cpu.vm
+                        .write_inst(
+                            (STACK_CODE + 4 * i as u64) as usize,
+                            assemble(line).unwrap(),
+                        )
+                        .unwrap();
+                }
+                if enforce {
+                    let text = Perms::from_p_flags(PF_R | PF_X);
+                    cpu.vm
+                        .set_perms(vec![(BASE as usize..(BASE + 12) as usize, text)]);
+                }
+                let result = cpu.cpu_exec(None);
+                if enforce {
+                    assert!(matches!(
+                        result,
+                        Err(Error::Exception(Exception::InstructionAccessFault(
+                            STACK_CODE
+                        )))
+                    ));
+                    assert_eq!(cpu.reg_file.read(10), 0);
+                } else {
+                    result.unwrap();
+                    assert_eq!(cpu.reg_file.read(10), 7);
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn same_seed_same_statistics() {
+        use crate::core::{
+            rng::Rng,
+            uninit::{Uninit, UninitFill},
+        };
+        use crate::multi_stage::cache::Cache;
+
+        // branches on uninitialized t1 and thrashes a 2-way cache set with
+        // random replacement
+        let program = [
+            "li a1, 16",
+            "andi t2, t1, 1",
+            "beq t2, zero, 8",
+            "ld a0, 0x100(sp)",
+            "srli t1, t1, 1",
+            "ld a0, 0(sp)",
+            "ld a0, 0x40(sp)",
+            "ld a0, 0x80(sp)",
+            "addi a1, a1, -1",
+            "bne a1, zero, -32",
+            "ebreak",
+        ];
+        let run = |seed: u64| {
+            let mut rng = Rng::new(seed);
+            with_pipeline(&program, PipelineConfig::default(), |cpu| {
+                cpu.fill_uninit_regs(&mut UninitFill::new(Uninit::Random, rng.fork()));
+                let mut dcache = Cache::new(1, 2, 64);
+                dcache.set_random_replacement(rng.fork());
+                cpu.set_dcache(Some(dcache), 10);
+                cpu.reg_file.write(2, BASE + 0x800);
+                cpu.cpu_exec(None).unwrap();
+                cpu.cpu_statistics.clone()
+            })
+        };
+
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn counters_match_single_cycle() {
+        // the first read and the 4 instructions after it retire between the
+        // reads, through a load-use stall and a taken branch
+        let program = [
+            "addi t1, t1, 1",
+            "addi t1, t1, 1",
+            "rdinstret a0",
+            "sd a0, 0(sp)",
+            "ld t0, 0(sp)",
+            "addi t0, t0, 1",
+            "beq zero, zero, 8",
+            "addi t1, t1, 1", // skipped
+            "rdinstret a1",
+            "rdcycle a2",
+            "rdcycle a3",
+            "ebreak",
+        ];
+        let sp = BASE + 0x800;
+
+        let single: Vec<u64> = with_single_cycle(&program, |cpu| {
+            cpu.init_blank(BASE, sp);
+            assert!(cpu.cpu_exec(None).is_err()); // halted by ebreak
+            (10..14).map(|i| cpu.reg_val(i)).collect()
+        });
+        let pipeline: Vec<u64> = with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.reg_file.write(2, sp);
+            cpu.cpu_exec(None).unwrap();
+            (10..14).map(|i| cpu.reg_file.read(i)).collect()
+        });
+        let multistage: Vec<u64> = with_multistage(&program, |cpu| {
+            cpu.reg_file.write(2, sp);
+            cpu.cpu_exec(None).unwrap();
+            (10..14).map(|i| cpu.reg_file.read(i)).collect()
+        });
+
+        for counters in [&single, &pipeline, &multistage] {
+            // instret counts the instructions retired before the read
+            assert_eq!(counters[0], 2);
+            assert_eq!(counters[1] - counters[0], 5);
+        }
+        assert_eq!(single[3] - single[2], 1);
+    }
+
+    #[test]
+    fn wfi_sleeps_until_timer() {
+        const INTERVAL: u64 = 1000;
+        // the handler at BASE + 36 counts ticks in a0 and returns after the
+        // wfi, which goes on to count in a1
+        let program = [
+            "auipc t0, 0",
+            "addi t0, t0, 36",
+            "csrrw zero, mtvec, t0",
+            "li t1, 128",
+            "csrrs zero, mie, t1",
+            "csrrsi zero, mstatus, 8",
+            "wfi",
+            "addi a1, a1, 1",
+            "ebreak",
+            "addi a0, a0, 1",
+            "mret",
+        ];
+
+        let single = with_single_cycle(&program, |cpu| {
+            cpu.set_timer_interval(INTERVAL);
+            assert!(cpu.cpu_exec(None).is_err()); // halted by ebreak
+            assert!(!cpu.waiting_for_interrupt());
+            (cpu.reg_val(10), cpu.reg_val(11), cpu.cycles())
+        });
+        let pipeline = with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.set_timer_interval(INTERVAL);
+            cpu.cpu_exec(None).unwrap();
+            assert!(!cpu.waiting_for_interrupt());
+            (cpu.reg_file.read(10), cpu.reg_file.read(11), cpu.cycles())
+        });
+        let multistage = with_multistage(&program, |cpu| {
+            cpu.set_timer_interval(INTERVAL);
+            cpu.cpu_exec(None).unwrap();
+            (cpu.reg_file.read(10), cpu.reg_file.read(11), cpu.cycles())
+        });
+
+        // one tick, taken right after the wfi
+        for (ticks, count, cycles) in [single, pipeline, multistage] {
+            assert_eq!((ticks, count), (1, 1));
+            assert!((INTERVAL..INTERVAL + 50).contains(&cycles));
+        }
+    }
+
+    #[test]
+    fn timer_interrupt_counts_ticks() {
+        const INTERVAL: u64 = 50;
+        // the handler at BASE + 48 counts ticks in a0 and returns to the
+        // interrupted instruction, while the loop counts to 200 in a1
+        let program = [
+            "auipc t0, 0",
+            "addi t0, t0, 48",
+            "csrrw zero, mtvec, t0",
+            "li t1, 128",
+            "csrrs zero, mie, t1",
+            "csrrsi zero, mstatus, 8",
+            "li t2, 200",
+            "addi a1, a1, 1",
+            "addi t2, t2, -1",
+            "bne t2, zero, -8",
+            "ebreak",
+            "nop",
+            "addi a0, a0, 1",
+            "mret",
+        ];
+
+        let (single, single_cycles) = with_single_cycle(&program, |cpu| {
+            cpu.set_timer_interval(INTERVAL);
+            assert!(cpu.cpu_exec(None).is_err()); // halted by ebreak
+            assert_eq!(cpu.reg_val(11), 200);
+            (cpu.reg_val(10), cpu.cycles())
+        });
+        let (pipeline, pipeline_cycles) =
+            with_pipeline(&program, PipelineConfig::default(), |cpu| {
+                cpu.set_timer_interval(INTERVAL);
+                cpu.cpu_exec(None).unwrap();
+                assert_eq!(cpu.reg_file.read(11), 200);
+                (cpu.reg_file.read(10), cpu.cycles())
+            });
+        let (multistage, multistage_cycles) = with_multistage(&program, |cpu| {
+            cpu.set_timer_interval(INTERVAL);
+            cpu.cpu_exec(None).unwrap();
+            assert_eq!(cpu.reg_file.read(11), 200);
+            (cpu.reg_file.read(10), cpu.cycles())
+        });
+
+        // one tick per interval, the last one possibly not taken yet
+        for (ticks, cycles) in [
+            (single, single_cycles),
+            (pipeline, pipeline_cycles),
+            (multistage, multistage_cycles),
+        ] {
+            assert!(ticks > 0);
+            assert!((cycles / INTERVAL - 1..=cycles / INTERVAL).contains(&ticks));
+        }
+    }
+
+    #[test]
+    fn pc_relative_matches_single_cycle() {
+        const DATA: u64 = 0x1234_5678_9abc_def0;
+        // auipc with high immediates, paired with addi and ld
+        let program = [
+            "auipc a0, 0xfffff",
+            "addi a0, a0, 0x7f0",
+            "auipc t0, 0",
+            "ld a1, 0x7f8(t0)",
+            "auipc a2, 0x80000",
+            "lui a3, 0x80000",
+            "ebreak",
+        ];
+        let expected = [BASE - 0x810, DATA, 0x10, 0xffff_ffff_8000_0000];
+
+        let single: Vec<u64> = with_single_cycle(&program, |cpu| {
+            cpu.mwrite::<u64>(BASE + 0x800, DATA).unwrap();
+            assert!(cpu.cpu_exec(None).is_err()); // halted by ebreak
+            (10..14).map(|i| cpu.reg_val(i)).collect()
+        });
+        let pipeline: Vec<u64> = with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.vm.mwrite::<u64>((BASE + 0x800) as usize, DATA).unwrap();
+            cpu.cpu_exec(None).unwrap();
+            (10..14).map(|i| cpu.reg_file.read(i)).collect()
+        });
+        assert_eq!(single, expected);
+        assert_eq!(pipeline, expected);
+    }
+
+    #[test]
+    fn itrace_file_matches_single_cycle() {
+        let program = [
+            "li a0, 0",
+            "li a1, 3",
+            "add a0, a0, a1",
+            "addi a1, a1, -1",
+            "bne a1, zero, -8",
+            "ebreak",
+        ];
+        let single_path = temp_path("itrace-matches-single.log");
+        let pipeline_path = temp_path("itrace-matches-pipeline.log");
+
+        with_single_cycle(&program, |cpu| {
+            cpu.set_itrace_file(&single_path).unwrap();
+            assert!(cpu.cpu_exec(None).is_err()); // halted by ebreak
+        });
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.set_itrace_file(&pipeline_path).unwrap();
+            cpu.cpu_exec(None).unwrap();
+        });
+
+        let single = std::fs::read_to_string(&single_path).unwrap();
+        let pipeline = std::fs::read_to_string(&pipeline_path).unwrap();
+        std::fs::remove_file(&single_path).unwrap();
+        std::fs::remove_file(&pipeline_path).unwrap();
+        // squashed instructions on the wrong path are not recorded
+        assert_eq!(single.lines().count(), 12);
+        assert_eq!(single, pipeline);
+    }
+
+    #[test]
+    fn itrace_file_skips_trapped() {
+        // the div traps to the handler at BASE + 32, which returns after it
+        let program = [
+            "auipc t0, 0",
+            "addi t0, t0, 32",
+            "csrrw zero, mtvec, t0",
+            "div a1, a2, zero",
+            "addi a3, a3, 1",
+            "ebreak",
+            "nop",
+            "nop",
+            "csrrs t1, mepc, zero",
+            "addi t1, t1, 4",
+            "csrrw zero, mepc, t1",
+            "mret",
+        ];
+        let single_path = temp_path("itrace-trapped-single.log");
+        let pipeline_path = temp_path("itrace-trapped-pipeline.log");
+
+        with_single_cycle(&program, |cpu| {
+            cpu.set_itrace_file(&single_path).unwrap();
+            cpu.set_trap_mode(TrapMode::Vectored);
+            cpu.set_div_trap(true);
+            assert!(cpu.cpu_exec(None).is_err()); // halted by ebreak
+        });
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.set_itrace_file(&pipeline_path).unwrap();
+            cpu.set_trap_mode(TrapMode::Vectored);
+            cpu.set_div_trap(true);
+            cpu.cpu_exec(None).unwrap();
+        });
+
+        let single = std::fs::read_to_string(&single_path).unwrap();
+        let pipeline = std::fs::read_to_string(&pipeline_path).unwrap();
+        std::fs::remove_file(&single_path).unwrap();
+        std::fs::remove_file(&pipeline_path).unwrap();
+        let div = format!("{:#x} ", BASE + 12);
+        assert!(!single.lines().any(|line| line.starts_with(&div)));
+        assert_eq!(single.lines().count(), 9);
+        assert_eq!(single, pipeline);
+    }
+
+    #[test]
+    fn entry_override() {
+        // the function at BASE + 8 is run on its own
+        let program = ["li a0, 1", "ebreak", "li a0, 7", "ebreak"];
+        let single_path = temp_path("entry-override-single.log");
+        let pipeline_path = temp_path("entry-override-pipeline.log");
+
+        with_single_cycle(&program, |cpu| {
+            cpu.set_itrace_file(&single_path).unwrap();
+            cpu.set_entry(BASE + 8);
+            assert!(cpu.cpu_exec(None).is_err()); // halted by ebreak
+        });
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.set_itrace_file(&pipeline_path).unwrap();
+            cpu.set_entry(BASE + 8);
+            cpu.cpu_exec(None).unwrap();
+            assert_eq!(cpu.reg_file.read(10), 7);
+        });
+
+        let single = std::fs::read_to_string(&single_path).unwrap();
+        let pipeline = std::fs::read_to_string(&pipeline_path).unwrap();
+        std::fs::remove_file(&single_path).unwrap();
+        std::fs::remove_file(&pipeline_path).unwrap();
+        let first = format!("{:#x} {:08x}", BASE + 8, assemble("li a0, 7").unwrap());
+        assert_eq!(single.lines().next(), Some(first.as_str()));
+        assert_eq!(single, pipeline);
+    }
+
+    #[test]
+    fn verify_catches_wrong_shift() {
+        let program = [
+            "li a0, 5",
+            "slli a0, a0, 1",
+            "sd a0, 0(sp)",
+            "addi a1, a0, 3",
+            "ebreak",
+        ];
+        let path = temp_path("verify-catches-wrong-shift.log");
+        // as if the emulator shifted by 2
+        let reference = |shifted: u64| {
+            let log = format!(
+                "core   0: 3 0x0000000080000000 (0x00500513) x10 0x0000000000000005\n\
+                 core   0: 3 0x0000000080000004 (0x00151513) x10 {shifted:#018x}\n\
+                 core   0: 3 0x0000000080000008 (0x00a13023) mem 0x0000000080000800 {shifted:#018x}\n\
+                 core   0: 3 0x000000008000000c (0x00350593) x11 {:#018x}\n",
+                shifted + 3
+            );
+            std::fs::write(&path, log).unwrap();
+        };
+
+        for (shifted, mismatch) in [(10, None), (20, Some(BASE + 4))] {
+            reference(shifted);
+            let single = with_single_cycle(&program, |cpu| {
+                cpu.set_verify_file(&path).unwrap();
+                cpu.init_blank(BASE, BASE + 0x800);
+                cpu.cpu_exec(None).unwrap_err()
+            });
+            let pipeline = with_pipeline(&program, PipelineConfig::default(), |cpu| {
+                cpu.set_verify_file(&path).unwrap();
+                cpu.reg_file.write(2, BASE + 0x800);
+                cpu.cpu_exec(None)
+            });
+            match mismatch {
+                // the single-cycle CPU stops ebreak with an error
+                None => {
+                    assert!(matches!(single, Error::Execute(_)), "{single:?}");
+                    pipeline.unwrap();
+                }
+                Some(pc) => {
+                    assert!(
+                        matches!(single, Error::Mismatch(at, _) if at == pc),
+                        "{single}"
+                    );
+                    let pipeline = pipeline.unwrap_err();
+                    assert!(
+                        matches!(pipeline, Error::Mismatch(at, _) if at == pc),
+                        "{pipeline}"
+                    );
+                }
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn strict_align() {
+        let program = ["li t0, 42", "sw t0, 1(sp)", "lw a0, 1(sp)", "ebreak"];
+        for strict_align in [false, true] {
+            with_pipeline(&program, PipelineConfig::default(), |cpu| {
+                cpu.set_strict_align(strict_align);
+                cpu.reg_file.write(2, BASE + 0x800);
+                let result = cpu.cpu_exec(None);
+                if strict_align {
+                    assert!(matches!(
+                        result,
+                        Err(Error::Exception(Exception::StoreAddressMisaligned(
+                            0x8000_0801
+                        )))
+                    ));
+                } else {
+                    result.unwrap();
+                    assert_eq!(cpu.reg_file.read(10), 42);
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        // running sum of 1..=100, stored on the stack every iteration
+        let program = [
+            "li a0, 0",
+            "li a1, 100",
+            "add a0, a0, a1",
+            "sd a0, 0(sp)",
+            "addi a1, a1, -1",
+            "bne a1, zero, -12",
+            "ebreak",
+        ];
+        with_pipeline(
+            &program,
+            PipelineConfig {
+                control_policy: ControlPolicy::DynamicPredict,
+                predict_policy: Some(PredictPolicy::TwoBitsPredict),
+                ..Default::default()
+            },
+            |cpu| {
+                cpu.reg_file.write(2, BASE + 0x800);
+                for _ in 0..20 {
+                    cpu.clock().unwrap();
+                }
+                let snapshot = cpu.snapshot();
+
+                for _ in 0..100 {
+                    cpu.clock().unwrap();
+                }
+                assert_ne!(cpu.reg_file.to_array(), snapshot.regs);
+                cpu.restore(&snapshot);
+                assert_eq!(cpu.reg_file.to_array(), snapshot.regs);
+                assert_eq!(cpu.pc.read(), snapshot.pc);
+                assert_eq!(cpu.clock, snapshot.clock);
+                assert_eq!(cpu.itl_m_w.pc, snapshot.itl_m_w.pc);
+                assert_eq!(cpu.vm.memory(), &snapshot.mm[..]);
+
+                // replaying from the snapshot is deterministic
+                let mut results = vec![];
+                for _ in 0..2 {
+                    cpu.restore(&snapshot);
+                    cpu.cpu_exec(None).unwrap();
+                    results.push((
+                        cpu.reg_file.read(10),
+                        cpu.clock,
+                        cpu.cpu_statistics.control_hazard_count,
+                        cpu.mread::<u64>(BASE + 0x800).unwrap(),
+                    ));
+                }
+                assert_eq!(results[0], results[1]);
+                assert_eq!(results[0].0, 5050);
+                assert_eq!(results[0].3, 5050);
+            },
+        );
+    }
+
+    #[test]
+    fn snapshot_restore_stdin() {
+        // read(0, sp, 1), then load the byte read
+        let program = [
+            "li a0, 0",
+            "mv a1, sp",
+            "li a2, 1",
+            "li a7, 63",
+            "ecall",
+            "lbu a3, 0(sp)",
+            "ebreak",
+        ];
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.reg_file.write(2, BASE + 0x800);
+            cpu.set_stdin(b"ab".to_vec());
+            let snapshot = cpu.snapshot();
+
+            // the replay reads the same input again
+            for _ in 0..2 {
+                cpu.restore(&snapshot);
+                cpu.cpu_exec(None).unwrap();
+                assert_eq!(cpu.reg_file.read(13), b'a' as u64);
+            }
+        });
+    }
+
+    #[test]
+    fn control_policy_comparison() {
+        // a backward loop branch taken 9 times out of 10
+        let program = [
+            "li a0, 0",
+            "li a1, 10",
+            "add a0, a0, a1",
+            "addi a1, a1, -1",
+            "bne a1, zero, -8",
+            "ebreak",
+        ];
+        let mut stats = vec![];
+        for control_policy in [
+            ControlPolicy::AllStall,
+            ControlPolicy::AlwaysNotTaken,
+            ControlPolicy::AlwaysTaken,
+            ControlPolicy::DynamicPredict,
+        ] {
+            let predict_policy = match control_policy {
+                ControlPolicy::DynamicPredict => Some(PredictPolicy::TwoBitsPredict),
+                _ => None,
+            };
+            with_pipeline(
+                &program,
+                PipelineConfig {
+                    control_policy,
+                    predict_policy,
+                    ..Default::default()
+                },
+                |cpu| {
+                    cpu.cpu_exec(None).unwrap();
+                    assert_eq!(cpu.reg_file.read(10), 55, "{:?}", control_policy);
+                    stats.push((cpu.cpu_statistics.control_hazard_count, cpu.clock));
+                },
+            );
+        }
+
+        let [all_stall, not_taken, taken, _] = stats[..] else {
+            unreachable!()
+        };
+        // only the loop exit is mispredicted when predicting taken
+        assert_eq!(not_taken.0, 9);
+        assert_eq!(taken.0, 1);
+        assert!(taken.1 < not_taken.1);
+        // every branch stalls for 2 cycles, the not taken one as well
+        assert_eq!(all_stall.0, 10);
+        assert_eq!(all_stall.1, not_taken.1 + 2);
+    }
+
+    #[test]
+    fn prediction_accuracy() {
+        // call a function 10 times in a loop. The nops keep the function
+        // out of the pipeline when ebreak retires.
+        let program = [
+            "li a1, 10",
+            "jal ra, 24",
+            "addi a1, a1, -1",
+            "bne a1, zero, -8",
+            "ebreak",
+            "nop",
+            "nop",
+            "addi a0, a0, 1",
+            "jalr zero, 0(ra)",
+        ];
+        let config = PipelineConfig {
+            control_policy: ControlPolicy::DynamicPredict,
+            predict_policy: Some(PredictPolicy::TwoBitsPredict),
+            ..Default::default()
+        };
+        with_pipeline(&program, config, |cpu| {
+            cpu.cpu_exec(None).unwrap();
+            assert_eq!(cpu.reg_file.read(10), 10);
+            let stats = &cpu.cpu_statistics;
+            assert_eq!(stats.executed_branch_count, 30);
+            // the first jal, ret and bne miss the BTB and are predicted not
+            // taken, and the last bne is predicted taken
+            assert_eq!(stats.control_hazard_count, 4);
+            assert_eq!(stats.direction_hit_count, 26);
+            assert_eq!(
+                percent(stats.direction_hit_count, stats.executed_branch_count),
+                "86.67%"
+            );
+            assert_eq!(
+                (stats.target_predicted_count, stats.target_hit_count),
+                (26, 26)
+            );
+            assert_eq!((stats.btb_hit_count, stats.btb_miss_count), (27, 3));
+            assert_eq!((stats.ras_hit_count, stats.ras_miss_count), (9, 0));
+        });
+    }
+
+    #[test]
+    fn gshare_predict() {
+        // the inner branch alternates between taken and not taken
+        let program = [
+            "li a0, 0",
+            "li a1, 64",
+            "andi t0, a1, 1",
+            "beq t0, zero, 8",
+            "addi a0, a0, 1",
+            "addi a1, a1, -1",
+            "bne a1, zero, -16",
+            "ebreak",
+        ];
+        let mut mispredicts = vec![];
+        for predict_policy in [PredictPolicy::TwoBitsPredict, PredictPolicy::Gshare] {
+            with_pipeline(
+                &program,
+                PipelineConfig {
+                    control_policy: ControlPolicy::DynamicPredict,
+                    predict_policy: Some(predict_policy),
+                    ..Default::default()
+                },
+                |cpu| {
+                    cpu.cpu_exec(None).unwrap();
+                    assert_eq!(cpu.reg_file.read(10), 32, "{:?}", predict_policy);
+                    mispredicts.push(cpu.cpu_statistics.control_hazard_count);
+                },
+            );
+        }
+        // two-bit counters keep missing the alternating pattern, which is
+        // captured by the global history
+        assert!(mispredicts[1] < mispredicts[0] / 2, "{:?}", mispredicts);
+    }
+
+    #[test]
+    fn inst_histogram() {
+        let program = [
+            "li a0, 0",
+            "li a1, 10",
+            "add a0, a0, a1",
+            "addi a1, a1, -1",
+            "bne a1, zero, -8",
+            "ebreak",
+        ];
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.cpu_exec(None).unwrap();
+
+            // li is addi, flushed wrong-path instructions are not counted
+            let histogram = &cpu.cpu_statistics.inst_histogram;
+            assert_eq!(histogram[&Inst64::addi], 12);
+            assert_eq!(histogram[&Inst64::add], 10);
+            assert_eq!(histogram[&Inst64::bne], 10);
+            let total: u64 = histogram.values().sum();
+            assert_eq!(total, cpu.cpu_statistics.executed_inst_count);
+        });
+    }
+
+    #[test]
+    fn write_stats() {
+        let program = ["li a0, 1", "add a1, a0, a0", "ebreak"];
+        with_pipeline(
+            &program,
+            PipelineConfig {
+                data_hazard_policy: DataHazardPolicy::NaiveStall,
+                ..Default::default()
+            },
+            |cpu| {
+                cpu.cpu_exec(None).unwrap();
+
+                let path = temp_path("write-stats.json");
+                cpu.write_stats(&path, StatsFormat::Json).unwrap();
+                let json = std::fs::read_to_string(&path).unwrap();
+                let cpi = cpu.clock as f64 / 3.0;
+                assert!(json.contains(&format!("\"cpi\": {cpi}")), "{json}");
+                assert!(json.contains("\"data_hazard_count\": 2"), "{json}");
+                assert!(
+                    json.contains("\"control_hazard_delayed_cycles\": 0"),
+                    "{json}"
+                );
+                assert!(json.contains("\"executed_inst_count\": 3"), "{json}");
+
+                cpu.write_stats(&path, StatsFormat::Csv).unwrap();
+                let csv = std::fs::read_to_string(&path).unwrap();
+                std::fs::remove_file(&path).unwrap();
+                let lines: Vec<_> = csv.lines().collect();
+                assert_eq!(lines.len(), 2);
+                assert!(lines[0].starts_with("clock,cpi,data_hazard_count,"));
+                assert!(lines[1].starts_with(&format!("{},{cpi},2,", cpu.clock)));
+            },
+        );
+    }
+
+    #[test]
+    fn hazard_trace_load_use() {
+        let program = ["ld a0, 0(sp)", "add a1, a0, a0", "ebreak"];
+        let path = temp_path("hazard-trace-load-use.log");
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.set_hazard_trace(&path).unwrap();
+            cpu.reg_file.write(2, BASE + 0x800);
+            cpu.cpu_exec(None).unwrap();
+        });
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let load_use: Vec<_> = content
+            .lines()
+            .filter(|line| line.split(' ').nth(1) == Some("load-use"))
+            .collect();
+        assert_eq!(load_use.len(), 1, "{content}");
+        let (clock, event) = load_use[0].split_once(' ').unwrap();
+        assert!(clock.parse::<u64>().is_ok());
+        assert_eq!(
+            event,
+            "load-use 0x80000000 -> 0x80000004 ID/EX=bubble IF/ID=stall PC=stall"
+        );
+        // the loaded value is then forwarded from MEM/WB
+        assert!(
+            content.contains("mem-wb 0x80000000 -> 0x80000004 -"),
+            "{content}"
+        );
+    }
+}
@@ -28,6 +28,8 @@ pub struct BranchFlags {
     pub pc_src: bool,
     pub predicted_src: bool,
     pub predicted_target: u64,
+    pub history: u64,  // global history when predicted
+    pub btb_hit: bool, // BTB held the target when predicted
 }
 
 impl BranchFlags {
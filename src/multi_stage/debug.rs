@@ -1,148 +1,141 @@
-use super::cpu::CPU;
+use super::cpu::{CpuSnapshot, CPU};
 use super::phases::{InternalDecodeExec, InternalExecMem, InternalFetchDecode, InternalMemWb};
-use crate::core::{insts::Inst64, reg::REGNAME};
-use crate::{
-    error::{Error, Result},
-    pinst,
+use crate::core::{
+    cond::Condition,
+    insts::disasm,
+    reg::{reg_index, REGNAME},
+    scan::{hex_bytes, Scan},
 };
+use crate::error::{Error, Exception, Result};
 use clap::{Parser, Subcommand};
 use clap_num::maybe_hex;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::io::{self, BufRead, Write};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Once,
+};
 
 pub fn f_pinst(itl: &InternalFetchDecode) -> String {
-    pinst(
+    disasm(
         itl.pc,
         itl.exec_flags.alu_op,
         itl.rd,
         itl.rs1,
         itl.rs2,
+        itl.rs3,
         itl.imm,
     )
 }
 
 pub fn d_pinst(itl: &InternalFetchDecode) -> String {
-    pinst(
+    disasm(
         itl.pc,
         itl.exec_flags.alu_op,
         itl.rd,
         itl.rs1,
         itl.rs2,
+        itl.rs3,
         itl.imm,
     )
 }
 
 pub fn e_pinst(itl: &InternalDecodeExec) -> String {
-    pinst(
+    disasm(
         itl.pc,
         itl.exec_flags.alu_op,
         itl.rd,
         itl.rs1,
         itl.rs2,
+        itl.rs3,
         itl.imm,
     )
 }
 
 pub fn m_pinst(itl: &InternalExecMem) -> String {
-    pinst(itl.pc, itl.alu_op, itl.rd, itl.rs1, itl.rs2, itl.imm)
+    disasm(
+        itl.pc, itl.alu_op, itl.rd, itl.rs1, itl.rs2, itl.rs3, itl.imm,
+    )
 }
 
 pub fn w_pinst(itl: &InternalMemWb) -> String {
-    pinst(itl.pc, itl.alu_op, itl.rd, itl.rs1, itl.rs2, itl.imm)
-}
-
-fn pinst(pc: u64, alu_op: Inst64, rd: u8, rs1: u8, rs2: u8, imm: u64) -> String {
-    use crate::core::insts::Inst64::*;
-    let msg = match alu_op {
-        noop => pinst!(pc, noop),
-        add => pinst!(pc, add, rd, rs1, rs2),
-        addi => pinst!(pc, addi, rd, rs1, imm=>imm),
-        addiw => pinst!(pc, addiw, rd, rs1, imm=>imm),
-        addw => pinst!(pc, addw, rd, rs1, rs2),
-        and => pinst!(pc, and, rd, rs1, rs2),
-        andi => pinst!(pc, andi, rd, rs1, imm=>imm),
-        auipc => pinst!(pc, auipc, rd, imm=>imm),
-        beq => pinst!(pc, beq, rs1, rs2, imm=>offset),
-        bge => pinst!(pc, bge, rs1, rs2, imm=>offset),
-        bgeu => pinst!(pc, bgeu, rs1, rs2, imm=>offset),
-        blt => pinst!(pc, blt, rs1, rs2, imm=>offset),
-        bltu => pinst!(pc, bltu, rs1, rs2, imm=>offset),
-        bne => pinst!(pc, bne, rs1, rs2, imm=>offset),
-        div => pinst!(pc, div, rd, rs1, rs2),
-        divu => pinst!(pc, divu, rd, rs1, rs2),
-        divuw => pinst!(pc, divuw, rd, rs1, rs2),
-        divw => pinst!(pc, divw, rd, rs1, rs2),
-        ebreak => pinst!(pc, ebreak),
-        ecall => pinst!(pc, ecall),
-        jal => pinst!(pc, jal, rd, imm=>offset),
-        jalr => pinst!(pc, jalr, rd, imm(rs1)),
-        lb => pinst!(pc, lb, rd, imm(rs1)),
-        lbu => pinst!(pc, lbu, rd, imm(rs1)),
-        ld => pinst!(pc, ld, rd, imm(rs1)),
-        lh => pinst!(pc, lh, rd, imm(rs1)),
-        lhu => pinst!(pc, lhu, rd, imm(rs1)),
-        lui => pinst!(pc, lui, rd, imm=>imm),
-        lw => pinst!(pc, lw, rd, imm(rs1)),
-        lwu => pinst!(pc, lwu, rd, imm(rs1)),
-        mret => pinst!(pc, mret),
-        mul => pinst!(pc, mul, rd, rs1, rs2),
-        mulh => pinst!(pc, mulh, rd, rs1, rs2),
-        mulhsu => pinst!(pc, mulhsu, rd, rs1, rs2),
-        mulhu => pinst!(pc, mulhu, rd, rs1, rs2),
-        mulw => pinst!(pc, mulw, rd, rs1, rs2),
-        or => pinst!(pc, or, rd, rs1, rs2),
-        ori => pinst!(pc, ori, rd, rs1, imm=>imm),
-        rem => pinst!(pc, rem, rd, rs1, rs2),
-        remu => pinst!(pc, remu, rd, rs1, rs2),
-        remuw => pinst!(pc, remuw, rd, rs1, rs2),
-        remw => pinst!(pc, remw, rd, rs1, rs2),
-        sb => pinst!(pc, sb, rs2, imm(rs1)),
-        sd => pinst!(pc, sd, rs2, imm(rs1)),
-        sh => pinst!(pc, sh, rs2, imm(rs1)),
-        sll => pinst!(pc, sll, rd, rs1, rs2),
-        slli => pinst!(pc, slli, rd, rs1, imm=>imm),
-        slliw => pinst!(pc, slliw, rd, rs1, imm=>imm),
-        sllw => pinst!(pc, sllw, rd, rs1, rs2),
-        slt => pinst!(pc, slt, rd, rs1, rs2),
-        slti => pinst!(pc, slti, rd, rs1, imm=>imm),
-        sltiu => pinst!(pc, sltiu, rd, rs1, imm=>imm),
-        sltu => pinst!(pc, sltu, rd, rs1, rs2),
-        sra => pinst!(pc, sra, rd, rs1, rs2),
-        srai => pinst!(pc, srai, rd, rs1, imm=>imm),
-        sraiw => pinst!(pc, sraiw, rd, rs1, imm=>imm),
-        sraw => pinst!(pc, sraw, rd, rs1, rs2),
-        sret => pinst!(pc, sret),
-        srl => pinst!(pc, srl, rd, rs1, rs2),
-        srli => pinst!(pc, srli, rd, rs1, imm=>imm),
-        srliw => pinst!(pc, srliw, rd, rs1, imm=>imm),
-        srlw => pinst!(pc, srlw, rd, rs1, rs2),
-        sub => pinst!(pc, sub, rd, rs1, rs2),
-        subw => pinst!(pc, subw, rd, rs1, rs2),
-        sw => pinst!(pc, sw, rs2, imm(rs1)),
-        xor => pinst!(pc, xor, rd, rs1, rs2),
-        xori => pinst!(pc, xori, rd, rs1, imm=>imm),
-        _ => format!("Unknown inst {:?}", alu_op),
-    };
-    msg
+    disasm(
+        itl.pc, itl.alu_op, itl.rd, itl.rs1, itl.rs2, itl.rs3, itl.imm,
+    )
 }
 
 const REDB_BUF_SIZE: usize = 64;
 
+// Stepping takes a checkpoint to step back from every REVERSE_INTERVAL
+// cycles, and the last REVERSE_CHECKPOINTS are kept
+const REVERSE_INTERVAL: u64 = 100;
+const REVERSE_CHECKPOINTS: usize = 10;
+
+// Ctrl-C interrupts a running command, or quits REDB at the prompt once the
+// line is read, returning `Error::Interrupted` so that everything is dropped
+static COMMAND_RUNNING: AtomicBool = AtomicBool::new(false);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static INTERRUPT_HANDLER: Once = Once::new();
+
+fn install_interrupt_handler() {
+    INTERRUPT_HANDLER.call_once(|| {
+        ctrlc::set_handler(|| {
+            INTERRUPTED.store(true, Ordering::Relaxed);
+            if !COMMAND_RUNNING.load(Ordering::Relaxed) {
+                println!("\nREDB: interrupted, press Enter to quit");
+            }
+        })
+        .expect("Fail to set Ctrl-C handler");
+    });
+}
+
 pub struct REDB<'a> {
     // Command line input buffer
     buf: String,
 
     // CPU
     cpu: &'a mut CPU<'a>,
+
+    // Breakpoints
+    breakpoints: BTreeSet<u64>,
+
+    // Conditions of the conditional breakpoints
+    conditions: BTreeMap<u64, Condition>,
+
+    // Watchpoints: the bytes watched from each address
+    watchpoints: BTreeMap<u64, u64>,
+
+    // Registers whose change of value stops c
+    reg_watches: BTreeSet<u8>,
+
+    // Saved state to re-run from
+    snapshot: Option<CpuSnapshot>,
+
+    // Recent states to step back from, the oldest first
+    checkpoints: VecDeque<CpuSnapshot>,
+
+    // Last command, repeated on an empty line
+    last_cmd: Option<Commands>,
+
+    // Command lines entered so far
+    history: Vec<String>,
 }
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about, disable_help_flag = true)]
+#[command(
+    author,
+    version,
+    about,
+    long_about,
+    disable_help_flag = true,
+    disable_help_subcommand = true
+)]
 struct DebugArgs {
     #[clap(subcommand)]
     command: Commands,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 enum Commands {
     #[clap(alias = "help")]
     H,
@@ -155,32 +148,229 @@ enum Commands {
         #[clap(default_value_t = 1)]
         n: i32,
     },
+    #[clap(alias = "n")]
+    Next {
+        #[clap(default_value_t = 1)]
+        n: i32,
+    },
+    #[clap(alias = "fin")]
+    Finish,
     Info {
         r: String,
     },
+    #[clap(alias = "b")]
+    Break {
+        #[clap(value_parser=maybe_hex::<u64>)]
+        addr: u64,
+        /// `if REG OP VALUE`
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        cond: Vec<String>,
+    },
+    #[clap(alias = "w")]
+    Watch {
+        #[clap(value_parser=maybe_hex::<u64>)]
+        addr: u64,
+        #[clap(value_parser=maybe_hex::<u64>, default_value_t = 8)]
+        len: u64,
+    },
+    #[clap(alias = "wr")]
+    WatchReg {
+        name: String,
+    },
+    #[clap(alias = "uwr")]
+    UnwatchReg {
+        name: String,
+    },
+    #[clap(alias = "d")]
+    Delete {
+        #[clap(value_parser=maybe_hex::<u64>)]
+        addr: u64,
+    },
     #[clap(alias = "x")]
     Scan {
-        n: u64,
+        /// `N` or `N/FMT`
+        spec: String,
         #[clap(value_parser=maybe_hex::<u64>)]
         vaddr: u64,
     },
     #[clap(alias = "bt")]
     Backtrace,
+    Cmp {
+        a: String,
+        b: String,
+    },
+    Set {
+        target: String,
+        #[clap(value_parser=maybe_hex::<u64>)]
+        value: u64,
+    },
+    Fill {
+        #[clap(value_parser=maybe_hex::<u64>)]
+        vaddr: u64,
+        #[clap(value_parser=maybe_hex::<u64>)]
+        len: u64,
+        #[clap(value_parser=maybe_hex::<u64>)]
+        value: u64,
+        #[clap(default_value_t = 1)]
+        width: u64,
+    },
+    Snapshot,
+    Restore,
+    #[clap(alias = "rsi")]
+    ReverseStep {
+        #[clap(default_value_t = 1)]
+        n: u64,
+    },
+    History,
 }
 
 impl<'a> REDB<'a> {
     pub fn new(cpu: &'a mut CPU<'a>) -> REDB<'a> {
+        install_interrupt_handler();
         REDB {
             buf: String::with_capacity(REDB_BUF_SIZE),
             cpu,
+            breakpoints: BTreeSet::new(),
+            conditions: BTreeMap::new(),
+            watchpoints: BTreeMap::new(),
+            reg_watches: BTreeSet::new(),
+            snapshot: None,
+            checkpoints: VecDeque::new(),
+            last_cmd: None,
+            history: Vec::new(),
+        }
+    }
+
+    /// Register a breakpoint at `addr`.
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.insert(addr);
+        self.conditions.remove(&addr);
+    }
+
+    /// Remove the breakpoint at `addr`, returns whether there was one.
+    pub fn delete_breakpoint(&mut self, addr: u64) -> bool {
+        self.conditions.remove(&addr);
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Print the pipeline diagram of the CPU, if recorded, once the session
+    /// is over.
+    pub fn print_pipeline_diagram(&self) {
+        self.cpu.print_pipeline_diagram();
+    }
+
+    // Register a breakpoint at `addr` which only stops when `cond` holds
+    fn add_conditional_breakpoint(&mut self, addr: u64, cond: Condition) -> Result<()> {
+        // refuse unknown registers now rather than at every hit
+        self.cpu.reg_val_by_name(&cond.reg)?;
+        self.breakpoints.insert(addr);
+        self.conditions.insert(addr, cond);
+        Ok(())
+    }
+
+    // Watch register `name`, x0 never changes and is refused
+    fn add_reg_watch(&mut self, name: &str) -> Result<()> {
+        let idx = reg_index(name).ok_or_else(|| Error::InvalidRegName(name.into()))?;
+        if idx == 0 {
+            return Err(Error::DbgParse(format!(
+                "{name} is hard-wired to zero and never changes"
+            )));
+        }
+        self.reg_watches.insert(idx);
+        Ok(())
+    }
+
+    // Stop watching register `name`, returns whether it was watched
+    fn delete_reg_watch(&mut self, name: &str) -> Result<bool> {
+        let idx = reg_index(name).ok_or_else(|| Error::InvalidRegName(name.into()))?;
+        Ok(self.reg_watches.remove(&idx))
+    }
+
+    // Run `CPU::cpu_exec_break` with the breakpoints and watches set, the
+    // watched registers holding `reg_values` before the run
+    fn exec_break(&mut self, reg_values: &BTreeMap<u8, u64>) -> Result<bool> {
+        self.cpu.cpu_exec_break(
+            &self.breakpoints,
+            &self.conditions,
+            &self.watchpoints,
+            reg_values,
+            &INTERRUPTED,
+        )
+    }
+
+    // whether the CPU is about to fetch at a breakpoint whose condition, if
+    // any, holds
+    fn on_breakpoint(&self) -> bool {
+        let pc = self.cpu.pc();
+        self.breakpoints.contains(&pc)
+            && match self.conditions.get(&pc) {
+                Some(cond) => self
+                    .cpu
+                    .reg_val_by_name(&cond.reg)
+                    .is_ok_and(|v| cond.holds(v)),
+                None => true,
+            }
+    }
+
+    // pc of the breakpoint the CPU stopped at: the instruction waiting to
+    // write back, or the fetch pc at the entry point
+    fn break_pc(&self) -> u64 {
+        match self.cpu.breakpoint_hit(&self.breakpoints, &self.conditions) {
+            Ok(Some(pc)) => pc,
+            _ => self.cpu.pc(),
         }
     }
 
-    pub fn run(&mut self) {
+    /// Run the program to the end without interaction, dumping the CPU state
+    /// at every breakpoint hit. Returns the error which stopped the CPU, e.g.
+    /// [`Error::Interrupted`] on Ctrl-C.
+    pub fn run_batch(&mut self) -> Result<()> {
+        COMMAND_RUNNING.store(true, Ordering::Relaxed);
+        // the entry point is checked before the first instruction is fetched
+        if self.on_breakpoint() {
+            println!("REDB: hit breakpoint at {:#x}", self.cpu.pc());
+            self.print_regs();
+        }
         loop {
+            let reg_values = self.reg_watch_values();
+            match self.exec_break(&reg_values) {
+                Ok(true) => {
+                    println!("REDB: hit breakpoint at {:#x}", self.break_pc());
+                    self.print_regs();
+                }
+                Ok(false) => {
+                    println!("REDB: CPU executed to end.");
+                    break;
+                }
+                Err(Error::Exception(Exception::Breakpoint(pc))) => {
+                    println!("REDB: ebreak at {:#x}, enter debugger", pc);
+                    return self.run();
+                }
+                Err(Error::Interrupted(pc)) => {
+                    println!("REDB: interrupted at {:#x}", pc);
+                    return Err(Error::Interrupted(pc));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Take commands until quit. Returns [`Error::Interrupted`] on Ctrl-C at
+    /// the prompt.
+    pub fn run(&mut self) -> Result<()> {
+        loop {
+            COMMAND_RUNNING.store(false, Ordering::Relaxed);
+            INTERRUPTED.store(false, Ordering::Relaxed);
             print!("(REDB)>>> ");
             io::stdout().flush().expect("Fail to flush");
-            let cmd = match self.listen() {
+            let cmd = self.listen();
+            if INTERRUPTED.load(Ordering::Relaxed) {
+                println!("REDB: Exit REDB");
+                return Err(Error::Interrupted(self.cpu.pc()));
+            }
+            COMMAND_RUNNING.store(true, Ordering::Relaxed);
+            let cmd = match cmd {
                 Ok(cmd) => cmd,
                 Err(e) => {
                     println!("{}", e);
@@ -192,16 +382,42 @@ impl<'a> REDB<'a> {
             }
             match cmd.unwrap() {
                 Commands::H => print_help_info(),
-                Commands::Continue => match self.cpu.cpu_exec(None) {
-                    Ok(_) => {
-                        println!("REDB: CPU executed to end.");
-                        break;
-                    }
-                    Err(e) => {
-                        println!("REDB: CPU raised exception: {}", e);
-                        continue;
+                Commands::Continue => {
+                    self.checkpoint();
+                    // values before the run, a run stops at the first watched store
+                    // or register change
+                    let watch_values = self.watch_values();
+                    let reg_values = self.reg_watch_values();
+                    match self.exec_break(&reg_values) {
+                        Ok(true) => {
+                            match self.cpu.reg_watch_hit(&reg_values) {
+                                Some((idx, old)) => self.print_reg_change(idx, old),
+                                None => self.print_stop(&watch_values),
+                            }
+                            continue;
+                        }
+                        Ok(false) if self.cpu.waiting_for_interrupt() => {
+                            println!("REDB: CPU paused at wfi");
+                            continue;
+                        }
+                        Ok(false) => {
+                            println!("REDB: CPU executed to end.");
+                            break;
+                        }
+                        Err(Error::Exception(Exception::Breakpoint(pc))) => {
+                            println!("REDB: ebreak at {:#x}", pc);
+                            continue;
+                        }
+                        Err(Error::Interrupted(pc)) => {
+                            println!("REDB: interrupted at {:#x}", pc);
+                            continue;
+                        }
+                        Err(e) => {
+                            println!("REDB: CPU raised exception: {}", e);
+                            continue;
+                        }
                     }
-                },
+                }
                 Commands::Quit => {
                     println!("REDB: Exit REDB");
                     break;
@@ -212,7 +428,7 @@ impl<'a> REDB<'a> {
                     }
                     println!("REDB: execute {n} steps");
                     for i in 1..=n {
-                        if let Err(e) = self.cpu.clock() {
+                        if let Err(e) = self.step() {
                             println!("REDB: stopped after executed {i} steps");
                             println!("{e}");
                             break;
@@ -220,15 +436,43 @@ impl<'a> REDB<'a> {
                     }
                     println!("REDB: executed {n} steps");
                 }
+                Commands::Next { n } => {
+                    if n.is_negative() {
+                        println!("REDB: steps cannot be negative");
+                    }
+                    for i in 1..=n {
+                        match self.next() {
+                            Ok(true) => {}
+                            Ok(false) => break,
+                            Err(e) => {
+                                println!("REDB: stopped after executed {i} steps");
+                                println!("{e}");
+                                break;
+                            }
+                        }
+                    }
+                }
+                Commands::Finish => {
+                    self.checkpoint();
+                    match self.finish() {
+                        Ok(()) => {}
+                        Err(Error::Interrupted(pc)) => {
+                            println!("REDB: interrupted at {:#x}", pc)
+                        }
+                        Err(e) => println!("REDB: CPU raised exception: {}", e),
+                    }
+                }
                 Commands::Info { r } => {
                     if r == "r" {
-                        for i in 0..32 {
-                            let reg_name = format!("x{i}");
-                            let reg = self.cpu.reg_val_by_name(&reg_name).unwrap();
-                            println!("{} ({}) \t: {}\t{:#x}", reg_name, REGNAME[i], reg, reg);
-                        }
-                        let pc = self.cpu.pc();
-                        println!("{}\t\t: {}\t{:#x}", "pc", pc, pc);
+                        self.print_regs();
+                    } else if r == "b" {
+                        self.print_breakpoints();
+                    } else if r == "w" {
+                        self.print_watchpoints();
+                    } else if r == "p" || r == "pipeline" {
+                        pipeline_lines(self.cpu)
+                            .iter()
+                            .for_each(|line| println!("{line}"));
                     } else {
                         match self.cpu.reg_val_by_name(&r) {
                             Ok(reg) => {
@@ -240,51 +484,697 @@ impl<'a> REDB<'a> {
                         }
                     }
                 }
-                Commands::Scan { n, vaddr } => {
-                    for i in 0..n {
-                        let p_vaddr = vaddr + 4 * i;
-                        let val = self.cpu.mread::<u64>(p_vaddr);
-                        println!("{:#x}: {:016x}", p_vaddr, val);
+                Commands::Break { addr, cond } => {
+                    // Continue always executes one instruction first, so a
+                    // breakpoint at the current pc only fires when reached again
+                    match cond.split_first() {
+                        None => {
+                            self.add_breakpoint(addr);
+                            println!("REDB: breakpoint at {:#x}", addr);
+                        }
+                        Some((kw, cond)) if kw == "if" => {
+                            match Condition::parse(&cond.join(" "))
+                                .and_then(|cond| self.add_conditional_breakpoint(addr, cond))
+                            {
+                                Ok(()) => println!(
+                                    "REDB: breakpoint at {:#x} if {}",
+                                    addr, self.conditions[&addr]
+                                ),
+                                Err(e) => println!("REDB: {e}"),
+                            }
+                        }
+                        Some((kw, _)) => println!("REDB: expect `if` after ADDR, got `{kw}`"),
+                    }
+                }
+                Commands::Watch { addr, len } => {
+                    if len == 0 {
+                        println!("REDB: nothing to watch in 0 bytes");
+                    } else if self.cpu.mem_contains(addr, len) {
+                        self.watchpoints.insert(addr, len);
+                        println!("REDB: watchpoint at {:#x}, {len} bytes", addr);
+                    } else {
+                        println!("REDB: {:#x} out of memory", addr);
+                    }
+                }
+                Commands::WatchReg { name } => match self.add_reg_watch(&name) {
+                    Ok(()) => println!("REDB: watching register {name}"),
+                    Err(e) => println!("REDB: {e}"),
+                },
+                Commands::UnwatchReg { name } => match self.delete_reg_watch(&name) {
+                    Ok(true) => println!("REDB: stopped watching register {name}"),
+                    Ok(false) => println!("REDB: register {name} is not watched"),
+                    Err(e) => println!("REDB: {e}"),
+                },
+                Commands::Delete { addr } => {
+                    let breakpoint = self.delete_breakpoint(addr);
+                    let watchpoint = self.watchpoints.remove(&addr).is_some();
+                    if breakpoint {
+                        println!("REDB: deleted breakpoint at {:#x}", addr);
+                    }
+                    if watchpoint {
+                        println!("REDB: deleted watchpoint at {:#x}", addr);
+                    }
+                    if !breakpoint && !watchpoint {
+                        println!("REDB: no breakpoint or watchpoint at {:#x}", addr);
                     }
                 }
+                Commands::Scan { spec, vaddr } => match Scan::parse(&spec) {
+                    Ok(scan) => self.scan(scan, vaddr),
+                    Err(e) => println!("REDB: {e}"),
+                },
                 Commands::Backtrace => {
                     println!("REDB: backtrace");
                     self.cpu.backtrace()
                 }
+                Commands::Cmp { a, b } => {
+                    if let Err(e) = self.cmp(&a, &b) {
+                        println!("REDB: {e}");
+                    }
+                }
+                Commands::Set { target, value } => match self.set(&target, value) {
+                    // re-running from older checkpoints would miss the change
+                    Ok(()) => self.checkpoints.clear(),
+                    Err(e) => println!("REDB: {e}"),
+                },
+                Commands::Fill {
+                    vaddr,
+                    len,
+                    value,
+                    width,
+                } => match self.fill(vaddr, len, value, width) {
+                    Ok(()) => self.checkpoints.clear(),
+                    Err(e) => println!("REDB: {e}"),
+                },
+                Commands::Snapshot => {
+                    self.snapshot = Some(self.cpu.snapshot());
+                    println!("REDB: snapshot taken at pc {:#x}", self.cpu.pc());
+                }
+                Commands::Restore => match &self.snapshot {
+                    Some(snapshot) => {
+                        self.cpu.restore(snapshot);
+                        self.checkpoints.clear();
+                        println!("REDB: restored snapshot at pc {:#x}", self.cpu.pc());
+                    }
+                    None => println!("REDB: no snapshot taken"),
+                },
+                Commands::ReverseStep { n } => match self.reverse_step(n) {
+                    Ok(()) => println!(
+                        "REDB: stepped back to clock {} at pc {:#x}",
+                        self.cpu.cycles(),
+                        self.cpu.pc()
+                    ),
+                    Err(e) => println!("REDB: {e}"),
+                },
+                Commands::History => {
+                    for (i, line) in self.history.iter().enumerate() {
+                        println!("{}\t{line}", i + 1);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Print all registers' status (including PC)
+    fn print_regs(&self) {
+        for i in 0..32 {
+            let reg_name = format!("x{i}");
+            let reg = self.cpu.reg_val_by_name(&reg_name).unwrap();
+            println!("{} ({}) \t: {}\t{:#x}", reg_name, REGNAME[i], reg, reg);
+        }
+        let pc = self.cpu.pc();
+        println!("{}\t\t: {}\t{:#x}", "pc", pc, pc);
+    }
+
+    // Keep the current state to step back from, dropping the oldest
+    fn checkpoint(&mut self) {
+        if self
+            .checkpoints
+            .back()
+            .is_some_and(|last| last.cycles() == self.cpu.cycles())
+        {
+            return;
+        }
+        if self.checkpoints.len() == REVERSE_CHECKPOINTS {
+            self.checkpoints.pop_front();
+        }
+        self.checkpoints.push_back(self.cpu.snapshot());
+    }
+
+    // Step one clock, taking a checkpoint every REVERSE_INTERVAL cycles
+    fn step(&mut self) -> Result<()> {
+        let due = self
+            .checkpoints
+            .back()
+            .is_none_or(|last| self.cpu.cycles() >= last.cycles() + REVERSE_INTERVAL);
+        if due {
+            self.checkpoint();
+        }
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            return Err(Error::Interrupted(self.cpu.pc()));
+        }
+        self.cpu.clock()
+    }
+
+    // Go back `n` steps by re-running from the newest checkpoint at least `n`
+    // steps back. A step may take several cycles, so the cycles after each
+    // step are found by running from the checkpoint up to now first.
+    // Output of the program is printed again.
+    fn reverse_step(&mut self, n: u64) -> Result<()> {
+        let now = self.cpu.cycles();
+        for index in (0..self.checkpoints.len()).rev() {
+            let checkpoint = &self.checkpoints[index];
+            self.cpu.restore(checkpoint);
+            let mut boundaries = vec![checkpoint.cycles()];
+            while self.cpu.cycles() < now {
+                self.cpu.clock()?;
+                boundaries.push(self.cpu.cycles());
+            }
+            let Some(target) = boundaries.len().checked_sub(n as usize + 1) else {
+                continue;
+            };
+            let target = boundaries[target];
+            self.cpu.restore(&self.checkpoints[index]);
+            while self.cpu.cycles() < target {
+                self.cpu.clock()?;
+            }
+            self.checkpoints.truncate(index + 1);
+            return Ok(());
+        }
+        Err(Error::DbgParse(format!(
+            "cannot step back {n} steps, only the last {} cycles are kept",
+            REVERSE_INTERVAL * REVERSE_CHECKPOINTS as u64
+        )))
+    }
+
+    // Step one clock, or over the whole call at pc.
+    // Returns whether the step finished without stopping elsewhere.
+    fn next(&mut self) -> Result<bool> {
+        let Some(return_addr) = self.cpu.call_return_addr() else {
+            self.step()?;
+            return Ok(true);
+        };
+        self.checkpoint();
+        let watch_values = self.watch_values();
+        if !self.cpu.cpu_exec_next(
+            return_addr,
+            &self.breakpoints,
+            &self.conditions,
+            &self.watchpoints,
+            &INTERRUPTED,
+        )? {
+            println!("REDB: CPU executed to end.");
+            return Ok(false);
+        }
+        if self.cpu.watch_hit(&self.watchpoints).is_some()
+            || self
+                .cpu
+                .breakpoint_hit(&self.breakpoints, &self.conditions)?
+                .is_some()
+        {
+            self.print_stop(&watch_values);
+            return Ok(false);
+        }
+        println!("REDB: stepped over the call, back at {:#x}", return_addr);
+        Ok(true)
+    }
+
+    // Run until the current function returns and print its return value
+    fn finish(&mut self) -> Result<()> {
+        if self.cpu.call_depth() == 0 {
+            println!("REDB: not inside a called function");
+            return Ok(());
+        }
+        let watch_values = self.watch_values();
+        if !self.cpu.cpu_exec_finish(
+            &self.breakpoints,
+            &self.conditions,
+            &self.watchpoints,
+            &INTERRUPTED,
+        )? {
+            println!("REDB: CPU executed to end.");
+        } else if self.cpu.watch_hit(&self.watchpoints).is_some()
+            || self
+                .cpu
+                .breakpoint_hit(&self.breakpoints, &self.conditions)?
+                .is_some()
+        {
+            self.print_stop(&watch_values);
+        } else {
+            let a0 = self.cpu.reg_val_by_name("a0")?;
+            println!("REDB: returned, a0\t: {}\t{:#x}", a0, a0);
+        }
+        Ok(())
+    }
+
+    // Tell which breakpoint or watchpoint stopped the CPU, with the watched
+    // bytes before the run
+    fn print_stop(&self, watch_values: &BTreeMap<u64, Vec<u8>>) {
+        match self.cpu.watch_hit(&self.watchpoints) {
+            Some(addr) => {
+                let new = self.watch_value(addr, self.watchpoints[&addr]);
+                println!("Watchpoint: {:#x} changed", addr);
+                println!("old: {}", hex_bytes(&watch_values[&addr]));
+                println!("new: {}", hex_bytes(&new));
+            }
+            None => println!("REDB: hit breakpoint at {:#x}", self.break_pc()),
+        }
+    }
+
+    // Current bytes of every watchpoint
+    fn watch_values(&self) -> BTreeMap<u64, Vec<u8>> {
+        self.watchpoints
+            .iter()
+            .map(|(&addr, &len)| (addr, self.watch_value(addr, len)))
+            .collect()
+    }
+
+    // `len` bytes from `addr` in address order
+    fn watch_value(&self, addr: u64, len: u64) -> Vec<u8> {
+        (addr..addr + len)
+            .map(|vaddr| self.cpu.mread::<u8>(vaddr).unwrap_or(0))
+            .collect()
+    }
+
+    // Current value of every watched register
+    fn reg_watch_values(&self) -> BTreeMap<u8, u64> {
+        self.reg_watches
+            .iter()
+            .map(|&idx| {
+                (
+                    idx,
+                    self.cpu.reg_val_by_name(REGNAME[idx as usize]).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    // Tell the register change which stopped the CPU, at the pc of the
+    // instruction writing it
+    fn print_reg_change(&self, idx: u8, old: u64) {
+        let name = REGNAME[idx as usize];
+        let new = self.cpu.reg_val_by_name(name).unwrap();
+        let pc = self.cpu.last_retire().unwrap_or(self.cpu.pc());
+        println!("reg {name}: {old:#x} -> {new:#x} at pc {pc:#x}");
+    }
+
+    // Print `scan` of the memory from `vaddr`
+    fn scan(&self, scan: Scan, vaddr: u64) {
+        let read = |vaddr, size| match size {
+            1 => self.cpu.mread::<u8>(vaddr).map(u64::from),
+            2 => self.cpu.mread::<u16>(vaddr).map(u64::from),
+            4 => self.cpu.mread::<u32>(vaddr).map(u64::from),
+            _ => self.cpu.mread::<u64>(vaddr),
+        };
+        for line in scan.lines(vaddr, read, |pc| self.cpu.disasm_at(pc)) {
+            println!("{line}");
+        }
+    }
+
+    // Print all breakpoints in address order
+    fn print_breakpoints(&self) {
+        if self.breakpoints.is_empty() {
+            println!("REDB: no breakpoints");
+        }
+        for (i, addr) in self.breakpoints.iter().enumerate() {
+            match self.conditions.get(addr) {
+                Some(cond) => println!("{i}\t{:#x}\tif {cond}", addr),
+                None => println!("{i}\t{:#x}", addr),
             }
         }
     }
 
+    // Print all watchpoints in address order, then the watched registers
+    fn print_watchpoints(&self) {
+        if self.watchpoints.is_empty() && self.reg_watches.is_empty() {
+            println!("REDB: no watchpoints");
+        }
+        for (i, (addr, len)) in self.watchpoints.iter().enumerate() {
+            println!("{i}\t{:#x}\t{len} bytes", addr);
+        }
+        for (i, &idx) in self.reg_watches.iter().enumerate() {
+            println!("{}\t{}", self.watchpoints.len() + i, REGNAME[idx as usize]);
+        }
+    }
+
+    // Compare register `a` against register `b`, or the quad-word at address `b`
+    fn cmp(&self, a: &str, b: &str) -> Result<()> {
+        let val_a = self.cpu.reg_val_by_name(a)?;
+        let val_b = match self.cpu.reg_val_by_name(b) {
+            Ok(val) => val,
+            Err(e) => match maybe_hex::<u64>(b) {
+                Ok(vaddr) => self.cpu.mread::<u64>(vaddr)?,
+                Err(_) => return Err(e),
+            },
+        };
+        let diff = val_a.wrapping_sub(val_b);
+        println!("{a}\t: {}\t{:#x}", val_a, val_a);
+        println!("{b}\t: {}\t{:#x}", val_b, val_b);
+        if val_a == val_b {
+            println!("equal");
+        } else {
+            println!(
+                "not equal, {a} - {b}: signed {}, unsigned {}",
+                diff as i64, diff
+            );
+            println!(
+                "{a} {} {b} (signed), {a} {} {b} (unsigned)",
+                if (val_a as i64) < (val_b as i64) {
+                    "<"
+                } else {
+                    ">"
+                },
+                if val_a < val_b { "<" } else { ">" }
+            );
+        }
+        Ok(())
+    }
+
+    // Write `value` into register `target`, or the quad-word at `@ADDR`
+    fn set(&mut self, target: &str, value: u64) -> Result<()> {
+        let old = match target.strip_prefix('@') {
+            Some(addr) => {
+                let vaddr = maybe_hex::<u64>(addr).map_err(Error::DbgParse)?;
+                let old = self.cpu.mread::<u64>(vaddr)?;
+                self.cpu.mwrite::<u64>(vaddr, value)?;
+                old
+            }
+            None => self.cpu.set_reg_by_name(target, value)?,
+        };
+        println!("{target}\t: {:#x} -> {:#x}", old, value);
+        Ok(())
+    }
+
+    // Fill `len` bytes from `vaddr` with `value` repeated every `width` bytes
+    fn fill(&mut self, vaddr: u64, len: u64, value: u64, width: u64) -> Result<()> {
+        if ![1, 2, 4, 8].contains(&width) {
+            return Err(Error::DbgParse(format!(
+                "width must be 1, 2, 4 or 8, got {width}"
+            )));
+        }
+        if !len.is_multiple_of(width) {
+            return Err(Error::DbgParse(format!(
+                "length {len} is not a multiple of width {width}"
+            )));
+        }
+        if width < 8 && value >> (8 * width) != 0 {
+            return Err(Error::DbgParse(format!(
+                "value {value:#x} does not fit in {width} bytes"
+            )));
+        }
+        if !self.cpu.mem_contains(vaddr, len) {
+            return Err(Error::DbgParse(format!(
+                "[{vaddr:#x}, {vaddr:#x} + {len:#x}) out of memory"
+            )));
+        }
+        let count = len / width;
+        match width {
+            1 => self.cpu.fill(vaddr, count, value as u8),
+            2 => self.cpu.fill(vaddr, count, value as u16),
+            4 => self.cpu.fill(vaddr, count, value as u32),
+            _ => self.cpu.fill(vaddr, count, value),
+        }?;
+        println!("REDB: filled {len} bytes at {vaddr:#x} with {value:#x}");
+        Ok(())
+    }
+
     // Listen for user's input
     fn listen(&mut self) -> Result<Option<Commands>> {
         self.buf.clear();
         let stdin = io::stdin();
         let mut stdin = stdin.lock();
-        stdin.read_line(&mut self.buf)?;
+        if stdin.read_line(&mut self.buf)? == 0 {
+            // end of input, nothing left to repeat
+            return Ok(Some(Commands::Quit));
+        }
+        let line = std::mem::take(&mut self.buf);
+        let cmd = self.parse_line(&line);
+        self.buf = line;
+        cmd
+    }
 
-        let buf = self.buf.trim();
-        if buf.is_empty() {
-            return Ok(None);
+    // Parse a command line, an empty one repeats the last command
+    fn parse_line(&mut self, line: &str) -> Result<Option<Commands>> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(self.last_cmd.clone());
         }
+        self.history.push(line.to_string());
 
-        let mut itr: Vec<&str> = self.buf.split_whitespace().collect();
+        let mut itr: Vec<&str> = line.split_whitespace().collect();
         itr.insert(0, "DebugArgs");
         let dbargs = DebugArgs::try_parse_from(itr).map_err(|e| Error::DbgParse(e.to_string()))?;
+        // quitting by a stray Enter would lose the session
+        self.last_cmd = match dbargs.command {
+            Commands::Quit => None,
+            ref cmd => Some(cmd.clone()),
+        };
         Ok(Some(dbargs.command))
     }
 }
 
+/// The instruction in each pipeline register with its state.
+fn pipeline_lines(cpu: &CPU) -> Vec<String> {
+    let [f_d, d_e, e_m, m_w] = cpu.pipeline_states();
+    let mut regs = vec![
+        ("IF/ID", f_d, f_pinst(cpu.itl_f_d())),
+        ("ID/EX", d_e, e_pinst(cpu.itl_d_e())),
+        ("EX/MEM", e_m, m_pinst(cpu.itl_e_m())),
+    ];
+    // MEM1/MEM2 moves on with MEM/WB
+    if let Some(itl_m_m) = cpu.itl_m_m() {
+        regs.push(("MEM1/MEM2", m_w, w_pinst(itl_m_m)));
+    }
+    regs.push(("MEM/WB", m_w, w_pinst(cpu.itl_m_w())));
+    regs.into_iter()
+        .map(|(name, state, inst)| format!("{name:6}\t{:6}\t{inst}", format!("{state:?}")))
+        .collect()
+}
+
 fn print_help_info() {
     let help = r#"
 REDB: RISC-V Environment DeBugger. 
     Command     Example         Detail
     help        help            Print this help.
-    c           c               Execute the program to end.
+    c           c               Execute the program to end or the next breakpoint.
     q           q               Quit the debugger (also the simulator).
     si [N]      si 10           Step the program for N steps and pause (N default to 1).
+    n [N]       n               Like si, but run a call (jal/jalr linking ra) at pc until it returns.
+    fin         fin             Run until the current function returns and print a0.
+    Ctrl-C stops c, si, n and fin where they are, and at the prompt quits once Enter is pressed.
     info <reg>  info sp         Print a register's status.
     info r      info r          Print all registers' status (including PC).
-    x N ADDR    x 10 0x80000000 Print N quad-words starting at ADDR.
+    info b      info b          List all breakpoints.
+    info w      info w          List all watchpoints and watched registers.
+    info p      info pipeline   Print the instruction in each pipeline register, and its state
+                in the next clock if a stall or bubble is already decided.
+    b ADDR      b 0x80000000    Set a breakpoint at ADDR.
+    b ADDR if REG OP V  b 0x80000000 if a0 == 5
+                Set a breakpoint at ADDR which only stops c when REG OP V holds,
+                with OP in == != < > <= >= and REG, V compared as signed.
+    d ADDR      d 0x80000000    Delete the breakpoint and the watchpoint at ADDR.
+    w ADDR [L]  w 0x80001000 4  Stop c when a store writes into the L bytes from ADDR (L default to 8).
+    wr REG      wr sp           Stop c when a new value is written into REG, except x0.
+    uwr REG     uwr sp          Stop watching REG.
+    x N[/F] ADDR  x 10/w 0x80000000
+                Print N units starting at ADDR, with F in b (u8), h (u16), w (u32),
+                g (u64, default) or i (instruction).
+    cmp A B     cmp a0 a1       Compare two registers, or a register with the quad-word at address B.
+    set T V     set a0 0x10     Set register T, or the quad-word at address T written as @ADDR, to V.
+    fill A L V [W]  fill 0x80000000 64 0xaa
+                Fill L bytes from A with V repeated every W bytes (W in 1/2/4/8, default to 1).
+    snapshot    snapshot        Save the CPU state and memory.
+    restore     restore         Go back to the saved snapshot, which could be restored again.
+    rsi [N]     rsi 3           Step back N steps (N default to 1) by re-running from a recent
+                state, as far as about 1000 clocks. Output of the program is printed again.
+    history     history         List the commands entered so far.
+    <Enter>                     Repeat the last command, e.g. si, except q.
 "#;
     println!("{help}")
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::callstack::CallStack;
+    use crate::core::{asm::assemble, vm::VirtualMemory};
+    use crate::multi_stage::cpu::{DataHazardPolicy, PipelineConfig};
+    use std::collections::HashMap;
+
+    #[test]
+    fn conditional_break_args() {
+        let args = "DebugArgs b 0x80001234 if a1 < -1".split_whitespace();
+        match DebugArgs::try_parse_from(args).unwrap().command {
+            Commands::Break { addr, cond } => {
+                assert_eq!(addr, 0x80001234);
+                assert_eq!(cond, ["if", "a1", "<", "-1"]);
+            }
+            cmd => panic!("{cmd:?}"),
+        }
+    }
+
+    #[test]
+    fn repeat_last_command() {
+        let mut vm = VirtualMemory::with_base(0x8000_0000, 0x1000, false);
+        let symbol_map = HashMap::new();
+        let mut callstack = CallStack::new(&symbol_map, false);
+        let mut cpu = CPU::new(
+            &mut vm,
+            &mut callstack,
+            PipelineConfig {
+                data_hazard_policy: DataHazardPolicy::NaiveStall,
+                ..Default::default()
+            },
+        );
+        let mut redb = REDB::new(&mut cpu);
+
+        assert!(redb.parse_line("\n").unwrap().is_none());
+        assert!(matches!(
+            redb.parse_line("si 3\n").unwrap(),
+            Some(Commands::Step { n: 3 })
+        ));
+        assert!(matches!(
+            redb.parse_line("  \n").unwrap(),
+            Some(Commands::Step { n: 3 })
+        ));
+        // a bad line keeps the last command, quit is never repeated
+        assert!(redb.parse_line("bogus\n").is_err());
+        assert!(matches!(
+            redb.parse_line("\n").unwrap(),
+            Some(Commands::Step { n: 3 })
+        ));
+        assert!(matches!(
+            redb.parse_line("q\n").unwrap(),
+            Some(Commands::Quit)
+        ));
+        assert!(redb.parse_line("\n").unwrap().is_none());
+        assert_eq!(redb.history, ["si 3", "bogus", "q"]);
+    }
+
+    #[test]
+    fn conditional_break_sees_older_writes() {
+        const BASE: u64 = 0x8000_0000;
+        let mut vm = VirtualMemory::with_base(BASE as usize, 0x1000, false);
+        // a0 is written by the instruction right before the breakpoint
+        let program = [
+            "addi a0, a0, 1",
+            "mv a1, a0",
+            "li t0, 5",
+            "bne a0, t0, -12",
+            "ebreak",
+        ];
+        for (i, line) in program.iter().enumerate() {
+            vm.write_inst(BASE as usize + 4 * i, assemble(line).unwrap())
+                .unwrap();
+        }
+        let symbol_map = HashMap::new();
+        let mut callstack = CallStack::new(&symbol_map, false);
+        let mut cpu = CPU::new(
+            &mut vm,
+            &mut callstack,
+            PipelineConfig::default(),
+        );
+        cpu.set_entry(BASE);
+        let mut redb = REDB::new(&mut cpu);
+        let cond = Condition::parse("a0 == 3").unwrap();
+        redb.add_conditional_breakpoint(BASE + 4, cond).unwrap();
+
+        assert!(redb.exec_break(&BTreeMap::new()).unwrap());
+        assert_eq!(redb.break_pc(), BASE + 4);
+        assert_eq!(redb.cpu.reg_val_by_name("a0").unwrap(), 3);
+        assert_eq!(redb.cpu.reg_val_by_name("a1").unwrap(), 2);
+        assert!(!redb.exec_break(&BTreeMap::new()).unwrap());
+        assert_eq!(redb.cpu.reg_val_by_name("a1").unwrap(), 5);
+    }
+
+    #[test]
+    fn watch_reg() {
+        const BASE: u64 = 0x8000_0000;
+        let mut vm = VirtualMemory::with_base(BASE as usize, 0x1000, false);
+        // t0 is written with its own value first, which does not stop
+        let program = ["li t0, 0", "li t1, 3", "addi t0, t1, 4", "ebreak"];
+        for (i, line) in program.iter().enumerate() {
+            vm.write_inst(BASE as usize + 4 * i, assemble(line).unwrap())
+                .unwrap();
+        }
+        let symbol_map = HashMap::new();
+        let mut callstack = CallStack::new(&symbol_map, false);
+        let mut cpu = CPU::new(
+            &mut vm,
+            &mut callstack,
+            PipelineConfig::default(),
+        );
+        cpu.set_entry(BASE);
+        let mut redb = REDB::new(&mut cpu);
+
+        assert!(matches!(
+            redb.parse_line("wr t0\n").unwrap(),
+            Some(Commands::WatchReg { name }) if name == "t0"
+        ));
+        assert!(redb.add_reg_watch("zero").is_err());
+        assert!(redb.add_reg_watch("x0").is_err());
+        assert!(redb.add_reg_watch("pc").is_err());
+        redb.add_reg_watch("t0").unwrap();
+
+        let reg_values = redb.reg_watch_values();
+        assert!(redb.exec_break(&reg_values).unwrap());
+        assert_eq!(redb.cpu.reg_watch_hit(&reg_values), Some((5, 0)));
+        assert_eq!(redb.cpu.reg_val_by_name("t0").unwrap(), 7);
+        assert_eq!(redb.cpu.last_retire(), Some(BASE + 8));
+
+        assert!(matches!(
+            redb.parse_line("uwr t0\n").unwrap(),
+            Some(Commands::UnwatchReg { name }) if name == "t0"
+        ));
+        assert!(redb.delete_reg_watch("t0").unwrap());
+        assert!(!redb.delete_reg_watch("t0").unwrap());
+        assert!(redb.delete_reg_watch("pc").is_err());
+        assert!(redb.reg_watch_values().is_empty());
+        assert!(!redb.exec_break(&BTreeMap::new()).unwrap());
+    }
+
+    #[test]
+    fn reverse_step() {
+        // at 0, where the pipeline starts fetching
+        let mut vm = VirtualMemory::with_base(0, 0x1000, false);
+        let program = ["addi a0, a0, 1", "sd a0, 0(sp)", "j -8"];
+        for (i, line) in program.iter().enumerate() {
+            vm.write_inst(4 * i, assemble(line).unwrap()).unwrap();
+        }
+        let symbol_map = HashMap::new();
+        let mut callstack = CallStack::new(&symbol_map, false);
+        let mut cpu = CPU::new(
+            &mut vm,
+            &mut callstack,
+            PipelineConfig::default(),
+        );
+        let sp = 0x800;
+        cpu.set_reg_by_name("sp", sp).unwrap();
+        let mut redb = REDB::new(&mut cpu);
+
+        // pc, a0 and the stored quad-word after each step
+        let state = |redb: &REDB| {
+            (
+                redb.cpu.pc(),
+                redb.cpu.reg_val_by_name("a0").unwrap(),
+                redb.cpu.mread::<u64>(sp).unwrap(),
+            )
+        };
+        let mut states = vec![state(&redb)];
+        for _ in 0..10 {
+            redb.step().unwrap();
+            states.push(state(&redb));
+        }
+        assert_ne!(states[7], states[10]);
+
+        redb.reverse_step(3).unwrap();
+        assert_eq!(state(&redb), states[7]);
+        assert_eq!(redb.cpu.cycles(), 7);
+        // and forward again the same way
+        redb.step().unwrap();
+        assert_eq!(state(&redb), states[8]);
+        redb.reverse_step(8).unwrap();
+        assert_eq!(state(&redb), states[0]);
+        assert!(redb.reverse_step(1).is_err());
+    }
+}
@@ -38,6 +38,7 @@ pub fn decode(
         wb_flags: itl_f_d.wb_flags,
         branch_flags: itl_f_d.branch_flags,
         pc: itl_f_d.pc,
+        inst_len: itl_f_d.inst_len,
         rs1: itl_f_d.rs1,
         rs2: itl_f_d.rs2,
         rs3: itl_f_d.rs3,
@@ -48,7 +49,11 @@ pub fn decode(
         forward_a: 0, // default using self
         forward_b: 0, // default using self
         ex_mem_forward: 0, // set by data forwarding logic
+        mem_mem_forward: 0, // set by data forwarding logic
         mem_wb_forward: 0, // set by data forwarding logic
+        fetch_fault: itl_f_d.fetch_fault,
+        illegal_inst: itl_f_d.illegal_inst,
+        unsupported: itl_f_d.unsupported,
     };
 
     itl_d_e
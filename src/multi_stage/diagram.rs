@@ -0,0 +1,294 @@
+//! Pipeline diagram, the classic chart of instructions flowing through the
+//! pipeline: one row per instruction, one column per cycle.
+
+use std::collections::VecDeque;
+
+use log::info;
+
+use super::cpu::PipelineState;
+
+/// IF, ID, EX, MEM and WB
+const STAGE_NAMES: [char; 5] = ['F', 'D', 'E', 'M', 'W'];
+/// IF, ID, EX, MEM1, MEM2 and WB
+const EXTRA_MEM_STAGE_NAMES: [char; 6] = ['F', 'D', 'E', 'M', 'M', 'W'];
+/// Cell of an instruction held in the same stage as in the cycle before
+const STALL: char = '-';
+
+#[derive(Debug)]
+struct Row {
+    label: String,
+    first_cycle: u64,
+    cells: Vec<char>, // from first_cycle on, ' ' where not in the pipeline
+}
+
+impl Row {
+    fn mark(&mut self, cycle: u64, cell: char) {
+        let i = (cycle - self.first_cycle) as usize;
+        if self.cells.len() <= i {
+            self.cells.resize(i + 1, ' ');
+        }
+        self.cells[i] = cell;
+    }
+}
+
+/// Instructions are told apart by their fetch order, so that every run of a
+/// loop gets its own row.
+#[derive(Debug)]
+pub struct PipelineDiagram {
+    // only the last `limit` instructions are kept
+    limit: usize,
+    rows: VecDeque<Row>,
+    // fetch order of rows[0]
+    first_id: usize,
+    names: &'static [char],
+    // fetch order of the instructions in IF, ID, EX, MEM and WB
+    stages: Vec<Option<usize>>,
+    // whether the stage holds the same instruction as in the cycle before
+    held: Vec<bool>,
+}
+
+impl PipelineDiagram {
+    /// Diagram of the last `limit` instructions, through MEM1 and MEM2
+    /// instead of MEM with `extra_mem_stage`.
+    pub fn new(limit: usize, extra_mem_stage: bool) -> PipelineDiagram {
+        let names: &[char] = if extra_mem_stage {
+            &EXTRA_MEM_STAGE_NAMES
+        } else {
+            &STAGE_NAMES
+        };
+        PipelineDiagram {
+            limit,
+            rows: VecDeque::new(),
+            first_id: 0,
+            names,
+            stages: vec![None; names.len()],
+            held: vec![false; names.len()],
+        }
+    }
+
+    /// Record the clock which began at `cycle` and took `cycles` cycles.
+    /// `fetched` labels the instruction fetched in it, if any.
+    /// `states` are the states of the pipeline registers in front of each
+    /// stage: pc, IF/ID, ID/EX, EX/MEM (, MEM1/MEM2) and MEM/WB. `occupied`
+    /// tells whether the pipeline registers hold an instruction afterwards.
+    pub fn clock(
+        &mut self,
+        cycle: u64,
+        cycles: u64,
+        fetched: Option<String>,
+        states: &[PipelineState],
+        occupied: &[bool],
+    ) {
+        assert_eq!(states.len(), self.names.len());
+        assert_eq!(occupied.len(), self.names.len() - 1);
+        if self.stages[0].is_none() {
+            if let Some(label) = fetched {
+                self.push_row(label, cycle);
+            }
+        }
+
+        // stalled instructions show up as `-` after their first cycle in a
+        // stage, and so does a clock taking more than one cycle
+        for (stage, id) in self.stages.clone().into_iter().enumerate() {
+            let name = if self.held[stage] {
+                STALL
+            } else {
+                self.names[stage]
+            };
+            let Some(row) = id.and_then(|id| self.row_mut(id)) else {
+                continue;
+            };
+            row.mark(cycle, name);
+            for extra in 1..cycles {
+                row.mark(cycle + extra, STALL);
+            }
+        }
+
+        let mut stages = vec![None; self.names.len()];
+        let mut held = vec![false; self.names.len()];
+        // the fetched instruction is fetched again only if it did not go
+        // into IF/ID
+        if states[0] == PipelineState::Stall && states[1] != PipelineState::Normal {
+            stages[0] = self.stages[0];
+            held[0] = stages[0].is_some();
+        }
+        for stage in 1..self.names.len() {
+            if !occupied[stage - 1] {
+                continue;
+            }
+            match states[stage] {
+                PipelineState::Normal => stages[stage] = self.stages[stage - 1],
+                PipelineState::Bubble => {}
+                PipelineState::Stall => {
+                    stages[stage] = self.stages[stage];
+                    held[stage] = stages[stage].is_some();
+                }
+            }
+        }
+        self.stages = stages;
+        self.held = held;
+    }
+
+    fn push_row(&mut self, label: String, cycle: u64) {
+        let id = self.first_id + self.rows.len();
+        self.rows.push_back(Row {
+            label,
+            first_cycle: cycle,
+            cells: Vec::new(),
+        });
+        if self.rows.len() > self.limit {
+            self.rows.pop_front();
+            self.first_id += 1;
+        }
+        self.stages[0] = Some(id);
+    }
+
+    fn row_mut(&mut self, id: usize) -> Option<&mut Row> {
+        id.checked_sub(self.first_id)
+            .and_then(|i| self.rows.get_mut(i))
+    }
+
+    /// The chart as lines of text. The header holds the last two digits of
+    /// each cycle.
+    pub fn lines(&self) -> Vec<String> {
+        let Some(start) = self.rows.iter().map(|row| row.first_cycle).min() else {
+            return Vec::new();
+        };
+        let width = self.rows.iter().map(|row| row.label.len()).max().unwrap();
+        let end = self
+            .rows
+            .iter()
+            .map(|row| row.first_cycle + row.cells.len() as u64)
+            .max()
+            .unwrap();
+
+        let mut lines = Vec::with_capacity(self.rows.len() + 1);
+        let header: String = (start..end)
+            .map(|cycle| format!("{:>3}", cycle % 100))
+            .collect();
+        lines.push(format!("{:width$}{}", "", header));
+        for row in &self.rows {
+            let indent = "   ".repeat((row.first_cycle - start) as usize);
+            let cells: String = row
+                .cells
+                .iter()
+                .map(|cell| format!("{:>3}", cell))
+                .collect();
+            lines.push(format!(
+                "{:width$}{}{}",
+                row.label,
+                indent,
+                cells.trim_end()
+            ));
+        }
+        lines
+    }
+
+    pub fn print(&self) {
+        let Some(start) = self.rows.iter().map(|row| row.first_cycle).min() else {
+            return;
+        };
+        info!(
+            "Pipeline diagram of the last {} instructions, from cycle {}:",
+            self.rows.len(),
+            start
+        );
+        for line in self.lines() {
+            info!("{line}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use PipelineState::*;
+
+    #[test]
+    fn diagram_test() {
+        let mut diagram = PipelineDiagram::new(3, false);
+        let label = |name: &str| Some(name.to_string());
+        // ld, then add stalled for one cycle by the load-use hazard, and sub
+        // fetched again. ld is dropped when and comes in.
+        diagram.clock(
+            0,
+            1,
+            label("ld"),
+            &[Normal; 5],
+            &[true, false, false, false],
+        );
+        diagram.clock(
+            1,
+            1,
+            label("add"),
+            &[Normal; 5],
+            &[true, true, false, false],
+        );
+        diagram.clock(
+            2,
+            1,
+            label("sub"),
+            &[Stall, Stall, Bubble, Normal, Normal],
+            &[true, false, true, false],
+        );
+        diagram.clock(3, 1, label("sub"), &[Normal; 5], &[true, true, false, true]);
+        diagram.clock(4, 1, label("and"), &[Normal; 5], &[true, true, true, false]);
+        diagram.clock(5, 1, None, &[Normal; 5], &[false, true, true, true]);
+        assert_eq!(
+            diagram.lines(),
+            [
+                "     1  2  3  4  5",
+                "add  F  D  -  E  M",
+                "sub     F  -  D  E",
+                "and           F  D",
+            ]
+        );
+
+        let mut diagram = PipelineDiagram::new(10, false);
+        // a clock of 3 cycles
+        diagram.clock(
+            0,
+            1,
+            label("div"),
+            &[Normal; 5],
+            &[true, false, false, false],
+        );
+        diagram.clock(
+            1,
+            1,
+            label("nop"),
+            &[Normal; 5],
+            &[true, true, false, false],
+        );
+        diagram.clock(2, 3, None, &[Normal; 5], &[false, true, true, false]);
+        assert_eq!(
+            diagram.lines(),
+            [
+                "     0  1  2  3  4",
+                "div  F  D  E  -  -",
+                "nop     F  D  -  -"
+            ]
+        );
+
+        // MEM1 and MEM2 with the extra MEM stage
+        let mut diagram = PipelineDiagram::new(10, true);
+        diagram.clock(
+            0,
+            1,
+            label("lw"),
+            &[Normal; 6],
+            &[true, false, false, false, false],
+        );
+        for stage in 1..=5 {
+            let mut occupied = [false; 5];
+            if stage < 5 {
+                occupied[stage] = true;
+            }
+            diagram.clock(stage as u64, 1, None, &[Normal; 6], &occupied);
+        }
+        assert_eq!(
+            diagram.lines(),
+            ["    0  1  2  3  4  5", "lw  F  D  E  M  M  W"]
+        );
+    }
+}
@@ -3,27 +3,48 @@ use std::ops::{BitAnd, BitOr, BitXor};
 use log::{trace, warn};
 
 use crate::{
-    callstack::CallStack, core::insts::{
-        get_high_64_bit, sext, trunc_to_16_bit, trunc_to_32_bit, trunc_to_5_bit,
-        trunc_to_5_bit_and_check, trunc_to_6_bit, trunc_to_8_bit, BYTE_BITWIDTH, HALF_BITWIDTH,
-        WORD_BITWIDTH,
-    }, error::{Error, Exception, Result}, multi_stage::{ctrl_flags::BranchFlags, debug::e_pinst}
+    callstack::CallStack, core::{insts::{
+        div_by_zero, div_rem, mulh_32, mulh_64, mulhsu_32, mulhsu_64, mulhu_32, mulhu_64, pc_upper_imm, sext, trunc_to_16_bit, trunc_to_32_bit, trunc_to_5_bit,
+        trunc_to_5_bit_and_check, trunc_to_8_bit, upper_imm, BYTE_BITWIDTH, HALF_BITWIDTH,
+        WORD_BITWIDTH, Xlen,
+    }, vm::check_align}, csr::Csr, error::{Error, Exception, Result}, multi_stage::{ctrl_flags::BranchFlags, debug::e_pinst}
 };
 
 use super::{branch_predict::RAS, phases::{InternalDecodeExec, InternalExecMem}};
 
+#[allow(clippy::too_many_arguments)]
 pub fn exec(
     itl_d_e: &InternalDecodeExec,
     pipeline_info: bool,
     callstack: &mut CallStack,
+    clock: u64,
     ras: Option<&mut RAS>,
+    csr: &mut Csr,
+    xlen: Xlen,
+    strict_align: bool,
+    div_trap: bool,
 ) -> Result<(InternalExecMem, u64, u64)> {
     use crate::core::insts::Inst64::*;
     if pipeline_info {
         trace!("EX : {}", e_pinst(itl_d_e));
     }
+    // raised here rather than at IF, where the fetch may be on a wrong path
+    if let Some(vaddr) = itl_d_e.fetch_fault {
+        return Err(Exception::InstructionAccessFault(vaddr).into());
+    }
+    if let Some(inst) = itl_d_e.illegal_inst {
+        return Err(Exception::IllegalInstruction(itl_d_e.pc, inst).into());
+    }
+    if let Some(inst) = itl_d_e.unsupported {
+        return Err(Error::Unsupported {
+            inst,
+            pc: itl_d_e.pc,
+            raw_inst: itl_d_e.raw_inst,
+        });
+    }
 
     let ex_mem_forward = itl_d_e.ex_mem_forward;
+    let mem_mem_forward = itl_d_e.mem_mem_forward;
     let mem_wb_forward = itl_d_e.mem_wb_forward;
 
     // data forward
@@ -41,6 +62,12 @@ pub fn exec(
             }
             mem_wb_forward
         }
+        0b11 => {
+            if pipeline_info {
+                warn!("ALU SRC A received data from MEM1/MEM2: {mem_mem_forward}");
+            }
+            mem_mem_forward
+        }
         _ => unreachable!("Data forwarding A"),
     };
 
@@ -58,15 +85,25 @@ pub fn exec(
             }
             mem_wb_forward
         }
+        0b11 => {
+            if pipeline_info {
+                warn!("ALU SRC B received data from MEM1/MEM2: {mem_mem_forward}");
+            }
+            mem_mem_forward
+        }
         _ => unreachable!("Data forwarding B"),
     };
 
+    // operate on values sign-extended from XLEN bits
+    let src1 = xlen.sext(src1);
+    let src2 = xlen.sext(src2);
+
     let imm = itl_d_e.imm;
     let pc = itl_d_e.pc;
 
     let mut pc_src = itl_d_e.branch_flags.pc_src;
-    let new_pc_0 = pc.wrapping_add(4);
-    let mut new_pc_1 = pc.wrapping_add(4);
+    let new_pc_0 = xlen.trunc(pc.wrapping_add(itl_d_e.inst_len));
+    let mut new_pc_1 = pc.wrapping_add(itl_d_e.inst_len);
 
     let mut mem_addr = 0;
     let mem_bitwidth = match itl_d_e.exec_flags.alu_op {
@@ -85,12 +122,9 @@ pub fn exec(
 
     let alu_out = match itl_d_e.exec_flags.alu_op {
         noop => 0,
-        auipc => pc.wrapping_add((imm as u64) << 12),
-        lui => {
-            let mask: u64 = !0b1111_1111_1111;
-            let result = ((imm << 12) as u64) & mask;
-            result
-        }
+        auipc => pc_upper_imm(pc, imm),
+        // the 32-bit result is sign-extended on RV64
+        lui => upper_imm(imm),
         lb | lh | lw | ld | lbu | lhu | lwu => {
             mem_addr = src1.wrapping_add(imm);
             0
@@ -119,6 +153,13 @@ pub fn exec(
             let result = src2;
             result
         }
+        lr_w | lr_d | sc_w | sc_d | amoswap_w | amoswap_d | amoadd_w | amoadd_d | amoxor_w
+        | amoxor_d | amoand_w | amoand_d | amoor_w | amoor_d | amomin_w | amomin_d | amomax_w
+        | amomax_d | amominu_w | amominu_d | amomaxu_w | amomaxu_d => {
+            // read-modify-write at MEM with x[rs2]
+            mem_addr = src1;
+            src2
+        }
         jal => {
             pc_src = true;
             new_pc_1 = pc.wrapping_add(imm);
@@ -131,7 +172,7 @@ pub fn exec(
                     ras.push(result);
                 }
             }
-            callstack.call(pc, new_pc_1);
+            callstack.call(pc, new_pc_1, clock);
 
             result
         }
@@ -143,7 +184,7 @@ pub fn exec(
             // ret
             // 00008067          	jalr	zero,0(ra)
             if itl_d_e.rd == 0 && itl_d_e.imm == 0 && itl_d_e.rs1 == 1 {
-                callstack.ret(pc);
+                callstack.ret(pc, clock);
             }
 
             result
@@ -192,10 +233,7 @@ pub fn exec(
             }
             0
         }
-        ecall => {
-            todo!("ecall");
-        }
-        ebreak => 0,
+        ecall | ebreak | wfi => 0, // handled at WB
         add => src1.wrapping_add(src2),
         addi => src1.wrapping_add(imm),
         addiw => {
@@ -249,19 +287,19 @@ pub fn exec(
         and => src1.bitand(src2),
         andi => src1.bitand(imm),
         sll => {
-            let t_src2 = trunc_to_6_bit(src2); // RV64
-            let result = src1.wrapping_shl(t_src2 as u32);
+            let t_src2 = xlen.shamt(src2);
+            let result = src1.wrapping_shl(t_src2);
             result
         }
         slli => {
-            let shamt = trunc_to_6_bit(imm);
-            let result = src1.wrapping_shl(shamt as u32);
+            let shamt = xlen.shamt(imm);
+            let result = src1.wrapping_shl(shamt);
             result
         }
         slliw => {
             let (shamt, legal) = trunc_to_5_bit_and_check(imm);
             if !legal {
-                return Err(Error::Exception(Exception::IllegalInstruction));
+                return Err(Exception::IllegalInstruction(itl_d_e.pc, itl_d_e.raw_inst).into());
             }
             let result = src1.wrapping_shl(shamt as u32);
             let result = sext(trunc_to_32_bit(result), WORD_BITWIDTH);
@@ -275,21 +313,21 @@ pub fn exec(
             result as u64
         }
         srl => {
-            let t_src2 = trunc_to_6_bit(src2); // RV64
-                                               // i64 shr automatically fill high bits with 0-bit
-            let result = (src1 as u64).wrapping_shr(t_src2 as u32);
+            let t_src2 = xlen.shamt(src2);
+            // u64 shr automatically fill high bits with 0-bit
+            let result = xlen.trunc(src1).wrapping_shr(t_src2);
             result as u64
         }
         srli => {
-            let shamt = trunc_to_6_bit(imm);
-            let result = (src1 as u64).wrapping_shr(shamt as u32);
+            let shamt = xlen.shamt(imm);
+            let result = xlen.trunc(src1).wrapping_shr(shamt);
             result
         }
         srliw => {
             let t_src1: u64 = trunc_to_32_bit(src1);
             let (shamt, legal) = trunc_to_5_bit_and_check(imm);
             if !legal {
-                return Err(Error::Exception(Exception::IllegalInstruction));
+                return Err(Exception::IllegalInstruction(itl_d_e.pc, itl_d_e.raw_inst).into());
             }
             let result = sext(
                 trunc_to_32_bit(t_src1.wrapping_shr(shamt as u32)),
@@ -305,21 +343,21 @@ pub fn exec(
             result as u64
         }
         sra => {
-            let t_src2 = trunc_to_6_bit(src2); // RV64
-                                               // i64 shr automatically fill high bits with sign-bit
-            let result = (src1 as i64).wrapping_shr(t_src2 as u32);
+            let t_src2 = xlen.shamt(src2);
+            // i64 shr automatically fill high bits with sign-bit
+            let result = (src1 as i64).wrapping_shr(t_src2);
             result as u64
         }
         srai => {
-            let shamt = trunc_to_6_bit(imm);
-            let result = (src1 as i64).wrapping_shr(shamt as u32);
+            let shamt = xlen.shamt(imm);
+            let result = (src1 as i64).wrapping_shr(shamt);
             result as u64
         }
         sraiw => {
             let t_src1: i64 = sext(trunc_to_32_bit(src1), WORD_BITWIDTH);
             let (shamt, legal) = trunc_to_5_bit_and_check(imm);
             if !legal {
-                return Err(Error::Exception(Exception::IllegalInstruction));
+                return Err(Exception::IllegalInstruction(itl_d_e.pc, itl_d_e.raw_inst).into());
             }
             let result = t_src1.wrapping_shr(shamt as u32);
             result as u64
@@ -332,97 +370,68 @@ pub fn exec(
             result as u64
         }
         mul => src1.wrapping_mul(src2),
-        mulh => {
-            // RV64
-            let result = (src1 as i128).wrapping_mul(src2 as i128);
-            let result = get_high_64_bit(result as u128);
-            result
-        }
-        mulhsu => {
-            let t_src1 = src1 as i64;
-            let t_src2 = src2 as u64;
-            let result = (t_src1 as i128).wrapping_mul(t_src2 as i128);
-            let result = get_high_64_bit(result as u128);
-            result
-        }
-        mulhu => {
-            let result = (src1 as u128).wrapping_mul(src2 as u128);
-            let result = get_high_64_bit(result);
-            result
-        }
+        mulh => match xlen {
+            Xlen::Rv32 => mulh_32(src1, src2),
+            Xlen::Rv64 => mulh_64(src1, src2),
+        },
+        mulhsu => match xlen {
+            Xlen::Rv32 => mulhsu_32(src1, src2),
+            Xlen::Rv64 => mulhsu_64(src1, src2),
+        },
+        mulhu => match xlen {
+            Xlen::Rv32 => mulhu_32(src1, src2),
+            Xlen::Rv64 => mulhu_64(src1, src2),
+        },
         mulw => {
             let result = src1.wrapping_mul(src2);
             let result = sext(trunc_to_32_bit(result), WORD_BITWIDTH);
             result as u64
         }
-        div => {
-            if src2 == 0 {
+        op @ (div | divu | divuw | divw | rem | remu | remuw | remw) => {
+            if div_trap && div_by_zero(op, src2) {
                 return Err(Error::Exception(Exception::DividedByZero));
             }
-            let result = (src1 as i64).wrapping_div(src2 as i64);
-            result as u64
+            div_rem(op, src1, src2, xlen)
         }
-        divu => {
-            if src2 == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let result = src1.wrapping_div(src2);
-            result
+        op @ (csrrc | csrrci | csrrs | csrrsi | csrrw | csrrwi) => {
+            csr.exec(op, imm as u16, itl_d_e.rd, itl_d_e.rs1, src1)
+                .map_err(|err| err.at_inst(itl_d_e.pc, itl_d_e.raw_inst))?
         }
-        divuw => {
-            if trunc_to_32_bit(src2) == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let result = trunc_to_32_bit(src1).wrapping_div(trunc_to_32_bit(src2));
-            let result = sext(trunc_to_32_bit(result), WORD_BITWIDTH);
-            result as u64
-        }
-        divw => {
-            if trunc_to_32_bit(src2) == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let result = (trunc_to_32_bit(src1) as i32).wrapping_div(trunc_to_32_bit(src2) as i32);
-            let result = sext(trunc_to_32_bit(result as u64), WORD_BITWIDTH);
-            result as u64
-        }
-        rem => {
-            if src2 == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let result = (src1 as i64).wrapping_rem(src2 as i64);
-            result as u64
-        }
-        remu => {
-            if src2 == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let result = src1.wrapping_rem(src2);
-            result
-        }
-        remuw => {
-            if src2 == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let t_src1 = trunc_to_32_bit(src1);
-            let t_src2 = trunc_to_32_bit(src2);
-            let result = t_src1.wrapping_rem(t_src2);
-            let result = sext(result, WORD_BITWIDTH);
-            result as u64
-        }
-        remw => {
-            if src2 == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let t_src1 = trunc_to_32_bit(src1);
-            let t_src2 = trunc_to_32_bit(src2);
-            let result = (t_src1 as i64).wrapping_rem(t_src2 as i64);
-            result as u64
+        // a single in-order hart needs no ordering, nor instruction cache
+        // synchronization as fetch always reads memory
+        fence | fence_i | pause => 0,
+        mret => {
+            pc_src = true;
+            new_pc_1 = csr
+                .mret()
+                .map_err(|err| err.at_inst(itl_d_e.pc, itl_d_e.raw_inst))?;
+            0
         }
-        csrrc | csrrci | csrrs | csrrsi | csrrw | csrrwi | mret | sret | fence | fence_i | wfi => {
-            unimplemented!("Control registers")
+        // S-mode is not supported
+        sret => return Err(Exception::IllegalInstruction(itl_d_e.pc, itl_d_e.raw_inst).into()),
+        fadd_s | fsub_s | fmul_s | fdiv_s | fsqrt_s | fcvt_w_s | fcvt_wu_s | fcvt_s_w
+        | fcvt_s_wu | fmv_x_w | fmv_w_x | fsgnj_s | fsgnjn_s | fsgnjx_s | fmin_s | fmax_s
+        | feq_s | flt_s | fle_s | fclass_s | flw | fsw | fmadd_s | fmsub_s | fnmsub_s
+        | fnmadd_s => {
+            // not decoded by the pipeline in the first place
+            let inst = itl_d_e.exec_flags.alu_op;
+            return Err(Error::Unsupported {
+                inst,
+                pc: itl_d_e.pc,
+                raw_inst: itl_d_e.raw_inst,
+            });
         }
     };
 
+    // misaligned loads and stores trap with --strict-align
+    if strict_align && mem_bitwidth != 0 {
+        check_align(
+            xlen.trunc(mem_addr),
+            mem_bitwidth as u64 / 8,
+            itl_d_e.mem_flags.mem_write,
+        )?;
+    }
+
     let itl_e_m = InternalExecMem {
         raw_inst: itl_d_e.raw_inst,
         mem_flags: itl_d_e.mem_flags,
@@ -438,7 +447,7 @@ pub fn exec(
         rd: itl_d_e.rd,
         imm: itl_d_e.imm,
         alu_out,
-        mem_addr,
+        mem_addr: xlen.trunc(mem_addr),
         mem_bitwidth,
         mem_sext_to,
         m2m_forward: false, // set by hazard detect unit
@@ -446,5 +455,5 @@ pub fn exec(
         alu_op: itl_d_e.exec_flags.alu_op,
     };
 
-    Ok((itl_e_m, new_pc_0, new_pc_1))
+    Ok((itl_e_m, new_pc_0, xlen.trunc(new_pc_1)))
 }
@@ -6,40 +6,56 @@
 use log::{error, trace};
 
 use crate::{
-    core::{insts::*, reg::ProgramCounter, vm::VirtualMemory},
-    error::{Error, Result},
+    core::{
+        decode_cache::DecodeCache,
+        insts::*,
+        reg::ProgramCounter,
+        rvc::{expand, inst_len},
+        vm::VirtualMemory,
+    },
+    error::{Error, Exception, Result},
     multi_stage::debug::f_pinst,
 };
 
 use super::{
-    branch_predict::{BHT, BTB, RAS},
+    branch_predict::{is_conditional_branch, is_control, BHT, BTB, RAS},
     cpu::ControlPolicy,
     ctrl_flags::{BranchFlags, DecodeFlags, ExecFlags, MemFlags, SextType, WbFlags},
     phases::InternalFetchDecode,
 };
 
 /// Fetch instruction
+#[allow(clippy::too_many_arguments)]
 pub fn fetch(
     pc: &ProgramCounter,
     vm: &VirtualMemory,
+    decode_cache: &mut DecodeCache<InternalFetchDecode>,
     pipeline_info: bool,
     control_policy: ControlPolicy,
     bht: Option<&mut BHT>,
     btb: Option<&BTB>,
     ras: Option<&mut RAS>,
+    xlen: Xlen,
 ) -> InternalFetchDecode {
     let pc = pc.read();
-    let inst = vm.fetch_inst_pipeline(pc as usize);
-
-    inst.and_then(|inst| inst_interpret(pc, inst))
+    let inst = vm.fetch(pc as usize);
+
+    inst.and_then(|inst| {
+        decode_cache
+            .decode(pc, inst, || inst_interpret(pc, inst, xlen))
+            .map_err(|err| match err {
+                err @ Error::Unsupported { .. } => err,
+                _ => Exception::IllegalInstruction(pc, inst).into(),
+            })
+    })
         .map(|itl| {
             if pipeline_info {
                     trace!("IF : {}", f_pinst(&itl));
             }
             itl
         })
-        .map(|itl| {
-            if control_policy == ControlPolicy::DynamicPredict {
+        .map(|itl| match control_policy {
+            ControlPolicy::DynamicPredict => {
                 assert!(bht.is_some() && btb.is_some());
                 branch_predict(
                     itl,
@@ -49,11 +65,51 @@ pub fn fetch(
                     btb.unwrap(),
                     ras.unwrap(),
                 )
-            } else {
-                itl
             }
+            ControlPolicy::AlwaysTaken => predict_taken(itl),
+            _ => itl,
         })
-        .unwrap_or_else(|_| InternalFetchDecode::default())
+        .unwrap_or_else(|err| match err {
+            // a bubble carrying the fault to EX
+            Error::Exception(Exception::InstructionAccessFault(vaddr)) => InternalFetchDecode {
+                pc,
+                fetch_fault: Some(vaddr),
+                ..Default::default()
+            },
+            // and so is an illegal instruction, which may be on a wrong path
+            Error::Exception(Exception::IllegalInstruction(_, inst)) => InternalFetchDecode {
+                pc,
+                illegal_inst: Some(inst),
+                ..Default::default()
+            },
+            Error::Unsupported { inst, raw_inst, .. } => InternalFetchDecode {
+                pc,
+                raw_inst,
+                unsupported: Some(inst),
+                ..Default::default()
+            },
+            _ => InternalFetchDecode::default(),
+        })
+}
+
+/// Decode the instruction at `pc` without any prediction, for the debugger.
+pub fn decode_at(pc: u64, vm: &VirtualMemory, xlen: Xlen) -> Result<InternalFetchDecode> {
+    vm.fetch(pc as usize).and_then(|inst| inst_interpret(pc, inst, xlen))
+}
+
+/// Static prediction: conditional branches and jal are taken.
+/// Their targets are pc-relative, so they are known at fetch. jalr is
+/// predicted not taken as its target depends on a register.
+fn predict_taken(mut itl_f_d: InternalFetchDecode) -> InternalFetchDecode {
+    use crate::core::insts::Inst64::*;
+    let offset = match itl_f_d.exec_flags.alu_op {
+        beq | bne | blt | bge | bltu | bgeu => sext(itl_f_d.imm, B_TYPE_IMM_BITWIDTH),
+        jal => sext(itl_f_d.imm, J_TYPE_IMM_BITWIDTH),
+        _ => return itl_f_d,
+    };
+    itl_f_d.branch_flags.predicted_src = true;
+    itl_f_d.branch_flags.predicted_target = itl_f_d.pc.wrapping_add(offset as u64);
+    itl_f_d
 }
 
 fn branch_predict(
@@ -68,18 +124,22 @@ fn branch_predict(
     // predict for next instruction
     use crate::core::insts::Inst64::*;
 
-    let next_inst_is_control = match itl_f_d.exec_flags.alu_op {
-        beq | bne | blt | bge | bltu | bgeu | jal | jalr => true,
-        _ => false,
-    };
-    if next_inst_is_control {
+    // snapshot for recovery if this instruction is discarded or mispredicted
+    itl_f_d.branch_flags.history = bht.history();
+
+    if is_control(itl_f_d.exec_flags.alu_op) {
         match control_policy {
-            ControlPolicy::AllStall => unimplemented!(),
-            ControlPolicy::AlwaysNotTaken => itl_f_d.branch_flags.predicted_src = false,
+            ControlPolicy::AlwaysTaken => unreachable!("Static prediction is done by predict_taken"),
+            // all-stall fetches nothing more until it resolves at EX, and
+            // then goes on at pc + 4 unless it is taken
+            ControlPolicy::AllStall | ControlPolicy::AlwaysNotTaken => {
+                itl_f_d.branch_flags.predicted_src = false
+            }
             ControlPolicy::DynamicPredict => {
                 // First check whether BTB is available
                 let target = btb.query_target(itl_f_d.pc);
                 let predicted_src = bht.predict(itl_f_d.pc);
+                itl_f_d.branch_flags.btb_hit = target.is_some();
 
                 if let Some(target) = target {
                     // BTB has information for this pc
@@ -129,6 +189,9 @@ fn branch_predict(
                     // for now just predict not taken (easiest way)
                     itl_f_d.branch_flags.predicted_src = false;
                 }
+                if is_conditional_branch(itl_f_d.exec_flags.alu_op) {
+                    bht.speculate(itl_f_d.branch_flags.predicted_src);
+                }
             }
         }
     } else {
@@ -139,7 +202,7 @@ fn branch_predict(
 }
 
 /// Decode phase.
-/// ```
+/// ```text
 /// R:  OP_IMM_32  AMO  OP  OP_32  OP_FP
 /// R4: MADD  MSUB  NMSUB  NMADD
 /// I:  LOAD  LOAD_FP  MISC_MEM  OP_IMM  JALR  SYSTEM
@@ -148,43 +211,118 @@ fn branch_predict(
 /// S:  STORE STORE_FP
 /// SB: BRANCH
 /// ```
-fn inst_interpret(pc: u64, inst: u32) -> Result<InternalFetchDecode> {
+pub fn inst_interpret(pc: u64, inst: u32, xlen: Xlen) -> Result<InternalFetchDecode> {
+    let mut itl_f_d = decode_any(inst, xlen).map_err(|err| err.at_inst(pc, inst));
+
+    if let Ok(ref mut itl_f_d) = itl_f_d {
+        itl_f_d.pc = pc;
+    } else {
+        error!("ERROR DECODING: {:#x}", inst);
+    }
+
+    let itl_f_d = itl_f_d?;
+    if !xlen.supports(itl_f_d.exec_flags.alu_op) {
+        let msg = format!("{} is not an RV32 instruction", itl_f_d.exec_flags.alu_op);
+        error!("{msg}");
+        return Err(Error::Decode(msg));
+    }
+
+    Ok(itl_f_d)
+}
+
+/// Decode a compressed or a 32-bit instruction.
+fn decode_any(inst: u32, xlen: Xlen) -> Result<InternalFetchDecode> {
+    if inst_len(inst) == 2 {
+        decode_compressed(inst as u16, xlen)
+    } else {
+        decode_inst(inst, xlen)
+    }
+}
+
+/// Expand a compressed instruction and decode its 32-bit equivalent.
+fn decode_compressed(inst: u16, xlen: Xlen) -> Result<InternalFetchDecode> {
+    let mut itl_f_d = decode_inst(expand(inst, xlen)?, xlen)?;
+    itl_f_d.inst_len = 2;
+    Ok(itl_f_d)
+}
+
+/// Decode a 32-bit instruction.
+fn decode_inst(inst: u32, xlen: Xlen) -> Result<InternalFetchDecode> {
     use crate::core::insts::inst_64_opcode::*;
     // Format
     let opcode = opcode(inst);
 
-    let mut itl_f_d = match opcode {
+    match opcode {
         LOAD => decode_load(inst),
-        LOAD_FP => return Err(Error::Fetch("todo".into())),
-        MISC_MEM => return Err(Error::Fetch("todo".into())),
-        OP_IMM => decode_op_imm(inst),
+        LOAD_FP => decode_fp(inst),
+        MISC_MEM => decode_misc_mem(inst),
+        OP_IMM => decode_op_imm(inst, xlen),
         AUIPC => decode_op_auipc(inst),
         OP_IMM_32 => decode_op_imm_32(inst),
         STORE => decode_store(inst),
-        STORE_FP => decode_store_fp(inst),
-        AMO => return Err(Error::Fetch("todo".into())),
+        STORE_FP => decode_fp(inst),
+        AMO => decode_amo(inst),
         OP => decode_op(inst),
         LUI => decode_lui(inst),
         OP_32 => decode_op_32(inst),
-        MADD => return Err(Error::Fetch("todo".into())),
-        MSUB => return Err(Error::Fetch("todo".into())),
-        NMSUB => return Err(Error::Fetch("todo".into())),
-        NMADD => return Err(Error::Fetch("todo".into())),
-        OP_FP => return Err(Error::Fetch("todo".into())),
+        MADD => decode_fp(inst),
+        MSUB => decode_fp(inst),
+        NMSUB => decode_fp(inst),
+        NMADD => decode_fp(inst),
+        OP_FP => decode_fp(inst),
         BRANCH => decode_branch(inst),
         JALR => decode_jalr(inst),
         JAL => decode_jal(inst),
         SYSTEM => decode_system(inst),
-        _ => return Err(Error::Fetch("Interpretation".into())),
-    };
-
-    if let Ok(ref mut itl_f_d) = itl_f_d {
-        itl_f_d.pc = pc;
-    } else {
-        error!("ERROR DECODING: {:#x}", inst);
+        _ => Err(Error::Decode(format!("unknown opcode {:#09b}", opcode))),
     }
+}
 
-    itl_f_d
+/// LOAD_FP, STORE_FP, MADD, MSUB, NMSUB, NMADD, OP_FP
+/// The pipeline has no float register file, F extension instructions run on
+/// the single cycle CPU only, and are unsupported here. The pc of the error
+/// is given by the caller, and the error is raised when the instruction
+/// reaches EX.
+fn decode_fp(inst: u32) -> Result<InternalFetchDecode> {
+    use crate::core::insts::inst_64_opcode::*;
+    let op = match (opcode(inst), funct3(inst), funct7(inst), rs2(inst)) {
+        (LOAD_FP, 0b010, _, _) => Inst64::flw,
+        (STORE_FP, 0b010, _, _) => Inst64::fsw,
+        (MADD, _, _, _) => Inst64::fmadd_s,
+        (MSUB, _, _, _) => Inst64::fmsub_s,
+        (NMSUB, _, _, _) => Inst64::fnmsub_s,
+        (NMADD, _, _, _) => Inst64::fnmadd_s,
+        (OP_FP, _, 0b0000000, _) => Inst64::fadd_s,
+        (OP_FP, _, 0b0000100, _) => Inst64::fsub_s,
+        (OP_FP, _, 0b0001000, _) => Inst64::fmul_s,
+        (OP_FP, _, 0b0001100, _) => Inst64::fdiv_s,
+        (OP_FP, _, 0b0101100, 0) => Inst64::fsqrt_s,
+        (OP_FP, 0b000, 0b0010000, _) => Inst64::fsgnj_s,
+        (OP_FP, 0b001, 0b0010000, _) => Inst64::fsgnjn_s,
+        (OP_FP, 0b010, 0b0010000, _) => Inst64::fsgnjx_s,
+        (OP_FP, 0b000, 0b0010100, _) => Inst64::fmin_s,
+        (OP_FP, 0b001, 0b0010100, _) => Inst64::fmax_s,
+        (OP_FP, 0b010, 0b1010000, _) => Inst64::feq_s,
+        (OP_FP, 0b001, 0b1010000, _) => Inst64::flt_s,
+        (OP_FP, 0b000, 0b1010000, _) => Inst64::fle_s,
+        (OP_FP, _, 0b1100000, 0) => Inst64::fcvt_w_s,
+        (OP_FP, _, 0b1100000, 1) => Inst64::fcvt_wu_s,
+        (OP_FP, _, 0b1101000, 0) => Inst64::fcvt_s_w,
+        (OP_FP, _, 0b1101000, 1) => Inst64::fcvt_s_wu,
+        (OP_FP, 0b000, 0b1110000, 0) => Inst64::fmv_x_w,
+        (OP_FP, 0b001, 0b1110000, 0) => Inst64::fclass_s,
+        (OP_FP, 0b000, 0b1111000, 0) => Inst64::fmv_w_x,
+        _ => {
+            let msg = format!("Unknown float instruction {inst:#010x}");
+            error!("{msg}");
+            return Err(Error::Decode(msg));
+        }
+    };
+    Err(Error::Unsupported {
+        inst: op,
+        pc: 0,
+        raw_inst: inst,
+    })
 }
 
 /// 0000011 LOAD: I type
@@ -225,33 +363,80 @@ fn decode_load(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            history: 0,
+            btb_hit: false,
         },
         wb_flags: WbFlags { mem_to_reg: true },
         pc: 0,
+        inst_len: 4,
         rs1,
         rs2: 0,
         rs3: 0,
         rd,
         imm,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
     };
 
     Ok(itl_f_d)
 }
 
-/// 0000111 LOAD_FP: I type
-#[allow(unused)]
-fn decode_load_fp(_inst: u32) -> Result<InternalFetchDecode> {
-    todo!()
-}
-
 /// 0001111 MISC_MEM: I type
-#[allow(unused)]
-fn decode_misc_mem(_inst: u32) -> Result<InternalFetchDecode> {
-    todo!()
+fn decode_misc_mem(inst: u32) -> Result<InternalFetchDecode> {
+    let funct3 = funct3(inst);
+    // fence.tso is a fence, its fm only weakens the ordering
+    let alu_op = match funct3 {
+        0b000 if inst == PAUSE => Inst64::pause,
+        0b000 => Inst64::fence,
+        0b001 => Inst64::fence_i,
+        _ => {
+            let msg = format!("Unknown MISC_MEM instruction funct3={funct3}");
+            error!("{msg}");
+            return Err(Error::Decode(msg));
+        }
+    };
+
+    // fm, pred and succ are ignored, rd and rs1 are reserved
+    let itl_f_d = InternalFetchDecode {
+        raw_inst: inst,
+        decode_flags: DecodeFlags {
+            sext: SextType::None,
+        },
+        exec_flags: ExecFlags {
+            alu_op,
+            alu_src: false,
+        },
+        mem_flags: MemFlags {
+            mem_read: false,
+            mem_write: false,
+        },
+        wb_flags: WbFlags { mem_to_reg: false },
+        branch_flags: BranchFlags {
+            branch: false,
+            pc_src: false,
+            predicted_src: false,
+            predicted_target: 0,
+            history: 0,
+            btb_hit: false,
+        },
+        pc: 0,
+        inst_len: 4,
+        rs1: 0,
+        rs2: 0,
+        rs3: 0,
+        rd: 0,
+        imm: 0,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
+    };
+
+    Ok(itl_f_d)
 }
 
 /// 0010011 OP_IMM: I type
-fn decode_op_imm(inst: u32) -> Result<InternalFetchDecode> {
+fn decode_op_imm(inst: u32, xlen: Xlen) -> Result<InternalFetchDecode> {
     let funct3 = funct3(inst);
     let funct7 = funct7(inst);
     let funct6 = funct6(inst);
@@ -263,9 +448,16 @@ fn decode_op_imm(inst: u32) -> Result<InternalFetchDecode> {
         0b110 => Inst64::ori,
         0b111 => Inst64::andi,
 
-        /* // RV32
-        0b001 => Inst64::slli,
-        0b101 => match funct7 {
+        // RV32: shamt[5] is reserved, so funct7 selects
+        0b001 if xlen == Xlen::Rv32 => match funct7 {
+            0b0000000 => Inst64::slli,
+            _ => {
+                let msg = format!("Unknown OP_IMM instruction NOT slli funct7={funct7}");
+                error!("{msg}");
+                return Err(Error::Decode(msg));
+            }
+        },
+        0b101 if xlen == Xlen::Rv32 => match funct7 {
             0b0000000 => Inst64::srli,
             0b0100000 => Inst64::srai,
             _ => {
@@ -274,7 +466,6 @@ fn decode_op_imm(inst: u32) -> Result<InternalFetchDecode> {
                 return Err(Error::Decode(msg));
             }
         },
-        */
         // RV64
         0b001 => Inst64::slli,
         0b101 => match funct6 {
@@ -297,7 +488,7 @@ fn decode_op_imm(inst: u32) -> Result<InternalFetchDecode> {
     let rs1 = rs1(inst);
     let imm = match funct3 {
         0b000 | 0b010 | 0b011 | 0b100 | 0b110 | 0b111 => imm_I(inst),
-        // 0b001 | 0b101 => rs2.into(), // RV32
+        0b001 | 0b101 if xlen == Xlen::Rv32 => rs2(inst).into(),
         0b001 | 0b101 => shift64_I(inst), // RV64
         _ => unreachable!("Should return error before control flow reaches here"),
     };
@@ -319,13 +510,19 @@ fn decode_op_imm(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            history: 0,
+            btb_hit: false,
         },
         pc: 0,
+        inst_len: 4,
         rs1,
         rs2: 0,
         rs3: 0,
         rd,
         imm,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
     };
 
     Ok(itl_f_d)
@@ -354,13 +551,19 @@ fn decode_op_auipc(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false,
             predicted_src: false,
             predicted_target: 0,
+            history: 0,
+            btb_hit: false,
         },
         pc: 0,
+        inst_len: 4,
         rs1: 0,
         rs2: 0,
         rs3: 0,
         rd,
         imm,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
     };
 
     Ok(itl_f_d)
@@ -416,13 +619,19 @@ fn decode_op_imm_32(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            history: 0,
+            btb_hit: false,
         },
         pc: 0,
+        inst_len: 4,
         rs1,
         rs2: 0,
         rs3: 0,
         rd,
         imm,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
     };
 
     Ok(itl_f_d)
@@ -464,28 +673,100 @@ fn decode_store(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            history: 0,
+            btb_hit: false,
         },
         pc: 0,
+        inst_len: 4,
         rs1,
         rs2,
         rs3: 0,
         rd: 0,
         imm,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
     };
 
     Ok(itl_f_d)
 }
 
-/// 0100111 STORE_FP: S type
-#[allow(unused)]
-fn decode_store_fp(_inst: u32) -> Result<InternalFetchDecode> {
-    todo!()
-}
-
 /// 0101111 AMO: R type
-#[allow(unused)]
-fn decode_amo(_inst: u32) -> Result<InternalFetchDecode> {
-    todo!()
+/// funct7 is funct5 followed by the aq and rl bits, which are ignored as
+/// there is only one hart.
+/// Atomics are done at MEM and their results are loaded values.
+fn decode_amo(inst: u32) -> Result<InternalFetchDecode> {
+    let funct3 = funct3(inst);
+    let funct5 = funct7(inst) >> 2;
+    let alu_op = match (funct5, funct3) {
+        (0b00010, 0b010) if rs2(inst) == 0 => Inst64::lr_w,
+        (0b00010, 0b011) if rs2(inst) == 0 => Inst64::lr_d,
+        (0b00011, 0b010) => Inst64::sc_w,
+        (0b00011, 0b011) => Inst64::sc_d,
+        (0b00001, 0b010) => Inst64::amoswap_w,
+        (0b00001, 0b011) => Inst64::amoswap_d,
+        (0b00000, 0b010) => Inst64::amoadd_w,
+        (0b00000, 0b011) => Inst64::amoadd_d,
+        (0b00100, 0b010) => Inst64::amoxor_w,
+        (0b00100, 0b011) => Inst64::amoxor_d,
+        (0b01100, 0b010) => Inst64::amoand_w,
+        (0b01100, 0b011) => Inst64::amoand_d,
+        (0b01000, 0b010) => Inst64::amoor_w,
+        (0b01000, 0b011) => Inst64::amoor_d,
+        (0b10000, 0b010) => Inst64::amomin_w,
+        (0b10000, 0b011) => Inst64::amomin_d,
+        (0b10100, 0b010) => Inst64::amomax_w,
+        (0b10100, 0b011) => Inst64::amomax_d,
+        (0b11000, 0b010) => Inst64::amominu_w,
+        (0b11000, 0b011) => Inst64::amominu_d,
+        (0b11100, 0b010) => Inst64::amomaxu_w,
+        (0b11100, 0b011) => Inst64::amomaxu_d,
+        _ => {
+            let msg = format!("Unknown AMO instruction funct3={funct3} funct5={funct5}");
+            error!("{msg}");
+            return Err(Error::Decode(msg));
+        }
+    };
+
+    let rd = rd(inst);
+    let rs1 = rs1(inst);
+    let rs2 = rs2(inst);
+
+    let itl_f_d = InternalFetchDecode {
+        raw_inst: inst,
+        decode_flags: DecodeFlags {
+            sext: SextType::None,
+        },
+        exec_flags: ExecFlags {
+            alu_op,
+            alu_src: false,
+        },
+        mem_flags: MemFlags {
+            mem_read: true,
+            mem_write: false,
+        },
+        wb_flags: WbFlags { mem_to_reg: true },
+        branch_flags: BranchFlags {
+            branch: false,
+            pc_src: false, // not set until exec phase
+            predicted_src: false,
+            predicted_target: 0,
+            history: 0,
+            btb_hit: false,
+        },
+        pc: 0,
+        inst_len: 4,
+        rs1,
+        rs2,
+        rs3: 0,
+        rd,
+        imm: 0,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
+    };
+
+    Ok(itl_f_d)
 }
 
 /// 0110011 OP: R type
@@ -597,13 +878,19 @@ fn decode_op(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            history: 0,
+            btb_hit: false,
         },
         pc: 0,
+        inst_len: 4,
         rs1,
         rs2,
         rs3: 0,
         rd,
         imm: 0,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
     };
 
     Ok(itl_f_d)
@@ -632,13 +919,19 @@ fn decode_lui(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            history: 0,
+            btb_hit: false,
         },
         pc: 0,
+        inst_len: 4,
         rs1: 0,
         rs2: 0,
         rs3: 0,
         rd,
         imm,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
     };
 
     Ok(itl_f_d)
@@ -724,48 +1017,24 @@ fn decode_op_32(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            history: 0,
+            btb_hit: false,
         },
         pc: 0,
+        inst_len: 4,
         rs1,
         rs2,
         rs3: 0,
         rd,
         imm: 0,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
     };
 
     Ok(itl_f_d)
 }
 
-/// 1000011 MADD: R4 type
-#[allow(unused)]
-fn decode_madd(_inst: u32) -> Result<InternalFetchDecode> {
-    todo!()
-}
-
-/// 1000111 MSUB: R4 type
-#[allow(unused)]
-fn decode_msub(_inst: u32) -> Result<InternalFetchDecode> {
-    todo!()
-}
-
-/// 1001011 NMSUB: R4 type
-#[allow(unused)]
-fn decode_nmsub(_inst: u32) -> Result<InternalFetchDecode> {
-    todo!()
-}
-
-/// 1001111 NMADD: R4 type
-#[allow(unused)]
-fn decode_nmadd(_inst: u32) -> Result<InternalFetchDecode> {
-    todo!()
-}
-
-/// 1010011 OP_FP: R type
-#[allow(unused)]
-fn decode_op_fp(_inst: u32) -> Result<InternalFetchDecode> {
-    todo!()
-}
-
 /// 1100011 BRANCH: SB type
 fn decode_branch(inst: u32) -> Result<InternalFetchDecode> {
     let funct3 = funct3(inst);
@@ -806,13 +1075,19 @@ fn decode_branch(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false,        // not set until exec phase
             predicted_src: false, // set by branch prediction logic
             predicted_target: 0,
+            history: 0,
+            btb_hit: false,
         },
         pc: 0,
+        inst_len: 4,
         rs1,
         rs2,
         rs3: 0,
         rd: 0,
         imm,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
     };
 
     Ok(itl_f_d)
@@ -851,13 +1126,19 @@ fn decode_jalr(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: true,         // always jump
             predicted_src: false, // always predicted as taken
             predicted_target: 0,
+            history: 0,
+            btb_hit: false,
         },
         pc: 0,
+        inst_len: 4,
         rs1,
         rs2: 0,
         rs3: 0,
         rd,
         imm,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
     };
 
     Ok(itl_f_d)
@@ -886,13 +1167,19 @@ fn decode_jal(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: true,         // not set until exec phase
             predicted_src: false, // always predicted as taken
             predicted_target: 0,
+            history: 0,
+            btb_hit: false,
         },
         pc: 0,
+        inst_len: 4,
         rs1: 0,
         rs2: 0,
         rs3: 0,
         rd,
         imm,
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
     };
 
     Ok(itl_f_d)
@@ -906,6 +1193,9 @@ fn decode_system(inst: u32) -> Result<InternalFetchDecode> {
         0b000 => match csr {
             0 => Inst64::ecall,
             1 => Inst64::ebreak,
+            0x102 => Inst64::sret,
+            0x302 => Inst64::mret,
+            0x105 => Inst64::wfi,
             _ => {
                 let msg = format!("Unknown SYSTEM E- instruction csr={csr}");
                 error!("{msg}");
@@ -927,6 +1217,13 @@ fn decode_system(inst: u32) -> Result<InternalFetchDecode> {
 
     let rd = rd(inst);
     let rs1 = rs1(inst); // zimm for csrrwi, csrrsi, csrrci
+    // CSR instructions write the old CSR value into rd
+    let reg_write = !matches!(
+        alu_op,
+        Inst64::ecall | Inst64::ebreak | Inst64::wfi | Inst64::mret | Inst64::sret
+    );
+    // mret jumps to mepc, which is only known at exec
+    let branch = alu_op == Inst64::mret;
 
     let itl_f_d = InternalFetchDecode {
         raw_inst: inst,
@@ -941,19 +1238,27 @@ fn decode_system(inst: u32) -> Result<InternalFetchDecode> {
             mem_read: false,
             mem_write: false,
         },
-        wb_flags: WbFlags { mem_to_reg: false },
+        wb_flags: WbFlags {
+            mem_to_reg: reg_write,
+        },
         branch_flags: BranchFlags {
-            branch: false,
+            branch,
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            history: 0,
+            btb_hit: false,
         },
         pc: 0,
+        inst_len: 4,
         rs1,
         rs2: 0,
         rs3: 0,
         rd,
-        imm: 0,
+        imm: csr, // CSR address
+        fetch_fault: None,
+        illegal_inst: None,
+        unsupported: None,
     };
 
     Ok(itl_f_d)
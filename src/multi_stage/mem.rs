@@ -1,7 +1,12 @@
 use log::{debug, trace};
 
 use crate::{
-    core::{insts::sext, vm::VirtualMemory},
+    core::{
+        amo::{exec_atomic, is_atomic, Reservation},
+        insts::sext,
+        vm::VirtualMemory,
+    },
+    error::Result,
     multi_stage::debug::m_pinst,
 };
 
@@ -10,8 +15,9 @@ use super::phases::{InternalExecMem, InternalMemWb};
 pub fn mem(
     itl_e_m: &InternalExecMem,
     vm: &mut VirtualMemory,
+    reservation: &mut Reservation,
     pipeline_info: bool,
-) -> InternalMemWb {
+) -> Result<InternalMemWb> {
     if pipeline_info {
         trace!("MEM: {}", m_pinst(itl_e_m));
     }
@@ -32,16 +38,22 @@ pub fn mem(
     };
 
     assert!(!(mem_read & mem_write));
-    if mem_read {
+    if is_atomic(itl_e_m.alu_op) {
+        if pipeline_info {
+            debug!("MEM.atomic {:#x}", vaddr);
+        }
+        // the result is loaded into rd like a load
+        regval = exec_atomic(itl_e_m.alu_op, vaddr as u64, regval, vm, reservation)?;
+    } else if mem_read {
         if pipeline_info {
             debug!("MEM.read {:#x}", vaddr);
         }
         assert!(!itl_e_m.m2m_forward);
         let result = match mem_bitwidth {
-            8 => vm.mread::<u8>(vaddr) as u64,
-            16 => vm.mread::<u16>(vaddr) as u64,
-            32 => vm.mread::<u32>(vaddr) as u64,
-            64 => vm.mread::<u64>(vaddr) as u64,
+            8 => vm.mread::<u8>(vaddr)? as u64,
+            16 => vm.mread::<u16>(vaddr)? as u64,
+            32 => vm.mread::<u32>(vaddr)? as u64,
+            64 => vm.mread::<u64>(vaddr)?,
             _ => unreachable!("MEM.read"),
         };
         let result = match mem_sext_to {
@@ -58,15 +70,16 @@ pub fn mem(
             debug!("MEM.write {:#x} -> M[{:#x}]", regval, vaddr);
         }
         match mem_bitwidth {
-            8 => vm.mwrite::<u8>(vaddr, regval as u8),
-            16 => vm.mwrite::<u16>(vaddr, regval as u16),
-            32 => vm.mwrite::<u32>(vaddr, regval as u32),
-            64 => vm.mwrite::<u64>(vaddr, regval),
+            8 => vm.mwrite::<u8>(vaddr, regval as u8)?,
+            16 => vm.mwrite::<u16>(vaddr, regval as u16)?,
+            32 => vm.mwrite::<u32>(vaddr, regval as u32)?,
+            64 => vm.mwrite::<u64>(vaddr, regval)?,
             _ => unreachable!("MEM.write"),
         }
+        reservation.store(vaddr as u64, *mem_bitwidth as u64 / 8);
     }
 
-    InternalMemWb {
+    Ok(InternalMemWb {
         raw_inst: itl_e_m.raw_inst,
         wb_flags: itl_e_m.wb_flags,
         branch_flags: itl_e_m.branch_flags,
@@ -79,5 +92,5 @@ pub fn mem(
         imm: itl_e_m.imm,
         regval,
         alu_op: itl_e_m.alu_op,
-    }
+    })
 }
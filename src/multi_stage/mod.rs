@@ -1,8 +1,10 @@
 pub mod branch_predict;
+pub mod cache;
 pub mod cpu;
 pub mod ctrl_flags;
 pub mod debug;
 pub mod decode;
+pub mod diagram;
 pub mod exec;
 pub mod fetch;
 pub mod mem;
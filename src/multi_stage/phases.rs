@@ -10,13 +10,17 @@ pub struct InternalFetchDecode {
     pub mem_flags: MemFlags,
     pub wb_flags: WbFlags,
     pub branch_flags: BranchFlags,
-    pub pc: u64, // current instruction PC
+    pub pc: u64,       // current instruction PC
+    pub inst_len: u64, // 2 for compressed instructions, otherwise 4
     pub rs1: u8,
     pub rs2: u8,
     #[allow(unused)]
     pub rs3: u8,
     pub rd: u8,
     pub imm: u64,
+    pub fetch_fault: Option<u64>, // vaddr of a fault raised at EX, on a bubble
+    pub illegal_inst: Option<u32>, // raw word of an illegal instruction raised at EX, on a bubble
+    pub unsupported: Option<Inst64>, // instruction not run by the pipeline, raised at EX, on a bubble
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -26,7 +30,8 @@ pub struct InternalDecodeExec {
     pub mem_flags: MemFlags,
     pub wb_flags: WbFlags,
     pub branch_flags: BranchFlags,
-    pub pc: u64, // current instruction PC
+    pub pc: u64,       // current instruction PC
+    pub inst_len: u64, // 2 for compressed instructions, otherwise 4
     pub rs1: u8,
     pub rs2: u8,
     #[allow(unused)]
@@ -38,7 +43,11 @@ pub struct InternalDecodeExec {
     pub forward_a: u8,
     pub forward_b: u8,
     pub ex_mem_forward: u64,
+    pub mem_mem_forward: u64, // from MEM1/MEM2 of the extra MEM stage
     pub mem_wb_forward: u64,
+    pub fetch_fault: Option<u64>,
+    pub illegal_inst: Option<u32>,
+    pub unsupported: Option<Inst64>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -67,6 +76,11 @@ impl InternalExecMem {
     pub fn is_ret(&self) -> bool {
         self.raw_inst == 0x00008067
     }
+
+    /// jal or jalr which links the return address into ra.
+    pub fn is_call(&self) -> bool {
+        matches!(self.alu_op, Inst64::jal | Inst64::jalr) && self.rd == 1
+    }
 }
 
 #[allow(unused)]
@@ -108,13 +122,19 @@ impl Default for InternalFetchDecode {
                 pc_src: false,
                 predicted_src: false,
                 predicted_target: 0,
+                history: 0,
+                btb_hit: false,
             },
             pc: 0,
+            inst_len: 4,
             rs1: 0,
             rs2: 0,
             rs3: 0,
             rd: 0,
             imm: 0,
+            fetch_fault: None,
+            illegal_inst: None,
+            unsupported: None,
         }
     }
 }
@@ -137,8 +157,11 @@ impl Default for InternalDecodeExec {
                 pc_src: false,
                 predicted_src: false,
                 predicted_target: 0,
+                history: 0,
+                btb_hit: false,
             },
             pc: 0,
+            inst_len: 4,
             rs1: 0,
             rs2: 0,
             rs3: 0,
@@ -149,7 +172,11 @@ impl Default for InternalDecodeExec {
             forward_a: 0,
             forward_b: 0,
             ex_mem_forward: 0,
+            mem_mem_forward: 0,
             mem_wb_forward: 0,
+            fetch_fault: None,
+            illegal_inst: None,
+            unsupported: None,
         }
     }
 }
@@ -168,6 +195,8 @@ impl Default for InternalExecMem {
                 pc_src: false,
                 predicted_src: false,
                 predicted_target: 0,
+                history: 0,
+                btb_hit: false,
             },
             pc: 0,
             rs1: 0,
@@ -196,6 +225,8 @@ impl Default for InternalMemWb {
                 pc_src: false,
                 predicted_src: false,
                 predicted_target: 0,
+                history: 0,
+                btb_hit: false,
             },
             mem_read: false,
             pc: 0,
@@ -2,24 +2,46 @@ use log::{debug, info, trace};
 
 use crate::{
     core::{
-        insts::Inst64,
-        reg::{RegisterFile, REGNAME},
+        insts::{Inst64, Xlen},
+        reg::{RegisterFile, TraceRegs, REGNAME},
+        vm::VirtualMemory,
     },
+    error::Result,
     multi_stage::{cpu::halt, debug::w_pinst},
+    syscall::{ecall, is_semihosting_call, semihost, Syscall},
+    trace::TraceFile,
+    verify::Verifier,
 };
 
 use super::phases::InternalMemWb;
 
+/// How the CPU goes on after an instruction writes back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retire {
+    Running,
+    Exit(u64), // the program ended with the exit code
+    Wait,      // retired a wfi, see `wait_for_interrupt`
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn writeback(
     itl_m_w: &InternalMemWb,
     reg_file: &mut RegisterFile,
+    vm: &mut VirtualMemory,
+    syscall: &mut dyn Syscall,
     pipeline_info: bool,
-) -> bool {
+    ebreak_as_trap: bool,
+    semihosting: bool,
+    xlen: Xlen,
+    trace_regs: Option<(TraceRegs, &mut TraceFile)>,
+    verifier: Option<&mut Verifier>,
+) -> Result<Retire> {
     if pipeline_info {
         trace!("WB : {}", w_pinst(itl_m_w));
     }
 
     let mem_to_reg = itl_m_w.wb_flags.mem_to_reg;
+    let old_rd = reg_file.read(itl_m_w.rd);
     if mem_to_reg {
         let write_val = if itl_m_w.rd != 0 { itl_m_w.regval } else { 0 };
         if pipeline_info {
@@ -31,13 +53,52 @@ pub fn writeback(
         reg_file.write(itl_m_w.rd, write_val);
     }
 
-    if itl_m_w.alu_op == Inst64::ebreak {
+    // checked against the reference before the syscall writes a0. ebreak
+    // other than semihosting calls stops or traps instead of retiring.
+    let stops =
+        itl_m_w.alu_op == Inst64::ebreak && !(semihosting && is_semihosting_call(vm, itl_m_w.pc));
+
+    // commit log of the retiring instruction, with the ebreak ending the
+    // program but not one trapping
+    let retires = itl_m_w.alu_op != Inst64::noop && !(stops && ebreak_as_trap);
+    if let Some((trace_regs, file)) = trace_regs.filter(|_| retires) {
+        let write = mem_to_reg.then_some((itl_m_w.rd, old_rd));
+        file.regs(trace_regs, itl_m_w.pc, write, reg_file);
+    }
+    if let Some(verifier) = verifier.filter(|_| itl_m_w.alu_op != Inst64::noop && !stops) {
+        let write = mem_to_reg.then(|| (itl_m_w.rd, reg_file.read(itl_m_w.rd)));
+        verifier.check(itl_m_w.pc, itl_m_w.raw_inst, write)?;
+    }
+
+    // ecall is served here, after every older instruction has written back
+    if itl_m_w.alu_op == Inst64::ecall {
+        if let Some(code) = ecall(syscall, reg_file, vm)? {
+            halt(itl_m_w.pc, code);
+            return Ok(Retire::Exit(code));
+        }
+    }
+
+    // the CPU sleeps until an interrupt is pending, or halts
+    if itl_m_w.alu_op == Inst64::wfi {
+        return Ok(Retire::Wait);
+    }
+
+    // a semihosting call is served as it retires, like ecall
+    if itl_m_w.alu_op == Inst64::ebreak && semihosting && is_semihosting_call(vm, itl_m_w.pc) {
+        if let Some(code) = semihost(reg_file, vm, xlen)? {
+            halt(itl_m_w.pc, code);
+            return Ok(Retire::Exit(code));
+        }
+        return Ok(Retire::Running);
+    }
+
+    if itl_m_w.alu_op == Inst64::ebreak && !ebreak_as_trap {
         let x10 = reg_file.read(10);
         let msg = format!("ebreak at {:#x}, code {}", itl_m_w.pc, x10);
         info!("{msg}");
         halt(itl_m_w.pc, x10); // HALT at current code.
-        false
+        Ok(Retire::Exit(x10))
     } else {
-        true
+        Ok(Retire::Running)
     }
 }
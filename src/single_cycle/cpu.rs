@@ -1,27 +1,63 @@
 //! Mono-core CPU
 
 use std::{
-    fmt::Display,
+    collections::{BTreeMap, BTreeSet, HashMap},
     ops::{BitAnd, BitOr, BitXor},
+    path::Path,
 };
 
 use log::{error, info, trace};
 
 use crate::{
-    callstack::CallStack,
-    check,
+    callstack::{CallStack, CallStackSnapshot},
     core::{
+        amo::{exec_atomic, Reservation},
+        decode_cache::DecodeCache,
+        histogram::print_inst_histogram,
         insts::*,
-        reg::{ProgramCounter, RegisterFile},
-        vm::VirtualMemory,
+        reg::{reg_index, FRegisterFile, ProgramCounter, RegisterFile, TraceRegs, REGNAME},
+        uninit::UninitFill,
+        vm::{self, Endian, MemData, VirtualMemory},
     },
+    csr::{Csr, TrapMode},
     elf::LoadElfInfo,
-    error::{Error, Exception, Result},
-    pinst,
+    error::{check_cycle_limit, Error, Exception, Result},
+    logger, pinst,
+    syscall::{ecall, is_semihosting_call, new_syscall, semihost, Syscall, SyscallAbi},
+    trace::TraceFile,
+    verify::Verifier,
 };
 
 use super::decode::decode;
 
+/// Architectural state of the single-cycle CPU, taken by [`CPU::snapshot`]
+/// and put back by [`CPU::restore`] to re-run from a point. Output of the
+/// program and trace records already written stay written.
+pub struct CpuSnapshot {
+    running: bool,
+    regs: [u64; 32],
+    fregs: FRegisterFile,
+    pc: u64,
+    clock: u64,
+    retired: u64,
+    inst_histogram: HashMap<Inst64, u64>,
+    csr: Csr,
+    wfi: bool,
+    exit_code: Option<u64>,
+    last_store: Option<(u64, u64)>,
+    reservation: Reservation,
+    syscall: Box<dyn Syscall>,
+    callstack: CallStackSnapshot,
+    mm: Vec<u8>,
+}
+
+impl CpuSnapshot {
+    /// Cycles run when the snapshot was taken.
+    pub fn cycles(&self) -> u64 {
+        self.clock
+    }
+}
+
 pub struct CPU<'a> {
     // indicate whether the CPU is running
     running: bool,
@@ -29,6 +65,9 @@ pub struct CPU<'a> {
     // General purpose register file
     reg_file: RegisterFile,
 
+    // Float register file
+    freg_file: FRegisterFile,
+
     // Program counter (PC) which is not included in general purpose register file.
     pc: ProgramCounter,
 
@@ -40,6 +79,69 @@ pub struct CPU<'a> {
 
     // Itrace switch
     itrace: bool,
+
+    // Itrace records of retired instructions
+    itrace_file: Option<TraceFile>,
+
+    // Itrace with operand values and results
+    itrace_verbose: bool,
+
+    // Registers logged after every retired instruction
+    trace_regs: Option<(TraceRegs, TraceFile)>,
+
+    // Reference commit log checked against every retired instruction
+    verifier: Option<Verifier>,
+
+    // Control and status registers
+    csr: Csr,
+
+    // Raise breakpoint exception on ebreak instead of halting
+    ebreak_as_trap: bool,
+
+    // Serve ebreak in the semihosting sequence as a semihosting call
+    semihosting: bool,
+
+    // Stopped by wfi, resumes after it on the next run
+    wfi: bool,
+
+    // Exit code of the program once it has ended
+    exit_code: Option<u64>,
+
+    // (vaddr, bytes) of the store of the last instruction
+    last_store: Option<(u64, u64)>,
+
+    // Width of the integer registers
+    xlen: Xlen,
+
+    // Decoded instructions, not decoded again while unchanged
+    decode_cache: DecodeCache<ExecInternal>,
+
+    // Syscall handler for ecall
+    syscall: Box<dyn Syscall>,
+
+    // LR/SC reservation set
+    reservation: Reservation,
+
+    // Raise address-misaligned exceptions on misaligned loads and stores
+    strict_align: bool,
+
+    // Stop with DividedByZero on division by zero instead of the spec results
+    div_trap: bool,
+
+    // Executed cycles, one per instruction
+    clock: u64,
+
+    // Retired instructions, which leave out the trapped ones
+    retired: u64,
+
+    // Retired count of each instruction
+    inst_histogram: HashMap<Inst64, u64>,
+
+    // Stop with an error once the clock reaches it
+    max_cycles: Option<u64>,
+
+    // Stop on exceptions, or jump to the handler at mtvec
+    trap_mode: TrapMode,
 }
 
 impl<'a> CPU<'a> {
@@ -47,30 +149,237 @@ impl<'a> CPU<'a> {
         vm: &'a mut VirtualMemory,
         callstack: &'a mut CallStack<'a>,
         itrace: bool,
+        warn_on_unknown_csr: bool,
+        ebreak_as_trap: bool,
+        syscall_abi: SyscallAbi,
+        xlen: Xlen,
     ) -> CPU<'a> {
+        // mstatus.UBE and MBE show the byte order of data accesses
+        let mut csr = Csr::new(warn_on_unknown_csr, xlen);
+        csr.set_big_endian(vm.data_endian() == Endian::Big);
+        csr.set_float(true);
+
         // x0 already set to 0
-        let reg_file = RegisterFile::empty();
-        let pc = ProgramCounter::new();
+        let mut reg_file = RegisterFile::empty();
+        reg_file.set_xlen(xlen);
+        let mut pc = ProgramCounter::new();
+        pc.set_xlen(xlen);
+        vm.set_xlen(xlen);
 
         CPU {
             running: false,
             reg_file,
+            freg_file: FRegisterFile::empty(),
             pc,
             vm,
             callstack,
             itrace,
+            itrace_file: None,
+            itrace_verbose: false,
+            trace_regs: None,
+            verifier: None,
+            csr,
+            wfi: false,
+            exit_code: None,
+            last_store: None,
+            ebreak_as_trap,
+            semihosting: false,
+            xlen,
+            decode_cache: DecodeCache::new(),
+            syscall: new_syscall(syscall_abi),
+            reservation: Reservation::default(),
+            strict_align: false,
+            div_trap: false,
+            clock: 0,
+            retired: 0,
+            inst_histogram: HashMap::new(),
+            max_cycles: None,
+            trap_mode: TrapMode::Fatal,
         }
     }
 
-    /// Initialize CPU with ELF info
-    pub fn init_elfinfo_64(&mut self, info: &LoadElfInfo) {
-        // make sure we are running a ELF64 executable
-        assert!(info.is_64_bit());
+    /// Give every register but x0 an initial value from `fill`. Call it
+    /// before [`Self::init_elfinfo_64`], which sets sp.
+    pub fn fill_uninit_regs(&mut self, fill: &mut UninitFill) {
+        self.reg_file.fill_uninit(fill);
+    }
+
+    /// Initialize CPU with ELF info, and pass `args` to the program as its
+    /// argv on the stack.
+    pub fn init_elfinfo_64(&mut self, info: &LoadElfInfo, args: &[String]) -> Result<()> {
+        // make sure the ELF class matches XLEN
+        assert_eq!(info.is_64_bit(), self.xlen == Xlen::Rv64);
+
+        let sp = self.vm.push_args(args)?;
+        self.reg_file.init_elfinfo_64(info, sp);
+
+        self.set_entry(info.entry_point());
+
+        // Heap begins right after the program
+        self.syscall.set_brk(info.max_vaddr() as u64);
+        Ok(())
+    }
+
+    /// Start running at `pc` instead of the entry point of the program, e.g.
+    /// at a function to test on its own.
+    pub fn set_entry(&mut self, pc: u64) {
+        self.pc.write(pc);
+    }
+
+    /// Write itrace records of every executed instruction into file at `path`.
+    pub fn set_itrace_file(&mut self, path: &Path) -> Result<()> {
+        self.itrace_file = Some(TraceFile::create(path)?);
+        Ok(())
+    }
+
+    /// Check every retired instruction against the reference commit log at
+    /// `path`, stopping with [`Error::Mismatch`] at the first difference.
+    pub fn set_verify_file(&mut self, path: &Path) -> Result<()> {
+        self.verifier = Some(Verifier::open(path, self.xlen)?);
+        Ok(())
+    }
+
+    /// Log the registers after every retired instruction, see [`TraceRegs`],
+    /// into file at `path` or else on stdout.
+    pub fn set_trace_regs(&mut self, trace_regs: TraceRegs, path: Option<&Path>) -> Result<()> {
+        let file = match path {
+            Some(path) => TraceFile::create(path)?,
+            None => TraceFile::stdout(),
+        };
+        self.trace_regs = Some((trace_regs, file));
+        Ok(())
+    }
+
+    /// Raise address-misaligned exceptions on misaligned loads and stores.
+    pub fn set_strict_align(&mut self, strict_align: bool) {
+        self.strict_align = strict_align;
+    }
+
+    /// Stop with [`Exception::DividedByZero`] on division by zero instead of
+    /// giving the results the spec defines.
+    pub fn set_div_trap(&mut self, div_trap: bool) {
+        self.div_trap = div_trap;
+    }
+
+    /// Stop with [`Error::CycleLimitExceeded`] once the clock reaches
+    /// `max_cycles`, however the CPU is run.
+    pub fn set_max_cycles(&mut self, max_cycles: Option<u64>) {
+        self.max_cycles = max_cycles;
+    }
+
+    /// Trace every instruction together with the values of its source
+    /// registers and its result. Replaces the plain itrace line.
+    pub fn set_itrace_verbose(&mut self, itrace_verbose: bool) {
+        self.itrace_verbose = itrace_verbose;
+        if itrace_verbose {
+            self.itrace = false;
+        }
+    }
+
+    /// Serve ebreak between `slli x0, x0, 0x1f` and `srai x0, x0, 7` as a
+    /// semihosting call, with the operation at a0 and its parameter at a1.
+    /// Any other ebreak raises a breakpoint exception as with ebreak as trap.
+    pub fn set_semihosting(&mut self, semihosting: bool) {
+        self.semihosting = semihosting;
+    }
+
+    /// Back `read` of stdin by `input`, which is empty unless set.
+    pub fn set_stdin(&mut self, input: Vec<u8>) {
+        self.syscall.set_stdin(input);
+    }
+
+    /// Stop on exceptions, or jump to the handler at mtvec.
+    pub fn set_trap_mode(&mut self, trap_mode: TrapMode) {
+        self.trap_mode = trap_mode;
+    }
+
+    /// Raise a timer interrupt every `interval` cycles, taken once enabled
+    /// by mstatus.MIE and mie.MTIE.
+    pub fn set_timer_interval(&mut self, interval: u64) {
+        self.csr.set_timer_interval(interval);
+    }
+
+    /// Summary of the run, printed at its end.
+    pub fn summary_lines(&self) -> Vec<String> {
+        vec![
+            format!("CPU run clock: {}", self.clock),
+            format!("CPU executed valid instructions: {}", self.retired),
+            match self.retired {
+                0 => "CPI = n/a".to_string(),
+                retired => format!("CPI = {}", (self.clock as f64) / (retired as f64)),
+            },
+        ]
+    }
+
+    /// Print how many times each instruction is retired.
+    pub fn print_inst_histogram(&self) {
+        print_inst_histogram(&self.inst_histogram, self.retired);
+    }
+
+    /// Print calls and cycles of each function, if profiling.
+    pub fn print_profile(&self) {
+        self.callstack.print_profile();
+    }
+
+    /// Exit code of the program, once it has ended by exit or ebreak.
+    pub fn exit_code(&self) -> Option<u64> {
+        self.exit_code
+    }
+
+    /// Cycles run so far, one per instruction.
+    pub fn cycles(&self) -> u64 {
+        self.clock
+    }
+
+    /// Instructions retired so far.
+    pub fn retired(&self) -> u64 {
+        self.retired
+    }
+
+    /// Take a snapshot of the CPU state and the memory.
+    pub fn snapshot(&self) -> CpuSnapshot {
+        CpuSnapshot {
+            running: self.running,
+            regs: self.reg_file.to_array(),
+            fregs: self.freg_file.clone(),
+            pc: self.pc.read(),
+            clock: self.clock,
+            retired: self.retired,
+            inst_histogram: self.inst_histogram.clone(),
+            csr: self.csr.clone(),
+            wfi: self.wfi,
+            exit_code: self.exit_code,
+            last_store: self.last_store,
+            reservation: self.reservation.clone(),
+            syscall: self.syscall.clone_box(),
+            callstack: self.callstack.snapshot(),
+            mm: self.vm.memory().to_vec(),
+        }
+    }
 
-        self.reg_file.init_elfinfo_64(info);
+    /// Put back a snapshot taken by [`CPU::snapshot`].
+    pub fn restore(&mut self, snapshot: &CpuSnapshot) {
+        self.running = snapshot.running;
+        self.reg_file = RegisterFile::from_array(&snapshot.regs, self.xlen);
+        self.freg_file = snapshot.fregs.clone();
+        self.pc.write(snapshot.pc);
+        self.clock = snapshot.clock;
+        self.retired = snapshot.retired;
+        self.inst_histogram = snapshot.inst_histogram.clone();
+        self.csr = snapshot.csr.clone();
+        self.wfi = snapshot.wfi;
+        self.exit_code = snapshot.exit_code;
+        self.last_store = snapshot.last_store;
+        self.reservation = snapshot.reservation.clone();
+        self.syscall = snapshot.syscall.clone_box();
+        self.callstack.restore(&snapshot.callstack);
+        self.vm.restore_memory(&snapshot.mm);
+    }
 
-        // Load program counter
-        self.pc.write(info.entry_point());
+    /// Initialize CPU for a blank machine without ELF.
+    pub fn init_blank(&mut self, pc: u64, sp: u64) {
+        self.reg_file.write(2, sp);
+        self.pc.write(pc);
     }
 
     /// Run the cpu.
@@ -78,6 +387,7 @@ impl<'a> CPU<'a> {
     /// exception raised.
     pub fn cpu_exec(&mut self, steps: Option<i32>) -> Result<()> {
         self.running = true;
+        self.wfi = false;
         let mut i = 0;
 
         while self.running {
@@ -91,17 +401,147 @@ impl<'a> CPU<'a> {
         Ok(())
     }
 
+    /// Run the cpu until it stops, pc hits one of `breakpoints`, or a store
+    /// writes into one of `watchpoints`, the bytes watched from each address.
+    /// Always executes at least one instruction, so that a CPU standing on a
+    /// breakpoint makes progress. Returns whether a breakpoint or watchpoint
+    /// was hit.
+    pub fn cpu_exec_break(
+        &mut self,
+        breakpoints: &BTreeSet<u64>,
+        watchpoints: &BTreeMap<u64, u64>,
+    ) -> Result<bool> {
+        self.running = true;
+        self.wfi = false;
+        self.exec_once()?;
+        while self.running {
+            if breakpoints.contains(&self.pc.read()) || self.watch_hit(watchpoints).is_some() {
+                return Ok(true);
+            }
+            self.exec_once()?;
+        }
+        Ok(false)
+    }
+
+    /// The first of `watchpoints`, the bytes watched from each address,
+    /// overlapped by the store of the last instruction.
+    pub fn watch_hit(&self, watchpoints: &BTreeMap<u64, u64>) -> Option<u64> {
+        let (vaddr, bytes) = self.last_store?;
+        watchpoints
+            .iter()
+            .find(|(&addr, &len)| addr < vaddr + bytes && vaddr < addr + len)
+            .map(|(&addr, _)| addr)
+    }
+
     ///  Simulate on instruction level
     pub fn exec_once(&mut self) -> Result<()> {
+        check_cycle_limit(self.max_cycles, self.clock, self.pc.read())?;
+        self.take_interrupt();
+        self.last_store = None;
+
         // Fetch
         let pc = self.pc.read();
-        let inst = self.fetch_inst(pc);
+        self.vm.set_access_pc(pc);
+        let inst = match self.fetch_inst(pc) {
+            Ok(inst) => inst,
+            // a faulting fetch traps as the instruction, which does not retire
+            Err(err @ Error::Exception(_)) => {
+                self.take_trap(err, pc)?;
+                self.csr.tick(1, 0);
+                self.clock += 1;
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
 
         // Decode
-        let exec_internal = decode(inst)?;
+        let xlen = self.xlen;
+        let exec_internal = match self.decode_cache.decode(pc, inst, || decode(inst, xlen)) {
+            Ok(exec_internal) => exec_internal,
+            // an undecodable word traps as an illegal instruction
+            Err(_) => {
+                let err = Exception::IllegalInstruction(pc, inst);
+                error!("{err}");
+                self.take_trap(err.into(), pc)?;
+                self.csr.tick(1, 0);
+                self.clock += 1;
+                return Ok(());
+            }
+        };
 
         // Execute
-        self.exec_inst(exec_internal)?;
+        // source values are gone once the instruction writes rd
+        let ExecInternal {
+            inst: op,
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            imm,
+            inst_len,
+            raw_inst,
+            ..
+        } = exec_internal;
+        let sources = if self.itrace_verbose {
+            self.itrace_sources(op, rs1, rs2)
+        } else {
+            Vec::new()
+        };
+        let old_rd = self.reg_file.read(rd);
+        let store = store_target(op, self.reg_file.read(rs1), imm);
+        let result = self.exec_inst(exec_internal);
+        // only retired instructions are recorded, not trapped ones
+        if result.is_ok() || self.exit_code.is_some() {
+            if let Some(itrace_file) = self.itrace_file.as_mut() {
+                itrace_file.inst(pc, raw_inst);
+            }
+        }
+        if self.itrace_verbose {
+            let mut operands = sources;
+            if result.is_ok() {
+                operands.extend(self.itrace_result(op, rd, pc, inst_len));
+            }
+            let line = disasm(pc, op, rd, rs1, rs2, rs3, imm);
+            if operands.is_empty() {
+                trace!("{line}");
+            } else {
+                trace!("{line}\t# {}", operands.join(" "));
+            }
+        }
+        if let Err(err) = result {
+            if self.exit_code.is_some() {
+                // an ebreak ending the program retires, as an exit does
+                self.csr.tick(1, 1);
+                self.clock += 1;
+                self.retired += 1;
+                *self.inst_histogram.entry(op).or_insert(0) += 1;
+                if let Some((trace_regs, file)) = self.trace_regs.as_mut() {
+                    file.regs(*trace_regs, pc, None, &self.reg_file);
+                }
+                return Err(err);
+            }
+            // the trapped instruction does not retire, and the CSR file
+            // raises illegal instructions without knowing them
+            self.take_trap(err.at_inst(pc, raw_inst), pc)?;
+            self.csr.tick(1, 0);
+            self.clock += 1;
+            return Ok(());
+        }
+        self.csr.tick(1, 1);
+        self.clock += 1;
+        self.retired += 1;
+        *self.inst_histogram.entry(op).or_insert(0) += 1;
+        self.last_store = store.map(|(vaddr, bytes)| (xlen.trunc(vaddr), bytes));
+        if let Some((trace_regs, file)) = self.trace_regs.as_mut() {
+            let (_, _, use_rd) = int_operands(op);
+            let write = use_rd.then_some((rd, old_rd));
+            file.regs(*trace_regs, pc, write, &self.reg_file);
+        }
+        if let Some(verifier) = self.verifier.as_mut() {
+            let (_, _, use_rd) = int_operands(op);
+            let write = use_rd.then(|| (rd, self.reg_file.read(rd)));
+            verifier.check(pc, raw_inst, write)?;
+        }
 
         // Memory
 
@@ -110,24 +550,69 @@ impl<'a> CPU<'a> {
         Ok(())
     }
 
-    pub fn fetch_inst(&mut self, pc: u64) -> u32 {
-        check!(pc != 0, "PC is zero.");
-        self.vm.fetch_inst(pc as usize)
+    /// Jump to the trap handler on an exception of the instruction at `epc`
+    /// in vectored trap mode. Other errors are returned as they are.
+    fn take_trap(&mut self, err: Error, epc: u64) -> Result<()> {
+        if self.trap_mode == TrapMode::Vectored {
+            if let Error::Exception(exception) = &err {
+                if let Some(handler) = self.csr.trap(exception, epc) {
+                    if self.itrace || self.itrace_verbose {
+                        trace!("Trap: {} at {:#x}, to {:#x}", exception, epc, handler);
+                    }
+                    self.pc.write(handler);
+                    return Ok(());
+                }
+            }
+        }
+        Err(err)
     }
 
-    /// Simulate 5-stage in-order CPU
-    #[allow(unused)]
-    pub fn exec_microarchitecture(&mut self) -> Result<()> {
-        // Fetch
-
-        // Decode
+    /// Jump to the trap handler on a pending timer interrupt, before the
+    /// instruction at pc.
+    fn take_interrupt(&mut self) {
+        let pc = self.pc.read();
+        if let Some(handler) = self.csr.interrupt(pc) {
+            if self.itrace || self.itrace_verbose {
+                trace!("Interrupt: timer at {:#x}, to {:#x}", pc, handler);
+            }
+            self.pc.write(handler);
+        }
+    }
 
-        // Execute
+    /// Values of the integer source registers of `op` for verbose itrace,
+    /// e.g. `a1=0x3`. x0 is left out.
+    fn itrace_sources(&self, op: Inst64, rs1: u8, rs2: u8) -> Vec<String> {
+        let (use_rs1, use_rs2, _) = int_operands(op);
+        [(use_rs1, rs1), (use_rs2, rs2)]
+            .into_iter()
+            .filter(|&(used, reg)| used && reg != 0)
+            .map(|(_, reg)| format!("{}={:#x}", REGNAME[reg as usize], self.reg_file.read(reg)))
+            .collect()
+    }
 
-        // Memory Access
+    /// Outcome of the retired instruction `op` at `pc` for verbose itrace:
+    /// whether a branch was taken, or the value written to rd.
+    fn itrace_result(&self, op: Inst64, rd: u8, pc: u64, inst_len: u64) -> Option<String> {
+        use Inst64::*;
+        if matches!(op, beq | bne | blt | bge | bltu | bgeu) {
+            let taken = self.pc.read() != pc.wrapping_add(inst_len);
+            return Some(if taken { "-> taken" } else { "-> not taken" }.to_string());
+        }
+        let (_, _, use_rd) = int_operands(op);
+        (use_rd && rd != 0)
+            .then(|| format!("-> {}={:#x}", REGNAME[rd as usize], self.reg_file.read(rd)))
+    }
 
-        // Write Back
-        todo!()
+    /// Fetch the instruction at `pc`. A zero pc, usually a jump through a
+    /// null pointer, and a pc out of memory raise an instruction access
+    /// fault.
+    pub fn fetch_inst(&mut self, pc: u64) -> Result<u32> {
+        if pc == 0 || !self.vm.contains(pc as usize, 2) {
+            let err = Exception::InstructionAccessFault(pc);
+            error!("{err}");
+            return Err(err.into());
+        }
+        self.vm.fetch(pc as usize)
     }
 }
 
@@ -138,21 +623,33 @@ impl<'a> CPU<'a> {
         // get pc
         exec_itrnl.pc = self.pc.read();
         let pc = exec_itrnl.pc; // read pc into intermediate register
+                                // 4 bytes on, or 2 for compressed instructions, wrapping at the top
+                                // of the address space
+        let next_pc = pc.wrapping_add(exec_itrnl.inst_len);
         let mut use_new_pc = false;
 
-        // Get source from register
+        // Get source from register, sign-extended from XLEN bits
+        let xlen = self.xlen;
         let reg_file = &mut self.reg_file;
-        let src1 = reg_file.read(exec_itrnl.rs1);
-        let src2 = reg_file.read(exec_itrnl.rs2);
-        #[allow(unused)]
-        let src3 = reg_file.read(exec_itrnl.rs3); // TODO: float instructions
+        let src1 = xlen.sext(reg_file.read(exec_itrnl.rs1));
+        let src2 = xlen.sext(reg_file.read(exec_itrnl.rs2));
         let imm = exec_itrnl.imm;
 
         let rs1 = exec_itrnl.rs1;
         let rs2 = exec_itrnl.rs2;
-        #[allow(unused)]
-        let rs3 = exec_itrnl.rs3; // TODO: float instructions
+        let rs3 = exec_itrnl.rs3; // float instructions only
         let rd = exec_itrnl.rd;
+        let raw_inst = exec_itrnl.raw_inst;
+
+        // misaligned loads and stores trap with --strict-align
+        let strict_align = self.strict_align;
+        let check_align = |vaddr: u64, bytes: u64, write: bool| -> Result<()> {
+            if strict_align {
+                vm::check_align(vaddr, bytes, write)
+            } else {
+                Ok(())
+            }
+        };
 
         // Calculation
         match exec_itrnl.inst {
@@ -190,6 +687,266 @@ impl<'a> CPU<'a> {
                 let result = sext(trunc_to_32_bit(result), WORD_BITWIDTH);
                 reg_file.write(rd, result as u64);
             }
+            Inst64::amoadd_d => {
+                // R x[rd] = AMO64(M[x[rs1]] + x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amoadd_d, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amoadd_d,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amoadd_w => {
+                // R x[rd] = AMO32(M[x[rs1]] + x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amoadd_w, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amoadd_w,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amoand_d => {
+                // R x[rd] = AMO64(M[x[rs1]] & x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amoand_d, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amoand_d,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amoand_w => {
+                // R x[rd] = AMO32(M[x[rs1]] & x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amoand_w, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amoand_w,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amomax_d => {
+                // R x[rd] = AMO64(M[x[rs1]] MAX x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amomax_d, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amomax_d,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amomax_w => {
+                // R x[rd] = AMO32(M[x[rs1]] MAX x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amomax_w, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amomax_w,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amomaxu_d => {
+                // R x[rd] = AMO64(M[x[rs1]] MAXU x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amomaxu_d, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amomaxu_d,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amomaxu_w => {
+                // R x[rd] = AMO32(M[x[rs1]] MAXU x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amomaxu_w, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amomaxu_w,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amomin_d => {
+                // R x[rd] = AMO64(M[x[rs1]] MIN x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amomin_d, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amomin_d,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amomin_w => {
+                // R x[rd] = AMO32(M[x[rs1]] MIN x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amomin_w, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amomin_w,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amominu_d => {
+                // R x[rd] = AMO64(M[x[rs1]] MINU x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amominu_d, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amominu_d,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amominu_w => {
+                // R x[rd] = AMO32(M[x[rs1]] MINU x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amominu_w, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amominu_w,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amoor_d => {
+                // R x[rd] = AMO64(M[x[rs1]] | x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amoor_d, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result =
+                    exec_atomic(Inst64::amoor_d, vaddr, src2, self.vm, &mut self.reservation)?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amoor_w => {
+                // R x[rd] = AMO32(M[x[rs1]] | x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amoor_w, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result =
+                    exec_atomic(Inst64::amoor_w, vaddr, src2, self.vm, &mut self.reservation)?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amoswap_d => {
+                // R x[rd] = AMO64(M[x[rs1]] SWAP x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amoswap_d, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amoswap_d,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amoswap_w => {
+                // R x[rd] = AMO32(M[x[rs1]] SWAP x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amoswap_w, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amoswap_w,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amoxor_d => {
+                // R x[rd] = AMO64(M[x[rs1]] ^ x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amoxor_d, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amoxor_d,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
+            Inst64::amoxor_w => {
+                // R x[rd] = AMO32(M[x[rs1]] ^ x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, amoxor_w, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(
+                    Inst64::amoxor_w,
+                    vaddr,
+                    src2,
+                    self.vm,
+                    &mut self.reservation,
+                )?;
+                reg_file.write(rd, result);
+            }
             Inst64::and => {
                 // R x[rd] = x[rs1] & x[rs2]
                 if self.itrace {
@@ -211,7 +968,7 @@ impl<'a> CPU<'a> {
                 if self.itrace {
                     trace!("{}", pinst!(pc, auipc, rd, imm=>imm));
                 }
-                let result = pc.wrapping_add((sext(imm, U_TYPE_IMM_BITWIDTH) as u64) << 12);
+                let result = pc_upper_imm(pc, imm);
                 reg_file.write(rd, result);
             }
             Inst64::beq => {
@@ -275,52 +1032,93 @@ impl<'a> CPU<'a> {
                 }
             }
 
+            Inst64::csrrc => {
+                // I t = CSRs[csr]; CSRs[csr] = t &~x[rs1]; x[rd] = t
+                if self.itrace {
+                    trace!("{}", pinst!(pc, csrrc, rd, imm=>csr, rs1));
+                }
+                let result = self.csr.exec(Inst64::csrrc, imm as u16, rd, rs1, src1)?;
+                reg_file.write(rd, result);
+            }
+            Inst64::csrrci => {
+                // I t = CSRs[csr]; CSRs[csr] = t &~zimm; x[rd] = t
+                if self.itrace {
+                    trace!("{}", pinst!(pc, csrrci, rd, imm=>csr, rs1=>imm));
+                }
+                let result = self.csr.exec(Inst64::csrrci, imm as u16, rd, rs1, src1)?;
+                reg_file.write(rd, result);
+            }
+            Inst64::csrrs => {
+                // I t = CSRs[csr]; CSRs[csr] = t | x[rs1]; x[rd] = t
+                if self.itrace {
+                    trace!("{}", pinst!(pc, csrrs, rd, imm=>csr, rs1));
+                }
+                let result = self.csr.exec(Inst64::csrrs, imm as u16, rd, rs1, src1)?;
+                reg_file.write(rd, result);
+            }
+            Inst64::csrrsi => {
+                // I t = CSRs[csr]; CSRs[csr] = t | zimm; x[rd] = t
+                if self.itrace {
+                    trace!("{}", pinst!(pc, csrrsi, rd, imm=>csr, rs1=>imm));
+                }
+                let result = self.csr.exec(Inst64::csrrsi, imm as u16, rd, rs1, src1)?;
+                reg_file.write(rd, result);
+            }
+            Inst64::csrrw => {
+                // I t = CSRs[csr]; CSRs[csr] = x[rs1]; x[rd] = t
+                if self.itrace {
+                    trace!("{}", pinst!(pc, csrrw, rd, imm=>csr, rs1));
+                }
+                let result = self.csr.exec(Inst64::csrrw, imm as u16, rd, rs1, src1)?;
+                reg_file.write(rd, result);
+            }
+            Inst64::csrrwi => {
+                // I x[rd] = CSRs[csr]; CSRs[csr] = zimm
+                if self.itrace {
+                    trace!("{}", pinst!(pc, csrrwi, rd, imm=>csr, rs1=>imm));
+                }
+                let result = self.csr.exec(Inst64::csrrwi, imm as u16, rd, rs1, src1)?;
+                reg_file.write(rd, result);
+            }
             Inst64::div => {
                 // R x[rd] = x[rs1] ÷s x[rs2]
                 if self.itrace {
                     trace!("{}", pinst!(pc, div, rd, rs1, rs2));
                 }
-                if src2 == 0 {
+                if self.div_trap && div_by_zero(Inst64::div, src2) {
                     return Err(Error::Exception(Exception::DividedByZero));
                 }
-                let result = (src1 as i64).wrapping_div(src2 as i64);
-                reg_file.write(rd, result as u64);
+                reg_file.write(rd, div_rem(Inst64::div, src1, src2, xlen));
             }
             Inst64::divu => {
                 // R x[rd] = x[rs1] ÷u x[rs2]
                 if self.itrace {
                     trace!("{}", pinst!(pc, divu, rd, rs1, rs2));
                 }
-                if src2 == 0 {
+                if self.div_trap && div_by_zero(Inst64::divu, src2) {
                     return Err(Error::Exception(Exception::DividedByZero));
                 }
-                let result = src1.wrapping_div(src2);
-                reg_file.write(rd, result);
+                reg_file.write(rd, div_rem(Inst64::divu, src1, src2, xlen));
             }
             Inst64::divuw => {
                 // R x[rd] = sext(x[rs1][31:0] ÷u x[rs2][31:0])
                 if self.itrace {
                     trace!("{}", pinst!(pc, divuw, rd, rs1, rs2));
                 }
-                if trunc_to_32_bit(src2) == 0 {
+                if self.div_trap && div_by_zero(Inst64::divuw, src2) {
                     return Err(Error::Exception(Exception::DividedByZero));
                 }
-                let result = trunc_to_32_bit(src1).wrapping_div(trunc_to_32_bit(src2));
-                let result = sext(trunc_to_32_bit(result), WORD_BITWIDTH);
-                reg_file.write(rd, result as u64);
+                reg_file.write(rd, div_rem(Inst64::divuw, src1, src2, xlen));
             }
             Inst64::divw => {
                 // R x[rd] = sext(x[rs1][31:0] ÷s x[rs2][31:0])
                 if self.itrace {
                     trace!("{}", pinst!(pc, divw, rd, rs1, rs2));
                 }
-                if trunc_to_32_bit(src2) == 0 {
+                if self.div_trap && div_by_zero(Inst64::divw, src2) {
                     return Err(Error::Exception(Exception::DividedByZero));
                 }
-                let result =
-                    (trunc_to_32_bit(src1) as i32).wrapping_div(trunc_to_32_bit(src2) as i32);
-                let result = sext(trunc_to_32_bit(result as u64), WORD_BITWIDTH);
-                reg_file.write(rd, result as u64);
+                reg_file.write(rd, div_rem(Inst64::divw, src1, src2, xlen));
             }
             Inst64::ebreak => {
                 // I RaiseException(Breakpoint)
@@ -328,42 +1126,337 @@ impl<'a> CPU<'a> {
                 if self.itrace {
                     trace!("{}", pinst!(pc, ebreak));
                 }
-                let x10 = reg_file.read(10);
-                self.halt(pc, x10); // HALT at current code.
-                let msg = format!("ebreak at {:#x}, code {}", pc, x10);
-                return Err(Error::Execute(msg)); // Simulate exception
+                if self.semihosting && is_semihosting_call(self.vm, pc) {
+                    if let Some(code) = semihost(reg_file, self.vm, self.xlen)? {
+                        self.halt(pc, code); // exit
+                        return Ok(());
+                    }
+                } else {
+                    if self.ebreak_as_trap || self.semihosting {
+                        // Resume after the ebreak once the debugger continues.
+                        self.pc.write(next_pc);
+                        return Err(Exception::Breakpoint(pc).into());
+                    }
+                    let x10 = reg_file.read(10);
+                    self.halt(pc, x10); // HALT at current code.
+                    let msg = format!("ebreak at {:#x}, code {}", pc, x10);
+                    return Err(Error::Execute(msg)); // Simulate exception
+                }
+            }
+            Inst64::wfi => {
+                // I while no interrupts pending: idle
+                // The timer interrupt is taken before the next instruction.
+                // If nothing could raise an interrupt, the program is over.
+                if self.itrace {
+                    trace!("{}", pinst!(pc, wfi));
+                }
+                match self.csr.wfi_cycles() {
+                    Some(cycles) => {
+                        self.csr.tick(cycles, 0);
+                        self.clock += cycles;
+                    }
+                    None => {
+                        info!("WFI: no pending interrupts, halting");
+                        self.running = false;
+                        self.wfi = true;
+                    }
+                }
             }
             Inst64::ecall => {
-                todo!("ecall");
+                // I RaiseException(EnvironmentCall)
+                // Served by the syscall handler: number at a7, return value at a0.
+                if self.itrace {
+                    trace!("{}", pinst!(pc, ecall));
+                }
+                if let Some(code) = ecall(self.syscall.as_mut(), reg_file, self.vm)? {
+                    self.halt(pc, code); // exit
+                    return Ok(());
+                }
+            }
+
+            Inst64::fadd_s => {
+                // R f[rd] = f[rs1] + f[rs2]
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fadd_s, f rd, f rs1, f rs2));
+                }
+                let (a, b) = (self.freg_file.read(rs1), self.freg_file.read(rs2));
+                let result = a + b;
+                self.csr.accrue_fflags(arith_flags(&[a, b], result));
+                self.freg_file.write(rd, result);
+            }
+            Inst64::fclass_s => {
+                // R x[rd] = classifys(f[rs1])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fclass_s, rd, f rs1));
+                }
+                reg_file.write(rd, fclass_s(self.freg_file.read(rs1)));
+            }
+            Inst64::fcvt_s_w => {
+                // R f[rd] = f32_{s32}(x[rs1])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fcvt_s_w, f rd, rs1));
+                }
+                let (result, flags) = fcvt_s_int(src1 as i32 as i64);
+                self.csr.accrue_fflags(flags);
+                self.freg_file.write(rd, result);
+            }
+            Inst64::fcvt_s_wu => {
+                // R f[rd] = f32_{u32}(x[rs1])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fcvt_s_wu, f rd, rs1));
+                }
+                let (result, flags) = fcvt_s_int(src1 as u32 as i64);
+                self.csr.accrue_fflags(flags);
+                self.freg_file.write(rd, result);
+            }
+            Inst64::fcvt_w_s => {
+                // R x[rd] = sext(s32_{f32}(f[rs1]))
+                // The rounding mode is honored, C casts use rtz
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fcvt_w_s, rd, f rs1));
+                }
+                let rm = rounding_mode(pc, exec_itrnl.raw_inst, self.csr.frm())?;
+                let (result, flags) = fcvt_w_s(self.freg_file.read(rs1), rm);
+                self.csr.accrue_fflags(flags);
+                reg_file.write(rd, result);
+            }
+            Inst64::fcvt_wu_s => {
+                // R x[rd] = sext(u32_{f32}(f[rs1]))
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fcvt_wu_s, rd, f rs1));
+                }
+                let rm = rounding_mode(pc, exec_itrnl.raw_inst, self.csr.frm())?;
+                let (result, flags) = fcvt_wu_s(self.freg_file.read(rs1), rm);
+                self.csr.accrue_fflags(flags);
+                reg_file.write(rd, result);
+            }
+            Inst64::fdiv_s => {
+                // R f[rd] = f[rs1] ÷ f[rs2]
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fdiv_s, f rd, f rs1, f rs2));
+                }
+                let (a, b) = (self.freg_file.read(rs1), self.freg_file.read(rs2));
+                let result = a / b;
+                // a finite non-zero dividend over zero is not an overflow
+                let flags = if b == 0.0 && a.is_finite() && a != 0.0 {
+                    FFLAG_DZ
+                } else {
+                    arith_flags(&[a, b], result)
+                };
+                self.csr.accrue_fflags(flags);
+                self.freg_file.write(rd, result);
+            }
+            Inst64::feq_s | Inst64::flt_s | Inst64::fle_s => {
+                // R x[rd] = f[rs1] == f[rs2] / f[rs1] < f[rs2] / f[rs1] ≤ f[rs2]
+                if self.itrace {
+                    match exec_itrnl.inst {
+                        Inst64::feq_s => trace!("{}", pinst!(pc, feq_s, rd, f rs1, f rs2)),
+                        Inst64::flt_s => trace!("{}", pinst!(pc, flt_s, rd, f rs1, f rs2)),
+                        _ => trace!("{}", pinst!(pc, fle_s, rd, f rs1, f rs2)),
+                    }
+                }
+                let (a, b) = (self.freg_file.read(rs1), self.freg_file.read(rs2));
+                let (result, flags) = fcmp_s(exec_itrnl.inst, a, b);
+                self.csr.accrue_fflags(flags);
+                reg_file.write(rd, result);
+            }
+            Inst64::fence | Inst64::fence_i | Inst64::pause => {
+                // I Fence(pred, succ) / Fence.i / Pause
+                // A single in-order hart always sees its own loads, stores
+                // and instruction writes in order, so fences are no-ops.
+                // Nothing else runs while the hart pauses.
+                if self.itrace {
+                    match exec_itrnl.inst {
+                        Inst64::fence => trace!("{}", pinst!(pc, fence)),
+                        Inst64::pause => trace!("{}", pinst!(pc, pause)),
+                        _ => trace!("{}", pinst!(pc, fence_i)),
+                    }
+                }
+            }
+            Inst64::flw => {
+                // I f[rd] = M[x[rs1] + sext(offset)][31:0]
+                if self.itrace {
+                    trace!("{}", pinst!(pc, flw, f rd, imm(rs1)));
+                }
+                let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
+                check_align(vaddr, 4, false)?;
+                let result = self.vm.mread::<u32>(vaddr as usize)?;
+                self.freg_file.write_bits(rd, result);
+            }
+            Inst64::fmadd_s => {
+                // R4 f[rd] = f[rs1] × f[rs2] + f[rs3]
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fmadd_s, f rd, f rs1, f rs2, f rs3));
+                }
+                let (a, b, c) = (
+                    self.freg_file.read(rs1),
+                    self.freg_file.read(rs2),
+                    self.freg_file.read(rs3),
+                );
+                let result = a.mul_add(b, c);
+                self.csr.accrue_fflags(arith_flags(&[a, b, c], result));
+                self.freg_file.write(rd, result);
+            }
+            Inst64::fmax_s | Inst64::fmin_s => {
+                // R f[rd] = max(f[rs1], f[rs2]) / min(f[rs1], f[rs2])
+                let max = exec_itrnl.inst == Inst64::fmax_s;
+                if self.itrace {
+                    if max {
+                        trace!("{}", pinst!(pc, fmax_s, f rd, f rs1, f rs2));
+                    } else {
+                        trace!("{}", pinst!(pc, fmin_s, f rd, f rs1, f rs2));
+                    }
+                }
+                let (a, b) = (self.freg_file.read(rs1), self.freg_file.read(rs2));
+                let (result, flags) = fmin_max_s(a, b, max);
+                self.csr.accrue_fflags(flags);
+                self.freg_file.write(rd, result);
+            }
+            Inst64::fmsub_s => {
+                // R4 f[rd] = f[rs1] × f[rs2] - f[rs3]
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fmsub_s, f rd, f rs1, f rs2, f rs3));
+                }
+                let (a, b, c) = (
+                    self.freg_file.read(rs1),
+                    self.freg_file.read(rs2),
+                    self.freg_file.read(rs3),
+                );
+                let result = a.mul_add(b, -c);
+                self.csr.accrue_fflags(arith_flags(&[a, b, c], result));
+                self.freg_file.write(rd, result);
+            }
+            Inst64::fmul_s => {
+                // R f[rd] = f[rs1] × f[rs2]
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fmul_s, f rd, f rs1, f rs2));
+                }
+                let (a, b) = (self.freg_file.read(rs1), self.freg_file.read(rs2));
+                let result = a * b;
+                self.csr.accrue_fflags(arith_flags(&[a, b], result));
+                self.freg_file.write(rd, result);
+            }
+            Inst64::fmv_w_x => {
+                // R f[rd] = x[rs1][31:0]
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fmv_w_x, f rd, rs1));
+                }
+                self.freg_file.write_bits(rd, src1 as u32);
+            }
+            Inst64::fmv_x_w => {
+                // R x[rd] = sext(f[rs1][31:0])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fmv_x_w, rd, f rs1));
+                }
+                let result = sext(self.freg_file.read_bits(rs1) as u64, WORD_BITWIDTH);
+                reg_file.write(rd, result as u64);
+            }
+            Inst64::fnmadd_s => {
+                // R4 f[rd] = -f[rs1] × f[rs2] - f[rs3]
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fnmadd_s, f rd, f rs1, f rs2, f rs3));
+                }
+                let (a, b, c) = (
+                    self.freg_file.read(rs1),
+                    self.freg_file.read(rs2),
+                    self.freg_file.read(rs3),
+                );
+                let result = (-a).mul_add(b, -c);
+                self.csr.accrue_fflags(arith_flags(&[a, b, c], result));
+                self.freg_file.write(rd, result);
+            }
+            Inst64::fnmsub_s => {
+                // R4 f[rd] = -f[rs1] × f[rs2] + f[rs3]
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fnmsub_s, f rd, f rs1, f rs2, f rs3));
+                }
+                let (a, b, c) = (
+                    self.freg_file.read(rs1),
+                    self.freg_file.read(rs2),
+                    self.freg_file.read(rs3),
+                );
+                let result = (-a).mul_add(b, c);
+                self.csr.accrue_fflags(arith_flags(&[a, b, c], result));
+                self.freg_file.write(rd, result);
+            }
+            Inst64::fsgnj_s | Inst64::fsgnjn_s | Inst64::fsgnjx_s => {
+                // R f[rd] = {f[rs2][31], f[rs1][30:0]}, with the sign of
+                // f[rs2] negated for fsgnjn, or xored with f[rs1] for fsgnjx
+                if self.itrace {
+                    match exec_itrnl.inst {
+                        Inst64::fsgnj_s => trace!("{}", pinst!(pc, fsgnj_s, f rd, f rs1, f rs2)),
+                        Inst64::fsgnjn_s => trace!("{}", pinst!(pc, fsgnjn_s, f rd, f rs1, f rs2)),
+                        _ => trace!("{}", pinst!(pc, fsgnjx_s, f rd, f rs1, f rs2)),
+                    }
+                }
+                const SIGN: u32 = 1 << 31;
+                let (a, b) = (self.freg_file.read_bits(rs1), self.freg_file.read_bits(rs2));
+                let sign = match exec_itrnl.inst {
+                    Inst64::fsgnj_s => b & SIGN,
+                    Inst64::fsgnjn_s => !b & SIGN,
+                    _ => (a ^ b) & SIGN,
+                };
+                self.freg_file.write_bits(rd, (a & !SIGN) | sign);
+            }
+            Inst64::fsqrt_s => {
+                // R f[rd] = √f[rs1]
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fsqrt_s, f rd, f rs1));
+                }
+                let a = self.freg_file.read(rs1);
+                let result = a.sqrt();
+                self.csr.accrue_fflags(arith_flags(&[a], result));
+                self.freg_file.write(rd, result);
+            }
+            Inst64::fsub_s => {
+                // R f[rd] = f[rs1] - f[rs2]
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fsub_s, f rd, f rs1, f rs2));
+                }
+                let (a, b) = (self.freg_file.read(rs1), self.freg_file.read(rs2));
+                let result = a - b;
+                self.csr.accrue_fflags(arith_flags(&[a, b], result));
+                self.freg_file.write(rd, result);
+            }
+            Inst64::fsw => {
+                // S M[x[rs1] + sext(offset)] = f[rs2][31:0]
+                if self.itrace {
+                    trace!("{}", pinst!(pc, fsw, f rs2, imm(rs1)));
+                }
+                let vaddr = src1.wrapping_add(sext(imm, S_TYPE_IMM_BITWIDTH) as u64);
+                check_align(vaddr, 4, true)?;
+                let write_val = self.freg_file.read_bits(rs2);
+                self.vm.mwrite::<u32>(vaddr as usize, write_val)?;
+                self.reservation.store(xlen.trunc(vaddr), 4);
             }
 
             Inst64::jal => {
-                // J x[rd] = pc+4; pc += sext(offset)
+                // J x[rd] = pc+len; pc += sext(offset)
                 if self.itrace {
                     trace!("{}", pinst!(pc, jal, rd, imm=>offset));
                 }
-                reg_file.write(rd, pc + 4); // rd default to x1
+                reg_file.write(rd, next_pc); // rd default to x1
                 exec_itrnl.pc = pc.wrapping_add(sext(imm, J_TYPE_IMM_BITWIDTH) as u64);
 
                 // call
                 let target_pc = exec_itrnl.pc;
-                self.callstack.call(pc, target_pc);
+                self.callstack.call(pc, target_pc, self.clock);
 
                 use_new_pc = true;
             }
             Inst64::jalr => {
-                // I t=pc+4; pc=(x[rs1]+sext(offset))&∼1; x[rd]=t
+                // I t=pc+len; pc=(x[rs1]+sext(offset))&∼1; x[rd]=t
                 if self.itrace {
                     trace!("{}", pinst!(pc, jalr, rd, imm(rs1)));
                 }
 
                 // ret
                 if exec_itrnl.raw_inst == 0x00008067 {
-                    self.callstack.ret(pc);
+                    self.callstack.ret(pc, self.clock);
                 }
 
                 exec_itrnl.pc = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64) & (!1);
-                reg_file.write(rd, pc + 4); // rd default to x1
+                reg_file.write(rd, next_pc); // rd default to x1
                 use_new_pc = true;
             }
 
@@ -373,7 +1466,7 @@ impl<'a> CPU<'a> {
                     trace!("{}", pinst!(pc, lb, rd, imm(rs1)));
                 }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
-                let result = self.vm.mread::<u8>(vaddr as usize);
+                let result = self.vm.mread::<u8>(vaddr as usize)?;
                 // SEXT in RV64I
                 let result = sext(result as u64, BYTE_BITWIDTH);
                 reg_file.write(rd, result as u64);
@@ -384,7 +1477,7 @@ impl<'a> CPU<'a> {
                     trace!("{}", pinst!(pc, lbu, rd, imm(rs1)));
                 }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
-                let result = self.vm.mread::<u8>(vaddr as usize);
+                let result = self.vm.mread::<u8>(vaddr as usize)?;
                 // ZERO extend: just as u64
                 reg_file.write(rd, result as u64);
             }
@@ -394,7 +1487,8 @@ impl<'a> CPU<'a> {
                     trace!("{}", pinst!(pc, ld, rd, imm(rs1)));
                 }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
-                let result = self.vm.mread::<u64>(vaddr as usize);
+                check_align(vaddr, 8, false)?;
+                let result = self.vm.mread::<u64>(vaddr as usize)?;
                 reg_file.write(rd, result);
             }
             Inst64::lh => {
@@ -403,7 +1497,8 @@ impl<'a> CPU<'a> {
                     trace!("{}", pinst!(pc, lh, rd, imm(rs1)));
                 }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
-                let result = self.vm.mread::<u16>(vaddr as usize);
+                check_align(vaddr, 2, false)?;
+                let result = self.vm.mread::<u16>(vaddr as usize)?;
                 // SEXT in RV64I
                 let result = sext(result as u64, HALF_BITWIDTH);
                 reg_file.write(rd, result as u64);
@@ -414,17 +1509,36 @@ impl<'a> CPU<'a> {
                     trace!("{}", pinst!(pc, lhu, rd, imm(rs1)));
                 }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
-                let result = self.vm.mread::<u16>(vaddr as usize);
+                check_align(vaddr, 2, false)?;
+                let result = self.vm.mread::<u16>(vaddr as usize)?;
                 // ZERO extend: just as u64
                 reg_file.write(rd, result as u64);
             }
+            Inst64::lr_d => {
+                // R x[rd] = LoadReserved64(M[x[rs1]])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, lr_d, rd, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(Inst64::lr_d, vaddr, 0, self.vm, &mut self.reservation)?;
+                reg_file.write(rd, result);
+            }
+            Inst64::lr_w => {
+                // R x[rd] = LoadReserved32(M[x[rs1]])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, lr_w, rd, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result = exec_atomic(Inst64::lr_w, vaddr, 0, self.vm, &mut self.reservation)?;
+                reg_file.write(rd, result);
+            }
             Inst64::lui => {
                 // U x[rd] = sext(immediate[31:12] << 12)
                 if self.itrace {
                     trace!("{}", pinst!(pc, lui, rd, imm=>imm));
                 }
-                let mask: u64 = !0b1111_1111_1111;
-                let result = ((sext(imm, U_TYPE_IMM_BITWIDTH) << 12) as u64) & mask;
+                // the 32-bit result is sign-extended on RV64
+                let result = upper_imm(imm);
                 reg_file.write(rd, result);
             }
             Inst64::lw => {
@@ -433,7 +1547,8 @@ impl<'a> CPU<'a> {
                     trace!("{}", pinst!(pc, lw, rd, imm(rs1)));
                 }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
-                let result = self.vm.mread::<u32>(vaddr as usize);
+                check_align(vaddr, 4, false)?;
+                let result = self.vm.mread::<u32>(vaddr as usize)?;
                 // SEXT in RV64I
                 let result = sext(result as u64, WORD_BITWIDTH);
                 reg_file.write(rd, result as u64);
@@ -444,7 +1559,8 @@ impl<'a> CPU<'a> {
                     trace!("{}", pinst!(pc, lwu, rd, imm(rs1)));
                 }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
-                let result = self.vm.mread::<u32>(vaddr as usize);
+                check_align(vaddr, 4, false)?;
+                let result = self.vm.mread::<u32>(vaddr as usize)?;
                 // ZERO extend: just as u64
                 reg_file.write(rd, result as u64);
             }
@@ -453,7 +1569,8 @@ impl<'a> CPU<'a> {
                 if self.itrace {
                     trace!("{}", pinst!(pc, mret));
                 }
-                todo!()
+                exec_itrnl.pc = self.csr.mret()?;
+                use_new_pc = true;
             }
             Inst64::mul => {
                 // R x[rd] = x[rs1] × x[rs2]
@@ -468,9 +1585,10 @@ impl<'a> CPU<'a> {
                 if self.itrace {
                     trace!("{}", pinst!(pc, mulh, rd, rs1, rs2));
                 }
-                // RV64
-                let result = (src1 as i128).wrapping_mul(src2 as i128);
-                let result = get_high_64_bit(result as u128);
+                let result = match xlen {
+                    Xlen::Rv32 => mulh_32(src1, src2),
+                    Xlen::Rv64 => mulh_64(src1, src2),
+                };
                 reg_file.write(rd, result);
             }
             Inst64::mulhsu => {
@@ -478,10 +1596,10 @@ impl<'a> CPU<'a> {
                 if self.itrace {
                     trace!("{}", pinst!(pc, mulhsu, rd, rs1, rs2));
                 }
-                let t_src1 = src1 as i64;
-                let t_src2 = src2 as u64;
-                let result = (t_src1 as i128).wrapping_mul(t_src2 as i128);
-                let result = get_high_64_bit(result as u128);
+                let result = match xlen {
+                    Xlen::Rv32 => mulhsu_32(src1, src2),
+                    Xlen::Rv64 => mulhsu_64(src1, src2),
+                };
                 reg_file.write(rd, result);
             }
             Inst64::mulhu => {
@@ -489,8 +1607,10 @@ impl<'a> CPU<'a> {
                 if self.itrace {
                     trace!("{}", pinst!(pc, mulhu, rd, rs1, rs2));
                 }
-                let result = (src1 as u128).wrapping_mul(src2 as u128);
-                let result = get_high_64_bit(result);
+                let result = match xlen {
+                    Xlen::Rv32 => mulhu_32(src1, src2),
+                    Xlen::Rv64 => mulhu_64(src1, src2),
+                };
                 reg_file.write(rd, result);
             }
             Inst64::mulw => {
@@ -524,49 +1644,40 @@ impl<'a> CPU<'a> {
                 if self.itrace {
                     trace!("{}", pinst!(pc, rem, rd, rs1, rs2));
                 }
-                if src2 == 0 {
+                if self.div_trap && div_by_zero(Inst64::rem, src2) {
                     return Err(Error::Exception(Exception::DividedByZero));
                 }
-                let result = (src1 as i64).wrapping_rem(src2 as i64);
-                reg_file.write(rd, result as u64);
+                reg_file.write(rd, div_rem(Inst64::rem, src1, src2, xlen));
             }
             Inst64::remu => {
                 // R x[rd] = x[rs1] %u x[rs2]
                 if self.itrace {
                     trace!("{}", pinst!(pc, remu, rd, rs1, rs2));
                 }
-                if src2 == 0 {
+                if self.div_trap && div_by_zero(Inst64::remu, src2) {
                     return Err(Error::Exception(Exception::DividedByZero));
                 }
-                let result = src1.wrapping_rem(src2);
-                reg_file.write(rd, result);
+                reg_file.write(rd, div_rem(Inst64::remu, src1, src2, xlen));
             }
             Inst64::remuw => {
                 // R x[rd] = sext(x[rs1][31:0] %u x[rs2][31:0])
                 if self.itrace {
                     trace!("{}", pinst!(pc, remuw, rd, rs1, rs2));
                 }
-                if src2 == 0 {
+                if self.div_trap && div_by_zero(Inst64::remuw, src2) {
                     return Err(Error::Exception(Exception::DividedByZero));
                 }
-                let t_src1 = trunc_to_32_bit(src1);
-                let t_src2 = trunc_to_32_bit(src2);
-                let result = t_src1.wrapping_rem(t_src2);
-                let result = sext(result, WORD_BITWIDTH);
-                reg_file.write(rd, result as u64);
+                reg_file.write(rd, div_rem(Inst64::remuw, src1, src2, xlen));
             }
             Inst64::remw => {
                 // R x[rd] = sext(x[rs1][31:0] %s x[rs2][31:0])
                 if self.itrace {
                     trace!("{}", pinst!(pc, remw, rd, rs1, rs2));
                 }
-                if src2 == 0 {
+                if self.div_trap && div_by_zero(Inst64::remw, src2) {
                     return Err(Error::Exception(Exception::DividedByZero));
                 }
-                let t_src1 = trunc_to_32_bit(src1);
-                let t_src2 = trunc_to_32_bit(src2);
-                let result = (t_src1 as i64).wrapping_rem(t_src2 as i64);
-                reg_file.write(rd, result as u64);
+                reg_file.write(rd, div_rem(Inst64::remw, src1, src2, xlen));
             }
             Inst64::sb => {
                 // S M[x[rs1] + sext(offset)] = x[rs2][7:0]
@@ -575,7 +1686,28 @@ impl<'a> CPU<'a> {
                 }
                 let vaddr = src1.wrapping_add(sext(imm, S_TYPE_IMM_BITWIDTH) as u64);
                 let result = trunc_to_8_bit(src2);
-                self.vm.mwrite::<u8>(vaddr as usize, result as u8);
+                self.vm.mwrite::<u8>(vaddr as usize, result as u8)?;
+                self.reservation.store(xlen.trunc(vaddr), 1);
+            }
+            Inst64::sc_d => {
+                // R x[rd] = StoreConditional64(M[x[rs1]], x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, sc_d, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result =
+                    exec_atomic(Inst64::sc_d, vaddr, src2, self.vm, &mut self.reservation)?;
+                reg_file.write(rd, result);
+            }
+            Inst64::sc_w => {
+                // R x[rd] = StoreConditional32(M[x[rs1]], x[rs2])
+                if self.itrace {
+                    trace!("{}", pinst!(pc, sc_w, rd, rs2, (rs1)));
+                }
+                let vaddr = xlen.trunc(src1);
+                let result =
+                    exec_atomic(Inst64::sc_w, vaddr, src2, self.vm, &mut self.reservation)?;
+                reg_file.write(rd, result);
             }
             Inst64::sd => {
                 // S M[x[rs1] + sext(offset)] = x[rs2][63:0]
@@ -583,7 +1715,9 @@ impl<'a> CPU<'a> {
                     trace!("{}", pinst!(pc, sd, rs2, imm(rs1)));
                 }
                 let vaddr = src1.wrapping_add(sext(imm, S_TYPE_IMM_BITWIDTH) as u64);
-                self.vm.mwrite::<u64>(vaddr as usize, src2);
+                check_align(vaddr, 8, true)?;
+                self.vm.mwrite::<u64>(vaddr as usize, src2)?;
+                self.reservation.store(xlen.trunc(vaddr), 8);
                 // self.vm.mread::<u64>(vaddr as usize);
             }
             Inst64::sh => {
@@ -592,17 +1726,18 @@ impl<'a> CPU<'a> {
                     trace!("{}", pinst!(pc, sh, rs2, imm(rs1)));
                 }
                 let vaddr = src1.wrapping_add(sext(imm, S_TYPE_IMM_BITWIDTH) as u64);
+                check_align(vaddr, 2, true)?;
                 self.vm
-                    .mwrite::<u16>(vaddr as usize, trunc_to_16_bit(src2) as u16);
+                    .mwrite::<u16>(vaddr as usize, trunc_to_16_bit(src2) as u16)?;
+                self.reservation.store(xlen.trunc(vaddr), 2);
             }
             Inst64::sll => {
                 // R x[rd] = x[rs1] << x[rs2]
                 if self.itrace {
                     trace!("{}", pinst!(pc, sll, rd, rs1, rs2));
                 }
-                // let t_src2 = trunc_to_5_bit(src2); // RV32
-                let t_src2 = trunc_to_6_bit(src2); // RV64
-                let result = src1.wrapping_shl(t_src2 as u32);
+                let t_src2 = xlen.shamt(src2);
+                let result = src1.wrapping_shl(t_src2);
                 reg_file.write(rd, result);
             }
             Inst64::slli => {
@@ -610,14 +1745,9 @@ impl<'a> CPU<'a> {
                 if self.itrace {
                     trace!("{}", pinst!(pc, slli, rd, rs1, imm=>imm));
                 }
-                // RV32I
-                // let (shamt, legal) = trunc_to_5_bit_and_check(imm);
-                // if !legal {
-                //     return Err(Error::Exception(Exception::IllegalInstruction));
-                // }
-                // RV64I
-                let shamt = trunc_to_6_bit(imm);
-                let result = src1.wrapping_shl(shamt as u32);
+                // shamt[5] is reserved on RV32, rejected by decode
+                let shamt = xlen.shamt(imm);
+                let result = src1.wrapping_shl(shamt);
                 reg_file.write(rd, result);
             }
             Inst64::slliw => {
@@ -627,7 +1757,7 @@ impl<'a> CPU<'a> {
                 }
                 let (shamt, legal) = trunc_to_5_bit_and_check(imm);
                 if !legal {
-                    return Err(Error::Exception(Exception::IllegalInstruction));
+                    return Err(Exception::IllegalInstruction(pc, raw_inst).into());
                 }
                 let result = src1.wrapping_shl(shamt as u32);
                 let result = sext(trunc_to_32_bit(result), WORD_BITWIDTH);
@@ -683,10 +1813,9 @@ impl<'a> CPU<'a> {
                 if self.itrace {
                     trace!("{}", pinst!(pc, sra, rd, rs1, rs2));
                 }
-                // let t_src2 = trunc_to_5_bit(src2); // RV32
-                let t_src2 = trunc_to_6_bit(src2); // RV64
-                                                   // i64 shr automatically fill high bits with sign-bit
-                let result = (src1 as i64).wrapping_shr(t_src2 as u32);
+                let t_src2 = xlen.shamt(src2);
+                // i64 shr automatically fill high bits with sign-bit
+                let result = (src1 as i64).wrapping_shr(t_src2);
                 reg_file.write(rd, result as u64);
             }
             Inst64::srai => {
@@ -694,14 +1823,9 @@ impl<'a> CPU<'a> {
                 if self.itrace {
                     trace!("{}", pinst!(pc, srai, rd, rs1, imm=>imm));
                 }
-                // RV32I
-                // let (shamt, legal) = trunc_to_5_bit_and_check(imm);
-                // if !legal {
-                //     return Err(Error::Exception(Exception::IllegalInstruction))
-                // }
-                // RV64I
-                let shamt = trunc_to_6_bit(imm);
-                let result = (src1 as i64).wrapping_shr(shamt as u32);
+                // shamt[5] is reserved on RV32, rejected by decode
+                let shamt = xlen.shamt(imm);
+                let result = (src1 as i64).wrapping_shr(shamt);
                 reg_file.write(rd, result as u64);
             }
             Inst64::sraiw => {
@@ -712,7 +1836,7 @@ impl<'a> CPU<'a> {
                 let t_src1: i64 = sext(trunc_to_32_bit(src1), WORD_BITWIDTH);
                 let (shamt, legal) = trunc_to_5_bit_and_check(imm);
                 if !legal {
-                    return Err(Error::Exception(Exception::IllegalInstruction));
+                    return Err(Exception::IllegalInstruction(pc, raw_inst).into());
                 }
                 let result = t_src1.wrapping_shr(shamt as u32);
                 reg_file.write(rd, result as u64);
@@ -733,17 +1857,17 @@ impl<'a> CPU<'a> {
                 if self.itrace {
                     trace!("{}", pinst!(pc, sret));
                 }
-                todo!()
+                // S-mode is not supported
+                return Err(Exception::IllegalInstruction(pc, raw_inst).into());
             }
             Inst64::srl => {
                 // R x[rd] = x[rs1] >>u x[rs2]
                 if self.itrace {
                     trace!("{}", pinst!(pc, srl, rd, rs1, rs2));
                 }
-                // let t_src2 = trunc_to_5_bit(src2); // RV32
-                let t_src2 = trunc_to_6_bit(src2); // RV64
-                                                   // i64 shr automatically fill high bits with 0-bit
-                let result = (src1 as u64).wrapping_shr(t_src2 as u32);
+                let t_src2 = xlen.shamt(src2);
+                // u64 shr automatically fill high bits with 0-bit
+                let result = xlen.trunc(src1).wrapping_shr(t_src2);
                 reg_file.write(rd, result as u64);
             }
             Inst64::srli => {
@@ -751,14 +1875,9 @@ impl<'a> CPU<'a> {
                 if self.itrace {
                     trace!("{}", pinst!(pc, srli, rd, rs1, imm=>imm));
                 }
-                // RV32I
-                // let (shamt, legal) = trunc_to_5_bit_and_check(imm);
-                // if !legal {
-                //     return Err(Error::Exception(Exception::IllegalInstruction))
-                // }
-                // RV64I
-                let shamt = trunc_to_6_bit(imm);
-                let result = (src1 as u64).wrapping_shr(shamt as u32);
+                // shamt[5] is reserved on RV32, rejected by decode
+                let shamt = xlen.shamt(imm);
+                let result = xlen.trunc(src1).wrapping_shr(shamt);
                 reg_file.write(rd, result as u64);
             }
             Inst64::srliw => {
@@ -769,7 +1888,7 @@ impl<'a> CPU<'a> {
                 let t_src1: u64 = trunc_to_32_bit(src1);
                 let (shamt, legal) = trunc_to_5_bit_and_check(imm);
                 if !legal {
-                    return Err(Error::Exception(Exception::IllegalInstruction));
+                    return Err(Exception::IllegalInstruction(pc, raw_inst).into());
                 }
                 let result = sext(
                     trunc_to_32_bit(t_src1.wrapping_shr(shamt as u32)),
@@ -811,8 +1930,10 @@ impl<'a> CPU<'a> {
                     trace!("{}", pinst!(pc, sw, rs2, imm(rs1)));
                 }
                 let vaddr = src1.wrapping_add(sext(imm, S_TYPE_IMM_BITWIDTH) as u64);
+                check_align(vaddr, 4, true)?;
                 let write_val = trunc_to_32_bit(src2);
-                self.vm.mwrite::<u32>(vaddr as usize, write_val as u32);
+                self.vm.mwrite::<u32>(vaddr as usize, write_val as u32)?;
+                self.reservation.store(xlen.trunc(vaddr), 4);
                 // self.vm.mread::<u64>(vaddr as usize);
             }
 
@@ -833,15 +1954,16 @@ impl<'a> CPU<'a> {
                 reg_file.write(rd, result);
             }
 
-            _ => error!("Unknown inst {:?}", exec_itrnl.inst),
+            _ => {
+                let err = Exception::IllegalInstruction(pc, raw_inst);
+                error!("{err}");
+                return Err(err.into());
+            }
         }
 
         // write pc back
         self.pc
-            .write(if use_new_pc { exec_itrnl.pc } else { pc + 4 });
-
-        // reset x0 to 0
-        reg_file.write(0, 0);
+            .write(if use_new_pc { exec_itrnl.pc } else { next_pc });
 
         Ok(())
     }
@@ -849,21 +1971,49 @@ impl<'a> CPU<'a> {
 
 impl<'a> CPU<'a> {
     pub fn halt(&mut self, pc: u64, code: u64) {
+        self.running = false;
+        self.exit_code = Some(code);
+        // printed rather than logged, to report the outcome at any log level
+        if logger::quiet() {
+            return;
+        }
         if code != 0 {
-            error!("HIT BAD TRAP!\n");
+            println!("HIT BAD TRAP!");
         } else {
-            info!("HIT GOOD TRAP!\n");
+            println!("HIT GOOD TRAP!");
         }
-        self.running = false;
-        info!("Program ended at pc {:#x}, with exit code {}", pc, code);
+        println!("Program ended at pc {:#x}, with exit code {}", pc, code);
+    }
+
+    /// Disassemble the instruction at `pc`, giving its length too.
+    pub(super) fn disasm_at(&self, pc: u64) -> Result<(String, u64)> {
+        let itl = decode(self.vm.fetch(pc as usize)?, self.xlen)?;
+        let line = disasm(pc, itl.inst, itl.rd, itl.rs1, itl.rs2, itl.rs3, itl.imm);
+        Ok((line, itl.inst_len))
     }
 
-    pub fn mread<T: Sized + Display>(&self, vaddr: u64) -> T {
+    pub fn mread<T: MemData>(&self, vaddr: u64) -> Result<T> {
         self.vm.mread(vaddr as usize)
     }
 
+    pub fn mwrite<T: MemData>(&mut self, vaddr: u64, value: T) -> Result<()> {
+        self.vm.mwrite(vaddr as usize, value)
+    }
+
+    pub fn fill<T: MemData>(&mut self, vaddr: u64, count: u64, value: T) -> Result<()> {
+        self.vm.fill(vaddr as usize, count as usize, value)
+    }
+
+    pub fn mem_contains(&self, vaddr: u64, len: u64) -> bool {
+        self.vm.contains(vaddr as usize, len as usize)
+    }
+
     pub fn backtrace(&self) {
-        self.callstack.backtrace();
+        self.callstack.backtrace(self.pc.read());
+    }
+
+    pub fn write_inst(&mut self, pc: u64, inst: u32) -> Result<()> {
+        self.vm.write_inst(pc as usize, inst)
     }
 }
 
@@ -872,50 +2022,605 @@ impl<'a> CPU<'a> {
         self.pc.read()
     }
 
+    /// Whether the CPU is stopped by wfi. Running again resumes after the
+    /// wfi.
+    pub fn waiting_for_interrupt(&self) -> bool {
+        self.wfi
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc.write(pc);
+    }
+
+    pub fn reg_val(&self, idx: u8) -> u64 {
+        self.reg_file.read(idx)
+    }
+
     pub fn reg_val_by_name(&self, name: &str) -> Result<u64> {
-        let idx = match name {
-            "zero" | "x0" => 0,
-            "ra" | "x1" => 1,
-            "sp" | "x2" => 2,
-            "gp" | "x3" => 3,
-            "tp" | "x4" => 4,
-            "t0" | "x5" => 5,
-            "t1" | "x6" => 6,
-            "t2" | "x7" => 7,
-            "s0" | "x8" => 8,
-            "s1" | "x9" => 9,
-            "a0" | "x10" => 10,
-            "a1" | "x11" => 11,
-            "a2" | "x12" => 12,
-            "a3" | "x13" => 13,
-            "a4" | "x14" => 14,
-            "a5" | "x15" => 15,
-            "a6" | "x16" => 16,
-            "a7" | "x17" => 17,
-            "s2" | "x18" => 18,
-            "s3" | "x19" => 19,
-            "s4" | "x20" => 20,
-            "s5" | "x21" => 21,
-            "s6" | "x22" => 22,
-            "s7" | "x23" => 23,
-            "s8" | "x24" => 24,
-            "s9" | "x25" => 25,
-            "s10" | "x26" => 26,
-            "s11" | "x27" => 27,
-            "t3" | "x28" => 28,
-            "t4" | "x29" => 29,
-            "t5" | "x30" => 30,
-            "t6" | "x31" => 31,
-            "pc" => return Ok(self.pc()),
-            _ => return Err(Error::InvalidRegName(name.into())),
-        };
+        if name == "pc" {
+            return Ok(self.pc());
+        }
+        let idx = reg_index(name).ok_or_else(|| Error::InvalidRegName(name.into()))?;
         Ok(self.reg_file.read(idx))
     }
+
+    /// Write `value` into register `name` and return the old value.
+    /// x0 is hard-wired to zero and refused.
+    pub fn set_reg_by_name(&mut self, name: &str, value: u64) -> Result<u64> {
+        let old = self.reg_val_by_name(name)?;
+        if name == "pc" {
+            self.set_pc(value);
+            return Ok(old);
+        }
+        let idx = reg_index(name).ok_or_else(|| Error::InvalidRegName(name.into()))?;
+        if idx == 0 {
+            return Err(Error::DbgParse("x0 is hard-wired to zero".into()));
+        }
+        self.reg_file.write(idx, value);
+        Ok(old)
+    }
+}
+
+/// Rounding mode of the float instruction `inst` at `pc`: its rm field,
+/// or `frm` for the dynamic mode. An invalid frm makes it illegal.
+fn rounding_mode(pc: u64, inst: u32, frm: u8) -> Result<u8> {
+    let rm = match funct3(inst) {
+        0b111 => frm,
+        rm => rm,
+    };
+    if rm > 0b100 {
+        return Err(Exception::IllegalInstruction(pc, inst).into());
+    }
+    Ok(rm)
+}
+
+/// (vaddr, bytes) written by `op` with `src1` in rs1, if it stores.
+/// A failing sc is taken as a store too.
+fn store_target(op: Inst64, src1: u64, imm: u64) -> Option<(u64, u64)> {
+    use Inst64::*;
+    let offset = sext(imm, S_TYPE_IMM_BITWIDTH) as u64;
+    match op {
+        sb => Some((src1.wrapping_add(offset), 1)),
+        sh => Some((src1.wrapping_add(offset), 2)),
+        sw | fsw => Some((src1.wrapping_add(offset), 4)),
+        sd => Some((src1.wrapping_add(offset), 8)),
+        amoadd_w | amoand_w | amomax_w | amomaxu_w | amomin_w | amominu_w | amoor_w | amoswap_w
+        | amoxor_w | sc_w => Some((src1, 4)),
+        amoadd_d | amoand_d | amomax_d | amomaxu_d | amomin_d | amominu_d | amoor_d | amoswap_d
+        | amoxor_d | sc_d => Some((src1, 8)),
+        _ => None,
+    }
+}
+
+/// Whether `op` reads integer rs1 and rs2 and writes integer rd.
+fn int_operands(op: Inst64) -> (bool, bool, bool) {
+    use Inst64::*;
+    match op {
+        noop | ebreak | ecall | fence | fence_i | pause | mret | sret | wfi => {
+            (false, false, false)
+        }
+        lui | auipc | jal | csrrwi | csrrsi | csrrci => (false, false, true),
+        beq | bne | blt | bge | bltu | bgeu | sb | sh | sw | sd => (true, true, false),
+        addi | addiw | andi | ori | xori | slti | sltiu | slli | slliw | srli | srliw | srai
+        | sraiw | lb | lbu | lh | lhu | lw | lwu | ld | jalr | csrrw | csrrs | csrrc | lr_w
+        | lr_d => (true, false, true),
+        flw | fsw | fcvt_s_w | fcvt_s_wu | fmv_w_x => (true, false, false),
+        fcvt_w_s | fcvt_wu_s | fmv_x_w | fclass_s | feq_s | flt_s | fle_s => (false, false, true),
+        fadd_s | fsub_s | fmul_s | fdiv_s | fsqrt_s | fmadd_s | fmsub_s | fnmadd_s | fnmsub_s
+        | fsgnj_s | fsgnjn_s | fsgnjx_s | fmin_s | fmax_s => (false, false, false),
+        _ => (true, true, true),
+    }
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use super::*;
+    use crate::{
+        core::{asm::assemble, vm::Perms},
+        multi_stage::cpu::{test::with_pipeline, PipelineConfig},
+        temp_path,
+    };
+    use goblin::elf::program_header::{PF_R, PF_X};
+
+    const BASE: u64 = 0x8000_0000;
+    const SIZE: u64 = 0x1000;
+
+    /// Hand `f` a single-cycle CPU at BASE of SIZE bytes of memory holding
+    /// `program`, with the stack at the end of the memory.
+    pub(crate) fn with_single_cycle<T>(program: &[&str], f: impl FnOnce(&mut CPU) -> T) -> T {
+        let mut vm = VirtualMemory::with_base(BASE as usize, SIZE as usize, false);
+        for (i, line) in program.iter().enumerate() {
+            vm.write_inst((BASE + 4 * i as u64) as usize, assemble(line).unwrap())
+                .unwrap();
+        }
+        let symbol_map = HashMap::new();
+        let mut callstack = CallStack::new(&symbol_map, false);
+        let mut cpu = CPU::new(
+            &mut vm,
+            &mut callstack,
+            false,
+            false,
+            false,
+            SyscallAbi::Linux,
+            Xlen::Rv64,
+        );
+        cpu.init_blank(BASE, BASE + SIZE);
+        f(&mut cpu)
+    }
+
+    #[test]
+    fn fetch_at_memory_end() {
+        with_single_cycle(&[], |cpu| {
+            // last instruction sits exactly at the end of the memory
+            let last = BASE + SIZE - 4;
+            cpu.write_inst(last, assemble("addi a0, zero, 1").unwrap())
+                .unwrap();
+            cpu.init_blank(last, BASE + SIZE);
+            cpu.exec_once().unwrap();
+            assert_eq!(cpu.reg_val(10), 1);
+
+            // falling off the end is a clean access fault
+            assert_eq!(cpu.pc(), BASE + SIZE);
+            assert!(matches!(
+                cpu.exec_once(),
+                Err(Error::Exception(Exception::InstructionAccessFault(vaddr))) if vaddr == BASE + SIZE
+            ));
+
+            // an instruction straddling the end faults at its upper half
+            cpu.fill::<u16>(BASE + SIZE - 2, 1, 0x0513).unwrap(); // low half of a 32-bit one
+            cpu.set_pc(BASE + SIZE - 2);
+            assert!(matches!(
+                cpu.exec_once(),
+                Err(Error::Exception(Exception::InstructionAccessFault(vaddr))) if vaddr == BASE + SIZE
+            ));
+        });
+    }
+
+    #[test]
+    fn exec_until_breakpoint() {
+        let program = ["addi a0, a0, 1", "addi a0, a0, 1", "addi a0, a0, 1", "ebreak"];
+        with_single_cycle(&program, |cpu| {
+            let breakpoints = BTreeSet::from([BASE, BASE + 8]);
+            // standing on a breakpoint still makes progress
+            assert!(cpu.cpu_exec_break(&breakpoints, &BTreeMap::new()).unwrap());
+            assert_eq!((cpu.pc(), cpu.reg_val(10)), (BASE + 8, 2));
+            assert!(cpu.cpu_exec_break(&breakpoints, &BTreeMap::new()).is_err()); // ebreak
+            assert_eq!(cpu.reg_val(10), 3);
+        });
+    }
+
+    #[test]
+    fn watchpoint() {
+        let program = [
+            "li t0, -1",
+            "sw t0, 12(sp)",
+            "sd t0, 0(sp)",
+            "amoadd.w zero, t0, (sp)",
+            "addi a0, a0, 1",
+            "ebreak",
+        ];
+        with_single_cycle(&program, |cpu| {
+            cpu.init_blank(BASE, BASE + 0x800);
+
+            // the sd covers the 2 watched bytes from its middle, the sw does not
+            let breakpoints = BTreeSet::new();
+            let watchpoints = BTreeMap::from([(BASE + 0x806, 2)]);
+            assert!(cpu.cpu_exec_break(&breakpoints, &watchpoints).unwrap());
+            assert_eq!(cpu.pc(), BASE + 12);
+            assert_eq!(cpu.watch_hit(&watchpoints), Some(BASE + 0x806));
+
+            // an AMO stores too
+            let watchpoints = BTreeMap::from([(BASE + 0x800, 1)]);
+            assert!(cpu.cpu_exec_break(&breakpoints, &watchpoints).unwrap());
+            assert_eq!(cpu.pc(), BASE + 16);
+            assert!(cpu.cpu_exec_break(&breakpoints, &watchpoints).is_err()); // ebreak
+            assert_eq!(cpu.reg_val(10), 1);
+        });
+    }
+
+    #[test]
+    fn inst_count_as_pipeline() {
+        let program = [
+            "li a0, 0",
+            "li a1, 10",
+            "add a0, a0, a1",
+            "addi a1, a1, -1",
+            "bne a1, zero, -8",
+            "ebreak",
+        ];
+        let pipeline_retired = with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.cpu_exec(None).unwrap();
+            assert_eq!(cpu.exit_code(), Some(55));
+            cpu.retired()
+        });
+        with_single_cycle(&program, |cpu| {
+            let _ = cpu.cpu_exec(None); // stopped by ebreak
+            assert_eq!(cpu.exit_code(), Some(55));
+
+            // 2 li, 10 iterations of 3, and the ebreak
+            assert_eq!(cpu.retired(), 33);
+            assert_eq!(cpu.retired(), pipeline_retired);
+            assert_eq!(cpu.inst_histogram[&Inst64::addi], 12);
+            assert_eq!(cpu.inst_histogram[&Inst64::bne], 10);
+            assert_eq!(cpu.inst_histogram.values().sum::<u64>(), cpu.retired());
+        });
+    }
+
+    #[test]
+    fn trace_regs_as_pipeline() {
+        let program = ["li a0, 5", "addi a0, a0, 1", "ebreak"];
+        let single_path = temp_path("trace-regs-single.log");
+        let pipeline_path = temp_path("trace-regs-pipeline.log");
+        with_single_cycle(&program, |cpu| {
+            cpu.set_trace_regs(TraceRegs::Changed, Some(&single_path))
+                .unwrap();
+            let _ = cpu.cpu_exec(None); // stopped by ebreak
+        });
+        with_pipeline(&program, PipelineConfig::default(), |cpu| {
+            cpu.set_trace_regs(TraceRegs::Changed, Some(&pipeline_path))
+                .unwrap();
+            cpu.cpu_exec(None).unwrap();
+        });
+        let single = std::fs::read_to_string(&single_path).unwrap();
+        let pipeline = std::fs::read_to_string(&pipeline_path).unwrap();
+        std::fs::remove_file(&single_path).unwrap();
+        std::fs::remove_file(&pipeline_path).unwrap();
+        // the ebreak ending the program is logged too
+        assert_eq!(
+            single,
+            "0x80000000 x10 0x0->0x5\n0x80000004 x10 0x5->0x6\n0x80000008\n"
+        );
+        assert_eq!(single, pipeline);
+    }
+
+    #[test]
+    fn store_into_decoded_inst() {
+        // the first instruction is decoded, then overwritten and run again
+        let program = ["addi a0, a0, 1", "sw t0, 0(t1)", "jal zero, -8"];
+        with_single_cycle(&program, |cpu| {
+            cpu.set_reg_by_name("t0", assemble("addi a0, a0, 16").unwrap() as u64)
+                .unwrap();
+            cpu.set_reg_by_name("t1", BASE).unwrap();
+            for _ in 0..4 {
+                cpu.exec_once().unwrap();
+            }
+            assert_eq!(cpu.reg_val(10), 17);
+        });
+    }
+
+    #[test]
+    fn ebreak_as_trap() {
+        let mut vm = VirtualMemory::with_base(BASE as usize, SIZE as usize, false);
+        let symbol_map = HashMap::new();
+        let mut callstack = CallStack::new(&symbol_map, false);
+        let mut cpu = CPU::new(
+            &mut vm,
+            &mut callstack,
+            false,
+            false,
+            true,
+            SyscallAbi::Linux,
+            Xlen::Rv64,
+        );
+
+        let program = ["ebreak", "addi a0, a0, 1", "ebreak", "addi a0, a0, 1"];
+        for (i, line) in program.iter().enumerate() {
+            cpu.write_inst(BASE + 4 * i as u64, assemble(line).unwrap())
+                .unwrap();
+        }
+        cpu.init_blank(BASE, BASE + SIZE);
+
+        // every ebreak traps and resumes after itself
+        let breakpoints = BTreeSet::new();
+        for pc in [BASE, BASE + 8] {
+            assert!(matches!(
+                cpu.cpu_exec_break(&breakpoints, &BTreeMap::new()),
+                Err(Error::Exception(Exception::Breakpoint(at))) if at == pc
+            ));
+            assert_eq!(cpu.pc(), pc + 4);
+        }
+        assert_eq!(cpu.reg_val(10), 1);
+    }
+
+    #[test]
+    fn semihosting() {
+        // SYS_WRITEC of the byte at BASE + 0x100, then SYS_EXIT with the
+        // block at BASE + 0x108, then a plain ebreak
+        let program = [
+            "auipc a1, 0",
+            "addi a1, a1, 256",
+            "li a0, 3",
+            "slli x0, x0, 0x1f",
+            "ebreak",
+            "srai x0, x0, 7",
+            "li a2, 5",
+            "li a0, 0x18",
+            "addi a1, a1, 8",
+            "slli x0, x0, 0x1f",
+            "ebreak",
+            "srai x0, x0, 7",
+            "li a2, 6",
+            "ebreak",
+        ];
+        with_single_cycle(&program, |cpu| {
+            cpu.set_semihosting(true);
+
+            cpu.mwrite::<u8>(BASE + 0x100, b'x').unwrap();
+            cpu.mwrite::<u64>(BASE + 0x108, 0x20026).unwrap(); // ADP_Stopped_ApplicationExit
+            cpu.mwrite::<u64>(BASE + 0x110, 0).unwrap();
+
+            cpu.cpu_exec(None).unwrap();
+            assert_eq!(cpu.pc(), BASE + 0x28);
+            assert_eq!(cpu.reg_val(12), 5);
+
+            // ebreak outside the sequence traps instead of halting
+            cpu.init_blank(BASE + 0x34, BASE + SIZE);
+            let breakpoints = BTreeSet::new();
+            assert!(matches!(
+                cpu.cpu_exec_break(&breakpoints, &BTreeMap::new()),
+                Err(Error::Exception(Exception::Breakpoint(at))) if at == BASE + 0x34
+            ));
+        });
+    }
+
+    #[test]
+    fn cycle_limit() {
+        let program = ["addi a0, a0, 1", "j -4"];
+        with_single_cycle(&program, |cpu| {
+            cpu.set_max_cycles(Some(1000));
+
+            let err = cpu.cpu_exec(None).unwrap_err();
+            assert!(matches!(err, Error::CycleLimitExceeded(BASE)));
+            assert_eq!(cpu.reg_val(10), 500);
+
+            // the debugger's runs are stopped too
+            cpu.set_max_cycles(Some(2000));
+            let err = cpu
+                .cpu_exec_break(&BTreeSet::new(), &BTreeMap::new())
+                .unwrap_err();
+            assert!(matches!(err, Error::CycleLimitExceeded(BASE)));
+            assert_eq!(cpu.reg_val(10), 1000);
+        });
+    }
+
+    #[test]
+    fn argc_argv() {
+        let args = ["prog".to_string(), "foo".to_string(), "bar".to_string()];
+        // a0 = argc, a1 = argv, a2 = argv[2][0]
+        let program = [
+            "ld a0, 0(sp)",
+            "addi a1, sp, 8",
+            "ld t0, 16(a1)",
+            "lbu a2, 0(t0)",
+            "ebreak",
+        ];
+        with_single_cycle(&program, |cpu| {
+            let sp = cpu.vm.push_args(&args).unwrap();
+            cpu.init_blank(BASE, sp);
+            cpu.cpu_exec(Some(4)).unwrap();
+            assert_eq!(cpu.reg_val(10), 3);
+            assert_eq!(cpu.reg_val(12), b'b' as u64);
+        });
+    }
+
+    #[test]
+    fn memcpy_load_dump() {
+        const SRC: u64 = BASE + 0x100;
+        const DST: u64 = BASE + 0x200;
+        let input = temp_path("memcpy-load-dump-input.bin");
+        let output = temp_path("memcpy-load-dump-output.bin");
+        let data: Vec<u8> = (0..32).map(|i| i * 7 + 1).collect();
+        std::fs::write(&input, &data).unwrap();
+
+        // copy 4 quad-words from SRC to DST
+        let program = [
+            "auipc a0, 0",
+            "addi a0, a0, 256",
+            "addi a1, a0, 256",
+            "li a2, 4",
+            "ld t0, 0(a0)",
+            "sd t0, 0(a1)",
+            "addi a0, a0, 8",
+            "addi a1, a1, 8",
+            "addi a2, a2, -1",
+            "bne a2, zero, -20",
+            "ebreak",
+        ];
+        with_single_cycle(&program, |cpu| {
+            cpu.vm.load_file(SRC as usize, &input).unwrap();
+            assert!(cpu.cpu_exec(None).is_err()); // halted by ebreak
+            cpu.vm.dump_file(DST as usize, data.len(), &output).unwrap();
+        });
+        let dumped = std::fs::read(&output).unwrap();
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        assert_eq!(dumped, data);
+    }
+
+    #[test]
+    fn fetch_from_zero() {
+        let program = ["li a0, 1", "jr zero"];
+        with_single_cycle(&program, |cpu| {
+            let err = cpu.cpu_exec(None).unwrap_err();
+            assert!(matches!(
+                err,
+                Error::Exception(Exception::InstructionAccessFault(0))
+            ));
+            assert_eq!(cpu.pc(), 0);
+            assert_eq!(cpu.reg_val(10), 1);
+
+            // out of memory is an error as well
+            cpu.set_pc(BASE + SIZE);
+            assert!(matches!(
+                cpu.cpu_exec(None),
+                Err(Error::Exception(Exception::InstructionAccessFault(_)))
+            ));
+        });
+    }
+
+    #[test]
+    fn wfi_halts() {
+        let program = ["li a0, 7", "wfi", "addi a0, a0, 1"];
+        with_single_cycle(&program, |cpu| {
+            cpu.cpu_exec(None).unwrap();
+            assert!(!cpu.running && cpu.waiting_for_interrupt());
+            assert_eq!(cpu.reg_val(10), 7);
+            assert_eq!(cpu.pc(), BASE + 8);
+
+            // the debugger could resume after the wfi
+            cpu.cpu_exec(Some(1)).unwrap();
+            assert!(!cpu.waiting_for_interrupt());
+            assert_eq!(cpu.reg_val(10), 8);
+        });
+    }
+
+    #[test]
+    fn mem_log_fetches() {
+        let path = temp_path("mem-log-fetches.log");
+        // a loop of 3 iterations storing and loading its counter
+        let program = [
+            "li t0, 3",
+            "sd t0, 0(sp)",
+            "ld t0, 0(sp)",
+            "addi t0, t0, -1",
+            "bne t0, zero, -12",
+            "li a0, 0",
+            "li a7, 93",
+            "ecall",
+        ];
+        // the log is flushed when the memory is dropped
+        let retired = with_single_cycle(&program, |cpu| {
+            cpu.vm.set_mem_log(&path).unwrap();
+            cpu.init_blank(BASE, BASE + 0x800);
+            cpu.cpu_exec(None).unwrap();
+            cpu.retired()
+        });
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let count = |kind: char| log.lines().filter(|l| l.starts_with(kind)).count() as u64;
+        assert_eq!(retired, 16);
+        assert_eq!(count('F'), retired);
+        assert_eq!(count('R'), 3);
+        assert_eq!(count('W'), 3);
+        assert!(log.contains("W 0x80000004 0x80000800 8 0x3\n"), "{log}");
+    }
+
+    #[test]
+    fn ecall_syscalls() {
+        let program = [
+            "li a7, 214", // brk(0)
+            "li a0, 0",
+            "ecall",
+            "addi a1, a0, 1",
+            "li a7, 93", // exit(a1)
+            "mv a0, a1",
+            "ecall",
+            "li a0, 0", // never reached
+        ];
+        with_single_cycle(&program, |cpu| {
+            cpu.syscall.set_brk(BASE + 0x800);
+
+            cpu.cpu_exec(None).unwrap();
+            assert!(!cpu.running);
+            assert_eq!(cpu.reg_val(10), BASE + 0x801);
+        });
+    }
+
+    #[test]
+    fn csr_instructions() {
+        let program = [
+            "li a0, -1",
+            "csrrs a0, mhartid, zero",
+            "csrrwi zero, mscratch, 5",
+            "csrrsi a1, mscratch, 2",
+            "csrrci zero, mscratch, 1",
+            "csrrs a2, mscratch, zero",
+        ];
+        with_single_cycle(&program, |cpu| {
+            cpu.cpu_exec(Some(program.len() as i32)).unwrap();
+            assert_eq!(cpu.reg_val(10), 0);
+            assert_eq!(cpu.reg_val(11), 5);
+            assert_eq!(cpu.reg_val(12), 6);
+        });
+    }
+
+    #[test]
+    fn counter_reads() {
+        // the first read and the 3 instructions of the block retire between
+        // the reads
+        let program = [
+            "rdinstret a0",
+            "rdtime a2",
+            "addi t0, zero, 1",
+            "addi t0, t0, 1",
+            "addi t0, t0, 1",
+            "rdinstret a1",
+            "rdtime a3",
+            "rdcycle a4",
+        ];
+        with_single_cycle(&program, |cpu| {
+            cpu.cpu_exec(Some(program.len() as i32)).unwrap();
+            assert_eq!(cpu.reg_val(11) - cpu.reg_val(10), 5);
+            // time follows the clock
+            assert_eq!(cpu.reg_val(13) - cpu.reg_val(12), 5);
+            assert_eq!(cpu.reg_val(14), 7);
+        });
+    }
+
+    #[test]
+    fn rv32_execution() {
+        let mut vm = VirtualMemory::with_base(BASE as usize, SIZE as usize, false);
+        let symbol_map = HashMap::new();
+        let mut callstack = CallStack::new(&symbol_map, false);
+        let mut cpu = CPU::new(
+            &mut vm,
+            &mut callstack,
+            false,
+            false,
+            false,
+            SyscallAbi::Linux,
+            Xlen::Rv32,
+        );
+
+        let program = [
+            "li a0, -1",
+            "srli a1, a0, 4",
+            "srai a2, a0, 4",
+            "slt a3, a0, zero",
+            "mulhu a4, a0, a0",
+            "addi a5, a0, 2",
+            "sw a0, -4(sp)", // sp has bit 31 set
+            "lw a6, -4(sp)",
+            "slli a7, a0, 31",
+            "addiw a0, a0, 1", // RV64 only
+        ];
+        for (i, line) in program.iter().enumerate() {
+            cpu.write_inst(BASE + 4 * i as u64, assemble(line).unwrap())
+                .unwrap();
+        }
+        cpu.init_blank(BASE, BASE + SIZE);
+        cpu.cpu_exec(Some(program.len() as i32 - 1)).unwrap();
+
+        let regs: Vec<u64> = (10..=17).map(|i| cpu.reg_val(i)).collect();
+        assert_eq!(
+            regs,
+            [
+                0xFFFF_FFFF,
+                0x0FFF_FFFF,
+                0xFFFF_FFFF,
+                1,
+                0xFFFF_FFFE,
+                1,
+                0xFFFF_FFFF,
+                0x8000_0000
+            ]
+        );
+        assert!(matches!(
+            cpu.exec_once(),
+            Err(Error::Exception(Exception::IllegalInstruction(pc, _))) if pc == BASE + 36
+        ));
+    }
 
     #[test]
     fn print_minus1_as_u64() {
@@ -967,4 +2672,440 @@ mod test {
         let result: u64 = trunc_to_32_bit(imm);
         assert_eq!(result, 0x00000000_FFFFFFFF);
     }
+
+    #[test]
+    fn compressed_execution() {
+        with_single_cycle(&[], |cpu| {
+            // sum 5..=1 in a loop, then call a function through a 2-byte aligned jal
+            let program: [u16; 11] = [
+                0x4515, // c.li a0, 5
+                0x4581, // c.li a1, 0
+                0x95aa, // loop: c.add a1, a0
+                0x157d, // c.addi a0, -1
+                0xfd75, // c.bnez a0, loop
+                0x00ef, // jal ra, func
+                0x0080, 0x8606, // c.mv a2, ra
+                0x9002, // c.ebreak
+                0x0585, // func: c.addi a1, 1
+                0x8082, // c.jr ra
+            ];
+            for (i, half) in program.iter().enumerate() {
+                cpu.fill::<u16>(BASE + 2 * i as u64, 1, *half).unwrap();
+            }
+            cpu.cpu_exec(Some(21)).unwrap();
+
+            assert_eq!(cpu.reg_val(11), 16);
+            assert_eq!(cpu.reg_val(12), BASE + 0xe);
+            assert_eq!(cpu.pc(), BASE + 0x10);
+        });
+    }
+
+    #[test]
+    fn link_wraps_at_top() {
+        const BASE: u64 = 0xffff_ffff_ffff_f000;
+        const FUNC: u64 = BASE + 0xff0;
+        let symbol_map = HashMap::new();
+
+        // a jal in the last word and a c.jalr in the last halfword
+        for (pc, compressed) in [(BASE + 0xffc, false), (BASE + 0xffe, true)] {
+            let mut vm = VirtualMemory::with_base(BASE as usize, SIZE as usize, false);
+            let mut callstack = CallStack::new(&symbol_map, false);
+            let mut cpu = CPU::new(
+                &mut vm,
+                &mut callstack,
+                false,
+                false,
+                false,
+                SyscallAbi::Linux,
+                Xlen::Rv64,
+            );
+            cpu.write_inst(FUNC, assemble("ebreak").unwrap()).unwrap();
+            if compressed {
+                cpu.fill::<u16>(pc, 1, 0x9302).unwrap(); // c.jalr t1
+                cpu.set_reg_by_name("t1", FUNC).unwrap();
+            } else {
+                cpu.write_inst(pc, assemble("jal ra, -12").unwrap())
+                    .unwrap();
+            }
+            cpu.init_blank(pc, BASE + 0x800);
+            assert!(cpu.cpu_exec(None).is_err()); // halted by ebreak
+            assert_eq!(cpu.reg_val(1), 0, "compressed: {compressed}");
+            assert_eq!(cpu.retired(), 2); // the call and the ebreak
+        }
+    }
+
+    #[test]
+    fn atomic_execution() {
+        let program = [
+            "addi a0, sp, -8",
+            "li a1, 42",
+            "lr.w a2, (a0)",
+            "sc.w a3, a1, (a0)",
+            "sc.w a4, a1, (a0)", // no reservation left
+            "lw a5, 0(a0)",
+            "amoadd.w a6, a1, (a0)",
+            "lw a7, 0(a0)",
+            "ebreak",
+        ];
+        with_single_cycle(&program, |cpu| {
+            cpu.cpu_exec(Some(program.len() as i32 - 1)).unwrap();
+
+            let regs: Vec<u64> = (12..=17).map(|i| cpu.reg_val(i)).collect();
+            assert_eq!(regs, [0, 0, 1, 42, 42, 84]);
+        });
+    }
+
+    #[test]
+    fn float_execution() {
+        with_single_cycle(&[], |cpu| {
+            let program: [u32; 17] = [
+                0x00700513, // li a0, 7
+                0xd0057553, // fcvt.s.w fa0, a0
+                0x00200593, // li a1, 2
+                0xd005f5d3, // fcvt.s.w fa1, a1
+                0x18b57653, // fdiv.s fa2, fa0, fa1
+                0xc0067653, // fcvt.w.s a2, fa2
+                0xc0061853, // fcvt.w.s a6, fa2, rtz
+                0x60b576c3, // fmadd.s fa3, fa0, fa1, fa2
+                0xfed12e27, // fsw fa3, -4(sp)
+                0xffc12787, // flw fa5, -4(sp)
+                0xe00786d3, // fmv.x.w a3, fa5
+                0x08b57853, // fsub.s fa6, fa0, fa1
+                0x110878d3, // fmul.s fa7, fa6, fa6
+                0x5808f0d3, // fsqrt.s ft1, fa7
+                0xc000f753, // fcvt.w.s a4, ft1
+                0x88b5704b, // fnmsub.s ft0, fa0, fa1, fa7
+                0xc00077d3, // fcvt.w.s a5, ft0
+            ];
+            for (i, inst) in program.iter().enumerate() {
+                cpu.write_inst(BASE + 4 * i as u64, *inst).unwrap();
+            }
+            cpu.cpu_exec(Some(program.len() as i32)).unwrap();
+
+            assert_eq!(cpu.reg_val(12), 4); // 3.5 rounds to even
+            assert_eq!(cpu.reg_val(16), 3); // 3.5 rounds towards zero
+            assert_eq!(cpu.reg_val(13), 17.5f32.to_bits() as u64);
+            assert_eq!(cpu.reg_val(14), 5);
+            assert_eq!(cpu.reg_val(15), 11);
+        });
+    }
+
+    #[test]
+    fn float_sign_compare_class() {
+        with_single_cycle(&[], |cpu| {
+            let program: [u32; 17] = [
+                0xffd00513, // li a0, -3
+                0xd0057553, // fcvt.s.w fa0, a0
+                0x20a525d3, // fsgnjx.s fa1, fa0, fa0
+                0x20a51653, // fsgnjn.s fa2, fa0, fa0
+                0x20a586d3, // fsgnj.s fa3, fa1, fa0
+                0x28b50753, // fmin.s fa4, fa0, fa1
+                0x28b517d3, // fmax.s fa5, fa0, fa1
+                0xa0f715d3, // flt.s a1, fa4, fa5
+                0xa0e78653, // fle.s a2, fa5, fa4
+                0xa0c5a6d3, // feq.s a3, fa1, fa2
+                0xe0069753, // fclass.s a4, fa3
+                0xc017f7d3, // fcvt.wu.s a5, fa5
+                0xc0157853, // fcvt.wu.s a6, fa0
+                0xd0157853, // fcvt.s.wu fa6, a0
+                0xe00808d3, // fmv.x.w a7, fa6
+                0x001022f3, // csrrs t0, fflags, zero
+                0x30102373, // csrrs t1, misa, zero
+            ];
+            for (i, inst) in program.iter().enumerate() {
+                cpu.write_inst(BASE + 4 * i as u64, *inst).unwrap();
+            }
+            cpu.cpu_exec(Some(program.len() as i32)).unwrap();
+
+            // fa4 = -3.0 and fa5 = 3.0
+            assert_eq!(cpu.reg_val(11), 1);
+            assert_eq!(cpu.reg_val(12), 0);
+            assert_eq!(cpu.reg_val(13), 1); // |-3.0| == -(-3.0)
+            assert_eq!(cpu.reg_val(14), 1 << 1); // -3.0 is a negative normal
+            assert_eq!(cpu.reg_val(15), 3);
+            assert_eq!(cpu.reg_val(16), 0); // saturated
+            assert_eq!(cpu.reg_val(17), 4294967296.0f32.to_bits() as u64);
+            assert_eq!(cpu.reg_val(5), FFLAG_NV | FFLAG_NX);
+            assert_eq!(cpu.reg_val(6) & (1 << 5), 1 << 5); // misa.F
+        });
+    }
+
+    #[test]
+    fn strict_align() {
+        let program = ["li t0, 42", "sw t0, 1(sp)", "lw a0, 1(sp)"];
+        with_single_cycle(&program, |cpu| {
+            // misaligned access is allowed by default
+            cpu.init_blank(BASE, BASE + 0x800);
+            cpu.cpu_exec(Some(3)).unwrap();
+            assert_eq!(cpu.reg_val(10), 42);
+
+            cpu.set_strict_align(true);
+            cpu.set_pc(BASE);
+            let err = cpu.cpu_exec(Some(2)).unwrap_err();
+            assert!(matches!(
+                err,
+                Error::Exception(Exception::StoreAddressMisaligned(0x8000_0801))
+            ));
+            cpu.set_pc(BASE + 8);
+            let err = cpu.cpu_exec(Some(1)).unwrap_err();
+            assert!(matches!(
+                err,
+                Error::Exception(Exception::LoadAddressMisaligned(0x8000_0801))
+            ));
+        });
+    }
+
+    #[test]
+    fn fence_execution() {
+        let program = [
+            "li a0, 1",
+            "fence",
+            "fence.i",
+            "fence.tso",
+            "pause",
+            "addi a0, a0, 1",
+        ];
+        with_single_cycle(&program, |cpu| {
+            cpu.init_blank(BASE, BASE + 0x800);
+            cpu.cpu_exec(Some(6)).unwrap();
+            assert_eq!(cpu.reg_val(10), 2);
+            assert_eq!(cpu.pc.read(), BASE + 24);
+
+            // fence iorw, iorw, fence.tso and pause
+            let decode_op = |inst| decode(inst, Xlen::Rv64).unwrap().inst;
+            assert_eq!(decode_op(0x0ff0000f), Inst64::fence);
+            assert_eq!(decode_op(0x8330000f), Inst64::fence);
+            assert_eq!(decode_op(0x0100000f), Inst64::pause);
+        });
+    }
+
+    #[test]
+    fn mret_jumps_to_mepc() {
+        let program = [
+            "auipc t0, 0",
+            "addi t0, t0, 20",
+            "csrrw zero, mepc, t0",
+            "mret",
+            "li a0, 1",
+            "li a1, 7",
+        ];
+        with_single_cycle(&program, |cpu| {
+            cpu.init_blank(BASE, BASE + 0x800);
+            cpu.cpu_exec(Some(4)).unwrap();
+            assert_eq!(cpu.pc(), BASE + 20);
+            cpu.cpu_exec(Some(1)).unwrap();
+            assert_eq!(cpu.reg_val(10), 0);
+            assert_eq!(cpu.reg_val(11), 7);
+        });
+    }
+
+    #[test]
+    fn itrace_verbose_operands() {
+        let program = [
+            "addi a1, zero, 3",
+            "addi a2, zero, 4",
+            "add a0, a1, a2",
+            "beq a0, a1, 8",
+            "bne a0, a1, 8",
+        ];
+        with_single_cycle(&program, |cpu| {
+            cpu.init_blank(BASE, BASE + 0x800);
+
+            // x0 is left out
+            assert!(cpu.itrace_sources(Inst64::addi, 0, 3).is_empty());
+            cpu.cpu_exec(Some(2)).unwrap();
+
+            assert_eq!(
+                cpu.itrace_sources(Inst64::add, 11, 12),
+                ["a1=0x3", "a2=0x4"]
+            );
+            cpu.cpu_exec(Some(1)).unwrap();
+            assert_eq!(
+                cpu.itrace_result(Inst64::add, 10, BASE + 8, 4).as_deref(),
+                Some("-> a0=0x7")
+            );
+
+            cpu.cpu_exec(Some(1)).unwrap();
+            assert_eq!(
+                cpu.itrace_result(Inst64::beq, 0, BASE + 12, 4).as_deref(),
+                Some("-> not taken")
+            );
+            cpu.cpu_exec(Some(1)).unwrap();
+            assert_eq!(
+                cpu.itrace_result(Inst64::bne, 0, BASE + 16, 4).as_deref(),
+                Some("-> taken")
+            );
+
+            // stores write no register
+            assert_eq!(cpu.itrace_result(Inst64::sd, 10, BASE, 4), None);
+        });
+    }
+
+    #[test]
+    fn vectored_trap() {
+        // the handler at BASE + 32 counts traps in a0 and returns after
+        // the trapped instruction
+        let program = [
+            "auipc t0, 0",
+            "addi t0, t0, 32",
+            "csrrw zero, mtvec, t0",
+            "div a1, a2, zero",
+            "addi a3, a3, 1",
+            "ebreak",
+            "nop",
+            "nop",
+            "csrrs t1, mepc, zero",
+            "addi t1, t1, 4",
+            "csrrw zero, mepc, t1",
+            "addi a0, a0, 1",
+            "mret",
+        ];
+        with_single_cycle(&program, |cpu| {
+            // the spec results without --div-trap
+            cpu.init_blank(BASE, BASE + 0x800);
+            cpu.cpu_exec(Some(4)).unwrap();
+            assert_eq!(cpu.reg_val(11), u64::MAX);
+
+            // fatal by default
+            cpu.set_div_trap(true);
+            cpu.init_blank(BASE, BASE + 0x800);
+            let err = cpu.cpu_exec(Some(4)).unwrap_err();
+            assert!(matches!(err, Error::Exception(Exception::DividedByZero)));
+
+            cpu.set_trap_mode(TrapMode::Vectored);
+            cpu.init_blank(BASE, BASE + 0x800);
+            cpu.cpu_exec(Some(10)).unwrap();
+            assert_eq!(cpu.pc(), BASE + 20);
+            assert_eq!(cpu.reg_val(10), 1);
+            assert_eq!(cpu.reg_val(13), 1);
+            assert_eq!(cpu.csr.read(crate::csr::MCAUSE).unwrap(), 24);
+        });
+    }
+
+    #[test]
+    fn enforce_perms_on_stack() {
+        const STACK_CODE: u64 = BASE + 0x400;
+        let program = ["auipc t0, 0", "addi t0, t0, 0x400", "jr t0"];
+        let stack_code = ["li a0, 7", "ebreak"];
+
+        for enforce in [false, true] {
+            with_single_cycle(&program, |cpu| {
+                for (i, line) in stack_code.iter().enumerate() {
+                    cpu.vm
+                        .write_inst(
+                            (STACK_CODE + 4 * i as u64) as usize,
+                            assemble(line).unwrap(),
+                        )
+                        .unwrap();
+                }
+                if enforce {
+                    let text = Perms::from_p_flags(PF_R | PF_X);
+                    cpu.vm
+                        .set_perms(vec![(BASE as usize..(BASE + 0x100) as usize, text)]);
+                }
+                cpu.init_blank(BASE, BASE + 0x800);
+                let err = cpu.cpu_exec(None).unwrap_err();
+                if enforce {
+                    assert!(matches!(
+                        err,
+                        Error::Exception(Exception::InstructionAccessFault(STACK_CODE))
+                    ));
+                    assert_eq!(cpu.reg_val(10), 0);
+                } else {
+                    assert_eq!(cpu.reg_val(10), 7); // halted by ebreak
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn guard_stack_overflow() {
+        const GUARD: u64 = BASE + 0x800;
+        // a function calling itself forever, pushing ra every call
+        let program = ["addi sp, sp, -16", "sd ra, 8(sp)", "jal ra, -8"];
+        with_single_cycle(&program, |cpu| {
+            cpu.vm.set_guard(GUARD as usize..(GUARD + 0x100) as usize);
+            let err = cpu.cpu_exec(None).unwrap_err();
+            // the stack stops at the guard instead of growing into the code
+            assert!(
+                matches!(
+                    err,
+                    Error::Exception(Exception::StoreAccessFault(addr)) if addr == GUARD + 0xf8
+                ),
+                "{err:?}"
+            );
+            assert_eq!(cpu.reg_val(2), GUARD + 0xf0);
+        });
+    }
+
+    #[test]
+    fn illegal_instruction() {
+        with_single_cycle(&[], |cpu| {
+            cpu.write_inst(BASE, assemble("li a0, 1").unwrap()).unwrap();
+            // opcode 0x7f is reserved for instructions longer than 64 bits
+            cpu.write_inst(BASE + 4, 0xffff_ffff).unwrap();
+            let err = cpu.cpu_exec(None).unwrap_err();
+            assert!(
+                matches!(
+                    err,
+                    Error::Exception(Exception::IllegalInstruction(pc, 0xffff_ffff)) if pc == BASE + 4
+                ),
+                "{err:?}"
+            );
+            assert_eq!(
+                err.to_string(),
+                "Illegal instruction 0xffffffff at 0x80000004"
+            );
+            assert_eq!(cpu.retired(), 1);
+        });
+    }
+
+    #[test]
+    fn set_reg_by_name() {
+        with_single_cycle(&[], |cpu| {
+            assert_eq!(cpu.set_reg_by_name("a0", 42).unwrap(), 0);
+            assert_eq!(cpu.set_reg_by_name("x10", 7).unwrap(), 42);
+            assert_eq!(cpu.reg_val(10), 7);
+            assert!(cpu.set_reg_by_name("zero", 1).is_err());
+            assert!(cpu.set_reg_by_name("x32", 1).is_err());
+            cpu.set_reg_by_name("pc", 0x8000_0010).unwrap();
+            assert_eq!(cpu.pc(), 0x8000_0010);
+        });
+    }
+
+    #[test]
+    fn set_less_than() {
+        with_single_cycle(&[], |cpu| {
+            cpu.reg_file.write(5, u64::MAX); // t0 = -1
+            cpu.reg_file.write(6, 1); // t1 = 1
+            cpu.reg_file.write(7, 0x1000); // t2 = 0x1000
+
+            let mut run = |inst, rs1, imm| {
+                let exec_internal = ExecInternal {
+                    inst,
+                    rd: 10,
+                    rs1,
+                    rs2: 6,
+                    imm,
+                    ..Default::default()
+                };
+                cpu.exec_inst(exec_internal).unwrap();
+                cpu.reg_val(10)
+            };
+
+            // -1 < 1 signed, but 0xFFFF_FFFF_FFFF_FFFF > 1 unsigned
+            assert_eq!(run(Inst64::slt, 5, 0), 1);
+            assert_eq!(run(Inst64::sltu, 5, 0), 0);
+            assert_eq!(run(Inst64::slti, 5, 1), 1);
+            assert_eq!(run(Inst64::sltiu, 5, 1), 0);
+            // sltiu sign-extends imm = -1 to 0xFFFF_FFFF_FFFF_FFFF before comparing
+            assert_eq!(run(Inst64::sltiu, 5, 0xfff), 0);
+            assert_eq!(run(Inst64::slti, 5, 0xfff), 0);
+            // without the sign-extension 0x1000 <u 0xfff would be false
+            assert_eq!(run(Inst64::sltiu, 7, 0xfff), 1);
+            // 0x7ff is the largest positive immediate
+            assert_eq!(run(Inst64::slti, 5, 0x7ff), 1);
+            assert_eq!(run(Inst64::sltiu, 5, 0x7ff), 0);
+        });
+    }
 }
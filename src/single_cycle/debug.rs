@@ -1,30 +1,68 @@
-use super::cpu::CPU;
+use super::cpu::{CpuSnapshot, CPU};
 use crate::{
-    core::reg::REGNAME,
-    error::{Error, Result},
+    core::{
+        cond::Condition,
+        reg::REGNAME,
+        scan::{hex_bytes, Scan},
+    },
+    error::{Error, Exception, Result},
 };
 use clap::{Parser, Subcommand};
 use clap_num::maybe_hex;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::io::{self, BufRead, Write};
 
 const REDB_BUF_SIZE: usize = 64;
 
+// Stepping takes a checkpoint to step back from every REVERSE_INTERVAL
+// cycles, and the last REVERSE_CHECKPOINTS are kept
+const REVERSE_INTERVAL: u64 = 100;
+const REVERSE_CHECKPOINTS: usize = 10;
+
 pub struct REDB<'a> {
     // Command line input buffer
     buf: String,
 
     // CPU
     cpu: &'a mut CPU<'a>,
+
+    // Breakpoints
+    breakpoints: BTreeSet<u64>,
+
+    // Conditions of the conditional breakpoints
+    conditions: BTreeMap<u64, Condition>,
+
+    // Watchpoints: the bytes watched from each address
+    watchpoints: BTreeMap<u64, u64>,
+
+    // Saved state to re-run from
+    snapshot: Option<CpuSnapshot>,
+
+    // Recent states to step back from, the oldest first
+    checkpoints: VecDeque<CpuSnapshot>,
+
+    // Last command, repeated on an empty line
+    last_cmd: Option<Commands>,
+
+    // Command lines entered so far
+    history: Vec<String>,
 }
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about, disable_help_flag = true)]
+#[command(
+    author,
+    version,
+    about,
+    long_about,
+    disable_help_flag = true,
+    disable_help_subcommand = true
+)]
 struct DebugArgs {
     #[clap(subcommand)]
     command: Commands,
 }
 
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Debug, Clone)]
 enum Commands {
     #[clap(alias = "help")]
     H,
@@ -40,14 +78,62 @@ enum Commands {
     Info {
         r: String,
     },
+    #[clap(alias = "b")]
+    Break {
+        #[clap(value_parser=maybe_hex::<u64>)]
+        addr: u64,
+        /// `if REG OP VALUE`
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        cond: Vec<String>,
+    },
+    #[clap(alias = "w")]
+    Watch {
+        #[clap(value_parser=maybe_hex::<u64>)]
+        addr: u64,
+        #[clap(value_parser=maybe_hex::<u64>, default_value_t = 8)]
+        len: u64,
+    },
+    #[clap(alias = "d")]
+    Delete {
+        #[clap(value_parser=maybe_hex::<u64>)]
+        addr: u64,
+    },
     #[clap(alias = "x")]
     Scan {
-        n: u64,
+        /// `N` or `N/FMT`
+        spec: String,
         #[clap(value_parser=maybe_hex::<u64>)]
         vaddr: u64,
     },
     #[clap(alias = "bt")]
     Backtrace,
+    Cmp {
+        a: String,
+        b: String,
+    },
+    Set {
+        target: String,
+        #[clap(value_parser=maybe_hex::<u64>)]
+        value: u64,
+    },
+    Fill {
+        #[clap(value_parser=maybe_hex::<u64>)]
+        vaddr: u64,
+        #[clap(value_parser=maybe_hex::<u64>)]
+        len: u64,
+        #[clap(value_parser=maybe_hex::<u64>)]
+        value: u64,
+        #[clap(default_value_t = 1)]
+        width: u64,
+    },
+    Snapshot,
+    Restore,
+    #[clap(alias = "rsi")]
+    ReverseStep {
+        #[clap(default_value_t = 1)]
+        n: u64,
+    },
+    History,
 }
 
 impl<'a> REDB<'a> {
@@ -55,9 +141,97 @@ impl<'a> REDB<'a> {
         REDB {
             buf: String::with_capacity(REDB_BUF_SIZE),
             cpu,
+            breakpoints: BTreeSet::new(),
+            conditions: BTreeMap::new(),
+            watchpoints: BTreeMap::new(),
+            snapshot: None,
+            checkpoints: VecDeque::new(),
+            last_cmd: None,
+            history: Vec::new(),
         }
     }
 
+    /// Register a breakpoint at `addr`.
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.insert(addr);
+        self.conditions.remove(&addr);
+    }
+
+    /// Remove the breakpoint at `addr`, returns whether there was one.
+    pub fn delete_breakpoint(&mut self, addr: u64) -> bool {
+        self.conditions.remove(&addr);
+        self.breakpoints.remove(&addr)
+    }
+
+    // Register a breakpoint at `addr` which only stops when `cond` holds
+    fn add_conditional_breakpoint(&mut self, addr: u64, cond: Condition) -> Result<()> {
+        // refuse unknown registers now rather than at every hit
+        self.cpu.reg_val_by_name(&cond.reg)?;
+        self.breakpoints.insert(addr);
+        self.conditions.insert(addr, cond);
+        Ok(())
+    }
+
+    // whether the CPU is at a breakpoint whose condition, if any, holds
+    fn on_breakpoint(&self) -> bool {
+        let pc = self.cpu.pc();
+        self.breakpoints.contains(&pc)
+            && match self.conditions.get(&pc) {
+                Some(cond) => self
+                    .cpu
+                    .reg_val_by_name(&cond.reg)
+                    .is_ok_and(|v| cond.holds(v)),
+                None => true,
+            }
+    }
+
+    // Run like `CPU::cpu_exec_break`, but go on past conditional breakpoints
+    // whose condition does not hold
+    fn exec_break(&mut self) -> Result<bool> {
+        loop {
+            if !self.cpu.cpu_exec_break(&self.breakpoints, &self.watchpoints)? {
+                return Ok(false);
+            }
+            if self.cpu.watch_hit(&self.watchpoints).is_some() {
+                return Ok(true);
+            }
+            match self.conditions.get(&self.cpu.pc()) {
+                Some(cond) if !cond.holds(self.cpu.reg_val_by_name(&cond.reg)?) => {}
+                _ => return Ok(true),
+            }
+        }
+    }
+
+    /// Run the program to the end without interaction, dumping the CPU state
+    /// at every breakpoint hit. Returns the error which stopped the CPU.
+    pub fn run_batch(&mut self) -> Result<()> {
+        // the entry point is checked before the first instruction runs
+        if self.on_breakpoint() {
+            println!("REDB: hit breakpoint at {:#x}", self.cpu.pc());
+            self.print_regs();
+        }
+        loop {
+            let watch_values = self.watch_values();
+            match self.exec_break() {
+                Ok(true) => {
+                    self.print_stop(&watch_values);
+                    self.print_regs();
+                }
+                Ok(false) => {
+                    println!("REDB: CPU executed to end.");
+                    break;
+                }
+                Err(Error::Exception(Exception::Breakpoint(pc))) => {
+                    println!("REDB: ebreak at {:#x}, enter debugger", pc);
+                    self.run();
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
     pub fn run(&mut self) {
         loop {
             print!("(REDB)>>> ");
@@ -74,16 +248,33 @@ impl<'a> REDB<'a> {
             }
             match cmd.unwrap() {
                 Commands::H => print_help_info(),
-                Commands::Continue => match self.cpu.cpu_exec(None) {
-                    Ok(_) => {
-                        println!("REDB: CPU executed to end.");
-                        break;
-                    }
-                    Err(e) => {
-                        println!("REDB: CPU raised exception: {}", e);
-                        continue;
+                Commands::Continue => {
+                    self.checkpoint();
+                    // values before the run, a run stops at the first watched store
+                    let watch_values = self.watch_values();
+                    match self.exec_break() {
+                        Ok(true) => {
+                            self.print_stop(&watch_values);
+                            continue;
+                        }
+                        Ok(false) if self.cpu.waiting_for_interrupt() => {
+                            println!("REDB: CPU paused at wfi");
+                            continue;
+                        }
+                        Ok(false) => {
+                            println!("REDB: CPU executed to end.");
+                            break;
+                        }
+                        Err(Error::Exception(Exception::Breakpoint(pc))) => {
+                            println!("REDB: ebreak at {:#x}", pc);
+                            continue;
+                        }
+                        Err(e) => {
+                            println!("REDB: CPU raised exception: {}", e);
+                            continue;
+                        }
                     }
-                },
+                }
                 Commands::Quit => {
                     println!("REDB: Exit REDB");
                     break;
@@ -94,7 +285,7 @@ impl<'a> REDB<'a> {
                     }
                     println!("REDB: execute {n} steps");
                     for i in 1..=n {
-                        if let Err(e) = self.cpu.exec_once() {
+                        if let Err(e) = self.step() {
                             println!("REDB: stopped after executed {i} steps");
                             println!("{e}");
                             break;
@@ -104,13 +295,11 @@ impl<'a> REDB<'a> {
                 }
                 Commands::Info { r } => {
                     if r == "r" {
-                        for i in 0..32 {
-                            let reg_name = format!("x{i}");
-                            let reg = self.cpu.reg_val_by_name(&reg_name).unwrap();
-                            println!("{} ({}) \t: {}\t{:#x}", reg_name, REGNAME[i], reg, reg);
-                        }
-                        let pc = self.cpu.pc();
-                        println!("{}\t\t: {}\t{:#x}", "pc", pc, pc);
+                        self.print_regs();
+                    } else if r == "b" {
+                        self.print_breakpoints();
+                    } else if r == "w" {
+                        self.print_watchpoints();
                     } else {
                         match self.cpu.reg_val_by_name(&r) {
                             Ok(reg) => {
@@ -122,36 +311,346 @@ impl<'a> REDB<'a> {
                         }
                     }
                 }
-                Commands::Scan { n, vaddr } => {
-                    for i in 0..n {
-                        let p_vaddr = vaddr + 4 * i;
-                        let val = self.cpu.mread::<u64>(p_vaddr);
-                        println!("{:#x}: {:016x}", p_vaddr, val);
+                Commands::Break { addr, cond } => {
+                    // Continue always executes one instruction first, so a
+                    // breakpoint at the current pc only fires when reached again
+                    match cond.split_first() {
+                        None => {
+                            self.add_breakpoint(addr);
+                            println!("REDB: breakpoint at {:#x}", addr);
+                        }
+                        Some((kw, cond)) if kw == "if" => {
+                            match Condition::parse(&cond.join(" "))
+                                .and_then(|cond| self.add_conditional_breakpoint(addr, cond))
+                            {
+                                Ok(()) => println!(
+                                    "REDB: breakpoint at {:#x} if {}",
+                                    addr, self.conditions[&addr]
+                                ),
+                                Err(e) => println!("REDB: {e}"),
+                            }
+                        }
+                        Some((kw, _)) => println!("REDB: expect `if` after ADDR, got `{kw}`"),
+                    }
+                }
+                Commands::Watch { addr, len } => {
+                    if len == 0 {
+                        println!("REDB: nothing to watch in 0 bytes");
+                    } else if self.cpu.mem_contains(addr, len) {
+                        self.watchpoints.insert(addr, len);
+                        println!("REDB: watchpoint at {:#x}, {len} bytes", addr);
+                    } else {
+                        println!("REDB: {:#x} out of memory", addr);
+                    }
+                }
+                Commands::Delete { addr } => {
+                    let breakpoint = self.delete_breakpoint(addr);
+                    let watchpoint = self.watchpoints.remove(&addr).is_some();
+                    if breakpoint {
+                        println!("REDB: deleted breakpoint at {:#x}", addr);
+                    }
+                    if watchpoint {
+                        println!("REDB: deleted watchpoint at {:#x}", addr);
+                    }
+                    if !breakpoint && !watchpoint {
+                        println!("REDB: no breakpoint or watchpoint at {:#x}", addr);
                     }
                 }
+                Commands::Scan { spec, vaddr } => match Scan::parse(&spec) {
+                    Ok(scan) => self.scan(scan, vaddr),
+                    Err(e) => println!("REDB: {e}"),
+                },
                 Commands::Backtrace => {
                     println!("REDB: backtrace");
                     self.cpu.backtrace()
                 }
+                Commands::Cmp { a, b } => {
+                    if let Err(e) = self.cmp(&a, &b) {
+                        println!("REDB: {e}");
+                    }
+                }
+                Commands::Set { target, value } => match self.set(&target, value) {
+                    // re-running from older checkpoints would miss the change
+                    Ok(()) => self.checkpoints.clear(),
+                    Err(e) => println!("REDB: {e}"),
+                },
+                Commands::Fill {
+                    vaddr,
+                    len,
+                    value,
+                    width,
+                } => match self.fill(vaddr, len, value, width) {
+                    Ok(()) => self.checkpoints.clear(),
+                    Err(e) => println!("REDB: {e}"),
+                },
+                Commands::Snapshot => {
+                    self.snapshot = Some(self.cpu.snapshot());
+                    println!("REDB: snapshot taken at pc {:#x}", self.cpu.pc());
+                }
+                Commands::Restore => match &self.snapshot {
+                    Some(snapshot) => {
+                        self.cpu.restore(snapshot);
+                        self.checkpoints.clear();
+                        println!("REDB: restored snapshot at pc {:#x}", self.cpu.pc());
+                    }
+                    None => println!("REDB: no snapshot taken"),
+                },
+                Commands::ReverseStep { n } => match self.reverse_step(n) {
+                    Ok(()) => println!(
+                        "REDB: stepped back to clock {} at pc {:#x}",
+                        self.cpu.cycles(),
+                        self.cpu.pc()
+                    ),
+                    Err(e) => println!("REDB: {e}"),
+                },
+                Commands::History => {
+                    for (i, line) in self.history.iter().enumerate() {
+                        println!("{}\t{line}", i + 1);
+                    }
+                }
+            }
+        }
+    }
+
+    // Print all registers' status (including PC)
+    fn print_regs(&self) {
+        for i in 0..32 {
+            let reg_name = format!("x{i}");
+            let reg = self.cpu.reg_val_by_name(&reg_name).unwrap();
+            println!("{} ({}) \t: {}\t{:#x}", reg_name, REGNAME[i], reg, reg);
+        }
+        let pc = self.cpu.pc();
+        println!("{}\t\t: {}\t{:#x}", "pc", pc, pc);
+    }
+
+    // Keep the current state to step back from, dropping the oldest
+    fn checkpoint(&mut self) {
+        if self
+            .checkpoints
+            .back()
+            .is_some_and(|last| last.cycles() == self.cpu.cycles())
+        {
+            return;
+        }
+        if self.checkpoints.len() == REVERSE_CHECKPOINTS {
+            self.checkpoints.pop_front();
+        }
+        self.checkpoints.push_back(self.cpu.snapshot());
+    }
+
+    // Step one instruction, taking a checkpoint every REVERSE_INTERVAL cycles
+    fn step(&mut self) -> Result<()> {
+        let due = self
+            .checkpoints
+            .back()
+            .is_none_or(|last| self.cpu.cycles() >= last.cycles() + REVERSE_INTERVAL);
+        if due {
+            self.checkpoint();
+        }
+        self.cpu.exec_once()
+    }
+
+    // Go back `n` steps by re-running from the newest checkpoint at least `n`
+    // steps back, every step taking one cycle.
+    // Output of the program is printed again.
+    fn reverse_step(&mut self, n: u64) -> Result<()> {
+        let target = self.cpu.cycles().saturating_sub(n);
+        let index = self
+            .checkpoints
+            .iter()
+            .rposition(|checkpoint| checkpoint.cycles() <= target)
+            .filter(|_| n <= self.cpu.cycles())
+            .ok_or_else(|| {
+                Error::DbgParse(format!(
+                    "cannot step back {n} steps, only the last {} cycles are kept",
+                    REVERSE_INTERVAL * REVERSE_CHECKPOINTS as u64
+                ))
+            })?;
+        self.cpu.restore(&self.checkpoints[index]);
+        while self.cpu.cycles() < target {
+            self.cpu.exec_once()?;
+        }
+        self.checkpoints.truncate(index + 1);
+        Ok(())
+    }
+
+    // Print `scan` of the memory from `vaddr`
+    fn scan(&self, scan: Scan, vaddr: u64) {
+        let read = |vaddr, size| match size {
+            1 => self.cpu.mread::<u8>(vaddr).map(u64::from),
+            2 => self.cpu.mread::<u16>(vaddr).map(u64::from),
+            4 => self.cpu.mread::<u32>(vaddr).map(u64::from),
+            _ => self.cpu.mread::<u64>(vaddr),
+        };
+        for line in scan.lines(vaddr, read, |pc| self.cpu.disasm_at(pc)) {
+            println!("{line}");
+        }
+    }
+
+    // Print all breakpoints in address order
+    fn print_breakpoints(&self) {
+        if self.breakpoints.is_empty() {
+            println!("REDB: no breakpoints");
+        }
+        for (i, addr) in self.breakpoints.iter().enumerate() {
+            match self.conditions.get(addr) {
+                Some(cond) => println!("{i}\t{:#x}\tif {cond}", addr),
+                None => println!("{i}\t{:#x}", addr),
             }
         }
     }
 
+    // Print all watchpoints in address order
+    fn print_watchpoints(&self) {
+        if self.watchpoints.is_empty() {
+            println!("REDB: no watchpoints");
+        }
+        for (i, (addr, len)) in self.watchpoints.iter().enumerate() {
+            println!("{i}\t{:#x}\t{len} bytes", addr);
+        }
+    }
+
+    // Tell which breakpoint or watchpoint stopped the CPU, with the watched
+    // bytes before the run
+    fn print_stop(&self, watch_values: &BTreeMap<u64, Vec<u8>>) {
+        match self.cpu.watch_hit(&self.watchpoints) {
+            Some(addr) => {
+                let new = self.watch_value(addr, self.watchpoints[&addr]);
+                println!("Watchpoint: {:#x} changed", addr);
+                println!("old: {}", hex_bytes(&watch_values[&addr]));
+                println!("new: {}", hex_bytes(&new));
+            }
+            None => println!("REDB: hit breakpoint at {:#x}", self.cpu.pc()),
+        }
+    }
+
+    // Current bytes of every watchpoint
+    fn watch_values(&self) -> BTreeMap<u64, Vec<u8>> {
+        self.watchpoints
+            .iter()
+            .map(|(&addr, &len)| (addr, self.watch_value(addr, len)))
+            .collect()
+    }
+
+    // `len` bytes from `addr` in address order
+    fn watch_value(&self, addr: u64, len: u64) -> Vec<u8> {
+        (addr..addr + len)
+            .map(|vaddr| self.cpu.mread::<u8>(vaddr).unwrap_or(0))
+            .collect()
+    }
+
+    // Compare register `a` against register `b`, or the quad-word at address `b`
+    fn cmp(&self, a: &str, b: &str) -> Result<()> {
+        let val_a = self.cpu.reg_val_by_name(a)?;
+        let val_b = match self.cpu.reg_val_by_name(b) {
+            Ok(val) => val,
+            Err(e) => match maybe_hex::<u64>(b) {
+                Ok(vaddr) => self.cpu.mread::<u64>(vaddr)?,
+                Err(_) => return Err(e),
+            },
+        };
+        let diff = val_a.wrapping_sub(val_b);
+        println!("{a}\t: {}\t{:#x}", val_a, val_a);
+        println!("{b}\t: {}\t{:#x}", val_b, val_b);
+        if val_a == val_b {
+            println!("equal");
+        } else {
+            println!(
+                "not equal, {a} - {b}: signed {}, unsigned {}",
+                diff as i64, diff
+            );
+            println!(
+                "{a} {} {b} (signed), {a} {} {b} (unsigned)",
+                if (val_a as i64) < (val_b as i64) {
+                    "<"
+                } else {
+                    ">"
+                },
+                if val_a < val_b { "<" } else { ">" }
+            );
+        }
+        Ok(())
+    }
+
+    // Write `value` into register `target`, or the quad-word at `@ADDR`
+    fn set(&mut self, target: &str, value: u64) -> Result<()> {
+        let old = match target.strip_prefix('@') {
+            Some(addr) => {
+                let vaddr = maybe_hex::<u64>(addr).map_err(Error::DbgParse)?;
+                let old = self.cpu.mread::<u64>(vaddr)?;
+                self.cpu.mwrite::<u64>(vaddr, value)?;
+                old
+            }
+            None => self.cpu.set_reg_by_name(target, value)?,
+        };
+        println!("{target}\t: {:#x} -> {:#x}", old, value);
+        Ok(())
+    }
+
+    // Fill `len` bytes from `vaddr` with `value` repeated every `width` bytes
+    fn fill(&mut self, vaddr: u64, len: u64, value: u64, width: u64) -> Result<()> {
+        if ![1, 2, 4, 8].contains(&width) {
+            return Err(Error::DbgParse(format!(
+                "width must be 1, 2, 4 or 8, got {width}"
+            )));
+        }
+        if !len.is_multiple_of(width) {
+            return Err(Error::DbgParse(format!(
+                "length {len} is not a multiple of width {width}"
+            )));
+        }
+        if width < 8 && value >> (8 * width) != 0 {
+            return Err(Error::DbgParse(format!(
+                "value {value:#x} does not fit in {width} bytes"
+            )));
+        }
+        if !self.cpu.mem_contains(vaddr, len) {
+            return Err(Error::DbgParse(format!(
+                "[{vaddr:#x}, {vaddr:#x} + {len:#x}) out of memory"
+            )));
+        }
+        let count = len / width;
+        match width {
+            1 => self.cpu.fill(vaddr, count, value as u8),
+            2 => self.cpu.fill(vaddr, count, value as u16),
+            4 => self.cpu.fill(vaddr, count, value as u32),
+            _ => self.cpu.fill(vaddr, count, value),
+        }?;
+        println!("REDB: filled {len} bytes at {vaddr:#x} with {value:#x}");
+        Ok(())
+    }
+
     // Listen for user's input
     fn listen(&mut self) -> Result<Option<Commands>> {
         self.buf.clear();
         let stdin = io::stdin();
         let mut stdin = stdin.lock();
-        stdin.read_line(&mut self.buf)?;
+        if stdin.read_line(&mut self.buf)? == 0 {
+            // end of input, nothing left to repeat
+            return Ok(Some(Commands::Quit));
+        }
+        let line = std::mem::take(&mut self.buf);
+        let cmd = self.parse_line(&line);
+        self.buf = line;
+        cmd
+    }
 
-        let buf = self.buf.trim();
-        if buf.is_empty() {
-            return Ok(None);
+    // Parse a command line, an empty one repeats the last command
+    fn parse_line(&mut self, line: &str) -> Result<Option<Commands>> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(self.last_cmd.clone());
         }
+        self.history.push(line.to_string());
 
-        let mut itr: Vec<&str> = self.buf.split_whitespace().collect();
+        let mut itr: Vec<&str> = line.split_whitespace().collect();
         itr.insert(0, "DebugArgs");
         let dbargs = DebugArgs::try_parse_from(itr).map_err(|e| Error::DbgParse(e.to_string()))?;
+        // quitting by a stray Enter would lose the session
+        self.last_cmd = match dbargs.command {
+            Commands::Quit => None,
+            ref cmd => Some(cmd.clone()),
+        };
         Ok(Some(dbargs.command))
     }
 }
@@ -161,12 +660,91 @@ fn print_help_info() {
 REDB: RISC-V Environment DeBugger. 
     Command     Example         Detail
     help        help            Print this help.
-    c           c               Execute the program to end.
+    c           c               Execute the program to end or the next breakpoint.
     q           q               Quit the debugger (also the simulator).
     si [N]      si 10           Step the program for N steps and pause (N default to 1).
     info <reg>  info sp         Print a register's status.
     info r      info r          Print all registers' status (including PC).
-    x N ADDR    x 10 0x80000000 Print N quad-words starting at ADDR.
+    info b      info b          List all breakpoints.
+    info w      info w          List all watchpoints.
+    b ADDR      b 0x80000000    Set a breakpoint at ADDR.
+    b ADDR if REG OP V  b 0x80000000 if a0 == 5
+                Set a breakpoint at ADDR which only stops c when REG OP V holds,
+                with OP in == != < > <= >= and REG, V compared as signed.
+    d ADDR      d 0x80000000    Delete the breakpoint and the watchpoint at ADDR.
+    w ADDR [L]  w 0x80001000 4  Stop c when a store writes into the L bytes from ADDR (L default to 8).
+    x N[/F] ADDR  x 10/w 0x80000000
+                Print N units starting at ADDR, with F in b (u8), h (u16), w (u32),
+                g (u64, default) or i (instruction).
+    cmp A B     cmp a0 a1       Compare two registers, or a register with the quad-word at address B.
+    set T V     set a0 0x10     Set register T, or the quad-word at address T written as @ADDR, to V.
+    fill A L V [W]  fill 0x80000000 64 0xaa
+                Fill L bytes from A with V repeated every W bytes (W in 1/2/4/8, default to 1).
+    snapshot    snapshot        Save the CPU state and memory.
+    restore     restore         Go back to the saved snapshot, which could be restored again.
+    rsi [N]     rsi 3           Step back N steps (N default to 1) by re-running from a recent
+                state, as far as about 1000 clocks. Output of the program is printed again.
+    history     history         List the commands entered so far.
+    <Enter>                     Repeat the last command, e.g. si, except q.
 "#;
     println!("{help}")
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::callstack::CallStack;
+    use crate::core::{asm::assemble, insts::Xlen, vm::VirtualMemory};
+    use crate::syscall::SyscallAbi;
+    use std::collections::HashMap;
+
+    #[test]
+    fn reverse_step() {
+        const BASE: u64 = 0x8000_0000;
+        let mut vm = VirtualMemory::with_base(BASE as usize, 0x1000, false);
+        let program = ["addi a0, a0, 1", "sd a0, 0(sp)", "j -8"];
+        for (i, line) in program.iter().enumerate() {
+            vm.write_inst(BASE as usize + 4 * i, assemble(line).unwrap())
+                .unwrap();
+        }
+        let symbol_map = HashMap::new();
+        let mut callstack = CallStack::new(&symbol_map, false);
+        let mut cpu = CPU::new(
+            &mut vm,
+            &mut callstack,
+            false,
+            false,
+            false,
+            SyscallAbi::Linux,
+            Xlen::Rv64,
+        );
+        let sp = BASE + 0x800;
+        cpu.init_blank(BASE, sp);
+        let mut redb = REDB::new(&mut cpu);
+
+        // pc, a0 and the stored quad-word after each step
+        let state = |redb: &REDB| {
+            (
+                redb.cpu.pc(),
+                redb.cpu.reg_val_by_name("a0").unwrap(),
+                redb.cpu.mread::<u64>(sp).unwrap(),
+            )
+        };
+        let mut states = vec![state(&redb)];
+        for _ in 0..10 {
+            redb.step().unwrap();
+            states.push(state(&redb));
+        }
+        assert_ne!(states[7], states[10]);
+
+        redb.reverse_step(3).unwrap();
+        assert_eq!(state(&redb), states[7]);
+        assert_eq!(redb.cpu.cycles(), 7);
+        // and forward again the same way
+        redb.step().unwrap();
+        assert_eq!(state(&redb), states[8]);
+        redb.reverse_step(8).unwrap();
+        assert_eq!(state(&redb), states[0]);
+        assert!(redb.reverse_step(1).is_err());
+    }
+}
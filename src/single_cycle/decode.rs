@@ -1,10 +1,11 @@
 //! Decode phase
 use crate::core::insts::*;
+use crate::core::rvc::{expand, inst_len};
 use crate::error::{Error, Result};
 use log::error;
 
 /// Decode phase.
-/// ```
+/// ```text
 /// R:  OP_IMM_32  AMO  OP  OP_32  OP_FP
 /// R4: MADD  MSUB  NMSUB  NMADD
 /// I:  LOAD  LOAD_FP  MISC_MEM  OP_IMM  JALR  SYSTEM
@@ -13,8 +14,16 @@ use log::error;
 /// S:  STORE STORE_FP
 /// SB: BRANCH
 /// ```
-pub fn decode(inst: u32) -> Result<ExecInternal> {
+/// Compressed instructions are expanded first.
+pub fn decode(inst: u32, xlen: Xlen) -> Result<ExecInternal> {
     use crate::core::insts::inst_64_opcode::*;
+    let len = inst_len(inst);
+    let inst = if len == 2 {
+        expand(inst as u16, xlen)?
+    } else {
+        inst
+    };
+
     // Format
     let opcode = opcode(inst);
 
@@ -22,7 +31,7 @@ pub fn decode(inst: u32) -> Result<ExecInternal> {
         LOAD => decode_load(inst),
         LOAD_FP => decode_load_fp(inst),
         MISC_MEM => decode_misc_mem(inst),
-        OP_IMM => decode_op_imm(inst),
+        OP_IMM => decode_op_imm(inst, xlen),
         AUIPC => decode_op_auipc(inst),
         OP_IMM_32 => decode_op_imm_32(inst),
         STORE => decode_store(inst),
@@ -40,7 +49,7 @@ pub fn decode(inst: u32) -> Result<ExecInternal> {
         JALR => decode_jalr(inst),
         JAL => decode_jal(inst),
         SYSTEM => decode_system(inst),
-        _ => todo!(),
+        _ => Err(Error::Decode(format!("unknown opcode {:#09b}", opcode))),
     };
 
     if let Ok(ref _ex_inst) = ex_inst {
@@ -49,13 +58,23 @@ pub fn decode(inst: u32) -> Result<ExecInternal> {
         error!("ERROR DECODING: {:#x}", inst);
     }
 
-    ex_inst
+    let mut ex_inst = ex_inst?;
+    ex_inst.inst_len = len;
+    if !xlen.supports(ex_inst.inst) {
+        let msg = format!("{} is not an RV32 instruction", ex_inst.inst);
+        error!("{msg}");
+        return Err(Error::Decode(msg));
+    }
+
+    Ok(ex_inst)
 }
 
 /// 0000011 LOAD: I type
 fn decode_load(inst: u32) -> Result<ExecInternal> {
-    let mut exec_internal = ExecInternal::default();
-    exec_internal.raw_inst = inst;
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
 
     let funct3 = funct3(inst);
     exec_internal.inst = match funct3 {
@@ -81,19 +100,59 @@ fn decode_load(inst: u32) -> Result<ExecInternal> {
 }
 
 /// 0000111 LOAD_FP: I type
-fn decode_load_fp(_inst: u32) -> Result<ExecInternal> {
-    todo!()
+fn decode_load_fp(inst: u32) -> Result<ExecInternal> {
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
+
+    let funct3 = funct3(inst);
+    exec_internal.inst = match funct3 {
+        0b010 => Inst64::flw,
+        _ => {
+            let msg = format!("Unknown LOAD_FP instruction funct3={funct3}");
+            error!("{msg}");
+            return Err(Error::Decode(msg));
+        }
+    };
+
+    exec_internal.rd = rd(inst);
+    exec_internal.rs1 = rs1(inst);
+    exec_internal.imm = imm_I(inst);
+
+    Ok(exec_internal)
 }
 
 /// 0001111 MISC_MEM: I type
-fn decode_misc_mem(_inst: u32) -> Result<ExecInternal> {
-    todo!()
+fn decode_misc_mem(inst: u32) -> Result<ExecInternal> {
+    let funct3 = funct3(inst);
+    // fence.tso is a fence, its fm only weakens the ordering
+    let op = match funct3 {
+        0b000 if inst == PAUSE => Inst64::pause,
+        0b000 => Inst64::fence,
+        0b001 => Inst64::fence_i,
+        _ => {
+            let msg = format!("Unknown MISC_MEM instruction funct3={funct3}");
+            error!("{msg}");
+            return Err(Error::Decode(msg));
+        }
+    };
+
+    // fm, pred and succ are ignored, rd and rs1 are reserved
+    Ok(ExecInternal {
+        raw_inst: inst,
+        inst: op,
+        imm: imm_I(inst),
+        ..Default::default()
+    })
 }
 
 /// 0010011 OP_IMM: I type
-fn decode_op_imm(inst: u32) -> Result<ExecInternal> {
-    let mut exec_internal = ExecInternal::default();
-    exec_internal.raw_inst = inst;
+fn decode_op_imm(inst: u32, xlen: Xlen) -> Result<ExecInternal> {
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
 
     let funct3 = funct3(inst);
     let funct7 = funct7(inst);
@@ -106,9 +165,16 @@ fn decode_op_imm(inst: u32) -> Result<ExecInternal> {
         0b110 => Inst64::ori,
         0b111 => Inst64::andi,
 
-        /* // RV32
-        0b001 => Inst64::slli,
-        0b101 => match funct7 {
+        // RV32: shamt[5] is reserved, so funct7 selects
+        0b001 if xlen == Xlen::Rv32 => match funct7 {
+            0b0000000 => Inst64::slli,
+            _ => {
+                let msg = format!("Unknown OP_IMM instruction NOT slli funct7={funct7}");
+                error!("{msg}");
+                return Err(Error::Decode(msg));
+            }
+        },
+        0b101 if xlen == Xlen::Rv32 => match funct7 {
             0b0000000 => Inst64::srli,
             0b0100000 => Inst64::srai,
             _ => {
@@ -117,7 +183,6 @@ fn decode_op_imm(inst: u32) -> Result<ExecInternal> {
                 return Err(Error::Decode(msg));
             }
         },
-        */
         // RV64
         0b001 => Inst64::slli,
         0b101 => match funct6 {
@@ -140,7 +205,7 @@ fn decode_op_imm(inst: u32) -> Result<ExecInternal> {
     exec_internal.rs1 = rs1(inst);
     exec_internal.imm = match funct3 {
         0b000 | 0b010 | 0b011 | 0b100 | 0b110 | 0b111 => imm_I(inst),
-        // 0b001 | 0b101 => rs2.into(), // RV32
+        0b001 | 0b101 if xlen == Xlen::Rv32 => rs2(inst).into(),
         0b001 | 0b101 => shift64_I(inst), // RV64
         _ => unreachable!("Should return error before control flow reaches here"),
     };
@@ -150,8 +215,10 @@ fn decode_op_imm(inst: u32) -> Result<ExecInternal> {
 
 /// 0010111 AUIPC: U type
 fn decode_op_auipc(inst: u32) -> Result<ExecInternal> {
-    let mut exec_internal = ExecInternal::default();
-    exec_internal.raw_inst = inst;
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
 
     exec_internal.inst = Inst64::auipc;
     exec_internal.rd = rd(inst);
@@ -162,8 +229,10 @@ fn decode_op_auipc(inst: u32) -> Result<ExecInternal> {
 
 /// 0011011 OP_IMM_32: R type
 fn decode_op_imm_32(inst: u32) -> Result<ExecInternal> {
-    let mut exec_internal = ExecInternal::default();
-    exec_internal.raw_inst = inst;
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
 
     let funct3 = funct3(inst);
     let funct7 = funct7(inst);
@@ -201,8 +270,10 @@ fn decode_op_imm_32(inst: u32) -> Result<ExecInternal> {
 
 /// 0100011 STORE: S type
 fn decode_store(inst: u32) -> Result<ExecInternal> {
-    let mut exec_internal = ExecInternal::default();
-    exec_internal.raw_inst = inst;
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
 
     let funct3 = funct3(inst);
 
@@ -226,19 +297,83 @@ fn decode_store(inst: u32) -> Result<ExecInternal> {
 }
 
 /// 0100111 STORE_FP: S type
-fn decode_store_fp(_inst: u32) -> Result<ExecInternal> {
-    todo!()
+fn decode_store_fp(inst: u32) -> Result<ExecInternal> {
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
+
+    let funct3 = funct3(inst);
+    exec_internal.inst = match funct3 {
+        0b010 => Inst64::fsw,
+        _ => {
+            let msg = format!("Unknown STORE_FP instruction funct3={funct3}");
+            error!("{msg}");
+            return Err(Error::Decode(msg));
+        }
+    };
+
+    exec_internal.rs1 = rs1(inst);
+    exec_internal.rs2 = rs2(inst);
+    exec_internal.imm = imm_S(inst);
+
+    Ok(exec_internal)
 }
 
 /// 0101111 AMO: R type
-fn decode_amo(_inst: u32) -> Result<ExecInternal> {
-    todo!()
+/// funct7 is funct5 followed by the aq and rl bits, which are ignored as
+/// there is only one hart.
+fn decode_amo(inst: u32) -> Result<ExecInternal> {
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
+
+    let funct3 = funct3(inst);
+    let funct5 = funct7(inst) >> 2;
+    exec_internal.inst = match (funct5, funct3) {
+        (0b00010, 0b010) if rs2(inst) == 0 => Inst64::lr_w,
+        (0b00010, 0b011) if rs2(inst) == 0 => Inst64::lr_d,
+        (0b00011, 0b010) => Inst64::sc_w,
+        (0b00011, 0b011) => Inst64::sc_d,
+        (0b00001, 0b010) => Inst64::amoswap_w,
+        (0b00001, 0b011) => Inst64::amoswap_d,
+        (0b00000, 0b010) => Inst64::amoadd_w,
+        (0b00000, 0b011) => Inst64::amoadd_d,
+        (0b00100, 0b010) => Inst64::amoxor_w,
+        (0b00100, 0b011) => Inst64::amoxor_d,
+        (0b01100, 0b010) => Inst64::amoand_w,
+        (0b01100, 0b011) => Inst64::amoand_d,
+        (0b01000, 0b010) => Inst64::amoor_w,
+        (0b01000, 0b011) => Inst64::amoor_d,
+        (0b10000, 0b010) => Inst64::amomin_w,
+        (0b10000, 0b011) => Inst64::amomin_d,
+        (0b10100, 0b010) => Inst64::amomax_w,
+        (0b10100, 0b011) => Inst64::amomax_d,
+        (0b11000, 0b010) => Inst64::amominu_w,
+        (0b11000, 0b011) => Inst64::amominu_d,
+        (0b11100, 0b010) => Inst64::amomaxu_w,
+        (0b11100, 0b011) => Inst64::amomaxu_d,
+        _ => {
+            let msg = format!("Unknown AMO instruction funct3={funct3} funct5={funct5}");
+            error!("{msg}");
+            return Err(Error::Decode(msg));
+        }
+    };
+
+    exec_internal.rd = rd(inst);
+    exec_internal.rs1 = rs1(inst);
+    exec_internal.rs2 = rs2(inst);
+
+    Ok(exec_internal)
 }
 
 /// 0110011 OP: R type
 fn decode_op(inst: u32) -> Result<ExecInternal> {
-    let mut exec_internal = ExecInternal::default();
-    exec_internal.raw_inst = inst;
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
 
     let funct3 = funct3(inst);
     let funct7 = funct7(inst);
@@ -333,8 +468,10 @@ fn decode_op(inst: u32) -> Result<ExecInternal> {
 
 /// 0110111 LUI: U type
 fn decode_lui(inst: u32) -> Result<ExecInternal> {
-    let mut exec_internal = ExecInternal::default();
-    exec_internal.raw_inst = inst;
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
 
     exec_internal.inst = Inst64::lui;
     exec_internal.rd = rd(inst);
@@ -345,8 +482,10 @@ fn decode_lui(inst: u32) -> Result<ExecInternal> {
 
 /// 0111011 OP_32: R type
 fn decode_op_32(inst: u32) -> Result<ExecInternal> {
-    let mut exec_internal = ExecInternal::default();
-    exec_internal.raw_inst = inst;
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
 
     let funct3 = funct3(inst);
     let funct7 = funct7(inst);
@@ -411,34 +550,129 @@ fn decode_op_32(inst: u32) -> Result<ExecInternal> {
 }
 
 /// 1000011 MADD: R4 type
-fn decode_madd(_inst: u32) -> Result<ExecInternal> {
-    todo!()
+fn decode_madd(inst: u32) -> Result<ExecInternal> {
+    decode_r4(inst, Inst64::fmadd_s, "MADD")
 }
 
 /// 1000111 MSUB: R4 type
-fn decode_msub(_inst: u32) -> Result<ExecInternal> {
-    todo!()
+fn decode_msub(inst: u32) -> Result<ExecInternal> {
+    decode_r4(inst, Inst64::fmsub_s, "MSUB")
 }
 
 /// 1001011 NMSUB: R4 type
-fn decode_nmsub(_inst: u32) -> Result<ExecInternal> {
-    todo!()
+fn decode_nmsub(inst: u32) -> Result<ExecInternal> {
+    decode_r4(inst, Inst64::fnmsub_s, "NMSUB")
 }
 
 /// 1001111 NMADD: R4 type
-fn decode_nmadd(_inst: u32) -> Result<ExecInternal> {
-    todo!()
+fn decode_nmadd(inst: u32) -> Result<ExecInternal> {
+    decode_r4(inst, Inst64::fnmadd_s, "NMADD")
+}
+
+/// Fused multiply-add R4 type, `inst` is the single-precision variant.
+/// funct2 selects the format, only S (00) is supported.
+fn decode_r4(inst: u32, op: Inst64, name: &str) -> Result<ExecInternal> {
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
+
+    let funct2 = funct2(inst);
+    if funct2 != 0b00 {
+        let msg = format!("Unknown {name} instruction funct2={funct2}");
+        error!("{msg}");
+        return Err(Error::Decode(msg));
+    }
+    check_rounding_mode(inst)?;
+
+    exec_internal.inst = op;
+    exec_internal.rd = rd(inst);
+    exec_internal.rs1 = rs1(inst);
+    exec_internal.rs2 = rs2(inst);
+    exec_internal.rs3 = rs3(inst);
+
+    Ok(exec_internal)
 }
 
 /// 1010011 OP_FP: R type
-fn decode_op_fp(_inst: u32) -> Result<ExecInternal> {
-    todo!()
+fn decode_op_fp(inst: u32) -> Result<ExecInternal> {
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
+
+    let funct3 = funct3(inst);
+    let funct7 = funct7(inst);
+    let rs2 = rs2(inst);
+    exec_internal.inst = match (funct7, rs2, funct3) {
+        (0b0000000, _, _) => Inst64::fadd_s,
+        (0b0000100, _, _) => Inst64::fsub_s,
+        (0b0001000, _, _) => Inst64::fmul_s,
+        (0b0001100, _, _) => Inst64::fdiv_s,
+        (0b0101100, 0, _) => Inst64::fsqrt_s,
+        (0b0010000, _, 0b000) => Inst64::fsgnj_s,
+        (0b0010000, _, 0b001) => Inst64::fsgnjn_s,
+        (0b0010000, _, 0b010) => Inst64::fsgnjx_s,
+        (0b0010100, _, 0b000) => Inst64::fmin_s,
+        (0b0010100, _, 0b001) => Inst64::fmax_s,
+        (0b1010000, _, 0b010) => Inst64::feq_s,
+        (0b1010000, _, 0b001) => Inst64::flt_s,
+        (0b1010000, _, 0b000) => Inst64::fle_s,
+        (0b1100000, 0, _) => Inst64::fcvt_w_s,
+        (0b1100000, 1, _) => Inst64::fcvt_wu_s,
+        (0b1101000, 0, _) => Inst64::fcvt_s_w,
+        (0b1101000, 1, _) => Inst64::fcvt_s_wu,
+        (0b1110000, 0, 0b000) => Inst64::fmv_x_w,
+        (0b1110000, 0, 0b001) => Inst64::fclass_s,
+        (0b1111000, 0, 0b000) => Inst64::fmv_w_x,
+        _ => {
+            let msg =
+                format!("Unknown OP_FP instruction funct7={funct7} rs2={rs2} funct3={funct3}");
+            error!("{msg}");
+            return Err(Error::Decode(msg));
+        }
+    };
+    // funct3 is the rounding mode of arithmetic and conversions, and
+    // selects the operation of the others
+    if has_rounding_mode(exec_internal.inst) {
+        check_rounding_mode(inst)?;
+    }
+
+    exec_internal.rd = rd(inst);
+    exec_internal.rs1 = rs1(inst);
+    exec_internal.rs2 = rs2;
+
+    Ok(exec_internal)
+}
+
+/// Whether funct3 of the OP_FP instruction `op` is a rounding mode.
+fn has_rounding_mode(op: Inst64) -> bool {
+    use Inst64::*;
+    matches!(
+        op,
+        fadd_s | fsub_s | fmul_s | fdiv_s | fsqrt_s | fcvt_w_s | fcvt_wu_s | fcvt_s_w | fcvt_s_wu
+    )
+}
+
+/// Reject the reserved rounding modes 101 and 110.
+/// Other modes are accepted but arithmetic always rounds to nearest even,
+/// only conversions to integers honor them.
+fn check_rounding_mode(inst: u32) -> Result<()> {
+    let rm = funct3(inst);
+    if rm == 0b101 || rm == 0b110 {
+        let msg = format!("Reserved rounding mode rm={rm}");
+        error!("{msg}");
+        return Err(Error::Decode(msg));
+    }
+    Ok(())
 }
 
 /// 1100011 BRANCH: SB type
 fn decode_branch(inst: u32) -> Result<ExecInternal> {
-    let mut exec_internal = ExecInternal::default();
-    exec_internal.raw_inst = inst;
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
 
     let funct3 = funct3(inst);
 
@@ -467,8 +701,10 @@ fn decode_branch(inst: u32) -> Result<ExecInternal> {
 
 /// 1100111 JALR: I type
 fn decode_jalr(inst: u32) -> Result<ExecInternal> {
-    let mut exec_internal = ExecInternal::default();
-    exec_internal.raw_inst = inst;
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
 
     let funct3 = funct3(inst);
     exec_internal.inst = match funct3 {
@@ -489,8 +725,10 @@ fn decode_jalr(inst: u32) -> Result<ExecInternal> {
 
 /// 1101111 JAL: UJ type
 fn decode_jal(inst: u32) -> Result<ExecInternal> {
-    let mut exec_internal = ExecInternal::default();
-    exec_internal.raw_inst = inst;
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
 
     exec_internal.inst = Inst64::jal;
     exec_internal.rd = rd(inst);
@@ -501,8 +739,10 @@ fn decode_jal(inst: u32) -> Result<ExecInternal> {
 
 /// 1110011 SYSTEM: I type
 fn decode_system(inst: u32) -> Result<ExecInternal> {
-    let mut exec_internal = ExecInternal::default();
-    exec_internal.raw_inst = inst;
+    let mut exec_internal = ExecInternal {
+        raw_inst: inst,
+        ..Default::default()
+    };
 
     let funct3 = funct3(inst);
     let csr = imm_I(inst);
@@ -510,6 +750,9 @@ fn decode_system(inst: u32) -> Result<ExecInternal> {
         0b000 => match csr {
             0 => Inst64::ecall,
             1 => Inst64::ebreak,
+            0x102 => Inst64::sret,
+            0x302 => Inst64::mret,
+            0x105 => Inst64::wfi,
             _ => {
                 let msg = format!("Unknown SYSTEM E- instruction csr={csr}");
                 error!("{msg}");
@@ -531,6 +774,51 @@ fn decode_system(inst: u32) -> Result<ExecInternal> {
 
     exec_internal.rd = rd(inst);
     exec_internal.rs1 = rs1(inst); // zimm for csrrwi, csrrsi, csrrci
+    exec_internal.imm = csr; // CSR address
 
     Ok(exec_internal)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{core::rng::Rng, multi_stage::fetch::inst_interpret};
+
+    #[test]
+    fn agrees_with_pipeline_decode() {
+        // random words, three in four of them compressed
+        let mut rng = Rng::new(0);
+        for _ in 0..200_000 {
+            let inst = rng.next_u64() as u32;
+            let single = decode(inst, Xlen::Rv64);
+            let pipeline = inst_interpret(0, inst, Xlen::Rv64);
+            match (single, pipeline) {
+                (Ok(single), Ok(pipeline)) => {
+                    let op = pipeline.exec_flags.alu_op;
+                    assert_eq!(single.inst, op, "{inst:#010x}");
+                    assert_eq!(single.inst_len, pipeline.inst_len, "{inst:#010x}");
+                    // only the pipeline leaves the fence fields out
+                    if !matches!(op, Inst64::fence | Inst64::fence_i) {
+                        assert_eq!(
+                            (single.rd, single.rs1, single.rs2, single.imm),
+                            (pipeline.rd, pipeline.rs1, pipeline.rs2, pipeline.imm),
+                            "{inst:#010x} {op}"
+                        );
+                    }
+                }
+                // the pipeline has no F extension
+                (Ok(single), Err(_)) => assert!(
+                    single.inst.mnemonic().starts_with('f')
+                        && !matches!(single.inst, Inst64::fence | Inst64::fence_i),
+                    "{inst:#010x} {} only decodes on the single cycle CPU",
+                    single.inst
+                ),
+                (Err(_), Ok(pipeline)) => panic!(
+                    "{inst:#010x} {} only decodes on the pipeline CPU",
+                    pipeline.exec_flags.alu_op
+                ),
+                (Err(_), Err(_)) => {}
+            }
+        }
+    }
+}
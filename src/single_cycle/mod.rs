@@ -1,3 +1,4 @@
 pub mod cpu;
 pub mod debug;
 pub mod decode;
+pub mod repl;
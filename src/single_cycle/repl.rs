@@ -0,0 +1,135 @@
+//! Assembly-level single-line REPL on a blank machine.
+
+use super::cpu::CPU;
+use crate::{
+    core::{asm::assemble, reg::REGNAME, rvc::inst_len},
+    error::Result,
+};
+use clap_num::maybe_hex;
+use std::io::{self, BufRead, Write};
+
+const REPL_BUF_SIZE: usize = 64;
+
+/// Where the blank machine's memory starts.
+pub const REPL_MEM_BASE: u64 = 0x8000_0000;
+
+/// Size of the blank machine's memory. Stack grows down from its end.
+pub const REPL_MEM_SIZE: u64 = 4 * 1024 * 1024;
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct REPL<'a> {
+    // Command line input buffer
+    buf: String,
+
+    // CPU
+    cpu: &'a mut CPU<'a>,
+}
+
+impl<'a> REPL<'a> {
+    pub fn new(cpu: &'a mut CPU<'a>) -> REPL<'a> {
+        REPL {
+            buf: String::with_capacity(REPL_BUF_SIZE),
+            cpu,
+        }
+    }
+
+    pub fn run(&mut self) {
+        println!("REPL: type one RISC-V instruction per line, `q` to quit.");
+        loop {
+            print!("(REPL)>>> ");
+            io::stdout().flush().expect("Fail to flush");
+            self.buf.clear();
+            match io::stdin().lock().read_line(&mut self.buf) {
+                Ok(0) => break, // EOF
+                Ok(_) => {}
+                Err(e) => {
+                    println!("REPL: {e}");
+                    continue;
+                }
+            }
+            let line = self.buf.trim().to_string();
+            match line.as_str() {
+                "" => continue,
+                "q" | "quit" => {
+                    println!("REPL: Exit REPL");
+                    break;
+                }
+                _ => {}
+            }
+            if let Err(e) = self.exec_line(&line) {
+                println!("REPL: {e}");
+            }
+        }
+    }
+
+    /// Assemble and execute one instruction at pc, then print what changed.
+    /// A number is taken as the encoding itself, which may be compressed.
+    fn exec_line(&mut self, line: &str) -> Result<()> {
+        let inst = match maybe_hex::<u32>(line) {
+            Ok(inst) => inst,
+            Err(_) => assemble(line)?,
+        };
+
+        let mut pc = self.cpu.pc();
+        if !(REPL_MEM_BASE..REPL_MEM_BASE + REPL_MEM_SIZE - 4).contains(&pc) {
+            println!("REPL: pc {pc:#x} out of memory, reset to {REPL_MEM_BASE:#x}");
+            pc = REPL_MEM_BASE;
+            self.cpu.set_pc(pc);
+        }
+
+        let before: Vec<u64> = (0..32).map(|i| self.cpu.reg_val(i)).collect();
+        self.cpu.write_inst(pc, inst)?;
+        self.cpu.exec_once()?;
+
+        for (i, old) in before.into_iter().enumerate() {
+            let new = self.cpu.reg_val(i as u8);
+            if new != old {
+                println!(
+                    "x{i} ({}) \t: {:#x} -> {:#x}\t({})",
+                    REGNAME[i], old, new, new as i64
+                );
+            }
+        }
+        let new_pc = self.cpu.pc();
+        if new_pc != pc.wrapping_add(inst_len(inst)) {
+            println!("pc\t\t: {:#x} -> {:#x}", pc, new_pc);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        callstack::CallStack,
+        core::{insts::Xlen, vm::VirtualMemory},
+        syscall::SyscallAbi,
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn compressed_line() {
+        let mut vm = VirtualMemory::with_base(REPL_MEM_BASE as usize, 0x1000, false);
+        let symbol_map = HashMap::new();
+        let mut callstack = CallStack::new(&symbol_map, false);
+        let mut cpu = CPU::new(
+            &mut vm,
+            &mut callstack,
+            false,
+            false,
+            false,
+            SyscallAbi::Linux,
+            Xlen::Rv64,
+        );
+        cpu.init_blank(REPL_MEM_BASE, REPL_MEM_BASE + 0x1000);
+        let mut repl = REPL::new(&mut cpu);
+
+        // c.addi a0, 1 moves on by 2 bytes, the next line is executed there
+        repl.exec_line("0x0505").unwrap();
+        assert_eq!(repl.cpu.pc(), REPL_MEM_BASE + 2);
+        repl.exec_line("addi a0, a0, 2").unwrap();
+        assert_eq!(repl.cpu.pc(), REPL_MEM_BASE + 6);
+        assert_eq!(repl.cpu.reg_val(10), 3);
+    }
+}
@@ -0,0 +1,225 @@
+//! riscv64 Linux syscalls
+
+use std::io::Write;
+
+use log::warn;
+
+use crate::{core::vm::VirtualMemory, error::Result};
+
+use super::{Syscall, SyscallResult};
+
+const SYS_CLOSE: u64 = 57;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_BRK: u64 = 214;
+
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const ENOSYS: i64 = 38;
+
+const STAT_SIZE: usize = 128; // sizeof(struct stat)
+const STAT_MODE_OFFSET: usize = 16; // offsetof(struct stat, st_mode)
+const S_IFCHR: u32 = 0o020000; // character device
+
+#[derive(Clone, Default)]
+pub struct LinuxSyscall {
+    // Current program break
+    brk: u64,
+    // Contents of stdin, and how much of it has been read
+    stdin: Vec<u8>,
+    stdin_pos: usize,
+}
+
+impl LinuxSyscall {
+    pub fn new() -> LinuxSyscall {
+        LinuxSyscall::default()
+    }
+
+    // Only stdin can be read, which is at its end once consumed.
+    fn read(&mut self, fd: u64, buf: u64, count: u64, vm: &mut VirtualMemory) -> i64 {
+        if fd != 0 {
+            return -EBADF;
+        }
+        let rest = &self.stdin[self.stdin_pos..];
+        let bytes = &rest[..rest.len().min(count as usize)];
+        if vm.write_bytes(buf as usize, bytes).is_err() {
+            return -EFAULT;
+        }
+        self.stdin_pos += bytes.len();
+        bytes.len() as i64
+    }
+
+    fn write(&self, fd: u64, buf: u64, count: u64, vm: &VirtualMemory) -> i64 {
+        let Some(bytes) = vm.read_bytes(buf as usize, count as usize) else {
+            return -EFAULT;
+        };
+        let result = match fd {
+            1 => std::io::stdout().write_all(bytes),
+            2 => std::io::stderr().write_all(bytes),
+            _ => return -EBADF,
+        };
+        match result {
+            Ok(()) => count as i64,
+            Err(_) => -EFAULT,
+        }
+    }
+
+    // Only the standard streams exist, all as character devices.
+    fn fstat(&self, fd: u64, statbuf: u64, vm: &mut VirtualMemory) -> i64 {
+        if fd > 2 {
+            return -EBADF;
+        }
+        let mut stat = [0u8; STAT_SIZE];
+        stat[STAT_MODE_OFFSET..STAT_MODE_OFFSET + 4]
+            .copy_from_slice(&(S_IFCHR | 0o620).to_le_bytes());
+        match vm.write_bytes(statbuf as usize, &stat) {
+            Ok(()) => 0,
+            Err(_) => -EFAULT,
+        }
+    }
+
+    // The heap grows into the free memory above the program, up to the
+    // stack guard page if any.
+    fn brk(&mut self, addr: u64, vm: &VirtualMemory) -> u64 {
+        let grows =
+            |len| vm.contains(self.brk as usize, len) && !vm.guarded(self.brk as usize, len);
+        if addr >= self.brk && grows((addr - self.brk) as usize) {
+            self.brk = addr;
+        }
+        self.brk
+    }
+}
+
+impl Syscall for LinuxSyscall {
+    fn syscall(
+        &mut self,
+        nr: u64,
+        args: [u64; 6],
+        vm: &mut VirtualMemory,
+    ) -> Result<SyscallResult> {
+        let ret = match nr {
+            SYS_CLOSE => 0,
+            SYS_READ => self.read(args[0], args[1], args[2], vm) as u64,
+            SYS_WRITE => self.write(args[0], args[1], args[2], vm) as u64,
+            SYS_FSTAT => self.fstat(args[0], args[1], vm) as u64,
+            SYS_EXIT | SYS_EXIT_GROUP => return Ok(SyscallResult::Exit(args[0])),
+            SYS_BRK => self.brk(args[0], vm),
+            _ => {
+                warn!("Unsupported syscall {nr}, returns -ENOSYS");
+                -ENOSYS as u64
+            }
+        };
+        Ok(SyscallResult::Return(ret))
+    }
+
+    fn set_brk(&mut self, brk: u64) {
+        self.brk = brk;
+    }
+
+    fn set_stdin(&mut self, input: Vec<u8>) {
+        self.stdin = input;
+        self.stdin_pos = 0;
+    }
+
+    fn clone_box(&self) -> Box<dyn Syscall> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn linux_syscall_test() {
+        let mut vm = VirtualMemory::with_base(0x1000, 0x1000, false);
+        let mut sys = LinuxSyscall::new();
+        sys.set_brk(0x1800);
+
+        let ret = |r: Result<SyscallResult>| match r.unwrap() {
+            SyscallResult::Return(v) => v as i64,
+            SyscallResult::Exit(_) => panic!("unexpected exit"),
+        };
+
+        // brk(0) queries, growing within memory succeeds, beyond fails
+        assert_eq!(ret(sys.syscall(SYS_BRK, [0; 6], &mut vm)), 0x1800);
+        assert_eq!(
+            ret(sys.syscall(SYS_BRK, [0x1900, 0, 0, 0, 0, 0], &mut vm)),
+            0x1900
+        );
+        assert_eq!(
+            ret(sys.syscall(SYS_BRK, [0x3000, 0, 0, 0, 0, 0], &mut vm)),
+            0x1900
+        );
+        // nor into the stack guard page
+        vm.set_guard(0x1c00..0x1d00);
+        assert_eq!(
+            ret(sys.syscall(SYS_BRK, [0x1c80, 0, 0, 0, 0, 0], &mut vm)),
+            0x1900
+        );
+
+        vm.fill::<u8>(0x1000, 3, b'o').unwrap();
+        assert_eq!(
+            ret(sys.syscall(SYS_WRITE, [2, 0x1000, 3, 0, 0, 0], &mut vm)),
+            3
+        );
+        assert_eq!(
+            ret(sys.syscall(SYS_WRITE, [7, 0x1000, 3, 0, 0, 0], &mut vm)),
+            -EBADF
+        );
+        assert_eq!(
+            ret(sys.syscall(SYS_WRITE, [1, 0x1ff0, 32, 0, 0, 0], &mut vm)),
+            -EFAULT
+        );
+
+        // stdin is empty unless set
+        assert_eq!(
+            ret(sys.syscall(SYS_READ, [0, 0x1000, 8, 0, 0, 0], &mut vm)),
+            0
+        );
+        sys.set_stdin(b"hello".to_vec());
+        assert_eq!(
+            ret(sys.syscall(SYS_READ, [0, 0x1000, 3, 0, 0, 0], &mut vm)),
+            3
+        );
+        assert_eq!(
+            ret(sys.syscall(SYS_READ, [1, 0x1003, 8, 0, 0, 0], &mut vm)),
+            -EBADF
+        );
+        assert_eq!(
+            ret(sys.syscall(SYS_READ, [0, 0x1fff, 8, 0, 0, 0], &mut vm)),
+            -EFAULT
+        );
+        assert_eq!(
+            ret(sys.syscall(SYS_READ, [0, 0x1003, 8, 0, 0, 0], &mut vm)),
+            2
+        );
+        assert_eq!(vm.read_bytes(0x1000, 5).unwrap(), b"hello");
+        assert_eq!(
+            ret(sys.syscall(SYS_READ, [0, 0x1000, 8, 0, 0, 0], &mut vm)),
+            0
+        );
+
+        assert_eq!(
+            ret(sys.syscall(SYS_FSTAT, [1, 0x1100, 0, 0, 0, 0], &mut vm)),
+            0
+        );
+        assert_eq!(vm.mread::<u32>(0x1110).unwrap() & S_IFCHR, S_IFCHR);
+        // a struct running past the end of memory is not written at all
+        vm.write_bytes(0x1fc0, &[0xaa; 0x40]).unwrap();
+        assert_eq!(
+            ret(sys.syscall(SYS_FSTAT, [1, 0x1fc0, 0, 0, 0, 0], &mut vm)),
+            -EFAULT
+        );
+        assert_eq!(vm.read_bytes(0x1fc0, 0x40).unwrap(), [0xaa; 0x40]);
+
+        assert_eq!(ret(sys.syscall(1234, [0; 6], &mut vm)), -ENOSYS);
+        assert_eq!(
+            sys.syscall(SYS_EXIT, [3, 0, 0, 0, 0, 0], &mut vm).unwrap(),
+            SyscallResult::Exit(3)
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! Environment calls
+
+mod linux;
+mod semihosting;
+
+use clap::ValueEnum;
+
+use crate::{
+    core::{reg::RegisterFile, vm::VirtualMemory},
+    error::{Error, Result},
+};
+
+pub use linux::LinuxSyscall;
+pub use semihosting::{is_semihosting_call, semihost};
+
+/// Syscall ABI served on ecall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SyscallAbi {
+    Linux, // riscv64 Linux syscalls, enough for newlib
+    None,  // ecall is an error
+}
+
+/// What the CPU should do after a syscall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallResult {
+    Return(u64), // write the value back to a0
+    Exit(u64),   // halt with the exit code
+}
+
+pub trait Syscall {
+    /// Serve syscall `nr` with arguments `args` taken from a0-a5.
+    fn syscall(&mut self, nr: u64, args: [u64; 6], vm: &mut VirtualMemory)
+        -> Result<SyscallResult>;
+
+    /// Set the initial program break, i.e. the end of the loaded program.
+    fn set_brk(&mut self, _brk: u64) {}
+
+    /// Set what the program reads from stdin.
+    fn set_stdin(&mut self, _input: Vec<u8>) {}
+
+    /// Copy of the handler and its state, e.g. the program break.
+    fn clone_box(&self) -> Box<dyn Syscall>;
+}
+
+/// Refuse every ecall.
+#[derive(Clone)]
+pub struct NoSyscall;
+
+impl Syscall for NoSyscall {
+    fn syscall(&mut self, nr: u64, _: [u64; 6], _: &mut VirtualMemory) -> Result<SyscallResult> {
+        Err(Error::Execute(format!(
+            "ecall with syscall number {nr}, but syscall ABI is none"
+        )))
+    }
+
+    fn clone_box(&self) -> Box<dyn Syscall> {
+        Box::new(self.clone())
+    }
+}
+
+/// Create the syscall handler for `abi`.
+pub fn new_syscall(abi: SyscallAbi) -> Box<dyn Syscall> {
+    match abi {
+        SyscallAbi::Linux => Box::new(LinuxSyscall::new()),
+        SyscallAbi::None => Box::new(NoSyscall),
+    }
+}
+
+/// Execute ecall: read the syscall number from a7 and arguments from a0-a5,
+/// then write the return value back to a0.
+/// Returns the exit code if the program exits.
+pub fn ecall(
+    handler: &mut dyn Syscall,
+    reg_file: &mut RegisterFile,
+    vm: &mut VirtualMemory,
+) -> Result<Option<u64>> {
+    let nr = reg_file.read(17);
+    let args = std::array::from_fn(|i| reg_file.read(10 + i as u8));
+    match handler.syscall(nr, args, vm)? {
+        SyscallResult::Return(ret) => {
+            reg_file.write(10, ret);
+            Ok(None)
+        }
+        SyscallResult::Exit(code) => Ok(Some(code)),
+    }
+}
@@ -0,0 +1,158 @@
+//! RISC-V semihosting: ARM semihosting calls made by an `ebreak` between
+//! `slli x0, x0, 0x1f` and `srai x0, x0, 7`, with the operation number at a0
+//! and a pointer to its parameter block at a1.
+
+use std::io::Write;
+
+use log::warn;
+
+use crate::{
+    core::{insts::Xlen, reg::RegisterFile, vm::VirtualMemory},
+    error::{Error, Result},
+};
+
+/// `slli x0, x0, 0x1f`, right before the ebreak
+const ENTRY_NOP: u32 = 0x01f01013;
+/// `ebreak`, which must not be compressed
+const EBREAK: u32 = 0x00100073;
+/// `srai x0, x0, 7`, right after the ebreak
+const EXIT_NOP: u32 = 0x40705013;
+
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_EXIT: u64 = 0x18;
+
+/// Reason of SYS_EXIT for a normal exit of the program
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// Whether the ebreak at `pc` is a semihosting call, i.e. sits in the magic
+/// sequence.
+pub fn is_semihosting_call(vm: &VirtualMemory, pc: u64) -> bool {
+    let Some(bytes) = pc
+        .checked_sub(4)
+        .and_then(|start| vm.read_bytes(start as usize, 12))
+    else {
+        return false;
+    };
+    let word = |i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+    word(0) == ENTRY_NOP && word(1) == EBREAK && word(2) == EXIT_NOP
+}
+
+/// Serve the semihosting call: read the operation from a0 and the parameter
+/// block pointer from a1, printing to stdout.
+/// Returns the exit code if the program exits.
+pub fn semihost(reg_file: &RegisterFile, vm: &VirtualMemory, xlen: Xlen) -> Result<Option<u64>> {
+    let op = reg_file.read(10);
+    let param = reg_file.read(11);
+    serve(op, param, vm, xlen, &mut std::io::stdout())
+}
+
+fn serve(
+    op: u64,
+    param: u64,
+    vm: &VirtualMemory,
+    xlen: Xlen,
+    out: &mut dyn Write,
+) -> Result<Option<u64>> {
+    match op {
+        SYS_WRITEC => {
+            let byte = read_bytes(vm, param, 1)?;
+            out.write_all(byte)?;
+            out.flush()?;
+        }
+        SYS_WRITE0 => {
+            let mut len = 0;
+            while read_bytes(vm, param + len, 1)?[0] != 0 {
+                len += 1;
+            }
+            out.write_all(read_bytes(vm, param, len)?)?;
+            out.flush()?;
+        }
+        SYS_EXIT => {
+            // RV32 passes the reason itself, RV64 a block of reason and
+            // exit code
+            let (reason, code) = match xlen {
+                Xlen::Rv32 => (param, 0),
+                Xlen::Rv64 => {
+                    let block = read_bytes(vm, param, 16)?;
+                    let word = |i: usize| u64::from_le_bytes(block[i..i + 8].try_into().unwrap());
+                    (word(0), word(8))
+                }
+            };
+            if reason == ADP_STOPPED_APPLICATION_EXIT {
+                return Ok(Some(code));
+            }
+            warn!("Semihosting exit with reason {reason:#x}");
+            return Ok(Some(1));
+        }
+        _ => {
+            let msg = format!("Unsupported semihosting operation {op:#x}");
+            return Err(Error::Execute(msg));
+        }
+    }
+    Ok(None)
+}
+
+fn read_bytes(vm: &VirtualMemory, vaddr: u64, len: u64) -> Result<&[u8]> {
+    vm.read_bytes(vaddr as usize, len as usize).ok_or_else(|| {
+        Error::Execute(format!(
+            "Semihosting parameter at {vaddr:#x} is out of memory"
+        ))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn semihosting_test() {
+        let mut vm = VirtualMemory::with_base(0x1000, 0x1000, false);
+        vm.write_inst(0x1000, ENTRY_NOP).unwrap();
+        vm.write_inst(0x1004, EBREAK).unwrap();
+        vm.write_inst(0x1008, EXIT_NOP).unwrap();
+        vm.write_inst(0x100c, EBREAK).unwrap();
+        assert!(is_semihosting_call(&vm, 0x1004));
+        assert!(!is_semihosting_call(&vm, 0x100c));
+        assert!(!is_semihosting_call(&vm, 0x1000));
+
+        vm.write_bytes(0x1100, b"hi\0").unwrap();
+        let mut out = Vec::new();
+        assert_eq!(
+            serve(SYS_WRITEC, 0x1101, &vm, Xlen::Rv64, &mut out).unwrap(),
+            None
+        );
+        assert_eq!(
+            serve(SYS_WRITE0, 0x1100, &vm, Xlen::Rv64, &mut out).unwrap(),
+            None
+        );
+        assert_eq!(out, b"ihi");
+        // unterminated at the end of memory
+        vm.write_bytes(0x1ffe, b"hi").unwrap();
+        assert!(serve(SYS_WRITE0, 0x1ffe, &vm, Xlen::Rv64, &mut out).is_err());
+        assert!(serve(0x01, 0x1100, &vm, Xlen::Rv64, &mut out).is_err());
+
+        vm.mwrite::<u64>(0x1200, ADP_STOPPED_APPLICATION_EXIT)
+            .unwrap();
+        vm.mwrite::<u64>(0x1208, 3).unwrap();
+        assert_eq!(
+            serve(SYS_EXIT, 0x1200, &vm, Xlen::Rv64, &mut out).unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            serve(
+                SYS_EXIT,
+                ADP_STOPPED_APPLICATION_EXIT,
+                &vm,
+                Xlen::Rv32,
+                &mut out
+            )
+            .unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            serve(SYS_EXIT, 0x20023, &vm, Xlen::Rv32, &mut out).unwrap(),
+            Some(1)
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Trace files.
+//! itrace, mtrace and ftrace records are written in a fixed format which does
+//! not depend on the log level, so traces of different CPU models running the
+//! same program could be compared byte by byte.
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    core::reg::{RegisterFile, TraceRegs},
+    error::Result,
+};
+
+/// Kind of a memory access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemKind {
+    Read,  // data load
+    Write, // data store
+    Fetch, // instruction fetch
+}
+
+/// A memory access, by the instruction at `pc`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemEvent {
+    pub kind: MemKind,
+    pub addr: u64,
+    pub size: u64, // bytes
+    pub value: u64,
+    pub pc: u64,
+}
+
+/// Hazard detector of the pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HazardKind {
+    LoadUse,      // a load in ID/EX feeding IF/ID
+    ExMem,        // a result in EX/MEM feeding the next instruction
+    MemWb,        // a result in MEM/WB feeding the instruction after next
+    MemMem,       // a result in MEM1/MEM2 of the extra MEM stage
+    MemToMem,     // a load feeding the data of a store
+    Mispredict,   // a branch or jump resolved at EX against its prediction
+    ControlStall, // a fetch waiting for a branch or jump to resolve at EX
+    Structural,   // a fetch waiting for MEM on a single memory port
+    Syscall,      // ID waiting for a syscall to write registers at WB
+}
+
+impl HazardKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            HazardKind::LoadUse => "load-use",
+            HazardKind::ExMem => "ex-mem",
+            HazardKind::MemWb => "mem-wb",
+            HazardKind::MemMem => "mem1-mem2",
+            HazardKind::MemToMem => "mem-to-mem",
+            HazardKind::Mispredict => "mispredict",
+            HazardKind::ControlStall => "control-stall",
+            HazardKind::Structural => "structural",
+            HazardKind::Syscall => "syscall",
+        }
+    }
+}
+
+/// A hazard found in clock `clock` between the older instruction at
+/// `producer` and the younger one at `consumer`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HazardEvent {
+    pub kind: HazardKind,
+    pub clock: u64,
+    pub producer: u64,
+    pub consumer: u64,
+    pub stages: Vec<String>, // `<stage>=<states>` set by the detector, none when forwarding
+}
+
+pub struct TraceFile {
+    writer: Box<dyn Write>,
+}
+
+impl std::fmt::Debug for TraceFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TraceFile").finish_non_exhaustive()
+    }
+}
+
+impl TraceFile {
+    /// Create (or truncate) the trace file at `path`.
+    pub fn create(path: &Path) -> Result<TraceFile> {
+        Ok(TraceFile {
+            writer: Box::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    /// Trace records on stdout, next to the output of the program.
+    pub fn stdout() -> TraceFile {
+        TraceFile {
+            writer: Box::new(io::stdout()),
+        }
+    }
+
+    /// itrace: `<pc> <inst>` for every retired instruction.
+    /// Compressed instructions are recorded in their expanded form.
+    pub fn inst(&mut self, pc: u64, raw_inst: u32) {
+        self.record(format_args!("{:#x} {:08x}", pc, raw_inst));
+    }
+
+    /// mtrace: `R|W <vaddr> <bytes> <value>` for every data access.
+    pub fn mem(&mut self, write: bool, vaddr: u64, bytes: usize, value: u64) {
+        let kind = if write { 'W' } else { 'R' };
+        self.record(format_args!("{kind} {:#x} {bytes} {:#x}", vaddr, value));
+    }
+
+    /// Memory log: `R|W|F <pc> <vaddr> <bytes> <value>` for every load,
+    /// store and instruction fetch.
+    pub fn mem_event(&mut self, event: &MemEvent) {
+        let kind = match event.kind {
+            MemKind::Read => 'R',
+            MemKind::Write => 'W',
+            MemKind::Fetch => 'F',
+        };
+        self.record(format_args!(
+            "{kind} {:#x} {:#x} {} {:#x}",
+            event.pc, event.addr, event.size, event.value
+        ));
+    }
+
+    /// Hazard trace: `<clock> <kind> <producer> -> <consumer> <stages>` for
+    /// every hazard detected, `-` for the stages when nothing is held.
+    pub fn hazard(&mut self, event: &HazardEvent) {
+        let stages = if event.stages.is_empty() {
+            "-".to_string()
+        } else {
+            event.stages.join(" ")
+        };
+        self.record(format_args!(
+            "{} {} {:#x} -> {:#x} {stages}",
+            event.clock,
+            event.kind.name(),
+            event.producer,
+            event.consumer
+        ));
+    }
+
+    /// Commit log: the registers after the instruction at `pc` retired, see
+    /// [`TraceRegs::line`].
+    pub fn regs(
+        &mut self,
+        trace_regs: TraceRegs,
+        pc: u64,
+        write: Option<(u8, u64)>,
+        reg_file: &RegisterFile,
+    ) {
+        self.record(format_args!("{}", trace_regs.line(pc, write, reg_file)));
+    }
+
+    /// ftrace: `<pc> call <func>@<target>` when calling a known function.
+    pub fn call(&mut self, pc: u64, func_name: &str, target_pc: u64) {
+        self.record(format_args!("{:#x} call {func_name}@{:#x}", pc, target_pc));
+    }
+
+    /// ftrace: `<pc> ret <func>` when returning from a known function.
+    pub fn ret(&mut self, pc: u64, func_name: &str) {
+        self.record(format_args!("{:#x} ret {func_name}", pc));
+    }
+
+    fn record(&mut self, args: std::fmt::Arguments) {
+        writeln!(self.writer, "{args}").expect("Fail to write trace file");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::temp_path;
+
+    #[test]
+    fn trace_file_test() {
+        let path = temp_path("trace-file.log");
+        {
+            let mut trace = TraceFile::create(&path).unwrap();
+            trace.inst(0x8000_0000, 0x00a00513);
+            trace.mem(true, 0x8000_1000, 4, 0x2a);
+            trace.mem_event(&MemEvent {
+                kind: MemKind::Fetch,
+                addr: 0x8000_0000,
+                size: 4,
+                value: 0x00a00513,
+                pc: 0x8000_0000,
+            });
+            trace.call(0x8000_0004, "main", 0x8000_0100);
+            trace.ret(0x8000_0108, "main");
+            let mut reg_file = RegisterFile::empty();
+            reg_file.write(10, 0x2a);
+            trace.regs(TraceRegs::Changed, 0x8000_010c, Some((10, 0)), &reg_file);
+            trace.hazard(&HazardEvent {
+                kind: HazardKind::LoadUse,
+                clock: 7,
+                producer: 0x8000_0000,
+                consumer: 0x8000_0004,
+                stages: vec!["ID/EX=bubble".to_string(), "PC=stall".to_string()],
+            });
+        }
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            content,
+            "0x80000000 00a00513\n\
+             W 0x80001000 4 0x2a\n\
+             F 0x80000000 0x80000000 4 0xa00513\n\
+             0x80000004 call main@0x80000100\n\
+             0x80000108 ret main\n\
+             0x8000010c x10 0x0->0x2a\n\
+             7 load-use 0x80000000 -> 0x80000004 ID/EX=bubble PC=stall\n"
+        );
+    }
+}
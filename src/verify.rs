@@ -0,0 +1,241 @@
+//! Differential testing against the commit log of a reference simulator.
+//! Each retired instruction is checked against the next line of the log,
+//! in the `--log-commits` form of Spike:
+//! `core 0: [priv] 0x<pc> (0x<inst>) [x<rd> 0x<value>]`.
+//! Other lines are skipped. The log is read as the program runs.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Lines},
+    path::Path,
+};
+
+use log::error;
+
+use crate::{
+    core::{insts::Xlen, rvc},
+    error::{Error, Result},
+};
+
+/// A retired instruction: its pc and the register it wrote, if any
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Commit {
+    pub pc: u64,
+    pub inst: u32,
+    pub write: Option<(u8, u64)>, // (rd, value), never x0
+}
+
+impl std::fmt::Display for Commit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#x} ({:#010x})", self.pc, self.inst)?;
+        if let Some((rd, value)) = self.write {
+            write!(f, " x{rd} {value:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Commit {
+    /// Parse a commit log line, [`None`] if it is not a commit.
+    pub fn parse(line: &str) -> Result<Option<Commit>> {
+        let err = || Error::InvalidReference(format!("invalid commit `{line}`"));
+        let hex = |s: &str| {
+            s.strip_prefix("0x")
+                .and_then(|h| u64::from_str_radix(h, 16).ok())
+        };
+        let mut tokens = line.split_whitespace().peekable();
+        if tokens.next() != Some("core") || !tokens.next().is_some_and(|t| t.ends_with(':')) {
+            return Ok(None);
+        }
+        // the privilege level is logged by newer versions of Spike
+        if tokens.peek().is_some_and(|t| t.len() == 1) {
+            tokens.next();
+        }
+        let Some(pc) = tokens.next().and_then(hex) else {
+            return Ok(None);
+        };
+        let inst = tokens
+            .next()
+            .and_then(|t| t.strip_prefix('(')?.strip_suffix(')'))
+            .and_then(hex)
+            .ok_or_else(err)?;
+        // writes to other than integer registers are left out
+        let rd = tokens
+            .next()
+            .and_then(|t| t.strip_prefix('x')?.parse::<u8>().ok());
+        let write = match rd {
+            Some(rd) => {
+                let value = tokens.next().and_then(hex).ok_or_else(err)?;
+                Some((rd, value)).filter(|&(rd, _)| rd != 0)
+            }
+            None => None,
+        };
+        Ok(Some(Commit {
+            pc,
+            inst: inst as u32,
+            write,
+        }))
+    }
+}
+
+/// Checks retired instructions against a reference commit log
+pub struct Verifier {
+    lines: Lines<Box<dyn BufRead>>,
+    next: usize, // index of the commit to check next
+    xlen: Xlen,
+}
+
+impl std::fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Verifier")
+            .field("next", &self.next)
+            .field("xlen", &self.xlen)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Verifier {
+    /// Open the reference commit log at `path` of a program of `xlen`.
+    pub fn open(path: &Path, xlen: Xlen) -> Result<Verifier> {
+        let log = BufReader::new(File::open(path)?);
+        Ok(Verifier::from_reader(Box::new(log), xlen))
+    }
+
+    fn from_reader(log: Box<dyn BufRead>, xlen: Xlen) -> Verifier {
+        Verifier {
+            lines: log.lines(),
+            next: 0,
+            xlen,
+        }
+    }
+
+    /// Next commit of the log, [`None`] at its end.
+    fn next_commit(&mut self) -> Result<Option<Commit>> {
+        for line in self.lines.by_ref() {
+            if let Some(commit) = Commit::parse(&line?)? {
+                return Ok(Some(commit));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Check the instruction `inst` at `pc` retiring with `write = Some((rd,
+    /// value))` if it wrote a register, against the next reference commit.
+    pub fn check(&mut self, pc: u64, inst: u32, write: Option<(u8, u64)>) -> Result<()> {
+        let actual = Commit {
+            pc,
+            inst,
+            write: write
+                .filter(|&(rd, _)| rd != 0)
+                .map(|(rd, value)| (rd, self.xlen.trunc(value))),
+        };
+        let n = self.next;
+        let Some(expected) = self.next_commit()? else {
+            let diff = format!("  reference: ended after {n} instructions\n  emulator:  {actual}");
+            return Err(mismatch(pc, diff));
+        };
+        self.next += 1;
+        let expected_write = expected
+            .write
+            .map(|(rd, value)| (rd, self.xlen.trunc(value)));
+        // the emulator retires compressed instructions expanded, so the low
+        // half logged by the reference is expanded too
+        let expected_inst = if rvc::inst_len(expected.inst) == 2 {
+            rvc::expand(expected.inst as u16, self.xlen).unwrap_or(expected.inst)
+        } else {
+            expected.inst
+        };
+        if expected.pc != actual.pc
+            || expected_inst != actual.inst
+            || expected_write != actual.write
+        {
+            let diff =
+                format!("  reference: {expected}\n  emulator:  {actual}\n  (instruction {n})");
+            return Err(mismatch(pc, diff));
+        }
+        Ok(())
+    }
+}
+
+/// Log the mismatch at `pc`, which stops the run.
+fn mismatch(pc: u64, diff: String) -> Error {
+    let err = Error::Mismatch(pc, diff);
+    error!("{err}");
+    err
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(
+            Commit::parse("core   0: 3 0x0000000080000000 (0x00000297) x5  0x0000000080000000")
+                .unwrap(),
+            Some(Commit {
+                pc: 0x8000_0000,
+                inst: 0x297,
+                write: Some((5, 0x8000_0000))
+            })
+        );
+        assert_eq!(
+            Commit::parse("core 0: 0x80000004 (0x00000013)").unwrap(),
+            Some(Commit {
+                pc: 0x8000_0004,
+                inst: 0x13,
+                write: None
+            })
+        );
+        // stores and x0 write nothing to check
+        let store = Commit::parse("core 0: 0x8 (0x00a12023) mem 0x80001000 0x5").unwrap();
+        assert_eq!(store.unwrap().write, None);
+        let x0 = Commit::parse("core 0: 0xc (0x00000013) x0 0x0").unwrap();
+        assert_eq!(x0.unwrap().write, None);
+
+        assert_eq!(Commit::parse("core   0: >>>>  main").unwrap(), None);
+        assert_eq!(Commit::parse("bbl loader").unwrap(), None);
+        assert!(Commit::parse("core 0: 0x10 (0x00b50533) x10").is_err());
+        assert!(Commit::parse("core 0: 0x10 0x00b50533").is_err());
+    }
+
+    #[test]
+    fn verify_test() {
+        let log = "core 0: 0x80000000 (0x00500513) x10 0x5\n\
+                   core 0: 0x80000004 (0x00151513) x10 0xa\n";
+        let mut verifier = Verifier::from_reader(Box::new(log.as_bytes()), Xlen::Rv64);
+        verifier
+            .check(0x8000_0000, 0x00500513, Some((10, 5)))
+            .unwrap();
+        // slli by a wrong shift amount
+        let err = verifier
+            .check(0x8000_0004, 0x00151513, Some((10, 0x14)))
+            .unwrap_err();
+        assert!(matches!(err, Error::Mismatch(0x8000_0004, _)), "{err}");
+        assert!(matches!(
+            verifier.check(0x8000_0008, 0x13, None),
+            Err(Error::Mismatch(0x8000_0008, _))
+        ));
+
+        // values compared in XLEN bits
+        let log = "core 0: 0x0 (0x00000013) x10 0xffffffff";
+        let mut verifier = Verifier::from_reader(Box::new(log.as_bytes()), Xlen::Rv32);
+        verifier.check(0, 0x13, Some((10, u64::MAX))).unwrap();
+
+        // c.li a0, 5 retires expanded, another instruction is a mismatch
+        let log = "core 0: 0x0 (0x4515) x10 0x5\n\
+                   core 0: 0x2 (0x00000013)\n\
+                   core 0: 0x6 (0x00b50533) x10\n";
+        let mut verifier = Verifier::from_reader(Box::new(log.as_bytes()), Xlen::Rv64);
+        verifier.check(0, 0x00500513, Some((10, 5))).unwrap();
+        assert!(matches!(
+            verifier.check(2, 0x00100013, None),
+            Err(Error::Mismatch(2, _))
+        ));
+        // lines are parsed as they are reached
+        assert!(matches!(
+            verifier.check(6, 0x00b50533, Some((10, 5))),
+            Err(Error::InvalidReference(_))
+        ));
+    }
+}
@@ -0,0 +1,18 @@
+    .text
+    .globl main
+    .type main, @function
+main:
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    jal ra, add_one
+    ld ra, 8(sp)
+    addi sp, sp, 16
+    ret
+    .size main, .-main
+
+    .globl add_one
+    .type add_one, @function
+add_one:
+    addi a0, a0, 1
+    ret
+    .size add_one, .-add_one
@@ -0,0 +1,207 @@
+//! Run the ELF fixtures of `tests/fixtures` to their end on every CPU, and
+//! check that all of them exit with the expected a0 and retire the same
+//! instructions. The fixtures are built from their sources by
+//! `tests/fixtures/mkelf.py`.
+
+use std::process::{Command, Output};
+
+/// CPU mode and policies of each run
+const CPUS: [&[&str]; 6] = [
+    &["-c", "single"],
+    &["-c", "multi"],
+    &[
+        "-c",
+        "pipeline",
+        "--data-hazard-policy",
+        "naive-stall",
+        "--control-policy",
+        "all-stall",
+    ],
+    &[
+        "-c",
+        "pipeline",
+        "--data-hazard-policy",
+        "data-forward",
+        "--control-policy",
+        "always-not-taken",
+    ],
+    &[
+        "-c",
+        "pipeline",
+        "--data-hazard-policy",
+        "data-forward",
+        "--control-policy",
+        "always-taken",
+    ],
+    &[
+        "-c",
+        "pipeline",
+        "--data-hazard-policy",
+        "data-forward",
+        "--control-policy",
+        "dynamic-predict",
+        "--predict-policy",
+        "gshare",
+    ],
+];
+
+/// A path in the temporary directory unique to this test process.
+fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("riscv-emulator-{}-{name}", std::process::id()))
+}
+
+/// Output of the emulator running `fixture` on `cpu` with `options`.
+fn emulate(options: &[&str], fixture: &str, cpu: &[&str]) -> Output {
+    let path = format!("{}/tests/fixtures/{fixture}", env!("CARGO_MANIFEST_DIR"));
+    Command::new(env!("CARGO_BIN_EXE_riscv-emulator"))
+        .args(options)
+        .args(cpu)
+        .args(["-i", &path])
+        .output()
+        .expect("Fail to run the emulator")
+}
+
+/// Exit code and retired instructions of `fixture` run headless on `cpu`.
+fn run(fixture: &str, cpu: &[&str]) -> (i64, u64) {
+    let output = emulate(&["--headless"], fixture, cpu);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let field = |name: &str| {
+        stdout
+            .split_whitespace()
+            .find_map(|token| token.strip_prefix(name)?.strip_prefix('='))
+            .unwrap_or_else(|| panic!("no {name} of {fixture} on {cpu:?}: {stdout}"))
+    };
+    let exit_code: i64 = field("exit_code").parse().unwrap();
+    let retired: u64 = field("retired").parse().unwrap();
+    // the process exits with the low byte of the exit code
+    assert_eq!(output.status.code(), Some(exit_code as u8 as i32));
+    (exit_code, retired)
+}
+
+/// Run `fixture` on every CPU, which all must exit with `exit_code`.
+fn check(fixture: &str, exit_code: i64) {
+    let (_, retired) = run(fixture, CPUS[0]);
+    for cpu in CPUS {
+        assert_eq!(
+            run(fixture, cpu),
+            (exit_code, retired),
+            "{fixture} on {cpu:?}"
+        );
+    }
+}
+
+#[test]
+fn factorial() {
+    check("factorial.elf", 3628800);
+}
+
+#[test]
+fn memcpy() {
+    // 1 + 2 + ... + 37
+    check("memcpy.elf", 703);
+}
+
+#[test]
+fn collatz() {
+    check("collatz.elf", 111);
+}
+
+#[test]
+fn quiet() {
+    // nothing but the result, not even what the pipeline logs on wrong paths
+    for cpu in CPUS {
+        let (_, retired) = run("collatz.elf", cpu);
+        let output = emulate(&["--quiet"], "collatz.elf", cpu);
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<_> = stdout.lines().collect();
+        assert_eq!(lines.len(), 1, "{cpu:?}: {stdout}");
+        assert!(
+            lines[0].starts_with("exit_code=111 clock="),
+            "{cpu:?}: {stdout}"
+        );
+        assert!(
+            lines[0].ends_with(&format!(" retired={retired}")),
+            "{cpu:?}: {stdout}"
+        );
+    }
+}
+
+#[test]
+fn stdin() {
+    let input = format!("{}/tests/fixtures/echo.in", env!("CARGO_MANIFEST_DIR"));
+    for cpu in CPUS {
+        let output = emulate(&["--quiet", "--stdin", &input], "echo.elf", cpu);
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<_> = stdout.lines().collect();
+        assert_eq!(lines.len(), 2, "{cpu:?}: {stdout}");
+        assert_eq!(lines[0], "first line", "{cpu:?}");
+        // the length of the line with its newline
+        assert!(lines[1].starts_with("exit_code=11 "), "{cpu:?}: {stdout}");
+    }
+    // stdin is empty without --stdin
+    assert_eq!(run("echo.elf", CPUS[0]).0, 0);
+}
+
+#[test]
+fn unsupported() {
+    assert_eq!(run("float.elf", CPUS[0]), (0, 4));
+    // the other CPUs stop on the flw with an error, not a panic
+    for cpu in &CPUS[1..] {
+        let output = emulate(&[], "float.elf", cpu);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(output.status.code(), Some(1), "{cpu:?}: {stdout}");
+        assert!(
+            stdout.contains("Unsupported instruction flw at 0x80000078"),
+            "{cpu:?}: {stdout}"
+        );
+    }
+    // told on stderr with --quiet, after what is asked for at the end
+    let stats = temp_path("unsupported.json");
+    let stats_output = stats.to_str().unwrap();
+    let output = emulate(
+        &["--quiet", "--stats-output", stats_output],
+        "float.elf",
+        CPUS[3],
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(
+        stderr.contains("Unsupported instruction flw at 0x80000078"),
+        "{stderr}"
+    );
+    assert!(std::fs::read_to_string(&stats).unwrap().contains("clock"));
+    std::fs::remove_file(&stats).unwrap();
+}
+
+#[test]
+fn preloaded_breakpoints() {
+    // the entry point stops before the first instruction runs
+    for cpu in [CPUS[0], CPUS[3]] {
+        let output = emulate(&["--break", "0x80000078"], "factorial.elf", cpu);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            stdout.contains("REDB: hit breakpoint at 0x80000078"),
+            "{cpu:?}: {stdout}"
+        );
+    }
+    // an unknown symbol is a usage error
+    let output = emulate(&["--break-symbol", "nosuch"], "factorial.elf", CPUS[0]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{stderr}");
+    assert!(stderr.contains("unknown symbol 'nosuch'"), "{stderr}");
+}
+
+#[test]
+fn pipeline_diagram_in_debugger() {
+    // printed when the batch debugger runs the program to the end too
+    let output = emulate(
+        &["--break", "0x80000078", "--pipeline-diagram", "3"],
+        "factorial.elf",
+        CPUS[3],
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Pipeline diagram of the last 3 instructions"),
+        "{stdout}"
+    );
+}
@@ -0,0 +1,22 @@
+# exit with the number of Collatz steps from 27 down to 1
+    .text
+    .globl _start
+_start:
+    li a1, 27
+    li a0, 0
+    li t1, 1
+1:
+    beq a1, t1, 3f
+    addi a0, a0, 1
+    andi t0, a1, 1
+    bnez t0, 2f
+    srli a1, a1, 1
+    j 1b
+2:
+    slli t0, a1, 1
+    add a1, a1, t0
+    addi a1, a1, 1
+    j 1b
+3:
+    li a7, 93
+    ecall
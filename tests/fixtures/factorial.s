@@ -0,0 +1,26 @@
+# exit(fact(10)), with fact recursive
+    .text
+    .globl _start
+_start:
+    li a0, 10
+    call fact
+    li a7, 93
+    ecall
+
+# a0 = a0!
+fact:
+    li t0, 1
+    ble a0, t0, 1f
+    addi sp, sp, -16
+    sd ra, 8(sp)
+    sd a0, 0(sp)
+    addi a0, a0, -1
+    call fact
+    ld t0, 0(sp)
+    ld ra, 8(sp)
+    addi sp, sp, 16
+    mul a0, a0, t0
+    ret
+1:
+    li a0, 1
+    ret
@@ -0,0 +1,48 @@
+# copy 37 bytes of 1, 2, ..., 37 a byte at a time, then exit with the sum of
+# the bytes copied, read back a word at a time
+    .text
+    .globl _start
+_start:
+    la a0, dst
+    la a1, src
+    li a2, 37
+    call memcpy
+    la t0, dst
+    addi t1, t0, 40
+    li a0, 0
+1:
+    lw t2, 0(t0)
+    andi t3, t2, 0xff
+    add a0, a0, t3
+    srli t3, t2, 8
+    andi t3, t3, 0xff
+    add a0, a0, t3
+    srli t3, t2, 16
+    andi t3, t3, 0xff
+    add a0, a0, t3
+    srli t3, t2, 24
+    add a0, a0, t3
+    addi t0, t0, 4
+    bltu t0, t1, 1b
+    li a7, 93
+    ecall
+
+# copy a2 bytes from a1 to a0
+memcpy:
+    beqz a2, 2f
+1:
+    lbu t0, 0(a1)
+    sb t0, 0(a0)
+    addi a0, a0, 1
+    addi a1, a1, 1
+    addi a2, a2, -1
+    bnez a2, 1b
+2:
+    ret
+
+src:
+    .byte 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19
+    .byte 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37
+    .byte 0, 0, 0 # to align dst
+dst:
+    .zero 40
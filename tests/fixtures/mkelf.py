@@ -0,0 +1,70 @@
+#!/usr/bin/env python3
+"""Build the ELF fixtures of the integration tests from their sources.
+
+Each `<name>.s` is assembled by llvm-mc into `<name>.elf`: an RV64 executable
+with a single RWX PT_LOAD segment of the whole file at 0x80000000, the text
+starting right after the headers. There is no linker, so a source is kept in
+one section and must not leave relocations behind.
+
+    python3 tests/fixtures/mkelf.py
+"""
+
+import pathlib
+import struct
+import subprocess
+import tempfile
+
+VADDR = 0x80000000
+TEXT_OFFSET = 64 + 56  # ELF header and one program header
+EM_RISCV = 243
+PT_LOAD = 1
+PF_RWX = 7
+
+
+def assemble(source: pathlib.Path) -> bytes:
+    with tempfile.TemporaryDirectory() as tmp:
+        obj = pathlib.Path(tmp) / "out.o"
+        text = pathlib.Path(tmp) / "out.bin"
+        subprocess.run(
+            ["llvm-mc", "-triple=riscv64", "-mattr=+m,+f,-relax", "-filetype=obj",
+             str(source), "-o", str(obj)],
+            check=True,
+        )
+        relocs = subprocess.run(
+            ["llvm-objdump", "-r", str(obj)], check=True, capture_output=True, text=True
+        ).stdout
+        if "R_RISCV" in relocs:
+            raise SystemExit(f"{source}: relocations left\n{relocs}")
+        subprocess.run(
+            ["llvm-objcopy", "-O", "binary", "--only-section=.text", str(obj), str(text)],
+            check=True,
+        )
+        return text.read_bytes()
+
+
+def elf(text: bytes) -> bytes:
+    size = TEXT_OFFSET + len(text)
+    ident = b"\x7fELF\x02\x01\x01".ljust(16, b"\0")
+    header = ident + struct.pack(
+        "<HHIQQQIHHHHHH",
+        2,  # ET_EXEC
+        EM_RISCV,
+        1,  # e_version
+        VADDR + TEXT_OFFSET,  # e_entry
+        64,  # e_phoff
+        0,  # e_shoff
+        0,  # e_flags
+        64, 56, 1, 64, 0, 0,  # e_ehsize, e_phentsize, e_phnum, section headers
+    )
+    phdr = struct.pack("<IIQQQQQQ", PT_LOAD, PF_RWX, 0, VADDR, VADDR, size, size, 0x1000)
+    return header + phdr + text
+
+
+def main():
+    fixtures = pathlib.Path(__file__).parent
+    for source in sorted(fixtures.glob("*.s")):
+        source.with_suffix(".elf").write_bytes(elf(assemble(source)))
+
+
+if __name__ == "__main__":
+    main()